@@ -0,0 +1,453 @@
+//! Active objects: players, mobs, dropped items and other entities, added and
+//! removed through TOCLIENT_ACTIVE_OBJECT_REMOVE_ADD and updated through
+//! TOCLIENT_ACTIVE_OBJECT_MESSAGES. They are entities in the World, see
+//! World::add_active_object.
+
+use std::f32::consts::PI;
+
+use glam::{EulerRot, I16Vec2, Quat, Vec2, Vec3, Vec4};
+use luanti_protocol::types::{ActiveObjectCommand, GenericInitData, ObjectProperties, SColor};
+
+use crate::camera_controller::{PhysicsOverride, PlayerPos};
+use crate::light::{self, LIGHT_SUN};
+use crate::luanti_client::BS;
+use crate::srgb_to_linear;
+
+/// Wraps an angle in degrees to -180..180.
+pub fn wrap_degrees_180(angle: f32) -> f32 {
+    (angle + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Converts a rotation in degrees, as sent by the server, to a quaternion.
+// Compare to Luanti, util/numeric.cpp, setPitchYawRollRad
+pub fn rotation_to_quat(rotation: Vec3) -> Quat {
+    let rad = rotation * PI / 180.0;
+    Quat::from_euler(EulerRot::YXZ, rad.y, rad.x, rad.z)
+}
+
+pub fn quat_to_rotation(quat: Quat) -> Vec3 {
+    let (yaw, pitch, roll) = quat.to_euler(EulerRot::YXZ);
+    Vec3::new(pitch, yaw, roll) * 180.0 / PI
+}
+
+/// Smoothly moves a value towards the target of the last update, over the
+/// time between updates.
+// Compare to Luanti, client/content_cao.h, SmoothTranslator
+#[derive(Debug, Clone, Copy)]
+struct SmoothTranslator {
+    old: Vec3,
+    current: Vec3,
+    target: Vec3,
+    /// Expected time until the next update, in seconds
+    anim_time: f32,
+    anim_time_counter: f32,
+    /// Whether the target is where the movement ends. If not, the value may
+    /// overshoot it while waiting for the next update.
+    aim_is_end: bool,
+    /// For angles in degrees: take the shorter way around
+    wrap: bool,
+}
+
+impl SmoothTranslator {
+    fn new(value: Vec3, wrap: bool) -> Self {
+        Self {
+            old: value,
+            current: value,
+            target: value,
+            anim_time: 0.0,
+            anim_time_counter: 0.0,
+            aim_is_end: true,
+            wrap,
+        }
+    }
+
+    /// Jumps to the value without interpolation.
+    fn init(&mut self, value: Vec3) {
+        *self = Self::new(value, self.wrap);
+    }
+
+    fn update(&mut self, target: Vec3, is_end: bool, update_interval: f32) {
+        self.aim_is_end = is_end;
+        self.old = self.current;
+        self.target = target;
+        if update_interval > 0.0 {
+            self.anim_time = update_interval;
+        } else if self.anim_time < 0.001 || self.anim_time > 1.0 {
+            self.anim_time = self.anim_time_counter;
+        } else {
+            self.anim_time = self.anim_time * 0.9 + self.anim_time_counter * 0.1;
+        }
+        self.anim_time_counter = 0.0;
+    }
+
+    fn translate(&mut self, dtime: f32) {
+        self.anim_time_counter += dtime;
+        let mut diff = self.target - self.old;
+        if self.wrap {
+            diff = diff.map(wrap_degrees_180);
+        }
+        let mut move_ratio = 1.0;
+        if self.anim_time > 0.001 {
+            move_ratio = self.anim_time_counter / self.anim_time;
+        }
+        let move_end = if self.aim_is_end { 1.0 } else { 1.5 };
+        // Move a bit less than we should, to avoid oscillation
+        move_ratio = (move_ratio * 0.8).min(move_end);
+        self.current = self.old + diff * move_ratio;
+    }
+}
+
+/// Spritesheet state of "sprite" visuals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpriteAnimation {
+    /// Column and row of the first frame in the spritesheet
+    pub base_pos: I16Vec2,
+    num_frames: u16,
+    /// In seconds
+    frame_length: f32,
+    /// Pick the column depending on the direction the object is seen from
+    pub select_horiz_by_yawpitch: bool,
+    timer: f32,
+    /// Frames go downwards from base_pos
+    pub frame: u16,
+}
+
+impl SpriteAnimation {
+    fn step(&mut self, dtime: f32) {
+        if self.num_frames <= 1 || self.frame_length <= 0.0 {
+            return;
+        }
+        self.timer += dtime;
+        while self.timer >= self.frame_length {
+            self.timer -= self.frame_length;
+            self.frame = (self.frame + 1) % self.num_frames;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub parent_id: u16,
+    /// Relative to the parent, in nodes
+    pub position: Vec3,
+    /// Relative to the parent, in degrees
+    pub rotation: Vec3,
+}
+
+/// An object lost health by being punched, see ActiveObject::process_message.
+pub struct Damage {
+    pub is_player: bool,
+    /// Whether the health reached 0
+    pub died: bool,
+}
+
+/// The text shown above an object, see ActiveObject::nametag.
+pub struct Nametag<'a> {
+    /// May contain color escapes and several lines
+    pub text: &'a str,
+    pub color: Vec4,
+    pub background: Vec4,
+    /// The bottom center of the nametag, in world coordinates
+    pub pos: Vec3,
+}
+
+fn color_to_linear(color: SColor) -> Vec4 {
+    Vec4::new(
+        srgb_to_linear(color.r),
+        srgb_to_linear(color.g),
+        srgb_to_linear(color.b),
+        color.a as f32 / 255.0,
+    )
+}
+
+/// An active object of the "generic" type, the only one used by current
+/// Luanti servers.
+// Compare to Luanti, client/content_cao.h, GenericCAO
+pub struct ActiveObject {
+    /// The player controlled by this client, which isn't drawn in first
+    /// person
+    pub is_local_player: bool,
+    pub is_player: bool,
+    hp: u16,
+    /// While positive, the damage_texture_modifier is applied, in seconds
+    damage_timer: f32,
+    /// Where the object is drawn, interpolated between updates. In nodes.
+    pub pos: Vec3,
+    /// Where the object is drawn, in degrees
+    pub rotation: Vec3,
+    /// None until TOCLIENT_ACTIVE_OBJECT_MESSAGES/SetProperties is received,
+    /// which is always part of the initialization data
+    pub props: Option<ObjectProperties>,
+    /// Appended to all textures
+    pub texture_mod: String,
+    pub sprite: SpriteAnimation,
+    /// Only used for the local player
+    pub physics_override: PhysicsOverride,
+    /// Day and night light at the object's position, see light::vertex_light.
+    /// Updated by World::update_object_light.
+    pub light: Vec2,
+
+    /// Last position sent by the server, extrapolated with the velocity and
+    /// acceleration. In nodes.
+    position: Vec3,
+    /// In nodes per second
+    velocity: Vec3,
+    /// In nodes per second²
+    acceleration: Vec3,
+    pos_translator: SmoothTranslator,
+    rot_translator: SmoothTranslator,
+    /// Attached objects are positioned by World::step_active_objects
+    pub attachment: Option<Attachment>,
+}
+
+impl ActiveObject {
+    // Compare to Luanti, activeobject.h, ActiveObjectType
+    pub const TYPE_GENERIC: u8 = 101;
+
+    /// Creates the object from its initialization data. `local_player_name`
+    /// is the name this client logged in with.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::initialize
+    pub fn from_init_data(data: GenericInitData, local_player_name: &str) -> Self {
+        let position = data.position / BS;
+        let mut object = Self {
+            is_local_player: data.is_player && data.name == local_player_name,
+            is_player: data.is_player,
+            hp: data.hp,
+            damage_timer: 0.0,
+            pos: position,
+            rotation: data.rotation,
+            props: None,
+            texture_mod: String::new(),
+            sprite: SpriteAnimation::default(),
+            physics_override: PhysicsOverride::default(),
+            light: light::vertex_light(LIGHT_SUN, 0, 0),
+
+            position,
+            velocity: Vec3::ZERO,
+            acceleration: Vec3::ZERO,
+            pos_translator: SmoothTranslator::new(position, false),
+            rot_translator: SmoothTranslator::new(data.rotation, true),
+            attachment: None,
+        };
+        for message in data.messages {
+            object.process_message(message);
+        }
+        object
+    }
+
+    /// Returns Some if the object lost health.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::processMessage
+    pub fn process_message(&mut self, message: ActiveObjectCommand) -> Option<Damage> {
+        match message {
+            ActiveObjectCommand::SetProperties(command) => {
+                self.sprite.base_pos = command.newprops.initial_sprite_basepos;
+                self.props = Some(command.newprops);
+            }
+            ActiveObjectCommand::UpdatePosition(command) => {
+                self.position = command.position / BS;
+                self.velocity = command.velocity / BS;
+                self.acceleration = command.acceleration / BS;
+
+                let physical = self.props.as_ref().is_some_and(|props| props.physical);
+                if !command.do_interpolate {
+                    self.pos_translator.init(self.position);
+                } else if !physical {
+                    self.pos_translator.update(
+                        self.position,
+                        command.is_end_position,
+                        command.update_interval,
+                    );
+                }
+                self.rot_translator
+                    .update(command.rotation, false, command.update_interval);
+            }
+            ActiveObjectCommand::SetTextureMod(command) => {
+                self.texture_mod = command.modifier;
+            }
+            ActiveObjectCommand::SetSprite(command) => {
+                self.sprite = SpriteAnimation {
+                    base_pos: command.base_pos,
+                    num_frames: command.anim_num_frames,
+                    frame_length: command.anim_frame_length,
+                    select_horiz_by_yawpitch: command.select_horiz_by_yawpitch,
+                    timer: 0.0,
+                    frame: 0,
+                };
+            }
+            ActiveObjectCommand::SetPhysicsOverride(command) => {
+                // TODO: the sneak, sneak_glitch and new_move flags
+                self.physics_override = PhysicsOverride {
+                    speed: command.override_speed,
+                    jump: command.override_jump,
+                    gravity: command.override_gravity,
+                };
+            }
+            ActiveObjectCommand::AttachTo(command) => {
+                // TODO: bones, once there are mesh visuals
+                self.attachment = u16::try_from(command.parent_id)
+                    .ok()
+                    .filter(|&parent_id| parent_id != 0)
+                    .map(|parent_id| Attachment {
+                        parent_id,
+                        position: command.position / BS,
+                        rotation: command.rotation,
+                    });
+            }
+            ActiveObjectCommand::Punched(command) => {
+                let damage = self.hp.saturating_sub(command.result_hp);
+                self.hp = command.result_hp;
+                if self.hp == 0 {
+                    // Dead objects fall off what they are attached to
+                    self.attachment = None;
+                }
+                if damage == 0 {
+                    return None;
+                }
+                // Flash longer for more damage, at most 1 second
+                let has_modifier = self
+                    .props
+                    .as_ref()
+                    .is_some_and(|props| !props.damage_texture_modifier.is_empty());
+                if self.hp > 0 && self.damage_timer <= 0.0 && has_modifier {
+                    let extra = if damage >= 2 {
+                        0.05 * damage as f32
+                    } else {
+                        0.0
+                    };
+                    self.damage_timer = (0.05 + extra).min(1.0);
+                }
+                return Some(Damage {
+                    is_player: self.is_player,
+                    died: self.hp == 0,
+                });
+            }
+            // TODO: the other messages, e.g. animations and armor groups
+            _ => (),
+        }
+        None
+    }
+
+    /// The texture modifier appended to all textures, including the damage
+    /// flash.
+    pub fn texture_modifier(&self) -> String {
+        match &self.props {
+            Some(props) if self.damage_timer > 0.0 => {
+                format!("{}{}", self.texture_mod, props.damage_texture_modifier)
+            }
+            _ => self.texture_mod.clone(),
+        }
+    }
+
+    /// Moves the object along its velocity and towards the last update.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::step
+    pub fn step(&mut self, dtime: f32) {
+        self.sprite.step(dtime);
+        self.damage_timer -= dtime;
+        if self.attachment.is_some() {
+            return;
+        }
+
+        // TODO: collisions for physical objects
+        self.position += dtime * self.velocity + 0.5 * dtime * dtime * self.acceleration;
+        self.velocity += dtime * self.acceleration;
+        let translator = self.pos_translator;
+        self.pos_translator
+            .update(self.position, translator.aim_is_end, translator.anim_time);
+        self.pos_translator.translate(dtime);
+        self.pos = self.pos_translator.current;
+
+        // In radians per second
+        let automatic_rotate = self
+            .props
+            .as_ref()
+            .map_or(0.0, |props| props.automatic_rotate);
+        if automatic_rotate.abs() > 0.001 {
+            let yaw = self.rot_translator.current.y + dtime * automatic_rotate * 180.0 / PI;
+            self.rot_translator.init(Vec3 {
+                y: yaw.rem_euclid(360.0),
+                ..self.rot_translator.current
+            });
+        } else {
+            self.rot_translator.translate(dtime);
+        }
+        self.rotation = self.rot_translator.current;
+    }
+
+    /// Moves the local player's object to where the player is. The server
+    /// doesn't send the position of its own object to a client.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::step
+    pub fn follow_local_player(&mut self, player: &PlayerPos) {
+        if self.attachment.is_some() {
+            return;
+        }
+        self.position = player.pos;
+        self.velocity = Vec3::ZERO;
+        self.acceleration = Vec3::ZERO;
+        self.pos_translator.init(player.pos);
+        // The client's yaw is mirrored, see LuantiClientRunner::network_player_pos
+        self.rot_translator
+            .init(Vec3::new(0.0, (-player.yaw).rem_euclid(360.0), 0.0));
+    }
+
+    /// The selection box in world coordinates, None if the object can't be
+    /// pointed at.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::getSelectionBox
+    pub fn selection_box(&self) -> Option<(Vec3, Vec3)> {
+        let props = self.props.as_ref()?;
+        if self.is_local_player || !props.pointable {
+            return None;
+        }
+        // TODO: rotate the box if "selectionbox.rotate" is set
+        let aabb = &props.selection_box;
+        Some((self.pos + aabb.min_edge, self.pos + aabb.max_edge))
+    }
+
+    /// The positions the object's light is sampled at: the corners and the
+    /// center of its collision box.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::getLightPosition
+    pub fn light_positions(&self) -> [Vec3; 3] {
+        let Some(props) = self.props.as_ref() else {
+            return [self.pos; 3];
+        };
+        let aabb = &props.collision_box;
+        [
+            self.pos + aabb.min_edge,
+            self.pos + aabb.max_edge,
+            self.pos + (aabb.min_edge + aabb.max_edge) / 2.0,
+        ]
+    }
+
+    /// The nametag shown above the object, None if it has none. Invisible
+    /// objects can still have one.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::updateNametag
+    pub fn nametag(&self) -> Option<Nametag<'_>> {
+        let props = self.props.as_ref()?;
+        if self.is_local_player || props.nametag.is_empty() || props.nametag_color.a == 0 {
+            return None;
+        }
+        // Compare to Luanti, client/camera.cpp, Nametag::getBgColor
+        let background = props.nametag_bgcolor.map_or(
+            Vec4::new(
+                srgb_to_linear(50),
+                srgb_to_linear(50),
+                srgb_to_linear(50),
+                50.0 / 255.0,
+            ),
+            color_to_linear,
+        );
+        Some(Nametag {
+            text: &props.nametag,
+            color: color_to_linear(props.nametag_color),
+            background,
+            pos: self.pos + Vec3::Y * (props.selection_box.max_edge.y + 0.3),
+        })
+    }
+
+    /// Whether the object should be drawn. In first person, the camera is
+    /// inside of the local player.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::updateVisibility
+    pub fn is_visible(&self, first_person: bool) -> bool {
+        !(self.is_local_player && first_person)
+            && self.props.as_ref().is_some_and(|props| props.is_visible)
+    }
+}
@@ -0,0 +1,259 @@
+//! Sound playback for TOCLIENT_PLAY_SOUND and TOCLIENT_STOP_SOUND, using
+//! rodio. Sounds are .ogg files in the MediaManager, see SoundManager.
+
+use std::collections::HashMap;
+
+use glam::Vec3;
+use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+
+use crate::media::MediaManager;
+use crate::sound::SoundManager;
+
+/// Where a sound is played.
+#[derive(Debug, Clone, Copy)]
+pub enum SoundLocation {
+    /// At the listener, e.g. UI sounds
+    Local,
+    /// At a position, in nodes
+    Pos(Vec3),
+    /// Attached to an active object
+    Object(u16),
+}
+
+/// A sound to play, as sent by the server.
+// Compare to Luanti, sound.h, SoundSpec
+#[derive(Debug, Clone)]
+pub struct SoundSpec {
+    /// A sound group: one of "<name>.ogg" and "<name>.<N>.ogg" is played
+    pub name: String,
+    pub gain: f32,
+    /// 1.0 is the original pitch, also changes the speed
+    pub pitch: f32,
+    pub looped: bool,
+    /// Gain per second to fade in with, 0 to start at full gain
+    pub fade: f32,
+    pub location: SoundLocation,
+    /// Ephemeral sounds can't be stopped and aren't reported when finished
+    pub ephemeral: bool,
+}
+
+struct PlayingSound {
+    sink: Sink,
+    /// Before distance attenuation
+    gain: f32,
+    target_gain: f32,
+    fade: f32,
+    location: SoundLocation,
+    /// Where the sound is played, None for local sounds. Follows the object
+    /// for object sounds, and stays at its last position if it's removed.
+    pos: Option<Vec3>,
+    /// None for ephemeral sounds and sounds started by the client
+    server_id: Option<i32>,
+}
+
+/// Plays the sounds sent by the server. Without an audio device, all sounds
+/// are ignored.
+pub struct Audio {
+    /// The stream must be kept alive for the handle to work
+    output: Option<(OutputStream, OutputStreamHandle)>,
+    /// By client-side handle. The server sends all ephemeral sounds with
+    /// the same id, so its ids can't be used.
+    sounds: HashMap<u64, PlayingSound>,
+    next_handle: u64,
+    /// Handles of the non-ephemeral sounds, by server id
+    server_ids: HashMap<i32, u64>,
+    sound_manager: SoundManager,
+}
+
+impl Audio {
+    /// Distance up to which sounds are played at full gain, in nodes. Like
+    /// OpenAL's AL_REFERENCE_DISTANCE in Luanti.
+    const REFERENCE_DISTANCE: f32 = 10.0;
+
+    /// `deterministic` seeds the choice of sound files, see
+    /// Settings::deterministic
+    pub fn new(deterministic: bool) -> Self {
+        let output = match OutputStream::try_default() {
+            Ok(output) => Some(output),
+            Err(err) => {
+                println!("Could not open audio device, sound is disabled: {}", err);
+                None
+            }
+        };
+        Self {
+            output,
+            sounds: HashMap::new(),
+            next_handle: 0,
+            server_ids: HashMap::new(),
+            sound_manager: SoundManager::new(deterministic),
+        }
+    }
+
+    /// Starts playing a sound sent by the server. `object_pos` is the
+    /// position of the active object with the given id, for object sounds.
+    // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_PlaySound
+    pub fn play_server(
+        &mut self,
+        media: &MediaManager,
+        server_id: i32,
+        spec: SoundSpec,
+        object_pos: impl Fn(u16) -> Option<Vec3>,
+    ) -> anyhow::Result<()> {
+        // Replaces (and stops) a sound with the same id
+        if !spec.ephemeral {
+            self.stop(server_id);
+        }
+        let ephemeral = spec.ephemeral;
+        let Some(handle) = self.play(media, spec, object_pos)? else {
+            return Ok(());
+        };
+        if !ephemeral {
+            self.sounds.get_mut(&handle).unwrap().server_id = Some(server_id);
+            self.server_ids.insert(server_id, handle);
+        }
+        Ok(())
+    }
+
+    /// Starts playing a sound, returning its handle. If no file of the sound
+    /// group exists, nothing is played, like in Luanti.
+    // Compare to Luanti, client/sound/sound_manager.cpp, OpenALSoundManager::playSound
+    fn play(
+        &mut self,
+        media: &MediaManager,
+        spec: SoundSpec,
+        object_pos: impl Fn(u16) -> Option<Vec3>,
+    ) -> anyhow::Result<Option<u64>> {
+        let Some((_, handle)) = &self.output else {
+            return Ok(None);
+        };
+        let Some(source) = self.sound_manager.get(media, &spec.name)? else {
+            println!("Sound \"{}\" not found", spec.name);
+            return Ok(None);
+        };
+
+        let sink = Sink::try_new(handle)?;
+        if spec.pitch > 0.0 {
+            sink.set_speed(spec.pitch);
+        }
+        if spec.looped {
+            sink.append(source.repeat_infinite());
+        } else {
+            sink.append(source);
+        }
+
+        let gain = if spec.fade > 0.0 { 0.0 } else { spec.gain };
+        // The volume is set by `step`, which also attenuates it
+        sink.set_volume(0.0);
+        let pos = match spec.location {
+            SoundLocation::Local => None,
+            SoundLocation::Pos(pos) => Some(pos),
+            // At the origin if the object is unknown, like in Luanti
+            SoundLocation::Object(id) => Some(object_pos(id).unwrap_or(Vec3::ZERO)),
+        };
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.sounds.insert(
+            handle,
+            PlayingSound {
+                sink,
+                gain,
+                target_gain: spec.gain,
+                fade: spec.fade,
+                location: spec.location,
+                pos,
+                server_id: None,
+            },
+        );
+        Ok(Some(handle))
+    }
+
+    /// Plays a sound started by the client itself, e.g. for player damage.
+    /// It gets its own handle like every sound, so it can neither replace
+    /// nor be replaced by the sounds of the server.
+    // Compare to Luanti, client/sound/sound_maker.h, SoundMaker
+    pub fn play_local(
+        &mut self,
+        media: &MediaManager,
+        name: &str,
+        gain: f32,
+        location: SoundLocation,
+    ) -> anyhow::Result<()> {
+        let spec = SoundSpec {
+            name: String::from(name),
+            gain,
+            pitch: 1.0,
+            looped: false,
+            fade: 0.0,
+            location,
+            // Not known to the server
+            ephemeral: true,
+        };
+        // Object sounds aren't supported, use the position of the object
+        self.play(media, spec, |_| None)?;
+        Ok(())
+    }
+
+    /// Stops a sound by its server id.
+    pub fn stop(&mut self, server_id: i32) {
+        if let Some(handle) = self.server_ids.remove(&server_id) {
+            self.sounds.remove(&handle).unwrap().sink.stop();
+        }
+    }
+
+    /// Stops all sounds and drops the decoded files, e.g. when
+    /// disconnecting.
+    pub fn clear(&mut self) {
+        for (_, sound) in self.sounds.drain() {
+            sound.sink.stop();
+        }
+        self.server_ids.clear();
+        self.sound_manager.clear();
+    }
+
+    /// Fades sounds in, moves object sounds along with their objects and
+    /// updates the gain for the listener position. Returns the server ids
+    /// of the non-ephemeral sounds that finished playing, which are
+    /// reported to the server with TOSERVER_REMOVED_SOUNDS.
+    // Compare to Luanti, client/client.cpp, Client::step
+    pub fn step(
+        &mut self,
+        dtime: f32,
+        listener_pos: Vec3,
+        object_pos: impl Fn(u16) -> Option<Vec3>,
+    ) -> Vec<i32> {
+        let mut finished = Vec::new();
+        self.sounds.retain(|_, sound| {
+            if sound.sink.empty() {
+                if let Some(server_id) = sound.server_id {
+                    finished.push(server_id);
+                }
+                return false;
+            }
+
+            if sound.gain < sound.target_gain {
+                sound.gain = (sound.gain + sound.fade * dtime).min(sound.target_gain);
+            }
+            if let SoundLocation::Object(id) = sound.location
+                && let Some(pos) = object_pos(id)
+            {
+                sound.pos = Some(pos);
+            }
+            // Inverse distance clamped, the model Luanti configures for OpenAL
+            // TODO: stereo panning
+            let attenuation = match sound.pos {
+                Some(pos) => {
+                    let distance = pos.distance(listener_pos).max(Self::REFERENCE_DISTANCE);
+                    Self::REFERENCE_DISTANCE / distance
+                }
+                None => 1.0,
+            };
+            sound.sink.set_volume(sound.gain * attenuation);
+            true
+        });
+        for server_id in &finished {
+            self.server_ids.remove(server_id);
+        }
+        finished
+    }
+}
@@ -7,8 +7,17 @@ pub struct CameraParams {
     pub fov_y: f32,
     pub size: winit::dpi::PhysicalSize<u32>,
     pub fog_color: glam::Vec3,
+    /// Distance at which the fog is fully opaque. Usually equal to z_far.
+    pub fog_end: f32,
     pub z_near: f32,
     pub z_far: f32,
+    /// Time in seconds, for animated textures and waving nodes
+    pub time: f32,
+    /// Whether nodes with ContentFeatures::waving move
+    pub waving: bool,
+    /// Brightness of sunlight, 0.0 to 1.0. Follows the time of day unless
+    /// the server overrides it.
+    pub daylight: f32,
 }
 
 impl CameraParams {
@@ -20,6 +29,20 @@ impl CameraParams {
         glam::Mat4::look_to_lh(self.pos, self.dir, Self::WORLD_UP)
     }
 
+    /// Projects a world position to window coordinates in pixels.
+    /// Returns None if the position is behind the camera.
+    pub fn world_to_screen(&self, pos: glam::Vec3) -> Option<glam::Vec2> {
+        let clip = self.build_proj_matrix() * self.build_view_matrix() * pos.extend(1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let ndc = clip.truncate() / clip.w;
+        Some(glam::Vec2::new(
+            (ndc.x + 1.0) / 2.0 * self.size.width as f32,
+            (1.0 - ndc.y) / 2.0 * self.size.height as f32,
+        ))
+    }
+
     fn build_proj_matrix(&self) -> glam::Mat4 {
         glam::Mat4::perspective_lh(
             self.fov_y,
@@ -36,7 +59,11 @@ struct CameraUniform {
     view: [f32; 16],
     view_proj: [f32; 16],
     fog_color: [f32; 3],
-    z_far: f32,
+    fog_end: f32,
+    time: f32,
+    waving: u32,
+    daylight: f32,
+    _padding: f32,
 }
 
 impl CameraUniform {
@@ -47,7 +74,11 @@ impl CameraUniform {
             view: view.to_cols_array(),
             view_proj: (proj * view).to_cols_array(),
             fog_color: params.fog_color.to_array(),
-            z_far: params.z_far,
+            fog_end: params.fog_end,
+            time: params.time,
+            waving: params.waving as u32,
+            daylight: params.daylight,
+            _padding: 0.0,
         }
     }
 }
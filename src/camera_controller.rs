@@ -1,10 +1,16 @@
+use std::collections::HashSet;
+use std::f32::consts::PI;
+
 use glam::Vec3;
-use winit::event::{DeviceEvent, ElementState, KeyEvent, WindowEvent};
+use winit::event::{DeviceEvent, ElementState, KeyEvent, MouseButton, WindowEvent};
 use winit::keyboard::{KeyCode, PhysicalKey};
 
 use crate::camera::CameraParams;
+use crate::gamepad::GamepadInput;
+use crate::physics::{self, CollisionWorld};
+use crate::settings::Settings;
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct PlayerPos {
     pub pos: Vec3,
     // Yaw is stored inverted compared to Luanti. Luanti actually inverts it when
@@ -15,12 +21,175 @@ pub struct PlayerPos {
     pub pitch: f32,
 }
 
+/// Movement parameters sent by the server with TOCLIENT_MOVEMENT, from the
+/// server's movement_* settings. Speeds are in nodes per second,
+/// accelerations in nodes per second².
+// Compare to Luanti, client/localplayer.h, LocalPlayer::movement_*
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovementSettings {
+    pub acceleration_default: f32,
+    pub acceleration_air: f32,
+    pub acceleration_fast: f32,
+    pub speed_walk: f32,
+    pub speed_crouch: f32,
+    pub speed_fast: f32,
+    pub speed_climb: f32,
+    pub speed_jump: f32,
+    pub liquid_fluidity: f32,
+    pub liquid_fluidity_smooth: f32,
+    pub liquid_sink: f32,
+    pub gravity: f32,
+}
+
+impl Default for MovementSettings {
+    // Compare to Luanti, defaultsettings.cpp, movement_*
+    fn default() -> Self {
+        Self {
+            acceleration_default: 3.0,
+            acceleration_air: 2.0,
+            acceleration_fast: 10.0,
+            speed_walk: 4.0,
+            speed_crouch: 1.35,
+            speed_fast: 20.0,
+            speed_climb: 3.0,
+            speed_jump: 6.5,
+            liquid_fluidity: 1.0,
+            liquid_fluidity_smooth: 0.5,
+            liquid_sink: 10.0,
+            gravity: 9.81,
+        }
+    }
+}
+
+/// Multipliers for the movement settings, set per player by server mods.
+/// Sent with the local player's object, see
+/// ActiveObject::physics_override.
+// Compare to Luanti, player.h, PlayerPhysicsOverride
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicsOverride {
+    pub speed: f32,
+    pub jump: f32,
+    pub gravity: f32,
+}
+
+impl Default for PhysicsOverride {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            jump: 1.0,
+            gravity: 1.0,
+        }
+    }
+}
+
+/// Cycled through with the camera mode key.
+// Compare to Luanti, client/camera.h, CameraMode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    First,
+    /// Behind the player, looking forward
+    ThirdBack,
+    /// In front of the player, looking back at them
+    ThirdFront,
+}
+
+impl CameraMode {
+    /// Decodes the mode sent with TOCLIENT_CAMERA. Returns None if any mode
+    /// is allowed.
+    pub fn from_network(mode: u8) -> Option<Self> {
+        match mode {
+            1 => Some(Self::First),
+            2 => Some(Self::ThirdBack),
+            3 => Some(Self::ThirdFront),
+            _ => None,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Self::First => Self::ThirdBack,
+            Self::ThirdBack => Self::ThirdFront,
+            Self::ThirdFront => Self::First,
+        }
+    }
+}
+
+/// The controls the player holds, on the keyboard or a gamepad.
+// Compare to Luanti, player.h, PlayerControl
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayerControl {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+    pub sneak: bool,
+    pub zoom: bool,
+    /// Not known to the CameraController, see State::player_control
+    pub dig: bool,
+    pub place: bool,
+}
+
+/// Camera offsets set by the server with TOCLIENT_EYE_OFFSET, in nodes.
+/// Relative to the player's yaw.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EyeOffsets {
+    pub first: Vec3,
+    pub third_back: Vec3,
+    pub third_front: Vec3,
+}
+
 pub struct CameraController {
     // The CameraController is the source of truth for this data
     pos: PlayerPos,
+    /// Debugging aid: while set, the player stays here and `pos` is a free
+    /// camera that flies through everything. See `toggle_detached`.
+    detached_player: Option<PlayerPos>,
 
+    /// In degrees per mouse count
     rotation_sensitivity: f32,
-    movement_speed: f32,
+    invert_mouse: bool,
+    /// See Settings::camera_smoothing
+    camera_smoothing: f32,
+    /// The yaw and pitch shown by the camera, lagging behind `pos` with
+    /// camera smoothing
+    camera_rotation: (f32, f32),
+    // TODO: use the climbing and liquid settings once climbing and swimming
+    // exist
+    movement: MovementSettings,
+    physics_override: PhysicsOverride,
+    /// In nodes per second
+    velocity: Vec3,
+    /// Whether the player stood on something after the last step
+    touching_ground: bool,
+    /// How far the eyes are currently lowered by sneaking, in nodes
+    sneak_eye_lowering: f32,
+
+    /// Toggled by the player, but only take effect with the matching
+    /// privilege
+    free_move: bool,
+    fast_move: bool,
+    noclip: bool,
+    /// See `set_privileges`
+    fly_allowed: bool,
+    fast_allowed: bool,
+    noclip_allowed: bool,
+
+    camera_mode: CameraMode,
+    /// Set by the server to force a camera mode, disables cycling
+    forced_camera_mode: Option<CameraMode>,
+    /// Height of the eyes above the player position (the feet), in nodes
+    // TODO: this should come from the local player's object properties
+    eye_height: f32,
+    eye_offsets: EyeOffsets,
+
+    /// Mouse movement not applied to the rotation yet
+    mouse_delta: (f64, f64),
+    /// Whether mouse movement is applied in `step` instead of when it
+    /// arrives, see Settings::mouse_at_render
+    mouse_at_render: bool,
+    /// Applied in addition to the keyboard and mouse, see `set_gamepad_input`
+    gamepad: GamepadInput,
 
     forward: bool,
     backward: bool,
@@ -29,15 +198,111 @@ pub struct CameraController {
 
     up: bool,
     down: bool,
+    zoom: bool,
+
+    /// Field of view in degrees when not zooming
+    fov: f32,
+    /// Field of view in degrees while zooming, 0 if zooming isn't allowed.
+    /// Set by the server with the zoom_fov object property.
+    zoom_fov: f32,
+    /// The current, interpolated field of view in degrees
+    current_fov: f32,
+    /// Set by the server with TOCLIENT_FOV, in degrees or as a multiplier
+    /// of `fov`. Takes the place of `fov` if set.
+    server_fov: Option<(f32, bool)>,
+    /// Remaining time of the transition to a new server FOV, in seconds
+    fov_transition_time: f32,
+    /// Set if the server FOV changed without a transition, the next step
+    /// jumps to it
+    fov_snap: bool,
+
+    view_bobbing: bool,
+    view_bobbing_amount: f32,
+    /// Phase of the view bobbing oscillation, advances while walking
+    bob_phase: f32,
+    /// Current view bobbing strength, smoothly follows whether we're walking
+    bob_strength: f32,
+    /// Remaining time of the punch animation, see `punch`
+    punch_timer: f32,
 }
 
 impl CameraController {
-    pub fn new() -> CameraController {
+    /// Vertical view bobbing amplitude in nodes, multiplied by view_bobbing_amount
+    const BOB_AMPLITUDE: f32 = 0.05;
+    /// Walked distance in nodes for one full bobbing cycle (two steps)
+    const BOB_CYCLE_LENGTH: f32 = 3.0;
+    /// Upper limit for the bobbing frequency in cycles per second, so it
+    /// doesn't get silly at high movement speeds
+    const BOB_MAX_FREQUENCY: f32 = 2.0;
+    /// How fast the bobbing fades in and out when starting/stopping to walk
+    const BOB_SMOOTHING: f32 = 8.0;
+
+    /// How fast the field of view follows zooming in and out
+    const ZOOM_SMOOTHING: f32 = 12.0;
+
+    // Compare to Luanti, object_properties.cpp, ObjectProperties::eye_height
+    const DEFAULT_EYE_HEIGHT: f32 = 1.625;
+    // TODO: these should come from the local player's object properties
+    // Compare to Luanti, object_properties.cpp, ObjectProperties::collisionbox
+    const COLLISIONBOX: (Vec3, Vec3) = (Vec3::new(-0.3, 0.0, -0.3), Vec3::new(0.3, 1.77, 0.3));
+    // Compare to Luanti, object_properties.cpp, ObjectProperties::stepheight
+    const STEPHEIGHT: f32 = 0.6;
+    /// Luanti multiplies the movement accelerations by BS once more than
+    /// the speeds, so they're effectively 10 times the setting
+    // Compare to Luanti, client/localplayer.cpp, LocalPlayer::applyControl
+    const ACCELERATION_FACTOR: f32 = 10.0;
+    /// How far the eyes go down while sneaking, in nodes
+    const SNEAK_EYE_LOWERING: f32 = 0.15;
+    /// How fast the eyes follow sneaking
+    const SNEAK_EYE_SMOOTHING: f32 = 10.0;
+    /// While sneaking, the player stops at edges with a larger drop than
+    /// this, in nodes. Lower steps like slabs can still be walked down.
+    const SNEAK_MAX_DROP: f32 = 0.55;
+    /// Luanti applies gravity twice as strong as the setting
+    // Compare to Luanti, client/clientenvironment.cpp, ClientEnvironment::step
+    const GRAVITY_FACTOR: f32 = 2.0;
+    /// Distance of the camera from the eyes in third person, in nodes
+    // TODO: move closer if there's a node in the way, like Luanti does
+    const THIRD_PERSON_DISTANCE: f32 = 2.0;
+
+    /// Factor for changing the mouse sensitivity at runtime
+    const SENSITIVITY_STEP: f32 = 1.1;
+
+    const PUNCH_DURATION: f32 = 0.25;
+    /// Maximum camera pitch offset in degrees during the punch animation
+    const PUNCH_PITCH: f32 = 1.5;
+
+    pub fn new(settings: &Settings) -> CameraController {
         CameraController {
             pos: PlayerPos::default(),
+            detached_player: None,
 
-            rotation_sensitivity: 0.1,
-            movement_speed: 20.0,
+            rotation_sensitivity: settings.mouse_sensitivity,
+            invert_mouse: settings.invert_mouse,
+            camera_smoothing: settings.camera_smoothing,
+            camera_rotation: (0.0, 0.0),
+            movement: MovementSettings::default(),
+            physics_override: PhysicsOverride::default(),
+            velocity: Vec3::ZERO,
+            touching_ground: false,
+            sneak_eye_lowering: 0.0,
+
+            // Compare to Luanti, defaultsettings.cpp, free_move etc.
+            free_move: false,
+            fast_move: false,
+            noclip: false,
+            fly_allowed: false,
+            fast_allowed: false,
+            noclip_allowed: false,
+
+            camera_mode: CameraMode::First,
+            forced_camera_mode: None,
+            eye_height: Self::DEFAULT_EYE_HEIGHT,
+            eye_offsets: EyeOffsets::default(),
+
+            mouse_delta: (0.0, 0.0),
+            mouse_at_render: settings.mouse_at_render,
+            gamepad: GamepadInput::default(),
 
             forward: false,
             backward: false,
@@ -46,6 +311,21 @@ impl CameraController {
 
             up: false,
             down: false,
+            zoom: false,
+
+            fov: settings.fov,
+            // Compare to Luanti, object_properties.cpp, ObjectProperties::zoom_fov
+            zoom_fov: 0.0,
+            current_fov: settings.fov,
+            server_fov: None,
+            fov_transition_time: 0.0,
+            fov_snap: false,
+
+            view_bobbing: settings.view_bobbing,
+            view_bobbing_amount: settings.view_bobbing_amount,
+            bob_phase: 0.0,
+            bob_strength: 0.0,
+            punch_timer: 0.0,
         }
     }
 
@@ -86,9 +366,69 @@ impl CameraController {
                         self.down = pressed;
                         true
                     }
+                    KeyCode::BracketLeft | KeyCode::BracketRight => {
+                        if pressed {
+                            let factor = if *keycode == KeyCode::BracketRight {
+                                Self::SENSITIVITY_STEP
+                            } else {
+                                1.0 / Self::SENSITIVITY_STEP
+                            };
+                            self.rotation_sensitivity *= factor;
+                            println!("Mouse sensitivity: {:.3}", self.rotation_sensitivity);
+                        }
+                        true
+                    }
+                    KeyCode::KeyZ => {
+                        self.zoom = pressed;
+                        true
+                    }
+                    KeyCode::KeyK => {
+                        if pressed {
+                            self.free_move = !self.free_move;
+                            Self::print_toggle("Fly", self.free_move, self.fly_allowed, "fly");
+                        }
+                        true
+                    }
+                    KeyCode::KeyJ => {
+                        if pressed {
+                            self.fast_move = !self.fast_move;
+                            Self::print_toggle("Fast", self.fast_move, self.fast_allowed, "fast");
+                        }
+                        true
+                    }
+                    KeyCode::KeyC => {
+                        if pressed && self.forced_camera_mode.is_some() {
+                            println!("Camera mode is set by the server");
+                        } else if pressed {
+                            self.camera_mode = self.camera_mode.next();
+                            println!("Camera mode: {:?}", self.camera_mode);
+                        }
+                        true
+                    }
+                    KeyCode::KeyH => {
+                        if pressed {
+                            self.noclip = !self.noclip;
+                            Self::print_toggle(
+                                "Noclip",
+                                self.noclip,
+                                self.noclip_allowed,
+                                "noclip",
+                            );
+                        }
+                        true
+                    }
                     _ => false,
                 }
             }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.punch();
+                // Other code may want to handle clicks as well
+                false
+            }
             _ => false,
         }
     }
@@ -96,33 +436,202 @@ impl CameraController {
     pub fn process_device_event(&mut self, event: &DeviceEvent) -> bool {
         match event {
             DeviceEvent::MouseMotion { delta } => {
-                self.pos.yaw += delta.0 as f32 * self.rotation_sensitivity;
-                self.pos.pitch += delta.1 as f32 * self.rotation_sensitivity;
-
-                // don't allow the camera to flip over :)
-                // 89 instead of 90 so the forward/up vectors don't end up being parallel
-                // (would cause flashing)
-                self.pos.pitch = self.pos.pitch.clamp(-89.0, 89.0);
-
+                self.mouse_delta.0 += delta.0;
+                self.mouse_delta.1 += delta.1;
+                if !self.mouse_at_render {
+                    self.apply_mouse_delta();
+                }
                 true
             }
             _ => false,
         }
     }
 
+    /// Drops the mouse movement that hasn't been applied yet, when the
+    /// cursor is released for the UI. With mouse_at_render, movement that
+    /// arrived after the last frame would otherwise turn the camera once
+    /// the cursor is grabbed again.
+    pub fn discard_mouse_delta(&mut self) {
+        self.mouse_delta = (0.0, 0.0);
+    }
+
+    /// Applies the mouse movement accumulated since the last call.
+    fn apply_mouse_delta(&mut self) {
+        let (dx, dy) = std::mem::take(&mut self.mouse_delta);
+        let dy = if self.invert_mouse { -dy } else { dy };
+        self.pos.yaw += dx as f32 * self.rotation_sensitivity;
+        self.pos.pitch += dy as f32 * self.rotation_sensitivity;
+
+        // don't allow the camera to flip over :)
+        // 89 instead of 90 so the forward/up vectors don't end up being parallel
+        // (would cause flashing)
+        self.pos.pitch = self.pos.pitch.clamp(-89.0, 89.0);
+    }
+
+    /// Sets the gamepad state for the next `step`.
+    pub fn set_gamepad_input(&mut self, input: GamepadInput) {
+        self.gamepad = input;
+    }
+
+    /// Moves the player, e.g. when teleported by the server.
     pub fn set_pos(&mut self, pos: PlayerPos) {
+        if let Some(player) = &mut self.detached_player {
+            *player = pos;
+            return;
+        }
+        self.camera_rotation = (pos.yaw, pos.pitch);
         self.pos = pos;
+        self.velocity = Vec3::ZERO;
     }
 
+    /// The player position, which stays where it is while the camera is
+    /// detached.
     pub fn get_pos(&self) -> &PlayerPos {
-        &self.pos
+        self.detached_player.as_ref().unwrap_or(&self.pos)
+    }
+
+    /// Whether the camera is in the player's head. Not while detached, since
+    /// the camera has left the player then.
+    pub fn is_first_person(&self) -> bool {
+        self.camera_mode == CameraMode::First && self.detached_player.is_none()
+    }
+
+    /// The ray pointing is cast along: from the player's head in the
+    /// direction they look, without the third person camera offset. None
+    /// in the front view, where the player faces the camera. Stays with the
+    /// player while the camera is detached.
+    // Compare to Luanti, client/game.cpp, Game::processPlayerInteraction
+    pub fn shootline(&self) -> Option<(Vec3, Vec3)> {
+        if self.camera_mode == CameraMode::ThirdFront {
+            return None;
+        }
+        let player = self.get_pos();
+        let rot_yaw = glam::Quat::from_rotation_y(player.yaw.to_radians());
+        let rot_pitch = glam::Quat::from_rotation_x(player.pitch.to_radians());
+        let pos = player.pos + Vec3::Y * self.eye_height;
+        Some((pos, rot_yaw * rot_pitch * CameraParams::WORLD_FORWARD))
     }
 
-    pub fn step(&mut self, dtime: f32, params: &mut CameraParams) {
+    /// Detaches the camera from the player, or moves it back to the player.
+    /// The position sent to the server stays at the player while detached.
+    /// Returns whether the camera is detached now.
+    pub fn toggle_detached(&mut self) -> bool {
+        match self.detached_player.take() {
+            Some(player) => {
+                self.set_pos(player);
+                println!("Camera attached to the player");
+                false
+            }
+            None => {
+                self.detached_player = Some(self.pos.clone());
+                self.velocity = Vec3::ZERO;
+                println!("Camera detached from the player");
+                true
+            }
+        }
+    }
+
+    /// Forces a camera mode, or allows cycling through all of them again if
+    /// None.
+    // Compare to Luanti, client/game.cpp, Game::updateCameraMode
+    pub fn set_forced_camera_mode(&mut self, mode: Option<CameraMode>) {
+        self.forced_camera_mode = mode;
+        if let Some(mode) = mode {
+            self.camera_mode = mode;
+        }
+    }
+
+    pub fn set_eye_offsets(&mut self, eye_offsets: EyeOffsets) {
+        self.eye_offsets = eye_offsets;
+    }
+
+    /// Sets the FOV while zooming from the local player's object
+    /// properties, in degrees. 0 disables zooming.
+    pub fn set_zoom_fov(&mut self, zoom_fov: f32) {
+        self.zoom_fov = zoom_fov;
+    }
+
+    /// Sets the FOV requested by the server, 0 restores the user's FOV.
+    /// The camera moves to the new FOV over `transition_time` seconds, or
+    /// right away if it's 0.
+    // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_Fov
+    pub fn set_server_fov(&mut self, fov: f32, is_multiplier: bool, transition_time: f32) {
+        self.server_fov = (fov > 0.0).then_some((fov, is_multiplier));
+        self.fov_transition_time = transition_time.max(0.0);
+        self.fov_snap = transition_time <= 0.0;
+    }
+
+    pub fn set_movement_settings(&mut self, movement: MovementSettings) {
+        self.movement = movement;
+    }
+
+    pub fn set_physics_override(&mut self, physics_override: PhysicsOverride) {
+        self.physics_override = physics_override;
+    }
+
+    /// Updates which modes are allowed, from the privileges sent with
+    /// TOCLIENT_PRIVILEGES.
+    // Compare to Luanti, client/localplayer.cpp, LocalPlayer::applyControl
+    pub fn set_privileges(&mut self, privileges: &HashSet<String>) {
+        self.fly_allowed = privileges.contains("fly");
+        self.fast_allowed = privileges.contains("fast");
+        self.noclip_allowed = privileges.contains("noclip");
+    }
+
+    /// Like Luanti, modes can be toggled without the privilege, they just
+    /// don't take effect until it is granted.
+    // Compare to Luanti, client/game.cpp, Game::toggleFreeMove etc.
+    fn print_toggle(mode: &str, enabled: bool, allowed: bool, privilege: &str) {
+        if !enabled {
+            println!("{} mode disabled", mode);
+        } else if allowed {
+            println!("{} mode enabled", mode);
+        } else {
+            println!("{} mode enabled (note: no '{}' privilege)", mode, privilege);
+        }
+    }
+
+    /// The movement controls that are held. Dig and place are never set.
+    pub fn control(&self) -> PlayerControl {
+        let movement = self.gamepad.movement;
+        PlayerControl {
+            up: self.forward || movement.y > 0.0,
+            down: self.backward || movement.y < 0.0,
+            left: self.left || movement.x < 0.0,
+            right: self.right || movement.x > 0.0,
+            jump: self.up_pressed(),
+            sneak: self.down_pressed(),
+            zoom: self.zoom,
+            dig: false,
+            place: false,
+        }
+    }
+
+    /// Starts the punch animation, a short dip of the camera.
+    /// Called when digging or placing.
+    pub fn punch(&mut self) {
+        self.punch_timer = Self::PUNCH_DURATION;
+    }
+
+    /// Should be called right before rendering, so the latest input is used.
+    /// The player collides with `world` unless noclip is active, and only
+    /// moves with noclip without it, e.g. while the node definitions are
+    /// missing.
+    pub fn step(&mut self, dtime: f32, params: &mut CameraParams, world: Option<&CollisionWorld>) {
+        self.apply_mouse_delta();
+        self.pos.yaw += self.gamepad.look.x * dtime;
+        self.pos.pitch = (self.pos.pitch + self.gamepad.look.y * dtime).clamp(-89.0, 89.0);
+
+        self.punch_timer = (self.punch_timer - dtime).max(0.0);
+        let punch_progress = 1.0 - self.punch_timer / Self::PUNCH_DURATION;
+        let punch_pitch = (punch_progress * PI).sin() * Self::PUNCH_PITCH;
+
+        let (camera_yaw, camera_pitch) = self.smooth_camera_rotation(dtime);
         let rot_yaw = glam::Quat::from_rotation_y(self.pos.yaw.to_radians());
-        let rot_pitch = glam::Quat::from_rotation_x(self.pos.pitch.to_radians());
+        let camera_rot_yaw = glam::Quat::from_rotation_y(camera_yaw.to_radians());
+        let rot_pitch = glam::Quat::from_rotation_x((camera_pitch + punch_pitch).to_radians());
 
-        params.dir = rot_yaw * rot_pitch * CameraParams::WORLD_FORWARD;
+        params.dir = camera_rot_yaw * rot_pitch * CameraParams::WORLD_FORWARD;
 
         let mut movement = glam::Vec3::ZERO;
 
@@ -138,22 +647,96 @@ impl CameraController {
         if self.left {
             movement.x -= 1.0;
         }
-        // avoids NaN from normalize
-        if movement.length_squared() != 0.0 {
-            movement = rot_yaw * movement.normalize();
-        }
+        // The stick allows walking slower than full speed
+        movement += Vec3::new(self.gamepad.movement.x, 0.0, self.gamepad.movement.y);
+        movement = rot_yaw * movement.clamp_length_max(1.0);
+        let mut walking = movement.length_squared() != 0.0;
 
-        if self.up {
-            movement.y += 1.0;
+        let detached = self.detached_player.is_some();
+        let fly = detached || (self.free_move && self.fly_allowed);
+        // Like in Luanti, noclip only works while flying
+        let noclip = detached || (fly && self.noclip && self.noclip_allowed);
+        let sneaking = !fly && self.down_pressed();
+        if fly {
+            if self.up_pressed() {
+                movement.y += 1.0;
+            }
+            if self.down_pressed() {
+                movement.y -= 1.0;
+            }
+            self.velocity = movement * self.speed();
+        } else {
+            self.apply_walk_control(dtime, movement);
+            walking &= self.touching_ground;
         }
-        if self.down {
-            movement.y -= 1.0;
+
+        match world {
+            _ if noclip => {
+                self.pos.pos += self.velocity * dtime;
+                self.touching_ground = false;
+            }
+            Some(world) => {
+                let result = physics::collision_move(
+                    world,
+                    Self::COLLISIONBOX,
+                    self.pos.pos,
+                    self.velocity,
+                    dtime,
+                    if fly { 0.0 } else { Self::STEPHEIGHT },
+                    self.touching_ground,
+                );
+                let mut pos = result.pos;
+                self.velocity = result.velocity;
+                if sneaking && self.touching_ground {
+                    pos = self.stop_at_edges(world, self.pos.pos, pos);
+                }
+                self.pos.pos = pos;
+                self.touching_ground = result.touching_ground;
+            }
+            // Don't fall while there's nothing to land on
+            None => self.velocity = Vec3::ZERO,
         }
 
-        movement = movement * self.movement_speed * dtime;
-        self.pos.pos += movement;
+        let target_lowering = if sneaking {
+            Self::SNEAK_EYE_LOWERING
+        } else {
+            0.0
+        };
+        self.sneak_eye_lowering += (target_lowering - self.sneak_eye_lowering)
+            * (1.0 - (-dtime * Self::SNEAK_EYE_SMOOTHING).exp());
 
-        params.pos = self.pos.pos;
+        params.pos = self.eye_pos(rot_yaw) + self.view_bobbing_offset(dtime, walking, rot_yaw);
+        match self.camera_mode {
+            CameraMode::First => (),
+            CameraMode::ThirdBack => params.pos -= params.dir * Self::THIRD_PERSON_DISTANCE,
+            CameraMode::ThirdFront => {
+                params.pos += params.dir * Self::THIRD_PERSON_DISTANCE;
+                params.dir = -params.dir;
+            }
+        }
+
+        let target_fov = if self.zoom && self.zoom_fov > 0.0 {
+            self.zoom_fov
+        } else {
+            match self.server_fov {
+                Some((fov, true)) => self.fov * fov,
+                Some((fov, false)) => fov,
+                None => self.fov,
+            }
+        };
+        if self.fov_snap {
+            self.current_fov = target_fov;
+            self.fov_snap = false;
+        } else if self.fov_transition_time > 0.0 {
+            // Linear, so it ends exactly after the requested time
+            let progress = (dtime / self.fov_transition_time).min(1.0);
+            self.current_fov += (target_fov - self.current_fov) * progress;
+            self.fov_transition_time -= dtime;
+        } else {
+            self.current_fov +=
+                (target_fov - self.current_fov) * (1.0 - (-dtime * Self::ZOOM_SMOOTHING).exp());
+        }
+        params.fov_y = self.current_fov.to_radians();
 
         /*
         println!(
@@ -171,4 +754,126 @@ impl CameraController {
         */
         // println!("dtime: {:.4}", dtime);
     }
+
+    /// Moves the camera rotation towards the player rotation, with a delay
+    /// if camera smoothing is enabled. Returns the new yaw and pitch.
+    // Compare to Luanti, client/game.cpp, Game::updateCameraOrientation
+    fn smooth_camera_rotation(&mut self, dtime: f32) -> (f32, f32) {
+        let target = (self.pos.yaw, self.pos.pitch);
+        if self.camera_smoothing <= 0.0 {
+            self.camera_rotation = target;
+            return target;
+        }
+        // The fraction left after one frame at 60 FPS
+        let keep = self.camera_smoothing.powf(dtime * 60.0);
+        let (yaw, pitch) = &mut self.camera_rotation;
+        *yaw += (target.0 - *yaw) * (1.0 - keep);
+        *pitch += (target.1 - *pitch) * (1.0 - keep);
+        self.camera_rotation
+    }
+
+    /// Where the player's eyes are, the camera position in first person.
+    // Compare to Luanti, client/camera.cpp, Camera::update
+    fn eye_pos(&self, rot_yaw: glam::Quat) -> Vec3 {
+        let offset = match self.camera_mode {
+            CameraMode::First => self.eye_offsets.first,
+            CameraMode::ThirdBack => self.eye_offsets.third_back,
+            CameraMode::ThirdFront => self.eye_offsets.third_front,
+        };
+        self.pos.pos + Vec3::Y * (self.eye_height - self.sneak_eye_lowering) + rot_yaw * offset
+    }
+
+    /// Accelerates towards the walking direction, jumps and applies gravity.
+    /// `direction` is horizontal, with a length of 1 or 0.
+    // Compare to Luanti, client/localplayer.cpp, LocalPlayer::applyControl
+    // and LocalPlayer::accelerate
+    fn apply_walk_control(&mut self, dtime: f32, direction: Vec3) {
+        let fast = self.fast_move && self.fast_allowed;
+        let speed = if self.down_pressed() {
+            self.movement.speed_crouch * self.physics_override.speed
+        } else {
+            self.speed()
+        };
+        let acceleration = if !self.touching_ground {
+            self.movement.acceleration_air
+        } else if fast {
+            self.movement.acceleration_fast
+        } else {
+            self.movement.acceleration_default
+        };
+
+        let max_increase = acceleration * Self::ACCELERATION_FACTOR * dtime;
+        let mut change = direction * speed - self.velocity;
+        change.y = 0.0;
+        self.velocity += change.clamp_length_max(max_increase);
+
+        if self.up_pressed() && self.touching_ground {
+            self.velocity.y = self.movement.speed_jump * self.physics_override.jump;
+            self.touching_ground = false;
+        }
+        let gravity = self.movement.gravity * self.physics_override.gravity;
+        self.velocity.y -= gravity * Self::GRAVITY_FACTOR * dtime;
+    }
+
+    /// Undoes the horizontal movement from `old` to `new` on each axis where
+    /// it would make the player fall off an edge. Returns the allowed
+    /// position.
+    // Compare to Luanti, client/localplayer.cpp, LocalPlayer::move (sneak)
+    fn stop_at_edges(&mut self, world: &CollisionWorld, old: Vec3, new: Vec3) -> Vec3 {
+        let mut pos = Vec3::new(old.x, new.y, old.z);
+        for axis in [0, 2] {
+            let mut candidate = pos;
+            candidate[axis] = new[axis];
+            if physics::has_ground(world, Self::COLLISIONBOX, candidate, Self::SNEAK_MAX_DROP) {
+                pos = candidate;
+            } else {
+                self.velocity[axis] = 0.0;
+            }
+        }
+        pos
+    }
+
+    /// Jump, or fly up
+    fn up_pressed(&self) -> bool {
+        self.up || self.gamepad.jump
+    }
+
+    /// Sneak, or fly down
+    fn down_pressed(&self) -> bool {
+        self.down || self.gamepad.sneak
+    }
+
+    /// Movement speed in nodes per second.
+    fn speed(&self) -> f32 {
+        let speed = if self.fast_move && self.fast_allowed {
+            self.movement.speed_fast
+        } else {
+            self.movement.speed_walk
+        };
+        speed * self.physics_override.speed
+    }
+
+    /// Advances the view bobbing animation.
+    /// Returns the offset to apply to the camera position.
+    fn view_bobbing_offset(&mut self, dtime: f32, walking: bool, rot_yaw: glam::Quat) -> Vec3 {
+        if !self.view_bobbing {
+            return Vec3::ZERO;
+        }
+
+        let target_strength = if walking { 1.0 } else { 0.0 };
+        self.bob_strength +=
+            (target_strength - self.bob_strength) * (1.0 - (-dtime * Self::BOB_SMOOTHING).exp());
+
+        if walking {
+            let frequency = (self.speed() / Self::BOB_CYCLE_LENGTH).min(Self::BOB_MAX_FREQUENCY);
+            self.bob_phase = (self.bob_phase + dtime * frequency * 2.0 * PI) % (2.0 * PI);
+        }
+
+        let amplitude = Self::BOB_AMPLITUDE * self.view_bobbing_amount * self.bob_strength;
+        let right = rot_yaw * Vec3::X;
+
+        // One vertical bounce per step, one horizontal sway per two steps
+        Vec3::Y * (self.bob_phase.sin().abs() * amplitude)
+            + right * (self.bob_phase.cos() * amplitude * 0.5)
+    }
 }
@@ -0,0 +1,343 @@
+//! Chat messages: the most recent ones are shown on the HUD and fade out
+//! after a while, the console (F10) shows the whole scrollback. Lines in the
+//! console can be selected with the mouse and copied with Ctrl+C.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use glam::{Vec2, Vec4};
+use winit::event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, ModifiersState, PhysicalKey};
+
+use crate::overlay::OverlayRenderer;
+use crate::text::{self, TextRenderer};
+
+struct ChatLine {
+    /// Local time the message was received, "HH:MM:SS"
+    timestamp: String,
+    /// See text::colorize
+    runs: Vec<(String, Vec4)>,
+    received: Instant,
+}
+
+impl ChatLine {
+    fn plain_text(&self) -> String {
+        let text: String = self.runs.iter().map(|(run, _)| run.as_str()).collect();
+        format!("[{}] {}", self.timestamp, text)
+    }
+}
+
+#[derive(Default)]
+pub struct Chat {
+    /// Oldest first
+    lines: VecDeque<ChatLine>,
+    console_open: bool,
+    /// Number of lines the console is scrolled up from the newest line
+    scroll: usize,
+    /// Scrolled distance in lines that hasn't been applied yet
+    scroll_accum: f32,
+    /// The first and last selected line as indices into `lines`, in the
+    /// order they were selected
+    selection: Option<(usize, usize)>,
+    /// Whether the left mouse button is held to extend the selection
+    selecting: bool,
+    cursor: Vec2,
+    modifiers: ModifiersState,
+    /// The console lines drawn last frame, as indices into `lines` with
+    /// their top edge, for finding the line under the cursor
+    drawn_lines: Vec<(usize, f32)>,
+    line_height: f32,
+    /// Created when copying for the first time. Kept alive, because on some
+    /// platforms the copied text is gone when the clipboard is dropped.
+    clipboard: Option<arboard::Clipboard>,
+}
+
+impl Chat {
+    // Compare to Luanti, chat.cpp, ChatBuffer (console_scrollback setting)
+    const MAX_LINES: usize = 500;
+    /// How long messages stay on the HUD, including fading out
+    const HUD_DURATION: Duration = Duration::from_secs(10);
+    const HUD_FADE_DURATION: Duration = Duration::from_secs(2);
+    const HUD_MAX_LINES: usize = 6;
+    /// Fraction of the screen height covered by the console
+    const CONSOLE_HEIGHT: f32 = 0.6;
+    /// Lines per step of the mouse wheel
+    const SCROLL_LINES: f32 = 3.0;
+    /// For touchpads, which report scrolling in pixels
+    const PIXELS_PER_LINE: f32 = 40.0;
+    const MARGIN: f32 = 16.0;
+    const PADDING: f32 = 8.0;
+    const TEXT_COLOR: Vec4 = Vec4::ONE;
+    const TIMESTAMP_COLOR: Vec4 = Vec4::new(0.5, 0.5, 0.5, 1.0);
+
+    /// Adds a message received from the server. Messages with several lines
+    /// are split.
+    pub fn add_message(&mut self, message: &str) {
+        let timestamp = chrono::Local::now().format("%H:%M:%S").to_string();
+        // Colors don't carry over to the next line, like in Luanti
+        for line in message.lines() {
+            if self.lines.len() == Self::MAX_LINES {
+                self.lines.pop_front();
+                // The indices changed
+                self.selection = None;
+            }
+            self.lines.push_back(ChatLine {
+                timestamp: timestamp.clone(),
+                runs: text::colorize(line, Self::TEXT_COLOR),
+                received: Instant::now(),
+            });
+            // Keep showing the same lines while scrolled up
+            if self.scroll > 0 {
+                self.scroll = (self.scroll + 1).min(self.lines.len() - 1);
+            }
+        }
+    }
+
+    pub fn console_open(&self) -> bool {
+        self.console_open
+    }
+
+    /// Opens the console scrolled to the newest line.
+    pub fn open_console(&mut self) {
+        self.console_open = true;
+        self.scroll = 0;
+        self.scroll_accum = 0.0;
+        self.selecting = false;
+        self.modifiers = ModifiersState::empty();
+    }
+
+    pub fn close_console(&mut self) {
+        self.console_open = false;
+        self.selection = None;
+        self.selecting = false;
+    }
+
+    /// Handles input while the console is open. Escape and F10 close it.
+    pub fn process_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = modifiers.state(),
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Vec2::new(position.x as f32, position.y as f32);
+                if self.selecting
+                    && let (Some((first, _)), Some(index)) = (self.selection, self.line_at_cursor())
+                {
+                    self.selection = Some((first, index));
+                }
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.selecting = *state == ElementState::Pressed;
+                if self.selecting {
+                    self.selection = self.line_at_cursor().map(|index| (index, index));
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y * Self::SCROLL_LINES,
+                    MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / Self::PIXELS_PER_LINE,
+                };
+                self.scroll_accum += lines;
+                let steps = self.scroll_accum.trunc();
+                self.scroll_accum -= steps;
+                self.scroll_by(steps as isize);
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(keycode),
+                        ..
+                    },
+                ..
+            } => self.process_key(*keycode),
+            _ => (),
+        }
+    }
+
+    fn process_key(&mut self, keycode: KeyCode) {
+        let page = self.drawn_lines.len().max(1) as isize;
+        match keycode {
+            KeyCode::Escape | KeyCode::F10 => self.close_console(),
+            KeyCode::PageUp => self.scroll_by(page),
+            KeyCode::PageDown => self.scroll_by(-page),
+            KeyCode::ArrowUp => self.scroll_by(1),
+            KeyCode::ArrowDown => self.scroll_by(-1),
+            KeyCode::Home => self.scroll_by(self.lines.len() as isize),
+            KeyCode::End => self.scroll = 0,
+            KeyCode::KeyA if self.modifiers.control_key() && !self.lines.is_empty() => {
+                self.selection = Some((0, self.lines.len() - 1));
+            }
+            KeyCode::KeyC if self.modifiers.control_key() => self.copy_selection(),
+            _ => (),
+        }
+    }
+
+    /// Scrolls up by `lines`, down if negative.
+    fn scroll_by(&mut self, lines: isize) {
+        let max = self.lines.len().saturating_sub(1);
+        self.scroll = self.scroll.saturating_add_signed(lines).min(max);
+    }
+
+    fn line_at_cursor(&self) -> Option<usize> {
+        self.drawn_lines
+            .iter()
+            .find(|(_, top)| (*top..*top + self.line_height).contains(&self.cursor.y))
+            .map(|(index, _)| *index)
+    }
+
+    /// Copies the selected lines to the clipboard, with their timestamps.
+    fn copy_selection(&mut self) {
+        let Some((first, last)) = self.selection else {
+            return;
+        };
+        let text = self
+            .lines
+            .range(first.min(last)..=first.max(last))
+            .map(ChatLine::plain_text)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if self.clipboard.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(err) => {
+                    println!("Could not access the clipboard: {:?}", err);
+                    return;
+                }
+            }
+        }
+        if let Err(err) = self.clipboard.as_mut().unwrap().set_text(text) {
+            println!("Could not copy to the clipboard: {:?}", err);
+        }
+    }
+
+    /// Draws the recent messages in the bottom left corner while the console
+    /// is closed, or the console at the top of the screen.
+    pub fn draw(
+        &mut self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        if self.console_open {
+            self.draw_console(overlay, text, screen_size);
+        } else {
+            self.draw_hud(overlay, text, screen_size);
+        }
+    }
+
+    // Compare to Luanti, client/gameui.cpp, GameUI::setChatText
+    fn draw_hud(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.4);
+        // Above the loading indicator
+        const BOTTOM_MARGIN: f32 = 96.0;
+
+        let now = Instant::now();
+        let recent: Vec<(&ChatLine, f32)> = self
+            .lines
+            .iter()
+            .rev()
+            .take(Self::HUD_MAX_LINES)
+            .map_while(|line| {
+                let remaining = Self::HUD_DURATION.checked_sub(now - line.received)?;
+                let alpha =
+                    (remaining.as_secs_f32() / Self::HUD_FADE_DURATION.as_secs_f32()).min(1.0);
+                Some((line, alpha))
+            })
+            .collect();
+
+        let line_height = text.line_height();
+        let mut y = screen_size.height as f32 - BOTTOM_MARGIN;
+        for (line, alpha) in recent {
+            y -= line_height;
+            let width = line
+                .runs
+                .iter()
+                .map(|(run, _)| text.width(run))
+                .sum::<f32>();
+            let min = Vec2::new(Self::MARGIN, y);
+            overlay.rect(
+                min - Vec2::new(Self::PADDING / 2.0, 0.0),
+                min + Vec2::new(width + Self::PADDING / 2.0, line_height),
+                BACKGROUND_COLOR.with_w(BACKGROUND_COLOR.w * alpha),
+            );
+            text.draw_colored(overlay, &line.runs, min, alpha);
+        }
+    }
+
+    // Compare to Luanti, gui/guiChatConsole.cpp, GUIChatConsole::drawText
+    fn draw_console(
+        &mut self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+        const SELECTION_COLOR: Vec4 = Vec4::new(0.2, 0.4, 0.8, 0.6);
+        const HINT_COLOR: Vec4 = Vec4::new(0.7, 0.7, 0.7, 1.0);
+
+        let screen_width = screen_size.width as f32;
+        let height = (screen_size.height as f32 * Self::CONSOLE_HEIGHT).round();
+        overlay.rect(
+            Vec2::ZERO,
+            Vec2::new(screen_width, height),
+            BACKGROUND_COLOR,
+        );
+
+        // The bottom line is for the scroll position
+        let line_height = text.line_height();
+        let bottom = height - Self::PADDING - line_height;
+        let visible = ((bottom - Self::PADDING) / line_height).max(0.0) as usize;
+        let end = self.lines.len() - self.scroll;
+        let start = end.saturating_sub(visible);
+        let selected = self
+            .selection
+            .map(|(first, last)| first.min(last)..=first.max(last));
+
+        self.drawn_lines.clear();
+        self.line_height = line_height;
+        let mut y = bottom - (end - start) as f32 * line_height;
+        for (index, line) in self.lines.range(start..end).enumerate() {
+            let index = start + index;
+            if selected
+                .as_ref()
+                .is_some_and(|range| range.contains(&index))
+            {
+                overlay.rect(
+                    Vec2::new(0.0, y),
+                    Vec2::new(screen_width, y + line_height),
+                    SELECTION_COLOR,
+                );
+            }
+            let timestamp = format!("[{}] ", line.timestamp);
+            let pos = Vec2::new(Self::MARGIN, y);
+            text.draw(overlay, &timestamp, pos, Self::TIMESTAMP_COLOR);
+            text.draw_colored(
+                overlay,
+                &line.runs,
+                pos + Vec2::new(text.width(&timestamp), 0.0),
+                1.0,
+            );
+            self.drawn_lines.push((index, y));
+            y += line_height;
+        }
+
+        let hint = if self.scroll > 0 {
+            format!(
+                "{} more line(s) below. F10: close, Ctrl+C: copy selection",
+                self.scroll
+            )
+        } else {
+            String::from("F10: close, Ctrl+C: copy selection")
+        };
+        text.draw(overlay, &hint, Vec2::new(Self::MARGIN, bottom), HINT_COLOR);
+    }
+}
@@ -0,0 +1,45 @@
+struct CameraUniform {
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    fog_color: vec3<f32>,
+    fog_end: f32,
+    time: f32,
+    waving: u32,
+    daylight: f32,
+}
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    // Face shading is already applied
+    @location(1) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) view_position: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    out.color = model.color;
+    out.view_position = (camera.view * vec4<f32>(model.position, 1.0)).xyz;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Same fog as for mapblocks, so clouds fade out at the view distance
+    let fog_end = camera.fog_end;
+    let fog_start = fog_end * 0.8;
+    let factor = smoothstep(fog_start, fog_end, length(in.view_position));
+    let color = mix(in.color.rgb, camera.fog_color, factor);
+
+    return vec4<f32>(color, in.color.a);
+}
@@ -0,0 +1,375 @@
+//! The clouds pass: a layer of boxes on a grid, filled according to 2D
+//! noise, drifting with the wind. Servers can change them with
+//! TOCLIENT_CLOUD_PARAMS, changes are faded in over a few seconds.
+
+use glam::{IVec2, Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+use crate::camera::CameraParams;
+use crate::post_process::PostProcess;
+use crate::sky::ease_curve;
+use crate::srgb_to_linear;
+use crate::texture::MyTexture;
+
+// Compare to Luanti, skyparams.h, CloudParams
+#[derive(Debug, Clone)]
+pub struct CloudParams {
+    /// 0.0 is a clear sky, 1.0 is fully covered
+    pub density: f32,
+    /// Linear color of the lit top faces, the alpha is the opacity
+    pub color: Vec4,
+    /// Linear color the clouds never get darker than
+    pub ambient: Vec3,
+    /// Y position of the bottom faces, in nodes
+    pub height: f32,
+    /// In nodes, flat clouds are drawn if 0
+    pub thickness: f32,
+    /// In nodes per second, X and Z
+    pub speed: Vec2,
+}
+
+impl Default for CloudParams {
+    // Compare to Luanti, skyparams.h, SkyboxDefaults::getCloudDefaults
+    fn default() -> Self {
+        Self {
+            density: 0.4,
+            color: Vec4::new(
+                srgb_to_linear(240),
+                srgb_to_linear(240),
+                srgb_to_linear(255),
+                229.0 / 255.0,
+            ),
+            ambient: Vec3::ZERO,
+            height: 120.0,
+            thickness: 16.0,
+            speed: Vec2::new(0.0, -2.0),
+        }
+    }
+}
+
+impl CloudParams {
+    fn lerp(&self, target: &Self, t: f32) -> Self {
+        Self {
+            density: self.density + (target.density - self.density) * t,
+            color: self.color.lerp(target.color, t),
+            ambient: self.ambient.lerp(target.ambient, t),
+            height: self.height + (target.height - self.height) * t,
+            thickness: self.thickness + (target.thickness - self.thickness) * t,
+            speed: self.speed.lerp(target.speed, t),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CloudVertex {
+    /// In nodes
+    position: Vec3,
+    color: Vec4,
+}
+
+impl CloudVertex {
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: [wgpu::VertexAttribute; 2] =
+            wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<CloudVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+pub struct CloudRenderer {
+    pipeline: wgpu::RenderPipeline,
+
+    /// What is currently drawn, moves towards `target`
+    params: CloudParams,
+    /// The last params sent by the server
+    target: CloudParams,
+    /// How far the wind has moved the clouds, in nodes
+    offset: Vec2,
+
+    vertices: Vec<CloudVertex>,
+    indices: Vec<u32>,
+}
+
+impl CloudRenderer {
+    /// Size of a grid cell, in nodes
+    // Compare to Luanti, client/clouds.cpp, cloud_size
+    const CELL_SIZE: f32 = 64.0;
+    /// Time for most of a change of the params to be visible, in seconds
+    const FADE_TIME: f32 = 2.0;
+    const SEED: i32 = 1;
+    // Face shading, compare to Luanti, client/clouds.cpp, Clouds::updateMesh
+    const SHADE_TOP: f32 = 1.0;
+    const SHADE_X: f32 = 0.95;
+    const SHADE_Z: f32 = 0.9;
+    const SHADE_BOTTOM: f32 = 0.8;
+
+    pub fn new(device: &wgpu::Device, camera_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Cloud pipeline layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("cloud_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Cloud render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[CloudVertex::layout()],
+            },
+            // Clouds are seen from inside as well
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: MyTexture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: PostProcess::COLOR_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            params: CloudParams::default(),
+            target: CloudParams::default(),
+            offset: Vec2::ZERO,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    /// Goes back to the default clouds, for connecting to another server.
+    pub fn reset(&mut self) {
+        self.params = CloudParams::default();
+        self.target = CloudParams::default();
+    }
+
+    pub fn set_params(&mut self, params: CloudParams) {
+        self.target = params;
+    }
+
+    /// Moves the clouds with the wind and fades towards the target params.
+    pub fn step(&mut self, dtime: f32) {
+        let t = 1.0 - (-dtime / Self::FADE_TIME * 3.0).exp();
+        self.params = self.params.lerp(&self.target, t);
+        self.offset += self.params.speed * dtime;
+    }
+
+    // Compare to Luanti, client/clouds.cpp, Clouds::gridFilled
+    fn grid_filled(&self, cell: IVec2) -> bool {
+        let noise = noise2d_perlin(
+            cell.as_vec2() * (Self::CELL_SIZE / 200.0),
+            Self::SEED,
+            3,
+            0.5,
+        );
+        // Normalize to 0..1, given 3 octaves
+        const NOISE_BOUND: f32 = 1.0 + 0.5 + 0.25;
+        let density = noise / NOISE_BOUND * 0.5 + 0.5;
+        density < self.params.density
+    }
+
+    /// Builds the geometry of the cells around the camera for this frame.
+    // Compare to Luanti, client/clouds.cpp, Clouds::updateMesh
+    pub fn prepare(&mut self, camera: &CameraParams) {
+        let mut vertices = std::mem::take(&mut self.vertices);
+        let mut indices = std::mem::take(&mut self.indices);
+        vertices.clear();
+        indices.clear();
+
+        let params = &self.params;
+        // Compare to Luanti, client/clouds.cpp, Clouds::update
+        let color = (params.color.truncate() * camera.daylight)
+            .max(params.ambient)
+            .extend(params.color.w);
+        let center = ((Vec2::new(camera.pos.x, camera.pos.z) - self.offset) / Self::CELL_SIZE)
+            .floor()
+            .as_ivec2();
+        // Clouds further away than the fog end are hidden anyway
+        let radius = (camera.fog_end / Self::CELL_SIZE).ceil() as i32 + 1;
+        let bottom = params.height;
+        let top = params.height + params.thickness;
+
+        for z in -radius..=radius {
+            for x in -radius..=radius {
+                let cell = center + IVec2::new(x, z);
+                if !self.grid_filled(cell) {
+                    continue;
+                }
+
+                let min = cell.as_vec2() * Self::CELL_SIZE + self.offset;
+                let max = min + Self::CELL_SIZE;
+                let (x0, x1, z0, z1) = (min.x, max.x, min.y, max.y);
+
+                let mut faces = vec![(
+                    [
+                        Vec3::new(x0, top, z0),
+                        Vec3::new(x1, top, z0),
+                        Vec3::new(x1, top, z1),
+                        Vec3::new(x0, top, z1),
+                    ],
+                    Self::SHADE_TOP,
+                )];
+                if params.thickness > 0.0 {
+                    faces.push((
+                        [
+                            Vec3::new(x0, bottom, z0),
+                            Vec3::new(x1, bottom, z0),
+                            Vec3::new(x1, bottom, z1),
+                            Vec3::new(x0, bottom, z1),
+                        ],
+                        Self::SHADE_BOTTOM,
+                    ));
+                    // Sides between two filled cells are never visible
+                    let sides = [
+                        (IVec2::NEG_X, x0, z0, x0, z1, Self::SHADE_X),
+                        (IVec2::X, x1, z0, x1, z1, Self::SHADE_X),
+                        (IVec2::NEG_Y, x0, z0, x1, z0, Self::SHADE_Z),
+                        (IVec2::Y, x0, z1, x1, z1, Self::SHADE_Z),
+                    ];
+                    for (dir, ax, az, bx, bz, shade) in sides {
+                        if self.grid_filled(cell + dir) {
+                            continue;
+                        }
+                        faces.push((
+                            [
+                                Vec3::new(ax, bottom, az),
+                                Vec3::new(bx, bottom, bz),
+                                Vec3::new(bx, top, bz),
+                                Vec3::new(ax, top, az),
+                            ],
+                            shade,
+                        ));
+                    }
+                }
+
+                for (corners, shade) in faces {
+                    let index_offset = vertices.len() as u32;
+                    let color = (color.truncate() * shade).extend(color.w);
+                    vertices.extend(corners.map(|position| CloudVertex { position, color }));
+                    indices.extend([0, 1, 2, 2, 3, 0].map(|index| index_offset + index));
+                }
+            }
+        }
+
+        self.vertices = vertices;
+        self.indices = indices;
+    }
+
+    /// Draws the geometry built by `prepare` into the scene.
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        color: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        if self.indices.is_empty() {
+            return;
+        }
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cloud vertex buffer"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cloud index buffer"),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Cloud pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, camera_bind_group, &[]);
+        pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        pass.draw_indexed(0..self.indices.len() as u32, 0, 0..1);
+    }
+}
+
+// Compare to Luanti, noise.cpp, noise2d
+fn noise2d(x: i32, y: i32, seed: i32) -> f32 {
+    let mut n = (1619i32
+        .wrapping_mul(x)
+        .wrapping_add(31337i32.wrapping_mul(y))
+        .wrapping_add(1013i32.wrapping_mul(seed))
+        & 0x7fffffff) as u32;
+    n ^= n >> 13;
+    n = n
+        .wrapping_mul(n.wrapping_mul(n).wrapping_mul(60493).wrapping_add(19990303))
+        .wrapping_add(1376312589)
+        & 0x7fffffff;
+    1.0 - n as f32 / 0x40000000 as f32
+}
+
+// Compare to Luanti, noise.cpp, noise2d_gradient
+fn noise2d_gradient(pos: Vec2, seed: i32) -> f32 {
+    let floor = pos.floor();
+    let (x0, y0) = (floor.x as i32, floor.y as i32);
+    let (tx, ty) = (ease_curve(pos.x - floor.x), ease_curve(pos.y - floor.y));
+    let v00 = noise2d(x0, y0, seed);
+    let v10 = noise2d(x0 + 1, y0, seed);
+    let v01 = noise2d(x0, y0 + 1, seed);
+    let v11 = noise2d(x0 + 1, y0 + 1, seed);
+    let u = v00 + (v10 - v00) * tx;
+    let v = v01 + (v11 - v01) * tx;
+    u + (v - u) * ty
+}
+
+// Compare to Luanti, noise.cpp, noise2d_perlin
+fn noise2d_perlin(pos: Vec2, seed: i32, octaves: i32, persistence: f32) -> f32 {
+    let mut result = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    for octave in 0..octaves {
+        result += amplitude * noise2d_gradient(pos * frequency, seed + octave);
+        frequency *= 2.0;
+        amplitude *= persistence;
+    }
+    result
+}
@@ -0,0 +1,96 @@
+use glam::{Vec2, Vec4};
+
+use crate::overlay::OverlayRenderer;
+use crate::profiler::Profiler;
+use crate::text::TextRenderer;
+
+/// The debug screen toggled with F3: statistics passed in by the caller,
+/// plus a graph of recent frame times to spot stutter. The frame times are
+/// the profiler's, see Profiler::frame_times.
+// Compare to Luanti, client/gameui.cpp, GameUI::update (profiler_graph and
+// debug text)
+#[derive(Default)]
+pub struct DebugScreen {
+    visible: bool,
+}
+
+impl DebugScreen {
+    /// Frame time at the top of the graph, in seconds
+    const GRAPH_MAX: f32 = 1.0 / 20.0;
+    /// Frame time marked by a line in the graph, in seconds
+    const GRAPH_TARGET: f32 = 1.0 / 60.0;
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Draws `lines` in the top left corner with the frame time graph below.
+    pub fn draw(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        lines: &[String],
+        profiler: &Profiler,
+    ) {
+        const MARGIN: f32 = 16.0;
+        const PADDING: f32 = 8.0;
+        const BAR_WIDTH: f32 = 2.0;
+        const GRAPH_HEIGHT: f32 = 80.0;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+        const BAR_COLOR: Vec4 = Vec4::new(0.3, 0.9, 0.3, 0.9);
+        const SLOW_BAR_COLOR: Vec4 = Vec4::new(1.0, 0.3, 0.2, 0.9);
+        const TARGET_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.4);
+
+        if !self.visible {
+            return;
+        }
+        let line_height = text.line_height();
+        let graph_width = Profiler::HISTORY as f32 * BAR_WIDTH;
+        let width = lines
+            .iter()
+            .map(|line| text.width(line))
+            .fold(graph_width, f32::max);
+        let text_height = lines.len() as f32 * line_height;
+        let min = Vec2::splat(MARGIN);
+        let size = Vec2::new(
+            width + 2.0 * PADDING,
+            text_height + PADDING + GRAPH_HEIGHT + 2.0 * PADDING,
+        );
+        overlay.rect(min, min + size, BACKGROUND_COLOR);
+        for (index, line) in lines.iter().enumerate() {
+            let pos = min + PADDING + Vec2::new(0.0, index as f32 * line_height);
+            text.draw(overlay, line, pos, TEXT_COLOR);
+        }
+
+        // Bars grow upwards from the bottom of the graph, newest on the right
+        let graph_bottom = min.y + size.y - PADDING;
+        let graph_right = min.x + PADDING + graph_width;
+        let frame_times = profiler.frame_times();
+        let count = frame_times.len();
+        for (index, dtime) in frame_times.enumerate() {
+            let height = (dtime / Self::GRAPH_MAX).min(1.0) * GRAPH_HEIGHT;
+            let x = graph_right - (count - index) as f32 * BAR_WIDTH;
+            let color = if dtime > 2.0 * Self::GRAPH_TARGET {
+                SLOW_BAR_COLOR
+            } else {
+                BAR_COLOR
+            };
+            overlay.rect(
+                Vec2::new(x, graph_bottom - height),
+                Vec2::new(x + BAR_WIDTH, graph_bottom),
+                color,
+            );
+        }
+        let target_y = graph_bottom - Self::GRAPH_TARGET / Self::GRAPH_MAX * GRAPH_HEIGHT;
+        overlay.rect(
+            Vec2::new(min.x + PADDING, target_y),
+            Vec2::new(graph_right, target_y + 1.0),
+            TARGET_COLOR,
+        );
+    }
+}
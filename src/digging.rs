@@ -0,0 +1,168 @@
+//! Digging nodes: how long it takes with the wielded tool, and when to tell
+//! the server that digging started, stopped or finished.
+
+use luanti_protocol::types::{ContentFeatures, InteractAction, ToolCapabilities};
+
+use crate::pointing::PointedThing;
+
+/// Result of comparing tool capabilities against the groups of a node.
+// Compare to Luanti, tool.h, DigParams
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DigParams {
+    pub diggable: bool,
+    /// In seconds
+    pub time: f32,
+}
+
+impl DigParams {
+    pub const NOT_DIGGABLE: Self = Self {
+        diggable: false,
+        time: 0.0,
+    };
+}
+
+/// Returns the rating of the node in the group, 0 if it isn't in it.
+// Compare to Luanti, itemgroup.h, itemgroup_get
+fn itemgroup_get(groups: &[(String, i16)], name: &str) -> i16 {
+    groups
+        .iter()
+        .find(|(group, _)| group == name)
+        .map_or(0, |(_, rating)| *rating)
+}
+
+/// Returns whether and how fast a node can be dug with the given tool
+/// capabilities.
+// Compare to Luanti, tool.cpp, getDigParams
+pub fn dig_params(def: &ContentFeatures, caps: &ToolCapabilities) -> DigParams {
+    let groups = &def.groups;
+
+    // The dig_immediate group has fixed times, unless the tool handles it
+    if !caps
+        .group_caps
+        .iter()
+        .any(|(name, _)| name == "dig_immediate")
+    {
+        match itemgroup_get(groups, "dig_immediate") {
+            2 => {
+                return DigParams {
+                    diggable: true,
+                    time: 0.5,
+                };
+            }
+            3 => {
+                return DigParams {
+                    diggable: true,
+                    time: 0.0,
+                };
+            }
+            _ => (),
+        }
+    }
+
+    let level = itemgroup_get(groups, "level");
+    let mut result = DigParams::NOT_DIGGABLE;
+    for (name, cap) in &caps.group_caps {
+        let leveldiff = cap.maxlevel - level;
+        if leveldiff < 0 {
+            continue;
+        }
+        let rating = itemgroup_get(groups, name);
+        let Some((_, mut time)) = cap.times.iter().find(|(r, _)| *r == rating).copied() else {
+            continue;
+        };
+        // Tools of a higher level dig faster
+        if leveldiff > 1 {
+            time /= leveldiff as f32;
+        }
+        if !result.diggable || time < result.time {
+            result = DigParams {
+                diggable: true,
+                time,
+            };
+        }
+    }
+    result
+}
+
+/// Tracks the node being dug while the dig button is held.
+// Compare to Luanti, client/game.cpp, Game::handleDigging
+#[derive(Default)]
+pub struct Digging {
+    /// The node being dug, always a PointedThing::Node
+    target: Option<PointedThing>,
+    params: Option<DigParams>,
+    /// Time spent digging the target, in seconds
+    time: f32,
+    /// Time until the next node may be dug, in seconds
+    nodig_delay: f32,
+}
+
+impl Digging {
+    /// Upper bound for the delay after digging a node
+    const NODIG_DELAY_MAX: f32 = 0.3;
+    /// Delay after digging a node instantly, so holding the button doesn't
+    /// dig a node every frame
+    const NODIG_DELAY_INSTANT: f32 = 0.15;
+    /// Number of crack stages the delay is based on, like Luanti's crack
+    /// texture
+    const CRACK_STAGES: f32 = 5.0;
+
+    /// Advances digging by `dtime` seconds. `pointed` is what the player
+    /// points at while the dig button is held, Nothing otherwise.
+    /// `dig_params` is called when starting to dig a node. Returns the
+    /// interactions to send to the server, in order.
+    pub fn step(
+        &mut self,
+        dtime: f32,
+        pointed: &PointedThing,
+        dig_params: impl FnOnce(&PointedThing) -> DigParams,
+    ) -> Vec<(InteractAction, PointedThing)> {
+        let mut actions = Vec::new();
+        self.nodig_delay = (self.nodig_delay - dtime).max(0.0);
+
+        let target_under = |thing: &PointedThing| match thing {
+            PointedThing::Node { under, .. } => Some(*under),
+            PointedThing::Nothing | PointedThing::Object { .. } => None,
+        };
+        let pointed_under = target_under(pointed);
+
+        // Stop when the button was released or the player looks elsewhere
+        if let Some(target) = &self.target
+            && target_under(target) != pointed_under
+        {
+            actions.push((InteractAction::StopDigging, self.target.take().unwrap()));
+            self.params = None;
+        }
+
+        if self.target.is_none() && pointed_under.is_some() && self.nodig_delay <= 0.0 {
+            self.params = Some(dig_params(pointed));
+            self.target = Some(pointed.clone());
+            self.time = 0.0;
+            actions.push((InteractAction::StartDigging, pointed.clone()));
+        }
+
+        if let (Some(target), Some(params)) = (&self.target, self.params) {
+            self.time += dtime;
+            if params.diggable && self.time >= params.time {
+                actions.push((InteractAction::DiggingCompleted, target.clone()));
+                self.nodig_delay = if params.time < 0.1 {
+                    Self::NODIG_DELAY_INSTANT
+                } else {
+                    (params.time / Self::CRACK_STAGES).min(Self::NODIG_DELAY_MAX)
+                };
+                self.target = None;
+                self.params = None;
+            }
+        }
+
+        actions
+    }
+
+    /// The node being dug and how far along digging it is, from 0.0 to 1.0.
+    /// None if nothing is being dug or the node can't be dug.
+    pub fn progress(&self) -> Option<(&PointedThing, f32)> {
+        let params = self.params.filter(|params| params.diggable)?;
+        let target = self.target.as_ref()?;
+        Some((target, (self.time / params.time).min(1.0)))
+    }
+}
@@ -0,0 +1,676 @@
+//! The entity pass: draws active objects into the scene, according to the
+//! "visual" in their object properties. Geometry is rebuilt every frame,
+//! there are few objects compared to mapblocks.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
+
+use glam::{Quat, Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+use crate::active_object::{ActiveObject, rotation_to_quat, wrap_degrees_180};
+use crate::camera::CameraParams;
+use crate::light::{self, LIGHT_SUN};
+use crate::luanti_client::BS;
+use crate::media::MediaManager;
+use crate::model::ModelManager;
+use crate::post_process::PostProcess;
+use crate::texture::MyTexture;
+use crate::world::{SmokePuff, World};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EntityVertex {
+    /// In nodes
+    position: Vec3,
+    uv: Vec2,
+    /// Multiplied with the texture color
+    color: Vec4,
+    /// Day and night light, see light::vertex_light
+    light: Vec2,
+}
+
+impl EntityVertex {
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+            0 => Float32x3,
+            1 => Float32x2,
+            2 => Float32x4,
+            3 => Float32x2
+        ];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<EntityVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+struct EntityTexture {
+    // Kept alive for the bind group, the size is used for animations
+    texture: MyTexture,
+    bind_group: wgpu::BindGroup,
+}
+
+/// A range of indices drawn with the same texture.
+struct Batch {
+    texture: String,
+    indices: Range<u32>,
+}
+
+/// A quad to draw, corners are counterclockwise when seen from the front,
+/// starting at the bottom left.
+struct Quad {
+    corners: [Vec3; 4],
+    uv_min: Vec2,
+    uv_max: Vec2,
+    shade: f32,
+}
+
+pub struct EntityRenderer {
+    pipeline: wgpu::RenderPipeline,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// By name, None if the texture couldn't be loaded
+    textures: HashMap<String, Option<EntityTexture>>,
+    /// For "mesh" visuals
+    models: ModelManager,
+
+    vertices: Vec<EntityVertex>,
+    indices: Vec<u32>,
+    batches: Vec<Batch>,
+}
+
+impl EntityRenderer {
+    // Same face shading as mapblocks, see mapblock_shader.wgsl
+    const SHADE_TOP: f32 = 1.0;
+    const SHADE_BOTTOM: f32 = 0.2;
+    const SHADE_X: f32 = 0.6;
+    const SHADE_Z: f32 = 0.8;
+
+    pub fn new(device: &wgpu::Device, camera_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Entity texture bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Entity sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Entity pipeline layout"),
+            bind_group_layouts: &[camera_bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("entity_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Entity render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[EntityVertex::layout()],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                ..wgpu::PrimitiveState::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: MyTexture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: PostProcess::COLOR_FORMAT,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            texture_bind_group_layout,
+            sampler,
+            textures: HashMap::new(),
+            models: ModelManager::default(),
+
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            batches: Vec::new(),
+        }
+    }
+
+    /// Uploads the texture if it hasn't been uploaded yet. Returns the name
+    /// of the texture to use, which is the fallback texture if it couldn't
+    /// be loaded.
+    fn load_texture<'a>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        name: &'a str,
+    ) -> &'a str {
+        if !self.textures.contains_key(name) {
+            // TODO: texture modifiers other than [brighten, which is the
+            // default damage_texture_modifier
+            let mut parts = name.split('^');
+            let base_name = parts.next().unwrap_or_default();
+            let brighten = parts.any(|modifier| modifier == "[brighten");
+            let texture = match media.load_image(base_name) {
+                Ok(Some(mut img)) => {
+                    if brighten {
+                        img = image::DynamicImage::ImageRgba8(Self::brighten(img.to_rgba8()));
+                    }
+                    // Only fails for invalid images, which DynamicImage can't be
+                    let texture = MyTexture::from_image(device, queue, name, &img).unwrap();
+                    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some(name),
+                        layout: &self.texture_bind_group_layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: wgpu::BindingResource::TextureView(&texture.view),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: wgpu::BindingResource::Sampler(&self.sampler),
+                            },
+                        ],
+                    });
+                    Some(EntityTexture {
+                        texture,
+                        bind_group,
+                    })
+                }
+                Ok(None) => {
+                    println!("Entity texture \"{}\" not found", name);
+                    None
+                }
+                Err(err) => {
+                    println!("Error while loading entity texture \"{}\": {:?}", name, err);
+                    None
+                }
+            };
+            self.textures.insert(String::from(name), texture);
+        }
+
+        if self.textures[name].is_some() || name == MediaManager::FALLBACK_TEXTURE {
+            name
+        } else {
+            self.load_texture(device, queue, media, MediaManager::FALLBACK_TEXTURE)
+        }
+    }
+
+    /// Blends the colors halfway towards white, keeping the alpha.
+    // Compare to Luanti, client/imagesource.cpp, brighten
+    fn brighten(mut img: image::RgbaImage) -> image::RgbaImage {
+        for pixel in img.pixels_mut() {
+            for c in &mut pixel.0[..3] {
+                *c = 128 + *c / 2;
+            }
+        }
+        img
+    }
+
+    /// Builds the geometry of all visible objects for this frame. The local
+    /// player is only drawn if not `first_person`.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::addToScene
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        world: &World,
+        camera: &CameraParams,
+        first_person: bool,
+    ) {
+        self.vertices.clear();
+        self.indices.clear();
+        self.batches.clear();
+
+        for (_, object) in world.ecs.query::<&ActiveObject>().iter() {
+            if !object.is_visible(first_person) {
+                continue;
+            }
+            let Some(props) = &object.props else {
+                continue;
+            };
+            // One texture per quad
+            let quads = match props.visual.as_str() {
+                "cube" => Self::cube_quads(object),
+                "sprite" => vec![Self::sprite_quad(object, camera)],
+                "upright_sprite" => Self::upright_sprite_quads(object, camera),
+                "mesh" => {
+                    self.add_mesh(device, queue, media, object);
+                    continue;
+                }
+                // TODO: "item" and "wielditem" visuals
+                _ => continue,
+            };
+
+            for (index, quad) in quads.into_iter().enumerate() {
+                let name = match props.textures.get(index) {
+                    Some(name) if !name.is_empty() => name.as_str(),
+                    // The back of upright sprites shows the front texture
+                    _ if props.visual == "upright_sprite" => {
+                        props.textures.first().map_or("", String::as_str)
+                    }
+                    _ => MediaManager::FALLBACK_TEXTURE,
+                };
+                let name = format!("{}{}", name, object.texture_modifier());
+                let texture = self.load_texture(device, queue, media, &name);
+                self.add_quad(texture, quad, object.light);
+            }
+        }
+
+        // No puff rather than the fallback texture
+        let puff_texture = MediaManager::SMOKE_PUFF_TEXTURE;
+        if self.load_texture(device, queue, media, puff_texture) == puff_texture {
+            for (_, puff) in world.ecs.query::<&SmokePuff>().iter() {
+                let quad = Quad {
+                    corners: Self::billboard(puff.pos, Vec2::ONE, camera),
+                    uv_min: Vec2::ZERO,
+                    uv_max: Vec2::ONE,
+                    shade: 1.0,
+                };
+                self.add_quad(puff_texture, quad, Self::sunlight());
+            }
+        }
+    }
+
+    /// Adds the crack overlay of a node being dug, covering the given boxes.
+    /// `progress` is from 0.0 to 1.0 and picks the frame of the crack
+    /// texture.
+    // Compare to Luanti, client/game.cpp, Game::handleDigging and
+    // client/mapblock_mesh.cpp, MapBlockMesh::animate
+    pub fn add_crack(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        boxes: &[(Vec3, Vec3)],
+        progress: f32,
+    ) {
+        const CRACK_TEXTURE: &str = "crack_anylength.png";
+        // Drawn slightly outside of the node, so it isn't hidden by it
+        const MARGIN: f32 = 0.002;
+
+        // No crack rather than the fallback texture on top of the node
+        if self.load_texture(device, queue, media, CRACK_TEXTURE) != CRACK_TEXTURE {
+            return;
+        }
+        // The frames are stacked vertically
+        let size = self.textures[CRACK_TEXTURE]
+            .as_ref()
+            .unwrap()
+            .texture
+            .texture
+            .size();
+        let frames = (size.height / size.width.max(1)).max(1);
+        let frame = ((progress * frames as f32) as u32).min(frames - 1);
+        let uv_min = Vec2::new(0.0, frame as f32 / frames as f32);
+        let uv_max = Vec2::new(1.0, (frame + 1) as f32 / frames as f32);
+
+        for (min, max) in boxes {
+            let center = (*min + *max) / 2.0;
+            let size = *max - *min + MARGIN;
+            for quad in Self::box_quads(center, size, Quat::IDENTITY) {
+                self.add_quad(
+                    CRACK_TEXTURE,
+                    Quad {
+                        uv_min,
+                        uv_max,
+                        ..quad
+                    },
+                    Self::sunlight(),
+                );
+            }
+        }
+    }
+
+    fn add_quad(&mut self, texture: &str, quad: Quad, light: Vec2) {
+        let index_offset = self.vertices.len() as u32;
+        let color = Vec4::new(quad.shade, quad.shade, quad.shade, 1.0);
+        let uvs = [
+            Vec2::new(quad.uv_min.x, quad.uv_max.y),
+            quad.uv_max,
+            Vec2::new(quad.uv_max.x, quad.uv_min.y),
+            quad.uv_min,
+        ];
+        for (position, uv) in quad.corners.into_iter().zip(uvs) {
+            self.vertices.push(EntityVertex {
+                position,
+                uv,
+                color,
+                light,
+            });
+        }
+
+        let first_index = self.indices.len() as u32;
+        self.indices
+            .extend([0, 1, 2, 2, 3, 0].map(|index| index_offset + index));
+        self.add_to_batch(texture, first_index);
+    }
+
+    /// Adds the indices from `first_index` on to the batch of the texture.
+    fn add_to_batch(&mut self, texture: &str, first_index: u32) {
+        let last_index = self.indices.len() as u32;
+        // Consecutive quads with the same texture are drawn together
+        match self.batches.last_mut() {
+            Some(batch) if batch.texture == texture => batch.indices.end = last_index,
+            _ => self.batches.push(Batch {
+                texture: String::from(texture),
+                indices: first_index..last_index,
+            }),
+        }
+    }
+
+    /// Adds the model of a "mesh" visual, without animations. Like for
+    /// cubes, the n-th mesh buffer uses the n-th texture.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::addToScene
+    fn add_mesh(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        object: &ActiveObject,
+    ) {
+        let props = object.props.as_ref().unwrap();
+        let model = match self.models.load(media, &props.mesh) {
+            Ok(Some(model)) => model,
+            Ok(None) => return,
+            Err(err) => {
+                println!("Error while loading mesh \"{}\": {:?}", props.mesh, err);
+                return;
+            }
+        };
+        // Entity models are in Luanti's internal units
+        let scale = props.visual_size / BS;
+        let rotation = rotation_to_quat(object.rotation);
+
+        for (index, buffer) in model.buffers.iter().enumerate() {
+            let name = match props.textures.get(index) {
+                Some(name) if !name.is_empty() => name.as_str(),
+                _ => MediaManager::FALLBACK_TEXTURE,
+            };
+            let name = format!("{}{}", name, object.texture_modifier());
+            let texture = self.load_texture(device, queue, media, &name);
+
+            let index_offset = self.vertices.len() as u32;
+            self.vertices.extend(buffer.vertices.iter().map(|vertex| {
+                let shade = Self::normal_shade(rotation * vertex.normal);
+                EntityVertex {
+                    position: object.pos + rotation * (vertex.position * scale),
+                    uv: vertex.uv,
+                    color: Vec4::new(shade, shade, shade, 1.0),
+                    light: object.light,
+                }
+            }));
+
+            let first_index = self.indices.len() as u32;
+            // Models are wound clockwise, this pass culls clockwise faces
+            self.indices.extend(
+                buffer
+                    .indices
+                    .chunks_exact(3)
+                    .flat_map(|triangle| [triangle[2], triangle[1], triangle[0]])
+                    .map(|index| index_offset + index),
+            );
+            self.add_to_batch(texture, first_index);
+        }
+    }
+
+    /// Light for things that aren't lit by the map, like smoke puffs and
+    /// the crack overlay.
+    fn sunlight() -> Vec2 {
+        light::vertex_light(LIGHT_SUN, 0, 0)
+    }
+
+    /// The face shading for any normal, blending the shades of the box
+    /// faces by the direction.
+    fn normal_shade(normal: Vec3) -> f32 {
+        let weights = normal * normal;
+        let shade_y = if normal.y > 0.0 {
+            Self::SHADE_TOP
+        } else {
+            Self::SHADE_BOTTOM
+        };
+        weights.x * Self::SHADE_X + weights.y * shade_y + weights.z * Self::SHADE_Z
+    }
+
+    /// A box of visual_size, with the textures in the order +Y, -Y, +X, -X,
+    /// +Z, -Z.
+    fn cube_quads(object: &ActiveObject) -> Vec<Quad> {
+        let size = object.props.as_ref().unwrap().visual_size;
+        let rotation = rotation_to_quat(object.rotation);
+        Self::box_quads(object.pos, size, rotation)
+    }
+
+    /// The faces of a box in the order +Y, -Y, +X, -X, +Z, -Z, each showing
+    /// the whole texture.
+    fn box_quads(pos: Vec3, size: Vec3, rotation: Quat) -> Vec<Quad> {
+        let faces = [
+            (Vec3::Y, Vec3::Z, Self::SHADE_TOP),
+            (Vec3::NEG_Y, Vec3::NEG_Z, Self::SHADE_BOTTOM),
+            (Vec3::X, Vec3::Y, Self::SHADE_X),
+            (Vec3::NEG_X, Vec3::Y, Self::SHADE_X),
+            (Vec3::Z, Vec3::Y, Self::SHADE_Z),
+            (Vec3::NEG_Z, Vec3::Y, Self::SHADE_Z),
+        ];
+
+        faces
+            .into_iter()
+            .map(|(normal, up, shade)| {
+                // Seen from outside, looking against the normal. The world
+                // is left-handed.
+                let right = up.cross(-normal);
+                let center = normal * size / 2.0;
+                let right = right * size / 2.0;
+                let up = up * size / 2.0;
+                let corners = [
+                    center - right - up,
+                    center + right - up,
+                    center + right + up,
+                    center - right + up,
+                ]
+                .map(|corner| pos + rotation * corner);
+                Quad {
+                    corners,
+                    uv_min: Vec2::ZERO,
+                    uv_max: Vec2::ONE,
+                    shade,
+                }
+            })
+            .collect()
+    }
+
+    /// The UV range of the current spritesheet frame.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::updateTexturePos
+    fn sprite_uvs(object: &ActiveObject, camera: &CameraParams) -> (Vec2, Vec2) {
+        let props = object.props.as_ref().unwrap();
+        let sprite = &object.sprite;
+        let mut col = sprite.base_pos.x as f32;
+        // Animations go downwards
+        let row = (sprite.base_pos.y + sprite.frame as i16) as f32;
+
+        if sprite.select_horiz_by_yawpitch {
+            let cam_to_entity = (object.pos - camera.pos).normalize_or_zero();
+            if cam_to_entity.y > 0.75 {
+                col += 5.0;
+            } else if cam_to_entity.y < -0.75 {
+                col += 4.0;
+            } else {
+                let mob_dir = cam_to_entity.z.atan2(cam_to_entity.x) * 180.0 / PI;
+                let dir = mob_dir - object.rotation.y;
+                let near = |angle: f32| wrap_degrees_180(dir - angle).abs() <= 45.1;
+                col += if near(0.0) {
+                    2.0
+                } else if near(90.0) {
+                    3.0
+                } else if near(180.0) {
+                    0.0
+                } else if near(-90.0) {
+                    1.0
+                } else {
+                    4.0
+                };
+            }
+        }
+
+        let frames = props.spritediv.as_vec2().max(Vec2::ONE);
+        let frame = Vec2::new(col, row);
+        (frame / frames, (frame + 1.0) / frames)
+    }
+
+    /// The corners of a quad of the given size centered at `pos`, facing
+    /// the camera.
+    fn billboard(pos: Vec3, size: Vec2, camera: &CameraParams) -> [Vec3; 4] {
+        let right = CameraParams::WORLD_UP
+            .cross(camera.dir)
+            .normalize_or(Vec3::X);
+        let up = camera.dir.cross(right).normalize_or(Vec3::Y);
+        let (right, up) = (right * size.x / 2.0, up * size.y / 2.0);
+        [-right - up, right - up, right + up, -right + up].map(|corner| pos + corner)
+    }
+
+    /// A quad of visual_size facing the camera.
+    fn sprite_quad(object: &ActiveObject, camera: &CameraParams) -> Quad {
+        let size = object.props.as_ref().unwrap().visual_size;
+        let (uv_min, uv_max) = Self::sprite_uvs(object, camera);
+        Quad {
+            corners: Self::billboard(object.pos, size.truncate(), camera),
+            uv_min,
+            uv_max,
+            shade: 1.0,
+        }
+    }
+
+    /// Two vertical quads of visual_size, the front facing -Z and the back
+    /// facing +Z before rotation.
+    fn upright_sprite_quads(object: &ActiveObject, camera: &CameraParams) -> Vec<Quad> {
+        let size = object.props.as_ref().unwrap().visual_size;
+        let rotation = rotation_to_quat(object.rotation);
+        let (dx, dy) = (size.x / 2.0, size.y / 2.0);
+        let (uv_min, uv_max) = Self::sprite_uvs(object, camera);
+        let quad = |corners: [Vec3; 4]| Quad {
+            corners: corners.map(|corner| object.pos + rotation * corner),
+            uv_min,
+            uv_max,
+            shade: 1.0,
+        };
+        vec![
+            quad([
+                Vec3::new(-dx, -dy, 0.0),
+                Vec3::new(dx, -dy, 0.0),
+                Vec3::new(dx, dy, 0.0),
+                Vec3::new(-dx, dy, 0.0),
+            ]),
+            quad([
+                Vec3::new(dx, -dy, 0.0),
+                Vec3::new(-dx, -dy, 0.0),
+                Vec3::new(-dx, dy, 0.0),
+                Vec3::new(dx, dy, 0.0),
+            ]),
+        ]
+    }
+
+    /// Draws the geometry built by `prepare` over the scene.
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        color: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        if self.indices.is_empty() {
+            return;
+        }
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Entity vertex buffer"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Entity index buffer"),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Entity pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, camera_bind_group, &[]);
+        pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        for batch in &self.batches {
+            // Batches only reference loaded textures
+            let Some(Some(texture)) = self.textures.get(&batch.texture) else {
+                continue;
+            };
+            pass.set_bind_group(1, &texture.bind_group, &[]);
+            pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+struct CameraUniform {
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    fog_color: vec3<f32>,
+    fog_end: f32,
+    time: f32,
+    waving: u32,
+    daylight: f32,
+}
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+@group(1) @binding(0)
+var the_texture: texture_2d<f32>;
+
+@group(1) @binding(1)
+var the_sampler: sampler;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    // Face shading, multiplied with the texture color
+    @location(2) color: vec4<f32>,
+    // Day and night light at the object's position
+    @location(3) light: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) view_position: vec3<f32>,
+    @location(3) light: f32,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    out.uv = model.uv;
+    out.color = model.color;
+    out.light = mix(model.light.y, model.light.x, camera.daylight);
+    out.view_position = (camera.view * vec4<f32>(model.position, 1.0)).xyz;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let tex_color = textureSample(the_texture, the_sampler, in.uv) * in.color;
+    // Same as for mapblocks, there's no sorting for semi-transparent objects
+    if tex_color.a == 0.0 {
+        discard;
+    }
+
+    // Lit by the node light like mapblocks, see mapblock_shader.wgsl
+    let lit_color = tex_color.rgb * in.light;
+
+    let fog_end = camera.fog_end;
+    let fog_start = fog_end * 0.8;
+    let factor = smoothstep(fog_start, fog_end, length(in.view_position));
+    let color = mix(lit_color, camera.fog_color, factor);
+
+    return vec4<f32>(color, 1.0);
+}
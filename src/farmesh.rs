@@ -0,0 +1,614 @@
+//! Far terrain: a coarse heightfield drawn beyond the view distance, so the
+//! world doesn't end in a wall of fog. Meshgen summarizes the surface of
+//! every mapblock it meshes, and the highest surface of each mapblock
+//! column becomes one vertex of the heightfield. Columns stay known after
+//! walking away from them, so the horizon fills in while exploring. They are
+//! saved per server in the `farmesh` subdirectory of the config directory,
+//! so the horizon is there right away on the next visit.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use glam::{I16Vec2, I16Vec3, IVec2, Vec2, Vec3};
+use luanti_core::{ContentId, MapBlockPos, MapNodePos};
+use luanti_protocol::types::DrawType;
+use wgpu::util::DeviceExt;
+
+use crate::camera::CameraParams;
+use crate::light::{self, LIGHT_SUN, LightBank};
+use crate::map::MeshgenMapData;
+use crate::map_image;
+use crate::media::MediaManager;
+use crate::node_def::NodeDefManager;
+use crate::post_process::PostProcess;
+use crate::settings::Settings;
+use crate::srgb_to_linear;
+use crate::texture::MyTexture;
+
+/// The sunlit surface of a mapblock.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockSurface {
+    /// Average Y position of the top faces, in nodes
+    pub height: f32,
+    /// The most common surface node
+    pub content_id: ContentId,
+}
+
+/// Finds the surface of a mapblock: the highest visible node of each node
+/// column that has direct sunlight above it. Columns covered by the
+/// mapblock above, like in caves, don't count. Returns None if no column
+/// has a surface.
+pub fn block_surface(data: &MeshgenMapData, node_def: &NodeDefManager) -> Option<BlockSurface> {
+    const SIZE: i16 = MapBlockPos::SIZE as i16;
+
+    let mut height_sum = 0;
+    let mut count = 0;
+    let mut contents: HashMap<ContentId, u32> = HashMap::new();
+    for z in 0..SIZE {
+        for x in 0..SIZE {
+            for y in (0..SIZE).rev() {
+                let node = data.get_node(MapNodePos(I16Vec3::new(x, y, z))).unwrap();
+                if node_def.get_with_fallback(node.content_id).drawtype == DrawType::AirLike {
+                    continue;
+                }
+                // Unknown if the mapblock above isn't loaded yet. It is
+                // meshed again once it is.
+                let above = data.get_node(MapNodePos(I16Vec3::new(x, y + 1, z)));
+                let sunlit = above.is_some_and(|above| {
+                    let def = node_def.get_with_fallback(above.content_id);
+                    light::get_light(above, LightBank::Day, def) == LIGHT_SUN
+                });
+                if sunlit {
+                    height_sum += y as i32;
+                    count += 1;
+                    *contents.entry(node.content_id).or_default() += 1;
+                }
+                break;
+            }
+        }
+    }
+
+    // Ties are broken by ID, so the result doesn't depend on the hash order
+    let (content_id, _) = contents
+        .into_iter()
+        .max_by_key(|&(content_id, count)| (count, content_id.0))?;
+    let origin = MapNodePos::from(data.get_blockpos()).0.y as f32;
+    Some(BlockSurface {
+        // Top faces are half a node above the node position
+        height: origin + height_sum as f32 / count as f32 + 0.5,
+        content_id,
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FarVertex {
+    /// In nodes
+    position: Vec3,
+    /// Linear, with slope shading applied
+    color: Vec3,
+    /// See light::vertex_light
+    light: Vec2,
+}
+
+impl FarVertex {
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: [wgpu::VertexAttribute; 3] =
+            wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3, 2 => Float32x2];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<FarVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FarMeshUniform {
+    /// The mapblock column of the first texel of the coverage texture
+    origin: IVec2,
+    near: f32,
+    _padding: f32,
+}
+
+/// The saved columns: the names of the surface nodes, then the mapblock
+/// position, height and node name index of each surface. Content IDs can
+/// change between connections, names don't.
+type SavedColumns = (Vec<String>, Vec<(i16, i16, i16, f32, usize)>);
+
+struct FarMeshBuffers {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    num_indices: u32,
+}
+
+pub struct FarMeshRenderer {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    /// Which mapblock columns around the camera the heightfield covers,
+    /// one texel per column. Mapblocks are only fogged at `far` where it
+    /// does, elsewhere they would end abruptly at `near`.
+    coverage: wgpu::Texture,
+    /// Distance up to which mapblocks are drawn, in nodes
+    near: f32,
+    /// Distance up to which the heightfield is drawn, in nodes
+    far: f32,
+    /// In mapblocks, the heightfield is built from the columns up to this
+    /// far from the camera's column
+    radius: i32,
+    /// Where the columns are saved, None until `load` was called
+    path: Option<PathBuf>,
+
+    /// The surfaces of each mapblock column, by mapblock X and Z, then by
+    /// mapblock Y
+    columns: HashMap<I16Vec2, BTreeMap<i16, BlockSurface>>,
+    /// Linear colors of the surface nodes
+    colors: HashMap<ContentId, Vec3>,
+    /// Whether `columns` changed since the last build
+    dirty: bool,
+    /// The mapblock column of the camera during the last build
+    center: IVec2,
+    next_build: Instant,
+    buffers: Option<FarMeshBuffers>,
+}
+
+impl FarMeshRenderer {
+    /// The heightfield is rebuilt at most this often
+    const BUILD_INTERVAL: Duration = Duration::from_millis(500);
+    /// How far the heightfield is moved down, in nodes. It is averaged over
+    /// whole mapblocks, so without this, it would stick out of the real
+    /// terrain where both are drawn.
+    const SINK: f32 = 2.0;
+
+    /// Only draws anything if `far` is larger than `near`.
+    pub fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        near: f32,
+        far: f32,
+    ) -> Self {
+        let radius = if far > near {
+            (far / MapBlockPos::SIZE as f32).ceil() as i32
+        } else {
+            0
+        };
+        let side = (radius * 2 + 1) as u32;
+
+        let uniform = FarMeshUniform {
+            origin: IVec2::ZERO,
+            near,
+            _padding: 0.0,
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Farmesh uniform buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Zeroed, nothing is covered until the first build
+        let coverage = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Farmesh coverage texture"),
+            size: wgpu::Extent3d {
+                width: side,
+                height: side,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let coverage_view = coverage.create_view(&wgpu::TextureViewDescriptor::default());
+        // Blends the fog distance between covered and uncovered columns
+        let coverage_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Farmesh coverage sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        // Also used by the mapblock pipeline, for the fog
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Farmesh bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Farmesh bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&coverage_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&coverage_sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Farmesh pipeline layout"),
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("farmesh_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Farmesh render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[FarVertex::layout()],
+            },
+            // Steep slopes can be seen from below
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: MyTexture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: PostProcess::COLOR_FORMAT,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            uniform_buffer,
+            coverage,
+            near,
+            far,
+            radius,
+            path: None,
+            columns: HashMap::new(),
+            colors: HashMap::new(),
+            dirty: false,
+            center: IVec2::ZERO,
+            next_build: Instant::now(),
+            buffers: None,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.far > self.near
+    }
+
+    /// For the mapblock pipeline: the uniform with `near` and the coverage
+    /// texture with its sampler, see `mapblock_shader.wgsl`.
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Forgets all columns, for connecting to another server. Call `save`
+    /// before to keep them.
+    pub fn reset(&mut self) {
+        self.columns.clear();
+        self.colors.clear();
+        self.buffers = None;
+        self.path = None;
+        // Clears the coverage with the next build
+        self.dirty = true;
+    }
+
+    /// Loads the columns saved for the given server. Needs the node
+    /// definitions, surfaces of nodes that no longer exist are dropped.
+    pub fn load(&mut self, address: SocketAddr, node_def: &NodeDefManager) -> anyhow::Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let mut path = Settings::config_dir()?;
+        path.push("farmesh");
+        fs::create_dir_all(&path)?;
+        // ':' isn't allowed in file names on Windows
+        path.push(format!("{}.json", address.to_string().replace(':', "_")));
+        self.path = Some(path.clone());
+
+        if !path.try_exists()? {
+            return Ok(());
+        }
+        let text = fs::read_to_string(&path)?;
+        let (names, surfaces): SavedColumns = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid farmesh columns {:?}", path))?;
+        let content_ids: Vec<_> = names.iter().map(|name| node_def.get_id(name)).collect();
+        for (x, y, z, height, index) in surfaces {
+            let Some(&Some(content_id)) = content_ids.get(index) else {
+                continue;
+            };
+            // Mapblocks meshed in the meantime are newer
+            self.columns
+                .entry(I16Vec2::new(x, z))
+                .or_default()
+                .entry(y)
+                .or_insert(BlockSurface { height, content_id });
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Saves the columns for the next visit to the same server, if `load`
+    /// was called for it.
+    pub fn save(&self, node_def: &NodeDefManager) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut names = Vec::new();
+        let mut name_indices: HashMap<ContentId, usize> = HashMap::new();
+        let mut surfaces = Vec::new();
+        for (key, column) in &self.columns {
+            for (&y, surface) in column {
+                let index = *name_indices.entry(surface.content_id).or_insert_with(|| {
+                    names.push(node_def.get_with_fallback(surface.content_id).name.clone());
+                    names.len() - 1
+                });
+                surfaces.push((key.x, y, key.y, surface.height, index));
+            }
+        }
+        let saved: SavedColumns = (names, surfaces);
+        fs::write(path, serde_json::to_string(&saved)?)?;
+        Ok(())
+    }
+
+    /// Updates the surface of a mapblock after it was meshed. None removes
+    /// it, e.g. after the surface was dug away.
+    pub fn set_surface(&mut self, blockpos: MapBlockPos, surface: Option<BlockSurface>) {
+        if !self.enabled() {
+            return;
+        }
+        let pos = blockpos.vec();
+        let key = I16Vec2::new(pos.x, pos.z);
+        match surface {
+            Some(surface) => {
+                self.columns.entry(key).or_default().insert(pos.y, surface);
+            }
+            None => {
+                let Some(column) = self.columns.get_mut(&key) else {
+                    return;
+                };
+                if column.remove(&pos.y).is_none() {
+                    return;
+                }
+                if column.is_empty() {
+                    self.columns.remove(&key);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Rebuilds the heightfield and its coverage around the camera if the
+    /// known columns changed or the camera moved to another mapblock column.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &CameraParams,
+        node_def: &NodeDefManager,
+        media: &MediaManager,
+    ) {
+        const SIZE: i32 = MapBlockPos::SIZE as i32;
+
+        let center = (Vec2::new(camera.pos.x, camera.pos.z) / SIZE as f32)
+            .floor()
+            .as_ivec2();
+        let now = Instant::now();
+        if !self.enabled() || (!self.dirty && center == self.center) || now < self.next_build {
+            return;
+        }
+        self.dirty = false;
+        self.center = center;
+        self.next_build = now + Self::BUILD_INTERVAL;
+
+        let radius = self.radius;
+        let side = radius * 2 + 1;
+        let height_at = |x: i32, z: i32| -> Option<&BlockSurface> {
+            if x.abs() > radius || z.abs() > radius {
+                return None;
+            }
+            let pos = center + IVec2::new(x, z);
+            let key = I16Vec2::new(pos.x.try_into().ok()?, pos.y.try_into().ok()?);
+            let (_, surface) = self.columns.get(&key)?.last_key_value()?;
+            Some(surface)
+        };
+
+        let light = light::vertex_light(LIGHT_SUN, 0, 0);
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        // Index of the vertex of each column in the grid, if known
+        let mut grid = vec![None; (side * side) as usize];
+        for z in -radius..=radius {
+            for x in -radius..=radius {
+                let Some(surface) = height_at(x, z) else {
+                    continue;
+                };
+                let height = surface.height;
+                // Slopes are darker, like the side faces of nodes
+                let slope_x = match (height_at(x - 1, z), height_at(x + 1, z)) {
+                    (Some(a), Some(b)) => (b.height - a.height) / 2.0,
+                    _ => 0.0,
+                };
+                let slope_z = match (height_at(x, z - 1), height_at(x, z + 1)) {
+                    (Some(a), Some(b)) => (b.height - a.height) / 2.0,
+                    _ => 0.0,
+                };
+                let normal = Vec3::new(-slope_x, SIZE as f32, -slope_z).normalize();
+                let shade = 0.6 + 0.4 * normal.y;
+
+                let color = *self.colors.entry(surface.content_id).or_insert_with(|| {
+                    let [r, g, b] = map_image::node_color(node_def, media, surface.content_id);
+                    Vec3::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+                });
+                let pos = (center + IVec2::new(x, z)) * SIZE;
+                grid[((z + radius) * side + x + radius) as usize] = Some(vertices.len() as u32);
+                vertices.push(FarVertex {
+                    // At the middle of the column
+                    position: Vec3::new(
+                        pos.x as f32 + (SIZE as f32 - 1.0) / 2.0,
+                        height - Self::SINK,
+                        pos.y as f32 + (SIZE as f32 - 1.0) / 2.0,
+                    ),
+                    color: color * shade,
+                    light,
+                });
+            }
+        }
+
+        // Two triangles between every four known columns
+        let mut coverage = vec![0u8; (side * side) as usize];
+        for z in 0..side - 1 {
+            for x in 0..side - 1 {
+                let corner = |dx: i32, dz: i32| ((z + dz) * side + x + dx) as usize;
+                let corners = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+                if let [Some(a), Some(b), Some(c), Some(d)] = corners.map(|i| grid[i]) {
+                    indices.extend([a, b, c, c, d, a]);
+                    for i in corners {
+                        coverage[i] = 255;
+                    }
+                }
+            }
+        }
+
+        queue.write_texture(
+            self.coverage.as_image_copy(),
+            &coverage,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(side as u32),
+                rows_per_image: None,
+            },
+            self.coverage.size(),
+        );
+        let uniform = FarMeshUniform {
+            origin: center - radius,
+            near: self.near,
+            _padding: 0.0,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        if indices.is_empty() {
+            self.buffers = None;
+            return;
+        }
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Farmesh vertex buffer"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Farmesh index buffer"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+        self.buffers = Some(FarMeshBuffers {
+            vertex_buffer,
+            index_buffer,
+            num_indices: indices.len() as u32,
+        });
+    }
+
+    /// Draws the heightfield built by `prepare` into the scene, beyond the
+    /// mapblocks.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        color: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        let Some(buffers) = &self.buffers else {
+            return;
+        };
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Farmesh pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, camera_bind_group, &[]);
+        pass.set_bind_group(1, &self.bind_group, &[]);
+        pass.set_index_buffer(buffers.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.set_vertex_buffer(0, buffers.vertex_buffer.slice(..));
+        pass.draw_indexed(0..buffers.num_indices, 0, 0..1);
+    }
+}
@@ -0,0 +1,63 @@
+struct CameraUniform {
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    fog_color: vec3<f32>,
+    fog_end: f32,
+    time: f32,
+    waving: u32,
+    daylight: f32,
+}
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct FarMeshUniform {
+    // The mapblock column of the first texel of the coverage texture
+    origin: vec2<i32>,
+    // Distance from the camera up to which mapblocks are drawn, in nodes
+    near: f32,
+}
+@group(1) @binding(0)
+var<uniform> farmesh: FarMeshUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    // Linear, the slope shading is already applied
+    @location(1) color: vec3<f32>,
+    // x: during the day, y: during the night
+    @location(2) light: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+    @location(1) view_position: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    out.color = model.color * mix(model.light.y, model.light.x, camera.daylight);
+    out.view_position = (camera.view * vec4<f32>(model.position, 1.0)).xyz;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let distance = length(in.view_position);
+    // The mapblocks are drawn there. Where both overlap, the heightfield is
+    // below the real terrain.
+    if distance < farmesh.near {
+        discard;
+    }
+
+    // Same fog as for mapblocks
+    let fog_end = camera.fog_end;
+    let fog_start = fog_end * 0.8;
+    let factor = smoothstep(fog_start, fog_end, distance);
+    let color = mix(in.color, camera.fog_color, factor);
+
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,829 @@
+//! Formspecs, Luanti's UI description language. A formspec is parsed into
+//! widgets, which are laid out and drawn in the overlay pass. Clicks and
+//! typing are turned into fields and inventory actions for the server.
+//!
+//! Only a subset of the elements is supported, enough for simple dialogs and
+//! chest-like interfaces. Unsupported elements are ignored.
+
+use glam::{UVec2, Vec2, Vec4};
+use luanti_core::MapNodePos;
+use winit::event::{ElementState, MouseButton, WindowEvent};
+use winit::keyboard::{Key, NamedKey};
+
+use crate::inventory::Inventories;
+use crate::item_def::ItemDefManager;
+use crate::media::MediaManager;
+use crate::overlay::{MediaImages, OverlayRenderer};
+use crate::text::{self, TextRenderer};
+
+/// The formspec version the client reports to the server, which servers
+/// use to pick a formspec layout for it. Elements of later versions are
+/// ignored like unsupported ones.
+// Compare to Luanti, network/networkprotocol.h, FORMSPEC_API_VERSION
+pub const FORMSPEC_API_VERSION: u16 = 8;
+
+/// Splits `s` at `separator`, ignoring separators escaped with a backslash.
+/// The escapes are kept, see `unescape`.
+pub fn split_escaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Escapes the characters with a special meaning in formspecs, for
+/// inserting text into a formspec.
+// Compare to Luanti, builtin/common/misc_helpers.lua, core.formspec_escape
+pub fn escape(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        if matches!(c, '\\' | '[' | ']' | ';' | ',' | '$') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Removes the backslashes escaping special characters.
+pub fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+        } else {
+            result.push(c);
+            escaped = false;
+        }
+    }
+    result
+}
+
+/// Splits a formspec into its elements. Returns the name and the arguments
+/// of each element, the arguments are still escaped.
+pub fn parse_elements(formspec: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut elements = split_escaped(formspec, ']');
+    // Anything after the last "]" is not a complete element
+    elements.pop();
+    elements
+        .into_iter()
+        .filter_map(|element| {
+            let (name, args) = element.trim().split_once('[')?;
+            Some((name, split_escaped(args, ';')))
+        })
+        .collect()
+}
+
+fn parse_vec2(s: &str) -> Option<Vec2> {
+    let (x, y) = s.split_once(',')?;
+    Some(Vec2::new(x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+/// An inventory slot shown by a list element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Slot {
+    location: String,
+    list: String,
+    index: usize,
+}
+
+/// A parsed element. Positions and sizes are in units, relative to the top
+/// left corner of the form.
+enum Widget {
+    Label {
+        /// The left end of the first line, vertically centered
+        pos: Vec2,
+        text: String,
+    },
+    Image {
+        pos: Vec2,
+        size: Vec2,
+        texture: String,
+    },
+    Button {
+        pos: Vec2,
+        size: Vec2,
+        name: String,
+        label: String,
+        /// Closes the formspec
+        exit: bool,
+        /// Background image, empty for none
+        texture: String,
+        /// Item whose image is shown, empty for none
+        item: String,
+    },
+    Field {
+        pos: Vec2,
+        size: Vec2,
+        name: String,
+        label: String,
+        value: String,
+        password: bool,
+    },
+    List {
+        location: String,
+        list: String,
+        pos: Vec2,
+        /// In slots
+        size: UVec2,
+        start: usize,
+        /// Distance between the top left corners of neighbouring slots
+        slot_pitch: Vec2,
+    },
+}
+
+/// What the formspec wants the client to do after an input event.
+pub enum FormspecEvent {
+    /// Send the fields to the server, the formspec stays open
+    Fields(Vec<(String, String)>),
+    /// Send the fields to the server and close the formspec
+    Close(Vec<(String, String)>),
+    /// Send the inventory action to the server, see TOSERVER_INVENTORY_ACTION
+    InventoryAction(String),
+}
+
+/// Items picked up by the first click on a slot, moved by the second one.
+struct Selection {
+    slot: Slot,
+    count: u16,
+}
+
+/// Where the form is on screen, computed when drawing.
+#[derive(Debug, Clone, Copy, Default)]
+struct Layout {
+    origin: Vec2,
+    /// Size of one unit in pixels
+    unit: f32,
+}
+
+impl Layout {
+    fn to_screen(self, pos: Vec2) -> Vec2 {
+        (self.origin + pos * self.unit).round()
+    }
+
+    fn contains(self, pos: Vec2, size: Vec2, point: Vec2) -> bool {
+        let min = self.to_screen(pos);
+        let max = self.to_screen(pos + size);
+        point.cmpge(min).all() && point.cmplt(max).all()
+    }
+}
+
+/// An open formspec, shown over the game until it is closed by the player
+/// or the server.
+pub struct Formspec {
+    /// Sent back with the fields, empty for the player inventory formspec
+    pub name: String,
+    /// The node whose "formspec" metadata field is shown. Its fields are
+    /// sent with TOSERVER_NODEMETA_FIELDS instead.
+    pub node: Option<MapNodePos>,
+    /// In units
+    size: Vec2,
+    widgets: Vec<Widget>,
+    /// Index of the field widget receiving typed text
+    focused: Option<usize>,
+    selected: Option<Selection>,
+    /// The lists shift-clicked items are moved between, as (location, list)
+    /// pairs. Items go to the list after the clicked one.
+    list_ring: Vec<(String, String)>,
+    shift: bool,
+    /// In pixels
+    cursor: Vec2,
+    /// Of the last drawn frame, for hit testing
+    layout: Layout,
+    images: MediaImages,
+}
+
+impl Formspec {
+    const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+    const TEXT_COLOR: Vec4 = Vec4::ONE;
+    const BUTTON_COLOR: Vec4 = Vec4::new(0.25, 0.25, 0.25, 1.0);
+    const BUTTON_HOVER_COLOR: Vec4 = Vec4::new(0.4, 0.4, 0.4, 1.0);
+    const FIELD_COLOR: Vec4 = Vec4::new(0.05, 0.05, 0.05, 1.0);
+    const FOCUS_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.7);
+    const SLOT_COLOR: Vec4 = Vec4::new(0.15, 0.15, 0.15, 1.0);
+    /// Legacy coordinates are multiplied with this, and so are the sizes
+    /// minus one
+    const LEGACY_SPACING: Vec2 = Vec2::new(5.0 / 4.0, 15.0 / 13.0);
+    /// Border around the form with legacy coordinates
+    const LEGACY_PADDING: f32 = 3.0 / 8.0;
+    /// Gap between list slots with real coordinates
+    const SLOT_GAP: f32 = 0.25;
+    /// The form is scaled so at least this many units fit on the screen
+    /// vertically, unless it is larger
+    const MIN_SCREEN_UNITS: f32 = 15.0;
+
+    /// Parses a formspec. Elements that aren't supported or have invalid
+    /// arguments are skipped. Lists in the "context" or "current_name"
+    /// location show the inventory at `context`, e.g. "nodemeta:1,2,3".
+    // Compare to Luanti, gui/guiFormSpecMenu.cpp, GUIFormSpecMenu::regenerateGui
+    pub fn parse(name: &str, formspec: &str, context: &str) -> Self {
+        let mut size = Vec2::new(8.0, 7.0);
+        let mut real_coordinates = false;
+        let mut widgets = Vec::new();
+        let mut list_ring = Vec::new();
+        // Compare to Luanti, gui/guiFormSpecMenu.cpp,
+        // GUIFormSpecMenu::parseList
+        let location = |location: String| match location.as_str() {
+            "context" | "current_name" => String::from(context),
+            _ => location,
+        };
+
+        for (element, args) in parse_elements(formspec) {
+            let (spacing, padding) = if real_coordinates {
+                (Vec2::ONE, 0.0)
+            } else {
+                (Self::LEGACY_SPACING, Self::LEGACY_PADDING)
+            };
+            let pos = |index: usize| {
+                let pos = parse_vec2(args.get(index)?)?;
+                Some(padding + pos * spacing)
+            };
+            let geom = |index: usize| {
+                let geom = parse_vec2(args.get(index)?)?;
+                Some(geom * spacing - (spacing - 1.0))
+            };
+            let arg = |index: usize| args.get(index).map(|arg| unescape(arg)).unwrap_or_default();
+            let label = |index: usize| text::strip_escapes(&arg(index));
+
+            let widget = match element {
+                "formspec_version" => {
+                    // Real coordinates are the default since version 2
+                    let version: u32 = arg(0).parse().unwrap_or(1);
+                    real_coordinates = version >= 2;
+                    None
+                }
+                "real_coordinates" => {
+                    real_coordinates = arg(0) == "true";
+                    None
+                }
+                "size" => {
+                    if let Some(geom) = parse_vec2(&arg(0)) {
+                        size = if real_coordinates {
+                            geom
+                        } else {
+                            2.0 * padding + geom * spacing - (spacing - 1.0)
+                        };
+                    }
+                    None
+                }
+                "label" => pos(0).map(|pos| Widget::Label {
+                    // Legacy labels are centered on the first unit
+                    pos: if real_coordinates {
+                        pos
+                    } else {
+                        pos + Vec2::new(0.0, 0.5)
+                    },
+                    text: label(1),
+                }),
+                "image" => pos(0).zip(geom(1)).map(|(pos, size)| Widget::Image {
+                    pos,
+                    size,
+                    texture: arg(2),
+                }),
+                "button" | "button_exit" => pos(0).zip(geom(1)).map(|(pos, size)| Widget::Button {
+                    pos,
+                    size,
+                    name: arg(2),
+                    label: label(3),
+                    exit: element == "button_exit",
+                    texture: String::new(),
+                    item: String::new(),
+                }),
+                "image_button" | "image_button_exit" | "item_image_button" => {
+                    pos(0).zip(geom(1)).map(|(pos, size)| {
+                        let (texture, item) = if element == "item_image_button" {
+                            (String::new(), arg(2))
+                        } else {
+                            (arg(2), String::new())
+                        };
+                        Widget::Button {
+                            pos,
+                            size,
+                            name: arg(3),
+                            label: label(4),
+                            exit: element == "image_button_exit",
+                            texture,
+                            item,
+                        }
+                    })
+                }
+                "field" | "pwdfield" | "textarea" => {
+                    pos(0).zip(geom(1)).map(|(pos, size)| Widget::Field {
+                        pos,
+                        size,
+                        name: arg(2),
+                        label: label(3),
+                        // Luanti's pwdfield has no default, but the main menu
+                        // pre-fills the password from the settings
+                        value: arg(4),
+                        password: element == "pwdfield",
+                    })
+                }
+                "list" => pos(2)
+                    .zip(parse_vec2(&arg(3)))
+                    .map(|(pos, size)| Widget::List {
+                        location: location(arg(0)),
+                        list: arg(1),
+                        pos,
+                        size: size.as_uvec2(),
+                        start: arg(4).parse().unwrap_or(0),
+                        slot_pitch: if real_coordinates {
+                            Vec2::splat(1.0 + Self::SLOT_GAP)
+                        } else {
+                            Self::LEGACY_SPACING
+                        },
+                    }),
+                // Compare to Luanti, gui/guiFormSpecMenu.cpp,
+                // GUIFormSpecMenu::parseListRing
+                "listring" => {
+                    if args.len() >= 2 {
+                        list_ring.push((location(arg(0)), arg(1)));
+                    } else {
+                        // Without arguments, the last two lists are linked
+                        let lists: Vec<_> = widgets
+                            .iter()
+                            .filter_map(|widget| match widget {
+                                Widget::List { location, list, .. } => {
+                                    Some((location.clone(), list.clone()))
+                                }
+                                _ => None,
+                            })
+                            .collect();
+                        if let [.., second_last, last] = lists.as_slice() {
+                            list_ring.push(second_last.clone());
+                            list_ring.push(last.clone());
+                        }
+                    }
+                    None
+                }
+                _ => None,
+            };
+            widgets.extend(widget);
+        }
+
+        Self {
+            name: String::from(name),
+            node: None,
+            size,
+            widgets,
+            focused: None,
+            selected: None,
+            list_ring,
+            shift: false,
+            cursor: Vec2::ZERO,
+            layout: Layout::default(),
+            images: MediaImages::default(),
+        }
+    }
+
+    /// Uploads the images used by the widgets and the items in the shown
+    /// lists that haven't been uploaded yet.
+    pub fn load_textures(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        overlay: &mut OverlayRenderer,
+        media: &MediaManager,
+        inventories: &Inventories,
+        item_def: Option<&ItemDefManager>,
+    ) {
+        let mut names = Vec::new();
+        for widget in &self.widgets {
+            match widget {
+                Widget::Image { texture, .. } => names.push(texture.as_str()),
+                Widget::Button { texture, item, .. } => {
+                    names.push(texture);
+                    names.extend(item_image(item_def, item));
+                }
+                Widget::List { location, list, .. } => {
+                    let Some(list) = inventories
+                        .get(location)
+                        .and_then(|inventory| inventory.list(list))
+                    else {
+                        continue;
+                    };
+                    for stack in &list.items {
+                        if !stack.is_empty() {
+                            names.extend(item_image(item_def, &stack.name));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for name in names {
+            self.images.load(device, queue, overlay, media, name);
+        }
+    }
+
+    /// Draws the form in the center of the screen.
+    // Compare to Luanti, gui/guiFormSpecMenu.cpp, GUIFormSpecMenu::drawMenu
+    pub fn draw(
+        &mut self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        inventories: &Inventories,
+        item_def: Option<&ItemDefManager>,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        let screen = Vec2::new(screen_size.width as f32, screen_size.height as f32);
+        // Scale down forms that wouldn't fit otherwise
+        let unit = (screen / self.size)
+            .min_element()
+            .min(screen.y / Self::MIN_SCREEN_UNITS)
+            .floor();
+        self.layout = Layout {
+            origin: ((screen - self.size * unit) / 2.0).round(),
+            unit,
+        };
+        let layout = self.layout;
+
+        overlay.rect(
+            layout.to_screen(Vec2::ZERO),
+            layout.to_screen(self.size),
+            Self::BACKGROUND_COLOR,
+        );
+
+        for (index, widget) in self.widgets.iter().enumerate() {
+            match widget {
+                Widget::Label { pos, text: label } => {
+                    let mut min = layout.to_screen(*pos) - Vec2::new(0.0, text.line_height() / 2.0);
+                    for line in label.lines() {
+                        text.draw(overlay, line, min, Self::TEXT_COLOR);
+                        min.y += text.line_height();
+                    }
+                }
+                Widget::Image { pos, size, texture } => {
+                    self.draw_image(
+                        overlay,
+                        texture,
+                        layout.to_screen(*pos),
+                        layout.to_screen(*pos + *size),
+                    );
+                }
+                Widget::Button {
+                    pos,
+                    size,
+                    label,
+                    texture,
+                    item,
+                    ..
+                } => {
+                    let min = layout.to_screen(*pos);
+                    let max = layout.to_screen(*pos + *size);
+                    if texture.is_empty() {
+                        let color = if layout.contains(*pos, *size, self.cursor) {
+                            Self::BUTTON_HOVER_COLOR
+                        } else {
+                            Self::BUTTON_COLOR
+                        };
+                        overlay.rect(min, max, color);
+                    } else {
+                        self.draw_image(overlay, texture, min, max);
+                    }
+                    if let Some(image) = item_image(item_def, item) {
+                        self.draw_image(overlay, image, min, max);
+                    }
+                    Self::draw_centered(overlay, text, label, (min + max) / 2.0);
+                }
+                Widget::Field {
+                    pos,
+                    size,
+                    label,
+                    value,
+                    password,
+                    ..
+                } => {
+                    let min = layout.to_screen(*pos);
+                    let max = layout.to_screen(*pos + *size);
+                    text.draw(
+                        overlay,
+                        label,
+                        min - Vec2::new(0.0, text.line_height()),
+                        Self::TEXT_COLOR,
+                    );
+                    if self.focused == Some(index) {
+                        overlay.rect(min - 1.0, max + 1.0, Self::FOCUS_COLOR);
+                    }
+                    overlay.rect(min, max, Self::FIELD_COLOR);
+
+                    let mut shown = if *password {
+                        "*".repeat(value.chars().count())
+                    } else {
+                        value.clone()
+                    };
+                    if self.focused == Some(index) {
+                        shown.push('_');
+                    }
+                    let text_pos =
+                        Vec2::new(min.x + 4.0, (min.y + max.y - text.line_height()) / 2.0);
+                    text.draw(overlay, &shown, text_pos, Self::TEXT_COLOR);
+                }
+                Widget::List {
+                    location,
+                    list,
+                    pos,
+                    size,
+                    start,
+                    slot_pitch,
+                } => {
+                    let list_items = inventories
+                        .get(location)
+                        .and_then(|inventory| inventory.list(list));
+                    for row in 0..size.y {
+                        for column in 0..size.x {
+                            let slot_index = start + (row * size.x + column) as usize;
+                            // Lists only show as many slots as they have
+                            let Some(stack) = list_items.and_then(|items| items.get(slot_index))
+                            else {
+                                continue;
+                            };
+                            let slot_pos =
+                                *pos + Vec2::new(column as f32, row as f32) * *slot_pitch;
+                            let min = layout.to_screen(slot_pos);
+                            let max = layout.to_screen(slot_pos + 1.0);
+
+                            let selected = self.selected.as_ref().filter(|selected| {
+                                selected.slot.location == *location
+                                    && selected.slot.list == *list
+                                    && selected.slot.index == slot_index
+                            });
+                            if selected.is_some() {
+                                overlay.rect(min - 2.0, max + 2.0, Self::FOCUS_COLOR);
+                            }
+                            overlay.rect(min, max, Self::SLOT_COLOR);
+
+                            if stack.is_empty() {
+                                continue;
+                            }
+                            if let Some(image) = item_image(item_def, &stack.name) {
+                                self.draw_image(overlay, image, min, max);
+                            }
+                            // The count of a partly picked up stack is the
+                            // one that is moved
+                            let count = selected.map_or(stack.count, |selected| selected.count);
+                            if count > 1 {
+                                let count = count.to_string();
+                                let text_pos =
+                                    max - Vec2::new(text.width(&count), text.line_height());
+                                text.draw(overlay, &count, text_pos, Self::TEXT_COLOR);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_image(&self, overlay: &mut OverlayRenderer, name: &str, min: Vec2, max: Vec2) {
+        if let Some(image) = self.images.get(name) {
+            overlay.image(image.id, min, max, Vec2::ZERO, Vec2::ONE, Vec4::ONE);
+        }
+    }
+
+    /// Draws each line of `label` horizontally centered on `center`, with
+    /// the whole text vertically centered.
+    fn draw_centered(
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        label: &str,
+        center: Vec2,
+    ) {
+        let lines: Vec<&str> = label.lines().collect();
+        let mut y = center.y - lines.len() as f32 * text.line_height() / 2.0;
+        for line in lines {
+            let x = center.x - text.width(line) / 2.0;
+            text.draw(overlay, line, Vec2::new(x, y), Self::TEXT_COLOR);
+            y += text.line_height();
+        }
+    }
+
+    /// The values of all named fields, sent along with every event.
+    fn fields(&self) -> Vec<(String, String)> {
+        self.widgets
+            .iter()
+            .filter_map(|widget| match widget {
+                Widget::Field { name, value, .. } if !name.is_empty() => {
+                    Some((name.clone(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Handles mouse and keyboard input. All input should be passed here
+    /// while the formspec is open, instead of to the game controls.
+    // Compare to Luanti, gui/guiFormSpecMenu.cpp, GUIFormSpecMenu::OnEvent
+    pub fn process_window_event(
+        &mut self,
+        event: &WindowEvent,
+        inventories: &Inventories,
+    ) -> Option<FormspecEvent> {
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Vec2::new(position.x as f32, position.y as f32);
+                None
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.shift = modifiers.state().shift_key();
+                None
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: button @ (MouseButton::Left | MouseButton::Right),
+                ..
+            } => self.click(*button, inventories),
+            WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed => {
+                match &event.logical_key {
+                    Key::Named(NamedKey::Escape) => {
+                        let mut fields = self.fields();
+                        fields.push((String::from("quit"), String::from("true")));
+                        Some(FormspecEvent::Close(fields))
+                    }
+                    Key::Named(NamedKey::Enter) => {
+                        let Widget::Field { name, .. } = &self.widgets[self.focused?] else {
+                            return None;
+                        };
+                        let name = name.clone();
+                        let mut fields = self.fields();
+                        fields.push((String::from("key_enter"), String::from("true")));
+                        fields.push((String::from("key_enter_field"), name));
+                        fields.push((String::from("quit"), String::from("true")));
+                        Some(FormspecEvent::Close(fields))
+                    }
+                    Key::Named(NamedKey::Backspace) => {
+                        if let Some(Widget::Field { value, .. }) =
+                            self.focused.map(|index| &mut self.widgets[index])
+                        {
+                            value.pop();
+                        }
+                        None
+                    }
+                    _ => {
+                        if let Some(Widget::Field { value, .. }) =
+                            self.focused.map(|index| &mut self.widgets[index])
+                            && let Some(typed) = &event.text
+                        {
+                            value.extend(typed.chars().filter(|c| !c.is_control()));
+                        }
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Buttons and fields only react to the left button, slots to both.
+    fn click(&mut self, button: MouseButton, inventories: &Inventories) -> Option<FormspecEvent> {
+        let layout = self.layout;
+        let cursor = self.cursor;
+        self.focused = None;
+
+        for (index, widget) in self.widgets.iter().enumerate() {
+            match widget {
+                Widget::Button {
+                    pos,
+                    size,
+                    name,
+                    label,
+                    exit,
+                    ..
+                } if button == MouseButton::Left && layout.contains(*pos, *size, cursor) => {
+                    let mut fields = self.fields();
+                    fields.push((name.clone(), label.clone()));
+                    if !*exit {
+                        return Some(FormspecEvent::Fields(fields));
+                    }
+                    fields.push((String::from("quit"), String::from("true")));
+                    return Some(FormspecEvent::Close(fields));
+                }
+                Widget::Field { pos, size, .. }
+                    if button == MouseButton::Left && layout.contains(*pos, *size, cursor) =>
+                {
+                    self.focused = Some(index);
+                    return None;
+                }
+                Widget::List {
+                    location,
+                    list,
+                    pos,
+                    size,
+                    start,
+                    slot_pitch,
+                } => {
+                    // The gaps between slots don't count
+                    let offset = (cursor - layout.to_screen(*pos)) / layout.unit;
+                    let cell = (offset / *slot_pitch).floor();
+                    if cell.cmplt(Vec2::ZERO).any()
+                        || cell.cmpge(size.as_vec2()).any()
+                        || (offset - cell * *slot_pitch).cmpge(Vec2::ONE).any()
+                    {
+                        continue;
+                    }
+                    let slot = Slot {
+                        location: location.clone(),
+                        list: list.clone(),
+                        index: start + (cell.y as u32 * size.x + cell.x as u32) as usize,
+                    };
+                    return self.click_slot(slot, button, inventories);
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// The first click picks up the items in a slot, the second one moves
+    /// them. Left clicks pick up or move all of them, right clicks pick up
+    /// half of the stack or move a single item. Shift-left-clicks move the
+    /// whole stack to the next list of the list ring right away.
+    // Compare to Luanti, gui/guiFormSpecMenu.cpp, the "Possibly send
+    // inventory action to server" part of GUIFormSpecMenu::OnEvent
+    fn click_slot(
+        &mut self,
+        slot: Slot,
+        button: MouseButton,
+        inventories: &Inventories,
+    ) -> Option<FormspecEvent> {
+        // Slots beyond the end of the list aren't shown
+        let stack = inventories
+            .get(&slot.location)
+            .and_then(|inventory| inventory.list(&slot.list))
+            .and_then(|list| list.get(slot.index))?;
+
+        if self.shift && button == MouseButton::Left && self.selected.is_none() && !stack.is_empty()
+        {
+            return self.quick_move(slot, stack.count);
+        }
+
+        let Some(selected) = self.selected.take() else {
+            if !stack.is_empty() {
+                let count = match button {
+                    MouseButton::Right => stack.count.div_ceil(2),
+                    _ => stack.count,
+                };
+                self.selected = Some(Selection { slot, count });
+            }
+            return None;
+        };
+        if selected.slot == slot {
+            return None;
+        }
+        let count = match button {
+            MouseButton::Right => 1,
+            _ => selected.count,
+        };
+        // The rest stays picked up
+        if count < selected.count {
+            self.selected = Some(Selection {
+                slot: selected.slot.clone(),
+                count: selected.count - count,
+            });
+        }
+        let from = selected.slot;
+        // Compare to Luanti, inventorymanager.cpp, IMoveAction::serialize
+        Some(FormspecEvent::InventoryAction(format!(
+            "Move {} {} {} {} {} {} {}",
+            count, from.location, from.list, from.index, slot.location, slot.list, slot.index
+        )))
+    }
+
+    /// Moves items to the list after the slot's one in the list ring, the
+    /// server puts them into the first free slots.
+    // Compare to Luanti, gui/guiFormSpecMenu.cpp, the shift-click part of
+    // GUIFormSpecMenu::OnEvent
+    fn quick_move(&self, slot: Slot, count: u16) -> Option<FormspecEvent> {
+        let index = self
+            .list_ring
+            .iter()
+            .position(|(location, list)| *location == slot.location && *list == slot.list)?;
+        let (to_location, to_list) = &self.list_ring[(index + 1) % self.list_ring.len()];
+        if *to_location == slot.location && *to_list == slot.list {
+            return None;
+        }
+        // Compare to Luanti, inventorymanager.cpp, IMoveAction::serialize
+        Some(FormspecEvent::InventoryAction(format!(
+            "MoveSomewhere {} {} {} {} {} {}",
+            count, slot.location, slot.list, slot.index, to_location, to_list
+        )))
+    }
+}
+
+/// The inventory image of an item, if it has one.
+fn item_image<'a>(item_def: Option<&'a ItemDefManager>, name: &str) -> Option<&'a str> {
+    item_def?.inventory_image(name)
+}
@@ -0,0 +1,94 @@
+//! Gamepad input through gilrs. The state of the most recently used gamepad
+//! is read once per frame and fed into the same paths as keyboard and mouse
+//! input.
+
+use gilrs::{Axis, Button, Gilrs};
+use glam::Vec2;
+
+use crate::settings::Settings;
+
+/// The gamepad state for one frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GamepadInput {
+    /// Left stick, x to the right and y forward, with a length of up to 1
+    pub movement: Vec2,
+    /// Right stick, in degrees per second. x turns right, y looks down.
+    pub look: Vec2,
+    pub jump: bool,
+    pub sneak: bool,
+    pub dig: bool,
+    pub place: bool,
+}
+
+pub struct Gamepads {
+    /// None if gamepad support is disabled or unavailable
+    gilrs: Option<Gilrs>,
+    /// The gamepad that was used last
+    active: Option<gilrs::GamepadId>,
+    /// Fraction of the stick range that is ignored around the center
+    deadzone: f32,
+    /// Look speed at full stick deflection, in degrees per second
+    look_sensitivity: f32,
+}
+
+impl Gamepads {
+    pub fn new(settings: &Settings) -> Self {
+        let gilrs = if settings.gamepad {
+            match Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(err) => {
+                    println!("Gamepad support unavailable: {:?}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Self {
+            gilrs,
+            active: None,
+            deadzone: settings.gamepad_deadzone,
+            look_sensitivity: settings.gamepad_look_sensitivity,
+        }
+    }
+
+    /// Processes pending gamepad events and returns the current state of the
+    /// active gamepad.
+    pub fn poll(&mut self) -> GamepadInput {
+        let Some(gilrs) = &mut self.gilrs else {
+            return GamepadInput::default();
+        };
+        while let Some(event) = gilrs.next_event() {
+            self.active = Some(event.id);
+        }
+        let Some(gamepad) = self.active.and_then(|id| gilrs.connected_gamepad(id)) else {
+            return GamepadInput::default();
+        };
+
+        let stick = |x, y| {
+            let value = Vec2::new(gamepad.value(x), gamepad.value(y));
+            Self::apply_deadzone(value, self.deadzone)
+        };
+        let look = stick(Axis::RightStickX, Axis::RightStickY);
+
+        GamepadInput {
+            movement: stick(Axis::LeftStickX, Axis::LeftStickY),
+            look: Vec2::new(look.x, -look.y) * self.look_sensitivity,
+            jump: gamepad.is_pressed(Button::South),
+            sneak: gamepad.is_pressed(Button::East),
+            dig: gamepad.is_pressed(Button::RightTrigger2),
+            place: gamepad.is_pressed(Button::LeftTrigger2),
+        }
+    }
+
+    /// Ignores small deflections and rescales the rest, so the output still
+    /// starts at 0 and reaches 1.
+    fn apply_deadzone(value: Vec2, deadzone: f32) -> Vec2 {
+        let length = value.length().min(1.0);
+        if length <= deadzone {
+            return Vec2::ZERO;
+        }
+        value.normalize() * (length - deadzone) / (1.0 - deadzone)
+    }
+}
@@ -0,0 +1,238 @@
+//! Exports the loaded mapblock meshes with their textures to a binary glTF
+//! file (.glb), e.g. for rendering builds in Blender. Meshes are normally
+//! only kept on the GPU, so this needs Settings::retain_meshes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::anyhow;
+use glam::{Vec2, Vec3};
+use image::ImageFormat;
+use serde_json::{Value, json};
+
+use crate::media::{MediaManager, NodeTextureData};
+use crate::world::{CpuMesh, World};
+
+// Compare to the glTF 2.0 specification
+const GLB_MAGIC: u32 = 0x46546c67;
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4e4f534a;
+const CHUNK_BIN: u32 = 0x004e4942;
+const COMPONENT_FLOAT: u32 = 5126;
+const COMPONENT_UNSIGNED_INT: u32 = 5125;
+const TARGET_ARRAY_BUFFER: u32 = 34962;
+const TARGET_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FILTER_NEAREST: u32 = 9728;
+const FILTER_NEAREST_MIPMAP_LINEAR: u32 = 9986;
+
+/// glTF is right-handed, Z is mirrored like when loading models
+const MIRROR: Vec3 = Vec3::new(1.0, 1.0, -1.0);
+
+/// The triangles of all mapblocks that use the same texture.
+#[derive(Default)]
+struct Primitive {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    indices: Vec<u32>,
+}
+
+/// The binary chunk and the JSON objects describing its contents.
+#[derive(Default)]
+struct Buffers {
+    bin: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Buffers {
+    /// Appends data to the binary chunk, returning the buffer view index.
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        // Accessors need their data aligned to the component size
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.bin.extend_from_slice(data);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    /// Appends a vertex attribute, returning the accessor index.
+    fn push_vectors<T: bytemuck::Pod>(&mut self, data: &[T], typ: &str) -> usize {
+        let view = self.push_view(bytemuck::cast_slice(data), Some(TARGET_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": COMPONENT_FLOAT,
+            "count": data.len(),
+            "type": typ,
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn push_positions(&mut self, positions: &[Vec3]) -> usize {
+        let accessor = self.push_vectors(positions, "VEC3");
+        // Required for positions
+        let min = positions.iter().copied().reduce(Vec3::min).unwrap();
+        let max = positions.iter().copied().reduce(Vec3::max).unwrap();
+        self.accessors[accessor]["min"] = json!(min.to_array());
+        self.accessors[accessor]["max"] = json!(max.to_array());
+        accessor
+    }
+
+    fn push_indices(&mut self, indices: &[u32]) -> usize {
+        let view = self.push_view(
+            bytemuck::cast_slice(indices),
+            Some(TARGET_ELEMENT_ARRAY_BUFFER),
+        );
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": COMPONENT_UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+}
+
+/// Writes the retained mapblock meshes to `path`, with one material per
+/// node texture. Animated textures are exported as their first frame.
+/// Returns the number of exported mapblocks.
+pub fn export(
+    world: &World,
+    textures: &NodeTextureData,
+    media: &MediaManager,
+    path: &Path,
+) -> anyhow::Result<usize> {
+    let mut primitives: BTreeMap<u32, Primitive> = BTreeMap::new();
+    let mut mapblock_count = 0;
+    for (_, CpuMesh(mesh)) in world.ecs.query::<&CpuMesh>().iter() {
+        mapblock_count += 1;
+        // Index of each vertex within its primitive, once it was added
+        let mut remap = vec![None; mesh.vertices.len()];
+        for triangle in mesh.indices.chunks_exact(3) {
+            let texture_index = mesh.vertices[triangle[0] as usize].texture_index;
+            let primitive = primitives.entry(texture_index).or_default();
+            // Reversed since Z is mirrored
+            for &index in triangle.iter().rev() {
+                let new_index = *remap[index as usize].get_or_insert_with(|| {
+                    let vertex = &mesh.vertices[index as usize];
+                    primitive.positions.push(vertex.position * MIRROR);
+                    primitive.normals.push(vertex.normal * MIRROR);
+                    primitive.uvs.push(vertex.uv);
+                    primitive.positions.len() as u32 - 1
+                });
+                primitive.indices.push(new_index);
+            }
+        }
+    }
+    if primitives.is_empty() {
+        return Err(anyhow!(
+            "No meshes to export, enable the retain_meshes setting and rejoin"
+        ));
+    }
+
+    let mut buffers = Buffers::default();
+    let mut gltf_primitives = Vec::new();
+    let mut materials = Vec::new();
+    let mut gltf_textures = Vec::new();
+    let mut images = Vec::new();
+    for (texture_index, primitive) in &primitives {
+        let (name, animation) = &textures.files[*texture_index as usize];
+        let mut material = json!({
+            "name": name,
+            "pbrMetallicRoughness": {
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0,
+            },
+            // Transparent pixels are cut out, like in the mapblock shader
+            "alphaMode": "MASK",
+        });
+        if let Some(img) = media.load_image(name)? {
+            let mut img = img.to_rgba8();
+            if let Some(animation) = animation {
+                img = animation.split(&img).swap_remove(0);
+            }
+            let mut png = Vec::new();
+            img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+            let view = buffers.push_view(&png, None);
+            images.push(json!({
+                "name": name,
+                "bufferView": view,
+                "mimeType": "image/png",
+            }));
+            gltf_textures.push(json!({
+                "sampler": 0,
+                "source": images.len() - 1,
+            }));
+            material["pbrMetallicRoughness"]["baseColorTexture"] =
+                json!({ "index": gltf_textures.len() - 1 });
+        }
+        materials.push(material);
+
+        gltf_primitives.push(json!({
+            "attributes": {
+                "POSITION": buffers.push_positions(&primitive.positions),
+                "NORMAL": buffers.push_vectors(&primitive.normals, "VEC3"),
+                "TEXCOORD_0": buffers.push_vectors(&primitive.uvs, "VEC2"),
+            },
+            "indices": buffers.push_indices(&primitive.indices),
+            "material": materials.len() - 1,
+        }));
+    }
+    buffers.bin.resize(buffers.bin.len().next_multiple_of(4), 0);
+
+    let document = json!({
+        "asset": {
+            "version": "2.0",
+            "generator": "cubetonic",
+        },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "name": "world", "mesh": 0 }],
+        "meshes": [{ "name": "world", "primitives": gltf_primitives }],
+        "materials": materials,
+        "textures": gltf_textures,
+        "images": images,
+        // Keep the pixelated look of the node textures
+        "samplers": [{
+            "magFilter": FILTER_NEAREST,
+            "minFilter": FILTER_NEAREST_MIPMAP_LINEAR,
+        }],
+        "buffers": [{ "byteLength": buffers.bin.len() }],
+        "bufferViews": buffers.buffer_views,
+        "accessors": buffers.accessors,
+    });
+
+    let mut json = serde_json::to_vec(&document)?;
+    json.resize(json.len().next_multiple_of(4), b' ');
+    fs::write(path, glb(&json, &buffers.bin))?;
+    Ok(mapblock_count)
+}
+
+/// Puts the JSON and binary chunks into a GLB container. Both need to be
+/// padded to 4 bytes already.
+fn glb(json: &[u8], bin: &[u8]) -> Vec<u8> {
+    const HEADER_SIZE: usize = 12;
+    const CHUNK_HEADER_SIZE: usize = 8;
+
+    let length = HEADER_SIZE + CHUNK_HEADER_SIZE * 2 + json.len() + bin.len();
+    let mut data = Vec::with_capacity(length);
+    for value in [GLB_MAGIC, GLB_VERSION, length as u32] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    for (typ, chunk) in [(CHUNK_JSON, json), (CHUNK_BIN, bin)] {
+        data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        data.extend_from_slice(&typ.to_le_bytes());
+        data.extend_from_slice(chunk);
+    }
+    data
+}
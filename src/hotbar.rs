@@ -0,0 +1,243 @@
+use glam::{Vec2, Vec4};
+use winit::event::{ElementState, KeyEvent, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+
+use crate::inventory::InventoryList;
+use crate::item_def::ItemDefManager;
+use crate::media::MediaManager;
+use crate::overlay::{MediaImages, OverlayRenderer, OverlayTextureId};
+use crate::settings::Settings;
+use crate::text::TextRenderer;
+
+/// The hotbar selection. The selected item is what the player wields.
+pub struct Hotbar {
+    /// Index of the selected slot
+    selected: u16,
+    /// Number of slots, set by the server with HUD_PARAM_HOTBAR_ITEMCOUNT
+    item_count: u16,
+
+    scroll_sensitivity: f32,
+    scroll_invert: bool,
+    /// Scrolled distance in slots that hasn't been applied yet
+    scroll_accum: f32,
+
+    /// Inventory images of the items in the slots
+    images: MediaImages,
+}
+
+impl Hotbar {
+    const DEFAULT_ITEM_COUNT: u16 = 8;
+    // Compare to Luanti, hud.h, HUD_HOTBAR_ITEMCOUNT_MAX
+    const MAX_ITEM_COUNT: u16 = 32;
+    /// For touchpads, which report scrolling in pixels
+    const PIXELS_PER_LINE: f32 = 40.0;
+    // Sizes in unscaled pixels
+    const SLOT_SIZE: f32 = 24.0;
+    const SPACING: f32 = 2.0;
+    const MARGIN: f32 = 8.0;
+    const TEXT_COLOR: Vec4 = Vec4::ONE;
+
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            selected: 0,
+            item_count: Self::DEFAULT_ITEM_COUNT,
+            scroll_sensitivity: settings.hotbar_scroll_sensitivity,
+            scroll_invert: settings.hotbar_scroll_invert,
+            scroll_accum: 0.0,
+            images: MediaImages::default(),
+        }
+    }
+
+    pub fn selected(&self) -> u16 {
+        self.selected
+    }
+
+    /// Sets the number of slots. Returns true if the selection changed
+    /// because the selected slot doesn't exist anymore.
+    pub fn set_item_count(&mut self, count: i32) -> bool {
+        self.item_count = count.clamp(1, Self::MAX_ITEM_COUNT as i32) as u16;
+        if self.selected < self.item_count {
+            return false;
+        }
+        self.selected = self.item_count - 1;
+        true
+    }
+
+    /// Returns true if the selection changed.
+    pub fn process_window_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::MouseWheel { delta, .. } => self.scroll(delta),
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(keycode),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => match Self::slot_key_index(*keycode) {
+                Some(index) if index < self.item_count && index != self.selected => {
+                    self.selected = index;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The slot selected by a number key, 1 to 9 and 0 for the tenth slot.
+    // Compare to Luanti, defaultsettings.cpp, keymap_slot1 to keymap_slot10
+    fn slot_key_index(keycode: KeyCode) -> Option<u16> {
+        let index = match keycode {
+            KeyCode::Digit1 => 0,
+            KeyCode::Digit2 => 1,
+            KeyCode::Digit3 => 2,
+            KeyCode::Digit4 => 3,
+            KeyCode::Digit5 => 4,
+            KeyCode::Digit6 => 5,
+            KeyCode::Digit7 => 6,
+            KeyCode::Digit8 => 7,
+            KeyCode::Digit9 => 8,
+            KeyCode::Digit0 => 9,
+            _ => return None,
+        };
+        Some(index)
+    }
+
+    fn scroll(&mut self, delta: &MouseScrollDelta) -> bool {
+        let lines = match delta {
+            MouseScrollDelta::LineDelta(_, y) => *y,
+            MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / Self::PIXELS_PER_LINE,
+        };
+        // Scrolling down selects the next slot, like in Luanti
+        let mut slots = -lines * self.scroll_sensitivity;
+        if self.scroll_invert {
+            slots = -slots;
+        }
+        self.scroll_accum += slots;
+
+        let steps = self.scroll_accum.trunc();
+        self.scroll_accum -= steps;
+        if steps == 0.0 {
+            return false;
+        }
+
+        let count = self.item_count as i32;
+        self.selected = (self.selected as i32 + steps as i32).rem_euclid(count) as u16;
+        true
+    }
+
+    /// Uploads the inventory images of the items in the hotbar that haven't
+    /// been uploaded yet.
+    pub fn load_textures(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        overlay: &mut OverlayRenderer,
+        media: &MediaManager,
+        item_def: &ItemDefManager,
+        list: &InventoryList,
+    ) {
+        for stack in list.items.iter().take(self.item_count as usize) {
+            if let Some(image) = item_def.inventory_image(&stack.name) {
+                self.images.load(device, queue, overlay, media, image);
+            }
+        }
+    }
+
+    /// The top left corner of the hotbar and its size, in pixels.
+    fn bounds(&self, screen_size: winit::dpi::PhysicalSize<u32>, scale: f32) -> (Vec2, Vec2) {
+        let slot_size = Self::SLOT_SIZE * scale;
+        let spacing = Self::SPACING * scale;
+        let width = self.item_count as f32 * (slot_size + spacing) - spacing;
+        let origin = Vec2::new(
+            (screen_size.width as f32 - width) / 2.0,
+            screen_size.height as f32 - Self::MARGIN * scale - slot_size,
+        );
+        (origin, Vec2::new(width, slot_size))
+    }
+
+    /// Draws the hotbar slots at the bottom center of the screen. `image` is
+    /// drawn behind the whole hotbar, `selected_image` replaces the frame of
+    /// the selected slot.
+    // Compare to Luanti, client/hud.cpp, Hud::drawItems
+    pub fn draw(
+        &self,
+        overlay: &mut OverlayRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+        scale: f32,
+        image: Option<OverlayTextureId>,
+        selected_image: Option<OverlayTextureId>,
+    ) {
+        const SLOT_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.5);
+        const SELECTED_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.7);
+
+        let slot_size = Self::SLOT_SIZE * scale;
+        let spacing = Self::SPACING * scale;
+        let (origin, size) = self.bounds(screen_size, scale);
+
+        if let Some(image) = image {
+            let max = origin + size;
+            overlay.image(
+                image,
+                origin - spacing,
+                max + spacing,
+                Vec2::ZERO,
+                Vec2::ONE,
+                Vec4::ONE,
+            );
+        }
+
+        for index in 0..self.item_count {
+            let min = origin + Vec2::new(index as f32 * (slot_size + spacing), 0.0);
+            if index == self.selected {
+                let (min, max) = (min - spacing, min + slot_size + spacing);
+                match selected_image {
+                    Some(selected_image) => {
+                        overlay.image(selected_image, min, max, Vec2::ZERO, Vec2::ONE, Vec4::ONE)
+                    }
+                    None => overlay.rect(min, max, SELECTED_COLOR),
+                }
+            }
+            overlay.rect(min, min + slot_size, SLOT_COLOR);
+        }
+    }
+
+    /// Draws the items of the "main" inventory list into the slots, with
+    /// their counts. Call after `draw`.
+    // Compare to Luanti, client/hud.cpp, Hud::drawItem
+    pub fn draw_items(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+        scale: f32,
+        item_def: &ItemDefManager,
+        list: &InventoryList,
+    ) {
+        let slot_size = Self::SLOT_SIZE * scale;
+        let spacing = Self::SPACING * scale;
+        let (origin, _) = self.bounds(screen_size, scale);
+
+        for (index, stack) in list.items.iter().take(self.item_count as usize).enumerate() {
+            if stack.is_empty() {
+                continue;
+            }
+            let min = origin + Vec2::new(index as f32 * (slot_size + spacing), 0.0);
+            let max = min + slot_size;
+            if let Some(image) = item_def
+                .inventory_image(&stack.name)
+                .and_then(|name| self.images.get(name))
+            {
+                overlay.image(image.id, min, max, Vec2::ZERO, Vec2::ONE, Vec4::ONE);
+            }
+            if stack.count > 1 {
+                let count = stack.count.to_string();
+                let text_pos = max - Vec2::new(text.width(&count), text.line_height());
+                text.draw(overlay, &count, text_pos, Self::TEXT_COLOR);
+            }
+        }
+    }
+}
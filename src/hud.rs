@@ -0,0 +1,595 @@
+//! Server-controlled HUD elements, added, changed and removed through
+//! TOCLIENT_HUDADD, TOCLIENT_HUDCHANGE and TOCLIENT_HUDRM.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use glam::{IVec2, Vec2, Vec3, Vec4};
+use luanti_protocol::types::HudStat;
+
+use crate::camera::CameraParams;
+use crate::inventory::Inventory;
+use crate::item_def::ItemDefManager;
+use crate::media::MediaManager;
+use crate::overlay::{MediaImage, MediaImages, OverlayRenderer, OverlayTextureId};
+use crate::srgb_to_linear;
+use crate::text::{self, TextRenderer};
+
+// Compare to Luanti, hud.h, HudElementType
+const HUD_ELEM_IMAGE: u8 = 0;
+const HUD_ELEM_TEXT: u8 = 1;
+const HUD_ELEM_STATBAR: u8 = 2;
+const HUD_ELEM_INVENTORY: u8 = 3;
+const HUD_ELEM_WAYPOINT: u8 = 4;
+const HUD_ELEM_IMAGE_WAYPOINT: u8 = 5;
+
+// Compare to Luanti, hud.h, HUD_FLAG_*
+pub const HUD_FLAG_HOTBAR_VISIBLE: u32 = 1 << 0;
+const HUD_FLAG_HEALTHBAR_VISIBLE: u32 = 1 << 1;
+pub const HUD_FLAG_CROSSHAIR_VISIBLE: u32 = 1 << 2;
+pub const HUD_FLAG_WIELDITEM_VISIBLE: u32 = 1 << 3;
+const HUD_FLAG_BREATHBAR_VISIBLE: u32 = 1 << 4;
+// TODO: HUD_FLAG_MINIMAP_VISIBLE and HUD_FLAG_MINIMAP_RADAR_VISIBLE, once
+// there's a minimap
+/// Allows the parts of the debug screen that give a gameplay advantage,
+/// like the position
+pub const HUD_FLAG_BASIC_DEBUG: u32 = 1 << 7;
+pub const HUD_FLAG_CHAT_VISIBLE: u32 = 1 << 8;
+const HUD_FLAG_ALL: u32 = (1 << 9) - 1;
+
+// Compare to Luanti, hud.h, HUD_DIR_*
+// Left to right is the default.
+const HUD_DIR_RIGHT_LEFT: u32 = 1;
+const HUD_DIR_TOP_BOTTOM: u32 = 2;
+const HUD_DIR_BOTTOM_TOP: u32 = 3;
+
+/// A HUD element. The meaning of most fields depends on the type.
+// Compare to Luanti, hud.h, HudElement
+#[derive(Debug, Clone)]
+pub struct HudElement {
+    /// See HUD_ELEM_*
+    pub typ: u8,
+    /// Position as a fraction of the screen size
+    pub pos: Vec2,
+    pub name: String,
+    pub scale: Vec2,
+    pub text: String,
+    pub number: u32,
+    pub item: u32,
+    /// See HUD_DIR_*
+    pub dir: u32,
+    /// Where the element is relative to its position, -1 to 1 on each axis
+    pub align: Vec2,
+    /// In pixels, multiplied with the HUD scale
+    pub offset: Vec2,
+    /// For waypoints, in nodes
+    pub world_pos: Vec3,
+    pub size: IVec2,
+    pub z_index: i16,
+    pub text2: String,
+}
+
+impl HudElement {
+    // Compare to Luanti, client/client.cpp, Client::handleCommand_HudChange
+    fn change(&mut self, stat: HudStat) {
+        match stat {
+            HudStat::Pos(pos) => self.pos = pos,
+            HudStat::Name(name) => self.name = name,
+            HudStat::Scale(scale) => self.scale = scale,
+            HudStat::Text(text) => self.text = text,
+            HudStat::Number(number) => self.number = number,
+            HudStat::Item(item) => self.item = item,
+            HudStat::Dir(dir) => self.dir = dir,
+            HudStat::Align(align) => self.align = align,
+            HudStat::Offset(offset) => self.offset = offset,
+            HudStat::WorldPos(world_pos) => self.world_pos = world_pos,
+            HudStat::Size(size) => self.size = size,
+            // Sent as u32
+            HudStat::ZIndex(z_index) => self.z_index = z_index as i16,
+            HudStat::Text2(text2) => self.text2 = text2,
+            // TODO: text styles (bold, italic, monospace)
+            HudStat::Style(_) => (),
+        }
+    }
+}
+
+/// The HUD elements sent by the server, drawn in the overlay pass, and the
+/// HUD flags and parameters controlling the builtin parts of the HUD.
+pub struct Hud {
+    /// By server id
+    elements: HashMap<u32, HudElement>,
+    images: MediaImages,
+
+    /// See HUD_FLAG_*
+    flags: u32,
+    /// Drawn behind the hotbar, empty for none
+    pub hotbar_image: String,
+    /// Drawn behind the selected hotbar slot, empty for none
+    pub hotbar_selected_image: String,
+    /// The player's health, None until TOCLIENT_HP is received
+    pub hp: Option<u16>,
+    /// The player's breath, None until TOCLIENT_BREATH is received
+    pub breath: Option<u16>,
+    /// For the images of inventory elements, None until the item
+    /// definitions are received
+    pub item_def: Option<Arc<ItemDefManager>>,
+}
+
+impl Default for Hud {
+    fn default() -> Self {
+        Self {
+            elements: HashMap::new(),
+            images: MediaImages::default(),
+            // Compare to Luanti, player.h, Player::hud_flags
+            flags: HUD_FLAG_ALL,
+            hotbar_image: String::new(),
+            hotbar_selected_image: String::new(),
+            hp: None,
+            breath: None,
+            item_def: None,
+        }
+    }
+}
+
+impl Hud {
+    /// Slot size for inventory elements, same as the hotbar
+    const SLOT_SIZE: f32 = 24.0;
+    const SLOT_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.5);
+    const SELECTED_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.7);
+    // Compare to Luanti, builtin/game/statbars.lua, health_bar_definition
+    // and breath_bar_definition
+    const HEALTH_BAR_IMAGE: &str = "heart.png";
+    const HEALTH_BAR_BACKGROUND: &str = "heart_gone.png";
+    const BREATH_BAR_IMAGE: &str = "bubble.png";
+    const BREATH_BAR_BACKGROUND: &str = "bubble_gone.png";
+    /// Number of half icons of a full bar
+    const STATBAR_MAX: u32 = 20;
+    // Compare to Luanti, constants.h, PLAYER_MAX_HP_DEFAULT and
+    // PLAYER_MAX_BREATH_DEFAULT
+    const PLAYER_MAX_HP_DEFAULT: u32 = 20;
+    const PLAYER_MAX_BREATH_DEFAULT: u32 = 10;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, id: u32, element: HudElement) {
+        self.elements.insert(id, element);
+    }
+
+    pub fn change(&mut self, id: u32, stat: HudStat) {
+        match self.elements.get_mut(&id) {
+            Some(element) => element.change(stat),
+            None => println!("Cannot change unknown HUD element {}", id),
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if self.elements.remove(&id).is_none() {
+            println!("Cannot remove unknown HUD element {}", id);
+        }
+    }
+
+    /// Sets the flags in `mask` to their value in `flags`.
+    // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_HudSetFlags
+    pub fn set_flags(&mut self, flags: u32, mask: u32) {
+        self.flags &= !mask;
+        self.flags |= flags & mask;
+    }
+
+    /// Whether all of the given HUD_FLAG_* flags are set.
+    pub fn is_visible(&self, flags: u32) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// Returns the uploaded hotbar image and selected hotbar image, if set.
+    pub fn hotbar_images(&self) -> (Option<OverlayTextureId>, Option<OverlayTextureId>) {
+        (
+            self.images.get(&self.hotbar_image).map(|image| image.id),
+            self.images
+                .get(&self.hotbar_selected_image)
+                .map(|texture| texture.id),
+        )
+    }
+
+    /// Uploads the textures used by the elements and the hotbar that haven't
+    /// been uploaded yet, including the images of the items shown by
+    /// inventory elements. Must be called before drawing for the images to
+    /// show up.
+    pub fn load_textures(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        overlay: &mut OverlayRenderer,
+        media: &MediaManager,
+        inventory: &Inventory,
+    ) {
+        let mut names = vec![
+            self.hotbar_image.as_str(),
+            self.hotbar_selected_image.as_str(),
+        ];
+        if self.hp.is_some() {
+            names.extend([Self::HEALTH_BAR_IMAGE, Self::HEALTH_BAR_BACKGROUND]);
+        }
+        if self.breath.is_some() {
+            names.extend([Self::BREATH_BAR_IMAGE, Self::BREATH_BAR_BACKGROUND]);
+        }
+        for element in self.elements.values() {
+            match element.typ {
+                HUD_ELEM_IMAGE | HUD_ELEM_IMAGE_WAYPOINT => names.push(&element.text),
+                HUD_ELEM_STATBAR => names.extend([element.text.as_str(), &element.text2]),
+                HUD_ELEM_INVENTORY => {
+                    if let (Some(item_def), Some(list)) =
+                        (&self.item_def, inventory.list(&element.text))
+                    {
+                        let count = (element.number as usize).min(list.items.len());
+                        names.extend(
+                            list.items[..count]
+                                .iter()
+                                .filter_map(|stack| item_def.inventory_image(&stack.name)),
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for name in names {
+            self.images.load(device, queue, overlay, media, name);
+        }
+    }
+
+    /// Draws all elements, ordered by z_index.
+    // Compare to Luanti, client/hud.cpp, Hud::drawLuaElements
+    pub fn draw(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        camera: &CameraParams,
+        inventory: &Inventory,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+        scale: f32,
+    ) {
+        let screen = Vec2::new(screen_size.width as f32, screen_size.height as f32);
+
+        let mut elements: Vec<_> = self.elements.iter().collect();
+        // Server ids are increasing, so elements with the same z_index are
+        // drawn in the order they were added, like in Luanti
+        elements.sort_by_key(|(id, element)| (element.z_index, **id));
+
+        for (_, element) in elements {
+            let offset = element.offset * scale;
+            let pos = (element.pos * screen).floor() + offset;
+
+            match element.typ {
+                HUD_ELEM_IMAGE => self.draw_image(overlay, element, pos, screen, scale),
+                HUD_ELEM_TEXT => Self::draw_text(overlay, text, element, pos),
+                HUD_ELEM_STATBAR => self.draw_statbar(overlay, element, pos, scale),
+                HUD_ELEM_INVENTORY => {
+                    self.draw_inventory(overlay, text, element, inventory, pos, scale)
+                }
+                HUD_ELEM_WAYPOINT | HUD_ELEM_IMAGE_WAYPOINT => {
+                    let Some(pos) = camera.world_to_screen(element.world_pos) else {
+                        continue;
+                    };
+                    if element.typ == HUD_ELEM_WAYPOINT {
+                        Self::draw_waypoint(overlay, text, element, pos + offset, camera.pos);
+                    } else {
+                        self.draw_image(overlay, element, pos + offset, screen, scale);
+                    }
+                }
+                // TODO: compass and minimap
+                _ => (),
+            }
+        }
+
+        self.draw_builtin_statbars(overlay, screen, scale);
+    }
+
+    /// Draws the health and breath bars for servers that don't add statbar
+    /// elements themselves. Current servers draw them through
+    /// builtin/game/statbars.lua, in which case nothing is drawn here.
+    fn draw_builtin_statbars(&self, overlay: &mut OverlayRenderer, screen: Vec2, scale: f32) {
+        if self
+            .elements
+            .values()
+            .any(|element| element.typ == HUD_ELEM_STATBAR)
+        {
+            return;
+        }
+
+        let mut statbars = Vec::new();
+        if let Some(hp) = self.hp
+            && self.is_visible(HUD_FLAG_HEALTHBAR_VISIBLE)
+        {
+            statbars.push(Self::builtin_statbar(
+                Self::HEALTH_BAR_IMAGE,
+                Self::HEALTH_BAR_BACKGROUND,
+                hp as u32 * Self::STATBAR_MAX / Self::PLAYER_MAX_HP_DEFAULT,
+                Vec2::new(-10.0 * 24.0 - 25.0, -(48.0 + 24.0 + 16.0)),
+            ));
+        }
+        // Only shown while the player is out of breath, e.g. under water
+        if let Some(breath) = self.breath
+            && (breath as u32) < Self::PLAYER_MAX_BREATH_DEFAULT
+            && self.is_visible(HUD_FLAG_BREATHBAR_VISIBLE)
+        {
+            statbars.push(Self::builtin_statbar(
+                Self::BREATH_BAR_IMAGE,
+                Self::BREATH_BAR_BACKGROUND,
+                breath as u32 * Self::STATBAR_MAX / Self::PLAYER_MAX_BREATH_DEFAULT,
+                Vec2::new(25.0, -(48.0 + 24.0 + 16.0)),
+            ));
+        }
+
+        for element in statbars {
+            let pos = (element.pos * screen).floor() + element.offset * scale;
+            self.draw_statbar(overlay, &element, pos, scale);
+        }
+    }
+
+    /// Returns a statbar element like the ones added by builtin, above the
+    /// hotbar. `number` is in half icons, out of STATBAR_MAX.
+    // Compare to Luanti, builtin/game/statbars.lua, scaleToHudMax
+    fn builtin_statbar(image: &str, background: &str, number: u32, offset: Vec2) -> HudElement {
+        HudElement {
+            typ: HUD_ELEM_STATBAR,
+            pos: Vec2::new(0.5, 1.0),
+            name: String::new(),
+            scale: Vec2::ONE,
+            text: String::from(image),
+            number,
+            item: Self::STATBAR_MAX,
+            dir: 0,
+            align: Vec2::ZERO,
+            offset,
+            world_pos: Vec3::ZERO,
+            size: IVec2::new(24, 24),
+            z_index: 0,
+            text2: String::from(background),
+        }
+    }
+
+    fn draw_image(
+        &self,
+        overlay: &mut OverlayRenderer,
+        element: &HudElement,
+        pos: Vec2,
+        screen: Vec2,
+        scale: f32,
+    ) {
+        let Some(texture) = self.images.get(&element.text) else {
+            return;
+        };
+
+        let mut size = texture.size * element.scale * scale;
+        // Negative scales are percentages of the screen size
+        if element.scale.x < 0.0 {
+            size.x = screen.x * -element.scale.x / 100.0;
+        }
+        if element.scale.y < 0.0 {
+            size.y = screen.y * -element.scale.y / 100.0;
+        }
+
+        let min = pos + (element.align - 1.0) * size / 2.0;
+        overlay.image(
+            texture.id,
+            min,
+            min + size,
+            Vec2::ZERO,
+            Vec2::ONE,
+            Vec4::ONE,
+        );
+    }
+
+    fn draw_text(
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        element: &HudElement,
+        pos: Vec2,
+    ) {
+        // TODO: font size (size.x) and text styles
+        let color = number_to_color(element.number);
+        let content = text::strip_escapes(&element.text);
+        let lines: Vec<&str> = content.lines().collect();
+
+        let height = lines.len() as f32 * text.line_height();
+        let mut y = pos.y + (element.align.y - 1.0) * height / 2.0;
+        for line in lines {
+            // Each line is aligned on its own
+            let x = pos.x + (element.align.x - 1.0) * text.width(line) / 2.0;
+            text.draw(overlay, line, Vec2::new(x, y), color);
+            y += text.line_height();
+        }
+    }
+
+    /// Draws `number` half icons, followed by background icons up to `item`
+    /// half icons.
+    // Compare to Luanti, client/hud.cpp, Hud::drawStatbar
+    fn draw_statbar(
+        &self,
+        overlay: &mut OverlayRenderer,
+        element: &HudElement,
+        pos: Vec2,
+        scale: f32,
+    ) {
+        let Some(texture) = self.images.get(&element.text) else {
+            return;
+        };
+        let background = self.images.get(&element.text2);
+        let count = element.number;
+        let max_count = element.item;
+
+        let size = if element.size == IVec2::ZERO {
+            texture.size * scale
+        } else {
+            element.size.as_vec2() * scale
+        };
+        let step = match element.dir {
+            HUD_DIR_RIGHT_LEFT => Vec2::new(-size.x, 0.0),
+            HUD_DIR_TOP_BOTTOM => Vec2::new(0.0, size.y),
+            HUD_DIR_BOTTOM_TOP => Vec2::new(0.0, -size.y),
+            _ => Vec2::new(size.x, 0.0),
+        };
+        // The first and the second half of an icon in drawing direction,
+        // as UV ranges
+        let (first_half, second_half) = match element.dir {
+            HUD_DIR_RIGHT_LEFT => (
+                (Vec2::new(0.5, 0.0), Vec2::ONE),
+                (Vec2::ZERO, Vec2::new(0.5, 1.0)),
+            ),
+            HUD_DIR_TOP_BOTTOM => (
+                (Vec2::ZERO, Vec2::new(1.0, 0.5)),
+                (Vec2::new(0.0, 0.5), Vec2::ONE),
+            ),
+            HUD_DIR_BOTTOM_TOP => (
+                (Vec2::new(0.0, 0.5), Vec2::ONE),
+                (Vec2::ZERO, Vec2::new(1.0, 0.5)),
+            ),
+            _ => (
+                (Vec2::ZERO, Vec2::new(0.5, 1.0)),
+                (Vec2::new(0.5, 0.0), Vec2::ONE),
+            ),
+        };
+        let mut draw_part = |texture: &MediaImage, pos: Vec2, (uv_min, uv_max): (Vec2, Vec2)| {
+            overlay.image(
+                texture.id,
+                pos + uv_min * size,
+                pos + uv_max * size,
+                uv_min,
+                uv_max,
+                Vec4::ONE,
+            );
+        };
+        let background = background.filter(|_| max_count > count);
+
+        let mut pos = pos;
+        for _ in 0..count / 2 {
+            draw_part(texture, pos, (Vec2::ZERO, Vec2::ONE));
+            pos += step;
+        }
+        if count % 2 == 1 {
+            draw_part(texture, pos, first_half);
+            if let Some(background) = background {
+                draw_part(background, pos, second_half);
+            }
+            pos += step;
+        }
+
+        if let Some(background) = background {
+            for _ in count.div_ceil(2)..max_count / 2 {
+                draw_part(background, pos, (Vec2::ZERO, Vec2::ONE));
+                pos += step;
+            }
+            if max_count % 2 == 1 {
+                draw_part(background, pos, first_half);
+            }
+        }
+    }
+
+    /// Draws `number` slots of the inventory list `text`, with the slot
+    /// `item` (1-based) selected.
+    // Compare to Luanti, client/hud.cpp, Hud::drawItems
+    fn draw_inventory(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        element: &HudElement,
+        inventory: &Inventory,
+        pos: Vec2,
+        scale: f32,
+    ) {
+        const COUNT_COLOR: Vec4 = Vec4::ONE;
+
+        let Some(list) = inventory.list(&element.text) else {
+            return;
+        };
+        let count = (element.number as usize).min(list.items.len());
+        let slot_size = Self::SLOT_SIZE * scale;
+
+        let (step, size) = match element.dir {
+            HUD_DIR_TOP_BOTTOM | HUD_DIR_BOTTOM_TOP => (
+                Vec2::new(0.0, slot_size),
+                Vec2::new(slot_size, count as f32 * slot_size),
+            ),
+            _ => (
+                Vec2::new(slot_size, 0.0),
+                Vec2::new(count as f32 * slot_size, slot_size),
+            ),
+        };
+        let origin = pos + (element.align - 1.0) * size / 2.0;
+
+        for index in 0..count {
+            let slot = match element.dir {
+                HUD_DIR_RIGHT_LEFT | HUD_DIR_BOTTOM_TOP => count - 1 - index,
+                _ => index,
+            };
+            let min = origin + step * index as f32;
+            let max = min + slot_size;
+            if element.item as usize == slot + 1 {
+                overlay.rect(min, max, Self::SELECTED_COLOR);
+            } else {
+                overlay.rect(min, max, Self::SLOT_COLOR);
+            }
+
+            let stack = &list.items[slot];
+            if stack.is_empty() {
+                continue;
+            }
+            if let Some(image) = self
+                .item_def
+                .as_ref()
+                .and_then(|item_def| item_def.inventory_image(&stack.name))
+                .and_then(|name| self.images.get(name))
+            {
+                overlay.image(image.id, min, max, Vec2::ZERO, Vec2::ONE, Vec4::ONE);
+            }
+            if stack.count > 1 {
+                let count = stack.count.to_string();
+                let text_pos = max - Vec2::new(text.width(&count), text.line_height());
+                text.draw(overlay, &count, text_pos, COUNT_COLOR);
+            }
+        }
+    }
+
+    /// Draws the name and, depending on the precision in `item`, the
+    /// distance from `camera_pos` with the unit in `text`.
+    fn draw_waypoint(
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        element: &HudElement,
+        pos: Vec2,
+        camera_pos: Vec3,
+    ) {
+        let color = number_to_color(element.number);
+        let name = text::strip_escapes(&element.name);
+        // item = precision + 1, and 0 means a precision of 10 for backwards
+        // compatibility
+        let precision = match element.item {
+            0 => 10.0,
+            item => item as f32 - 1.0,
+        };
+
+        let line_count = if precision > 0.0 { 2.0 } else { 1.0 };
+        let y = pos.y + (element.align.y - 1.0) * line_count * text.line_height() / 2.0;
+        let x = pos.x + (element.align.x - 1.0) * text.width(&name) / 2.0;
+        text.draw(overlay, &name, Vec2::new(x, y), color);
+
+        if precision > 0.0 {
+            let distance = (precision * camera_pos.distance(element.world_pos)).floor() / precision;
+            let line = format!("{}{}", distance, text::strip_escapes(&element.text));
+            let x = pos.x + (element.align.x - 1.0) * text.width(&line) / 2.0;
+            text.draw(overlay, &line, Vec2::new(x, y + text.line_height()), color);
+        }
+    }
+}
+
+/// Converts a 0xRRGGBB color, as used by text and waypoint elements.
+fn number_to_color(number: u32) -> Vec4 {
+    Vec4::new(
+        srgb_to_linear((number >> 16) as u8),
+        srgb_to_linear((number >> 8) as u8),
+        srgb_to_linear(number as u8),
+        1.0,
+    )
+}
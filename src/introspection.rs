@@ -0,0 +1,85 @@
+//! Human-readable dumps of node, item and texture information, for debugging why
+//! something renders wrong on a particular server.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use luanti_core::MapNodePos;
+
+use crate::item_def::ItemDefManager;
+use crate::map::LuantiMap;
+use crate::media::MediaOrigin;
+use crate::node_def::NodeDefManager;
+
+/// Dumps the node at `pos` and its ContentFeatures.
+pub fn describe_node(map: &LuantiMap, node_def: &NodeDefManager, pos: MapNodePos) -> String {
+    let Some(node) = map.get_node(&pos) else {
+        return format!("Node at {} is not loaded", pos.0);
+    };
+
+    let mut text = String::new();
+    writeln!(
+        text,
+        "Node at {}: content_id = {}, param1 = {}, param2 = {}",
+        pos.0, node.content_id.0, node.param1, node.param2
+    )
+    .unwrap();
+    match node_def.get(node.content_id) {
+        Some(def) => write!(text, "{:#?}", def).unwrap(),
+        None => write!(text, "Unknown content_id, rendered as \"unknown\"").unwrap(),
+    }
+    if let Some(metadata) = map.get_node_metadata(&pos) {
+        write!(text, "\nMetadata: {:#?}", metadata).unwrap();
+    }
+    text
+}
+
+/// Dumps the definition of an item, following aliases.
+pub fn describe_item(item_def: &ItemDefManager, name: &str) -> String {
+    let mut text = String::new();
+    let resolved = item_def.resolve_alias(name);
+    if resolved != name {
+        writeln!(text, "\"{}\" is an alias for \"{}\"", name, resolved).unwrap();
+    }
+    let Some(def) = item_def.get(resolved) else {
+        write!(text, "Unknown item \"{}\"", resolved).unwrap();
+        return text;
+    };
+    writeln!(text, "{:#?}", def).unwrap();
+    if def.tool_capabilities.is_none() {
+        write!(
+            text,
+            "Uses the tool capabilities of the hand: {:#?}",
+            item_def.tool_capabilities(resolved)
+        )
+        .unwrap();
+    }
+    text
+}
+
+/// Dumps where a texture was resolved from and which nodes use it.
+pub fn describe_texture(
+    name: &str,
+    node_def: Option<&NodeDefManager>,
+    origins: &HashMap<String, MediaOrigin>,
+) -> String {
+    let mut text = String::new();
+
+    match origins.get(name) {
+        Some(origin) => writeln!(text, "Texture \"{}\": {}", name, origin).unwrap(),
+        None => writeln!(text, "Texture \"{}\": not available", name).unwrap(),
+    }
+
+    if let Some(node_def) = node_def {
+        let mut users: Vec<&str> = node_def
+            .map
+            .values()
+            .filter(|def| def.tiledef.iter().any(|tile| tile.name == name))
+            .map(|def| def.name.as_str())
+            .collect();
+        users.sort();
+        write!(text, "Used by {} nodes: {}", users.len(), users.join(", ")).unwrap();
+    }
+
+    text
+}
@@ -0,0 +1,329 @@
+//! Data model for inventories, deserialized from the text format Luanti sends
+//! in TOCLIENT_INVENTORY and TOCLIENT_DETACHED_INVENTORY.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::anyhow;
+use glam::I16Vec3;
+use luanti_core::MapNodePos;
+
+use crate::node_metadata::NodeMetadataStore;
+
+/// A stack of items in an inventory slot.
+// Compare to Luanti, inventory.h, ItemStack
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemStack {
+    /// Empty if the slot is empty
+    pub name: String,
+    pub count: u16,
+    /// Tool wear, 0 to 65535
+    pub wear: u16,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ItemStack {
+    /// Start of serialized item metadata
+    const META_START: char = '\x01';
+    /// Separates a key from its value
+    const META_KV_DELIM: char = '\x02';
+    /// Terminates a key-value pair
+    const META_PAIR_DELIM: char = '\x03';
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty() || self.count == 0
+    }
+
+    /// Parses an item string like `default:pick_steel 1 1234 "\u0001..."`.
+    /// Missing count and wear default to 1 and 0.
+    // Compare to Luanti, inventory.cpp, ItemStack::deSerialize
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser(text.trim_start());
+        let name = parser.next_word()?;
+        if name.is_empty() {
+            return Ok(Self::default());
+        }
+        if matches!(name.as_str(), "NodeItem" | "CraftItem" | "ToolItem") {
+            return Err(anyhow!("Legacy item string \"{}\" is not supported", text));
+        }
+
+        let count = match parser.next_word()? {
+            word if word.is_empty() => 1,
+            word => word.parse()?,
+        };
+        let wear = match parser.next_word()? {
+            word if word.is_empty() => 0,
+            word => word.parse()?,
+        };
+        let metadata = Self::parse_metadata(&parser.next_word()?)?;
+
+        Ok(Self {
+            name,
+            count,
+            wear,
+            metadata,
+        })
+    }
+
+    // Compare to Luanti, itemstackmetadata.cpp, ItemStackMetadata::deSerialize
+    fn parse_metadata(text: &str) -> anyhow::Result<HashMap<String, String>> {
+        let mut metadata = HashMap::new();
+        let Some(pairs) = text.strip_prefix(Self::META_START) else {
+            // Legacy: the whole string is the "" field
+            if !text.is_empty() {
+                metadata.insert(String::new(), String::from(text));
+            }
+            return Ok(metadata);
+        };
+
+        for pair in pairs.split_terminator(Self::META_PAIR_DELIM) {
+            let (key, value) = pair
+                .split_once(Self::META_KV_DELIM)
+                .ok_or_else(|| anyhow!("Invalid item metadata pair {:?}", pair))?;
+            metadata.insert(String::from(key), String::from(value));
+        }
+        Ok(metadata)
+    }
+}
+
+/// Formats the stack as an item string, e.g. "default:torch 5". Like Luanti,
+/// count, wear and metadata are only included if needed.
+// Compare to Luanti, inventory.cpp, ItemStack::serialize
+impl fmt::Display for ItemStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        let with_metadata = !self.metadata.is_empty();
+        let with_wear = self.wear != 0 || with_metadata;
+        if self.count != 1 || with_wear {
+            write!(f, " {}", self.count)?;
+        }
+        if with_wear {
+            write!(f, " {}", self.wear)?;
+        }
+        if with_metadata {
+            // Sorted so the item string is stable
+            let mut pairs: Vec<_> = self.metadata.iter().collect();
+            pairs.sort();
+
+            let mut metadata = String::from(Self::META_START);
+            for (key, value) in pairs {
+                metadata.push_str(key);
+                metadata.push(Self::META_KV_DELIM);
+                metadata.push_str(value);
+                metadata.push(Self::META_PAIR_DELIM);
+            }
+            write!(f, " {}", serialize_json_string(&metadata))?;
+        }
+        Ok(())
+    }
+}
+
+/// A named list of slots, e.g. "main" or "craft".
+// Compare to Luanti, inventory.h, InventoryList
+#[derive(Debug, Clone, Default)]
+pub struct InventoryList {
+    pub name: String,
+    pub items: Vec<ItemStack>,
+}
+
+impl InventoryList {
+    /// Reads the list up to its "EndInventoryList" line. `previous` is the
+    /// current state of the list, for slots sent as "Keep".
+    // Compare to Luanti, inventory.cpp, InventoryList::deSerialize
+    fn deserialize<'a>(
+        name: &str,
+        size: usize,
+        previous: Option<&InventoryList>,
+        lines: &mut impl Iterator<Item = &'a str>,
+    ) -> anyhow::Result<Self> {
+        let mut list = Self {
+            name: String::from(name),
+            items: vec![ItemStack::default(); size],
+        };
+
+        let mut index = 0;
+        for line in lines {
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            if keyword == "EndInventoryList" || keyword == "end" {
+                return Ok(list);
+            }
+            if keyword == "Width" {
+                // Only relevant for displaying the list in formspecs
+                continue;
+            }
+
+            let item = match keyword {
+                "Empty" => ItemStack::default(),
+                "Item" => ItemStack::parse(rest)?,
+                "Keep" => previous
+                    .and_then(|previous| previous.items.get(index))
+                    .cloned()
+                    .unwrap_or_default(),
+                _ => return Err(anyhow!("Unexpected line {:?} in inventory list", line)),
+            };
+            if index < size {
+                list.items[index] = item;
+            }
+            index += 1;
+        }
+
+        Err(anyhow!("Inventory list \"{}\" isn't terminated", name))
+    }
+
+    pub fn get(&self, index: usize) -> Option<&ItemStack> {
+        self.items.get(index)
+    }
+}
+
+/// A set of inventory lists, e.g. the player inventory or a detached one.
+// Compare to Luanti, inventory.h, Inventory
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    pub lists: Vec<InventoryList>,
+}
+
+impl Inventory {
+    /// Updates the inventory from its serialized form. The server may only
+    /// send the lists and slots that changed, everything else is marked with
+    /// "KeepList" and "Keep". Lists that aren't mentioned are removed.
+    // Compare to Luanti, inventory.cpp, Inventory::deSerialize
+    pub fn deserialize(&mut self, text: &str) -> anyhow::Result<()> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        let mut lists = Vec::new();
+
+        while let Some(line) = lines.next() {
+            let mut words = line.split(' ');
+            match words.next() {
+                Some("EndInventory" | "end") => {
+                    self.lists = lists;
+                    return Ok(());
+                }
+                Some("List") => {
+                    let name = words.next().unwrap_or_default();
+                    let size = words
+                        .next()
+                        .ok_or_else(|| anyhow!("Inventory list \"{}\" has no size", name))?
+                        .parse()?;
+                    let list = InventoryList::deserialize(name, size, self.list(name), &mut lines)?;
+                    lists.push(list);
+                }
+                Some("KeepList") => {
+                    let name = words.next().unwrap_or_default();
+                    match self.list(name) {
+                        Some(list) => lists.push(list.clone()),
+                        None => println!("Inventory: cannot keep unknown list \"{}\"", name),
+                    }
+                }
+                _ => return Err(anyhow!("Unexpected line {:?} in inventory", line)),
+            }
+        }
+
+        Err(anyhow!("Inventory isn't terminated"))
+    }
+
+    pub fn list(&self, name: &str) -> Option<&InventoryList> {
+        self.lists.iter().find(|list| list.name == name)
+    }
+}
+
+/// Quotes the string as JSON. Metadata always contains control characters,
+/// so this doesn't check whether quoting is needed.
+// Compare to Luanti, util/string.cpp, serializeJsonString
+fn serialize_json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\x08' => quoted.push_str("\\b"),
+            '\x0c' => quoted.push_str("\\f"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits an item string into words. Words may be JSON-quoted, which is used
+/// for metadata containing spaces or control characters.
+struct Parser<'a>(&'a str);
+
+impl Parser<'_> {
+    /// Returns the next word, or an empty string at the end.
+    // Compare to Luanti, util/string.cpp, deSerializeJsonStringIfNeeded
+    fn next_word(&mut self) -> anyhow::Result<String> {
+        let text = self.0.trim_start_matches(' ');
+        if !text.starts_with('"') {
+            let end = text.find(' ').unwrap_or(text.len());
+            self.0 = &text[end..];
+            return Ok(String::from(&text[..end]));
+        }
+
+        let mut word = String::new();
+        let mut chars = text[1..].char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = &text[index + 2..];
+                    return Ok(word);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('b') => '\x08',
+                        Some('f') => '\x0c',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            let code = u32::from_str_radix(&hex, 16)?;
+                            char::from_u32(code)
+                                .ok_or_else(|| anyhow!("Invalid escape \\u{}", hex))?
+                        }
+                        Some(c) => c,
+                        None => break,
+                    };
+                    word.push(escaped);
+                }
+                c => word.push(c),
+            }
+        }
+        Err(anyhow!("Unterminated string in {:?}", self.0))
+    }
+}
+
+/// The inventories known to the client, for looking them up by their
+/// location string, e.g. "current_player", "detached:creative" or
+/// "nodemeta:1,2,3".
+pub struct Inventories<'a> {
+    pub player: &'a Inventory,
+    pub detached: &'a HashMap<String, Inventory>,
+    pub node_metadata: &'a NodeMetadataStore,
+}
+
+impl Inventories<'_> {
+    // Compare to Luanti, inventorymanager.cpp, InventoryLocation::deSerialize
+    pub fn get(&self, location: &str) -> Option<&Inventory> {
+        if location == "current_player" {
+            return Some(self.player);
+        }
+        if let Some(name) = location.strip_prefix("detached:") {
+            return self.detached.get(name);
+        }
+        let coords: Vec<i16> = location
+            .strip_prefix("nodemeta:")?
+            .split(',')
+            .map(|coord| coord.trim().parse())
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let [x, y, z] = coords[..] else {
+            return None;
+        };
+        let metadata = self.node_metadata.get(&MapNodePos(I16Vec3::new(x, y, z)))?;
+        Some(&metadata.inventory)
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use luanti_protocol::types::{ItemDef, ItemType, ToolCapabilities};
+
+use crate::pointing;
+
+pub struct ItemDefManager {
+    defs: HashMap<String, ItemDef>,
+    /// Alias name -> item name
+    aliases: HashMap<String, String>,
+}
+
+impl ItemDefManager {
+    /// Name of the hand item, whose tool capabilities are used for items
+    /// without any.
+    pub const HAND: &str = "";
+
+    /// Creates a new ItemDefManager from luanti_protocol data.
+    // Compare to Luanti, itemdef.cpp, CItemDefManager::deSerialize
+    pub fn from_network(data: luanti_protocol::types::ItemdefList) -> Self {
+        let mut defs = HashMap::new();
+        // Compare to Luanti, itemdef.cpp, CItemDefManager::clear
+        // These three are not sent via the network by Luanti, we are expected
+        // to initialize them ourselves.
+        defs.insert(
+            String::from("unknown"),
+            ItemDef {
+                name: String::from("unknown"),
+                description: String::from("Unknown Item"),
+                inventory_image: String::from("unknown_item.png"),
+                item_type: ItemType::None,
+                ..ItemDef::default()
+            },
+        );
+        defs.insert(
+            String::from("air"),
+            ItemDef {
+                name: String::from("air"),
+                item_type: ItemType::None,
+                ..ItemDef::default()
+            },
+        );
+        defs.insert(
+            String::from("ignore"),
+            ItemDef {
+                name: String::from("ignore"),
+                item_type: ItemType::None,
+                ..ItemDef::default()
+            },
+        );
+        for def in data.defs {
+            defs.insert(def.name.clone(), def);
+        }
+
+        let aliases = data
+            .aliases
+            .into_iter()
+            .map(|alias| (alias.name, alias.convert_to))
+            .collect();
+
+        Self { defs, aliases }
+    }
+
+    /// Returns the name of the item `name` refers to, resolving aliases.
+    // Compare to Luanti, itemdef.cpp, CItemDefManager::getAlias
+    pub fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        // Aliases for registered items are ignored, like in Luanti
+        if self.defs.contains_key(name) {
+            return name;
+        }
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ItemDef> {
+        self.defs.get(self.resolve_alias(name))
+    }
+
+    /// Returns the inventory image of the item, None if it has none.
+    pub fn inventory_image(&self, name: &str) -> Option<&str> {
+        if name.is_empty() {
+            return None;
+        }
+        let image = &self.get(name)?.inventory_image;
+        Some(image.as_str()).filter(|image| !image.is_empty())
+    }
+
+    /// Returns the pointing range of the item in nodes, falling back to the
+    /// one of the hand.
+    // Compare to Luanti, client/game.cpp, Game::processPlayerInteraction
+    pub fn range(&self, name: &str) -> f32 {
+        let range = self.get(name).map_or(-1.0, |def| def.range);
+        let hand_range = self.defs.get(Self::HAND).map_or(-1.0, |def| def.range);
+        if range >= 0.0 {
+            range
+        } else if hand_range >= 0.0 {
+            hand_range
+        } else {
+            pointing::DEFAULT_RANGE
+        }
+    }
+
+    /// Returns the tool capabilities of the item, falling back to the ones
+    /// of the hand.
+    // Compare to Luanti, inventory.h, ItemStack::getToolCapabilities
+    pub fn tool_capabilities(&self, name: &str) -> Option<&ToolCapabilities> {
+        self.get(name)
+            .and_then(|def| def.tool_capabilities.as_ref())
+            .or_else(|| {
+                self.defs
+                    .get(Self::HAND)
+                    .and_then(|def| def.tool_capabilities.as_ref())
+            })
+    }
+}
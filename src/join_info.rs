@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use glam::{Vec2, Vec4};
+
+use crate::formspec::{parse_elements, unescape};
+use crate::overlay::OverlayRenderer;
+use crate::text::{TextRenderer, strip_escapes};
+
+/// Chat messages and formspecs received right after joining a server, which
+/// usually contain the server rules or a message of the day. They are shown
+/// in a panel until dismissed, so they aren't missed among other messages.
+#[derive(Default)]
+pub struct JoinInfo {
+    lines: Vec<String>,
+    joined_at: Option<Instant>,
+    dismissed: bool,
+}
+
+impl JoinInfo {
+    /// How long after joining messages are captured
+    const CAPTURE_DURATION: Duration = Duration::from_secs(15);
+    const MAX_LINES: usize = 100;
+
+    /// Starts capturing, discarding messages from a previous server.
+    pub fn joined(&mut self) {
+        *self = Self {
+            joined_at: Some(Instant::now()),
+            ..Self::default()
+        };
+    }
+
+    fn capturing(&self) -> bool {
+        !self.dismissed
+            && self
+                .joined_at
+                .is_some_and(|time| time.elapsed() < Self::CAPTURE_DURATION)
+    }
+
+    fn add_line(&mut self, line: String) {
+        if self.lines.len() < Self::MAX_LINES {
+            self.lines.push(line);
+        }
+    }
+
+    pub fn add_chat_message(&mut self, message: &str) {
+        if !self.capturing() {
+            return;
+        }
+        for line in strip_escapes(message).lines() {
+            self.add_line(String::from(line));
+        }
+    }
+
+    pub fn add_formspec(&mut self, formspec: &str) {
+        if !self.capturing() {
+            return;
+        }
+        for line in formspec_text(formspec) {
+            self.add_line(line);
+        }
+    }
+
+    fn visible(&self) -> bool {
+        !self.dismissed && !self.lines.is_empty()
+    }
+
+    /// Hides the panel and stops capturing.
+    pub fn dismiss(&mut self) {
+        self.dismissed = true;
+    }
+
+    /// Draws the panel in the center of the screen.
+    pub fn draw(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        const PADDING: f32 = 16.0;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+        const TEXT_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 1.0);
+        const HINT_COLOR: Vec4 = Vec4::new(0.7, 0.7, 0.7, 1.0);
+        const HINT: &str = "Press Enter to close";
+
+        if !self.visible() {
+            return;
+        }
+
+        let screen = Vec2::new(screen_size.width as f32, screen_size.height as f32);
+        let max_size = screen * 0.8;
+
+        let lines: Vec<String> = self
+            .lines
+            .iter()
+            .flat_map(|line| text.wrap(line, max_size.x - 2.0 * PADDING))
+            .collect();
+        // Lines that don't fit are cut off, the panel is for a quick overview
+        // One line is taken by the hint
+        let max_lines =
+            (((max_size.y - 2.0 * PADDING) / text.line_height()) as usize).saturating_sub(1);
+        let lines = &lines[..lines.len().min(max_lines)];
+
+        let width = lines
+            .iter()
+            .map(|line| text.width(line))
+            .fold(text.width(HINT), f32::max);
+        let size = Vec2::new(
+            width + 2.0 * PADDING,
+            (lines.len() + 1) as f32 * text.line_height() + 2.0 * PADDING,
+        );
+        let min = ((screen - size) / 2.0).round();
+        overlay.rect(min, min + size, BACKGROUND_COLOR);
+
+        let mut pos = min + PADDING;
+        for line in lines {
+            text.draw(overlay, line, pos, TEXT_COLOR);
+            pos.y += text.line_height();
+        }
+        text.draw(overlay, HINT, pos, HINT_COLOR);
+    }
+}
+
+/// Extracts the human-readable text of a formspec: labels, text areas and
+/// hypertext elements.
+fn formspec_text(formspec: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for (name, args) in parse_elements(formspec) {
+        let text = match name {
+            "label" => args.get(1).map(|text| unescape(text)),
+            "textarea" => args.last().map(|text| unescape(text)),
+            "hypertext" => args.last().map(|text| strip_tags(&unescape(text))),
+            _ => None,
+        };
+        if let Some(text) = text {
+            lines.extend(strip_escapes(&text).lines().map(String::from));
+        }
+    }
+
+    lines
+}
+
+/// Removes `<tag>`s from hypertext.
+fn strip_tags(text: &str) -> String {
+    let mut result = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => result.push(c),
+            _ => (),
+        }
+    }
+    result
+}
@@ -0,0 +1,246 @@
+//! Node light, stored in param1. Servers send mapblocks with their light
+//! already calculated, but single node changes have to be lit by the
+//! client, or the light around them stays wrong until the server resends
+//! the mapblocks.
+
+use std::collections::{HashSet, VecDeque};
+
+use glam::Vec2;
+use luanti_core::{MapBlockPos, MapNode, MapNodePos};
+use luanti_protocol::types::{ContentFeatures, ParamType};
+
+use crate::map::{LuantiMap, NEIGHBOR_DIRS};
+use crate::node_def::NodeDefManager;
+use crate::srgb_to_linear;
+
+/// Light of nodes with direct sunlight. Only reached by sunlight, which
+/// spreads downwards without getting darker.
+pub const LIGHT_SUN: u8 = 15;
+/// The brightest light a light source can have.
+pub const LIGHT_MAX: u8 = 14;
+
+/// The two kinds of light stored in param1. Nodes are lit by the day bank
+/// during the day and by the night bank during the night, with sunlight
+/// only being part of the day bank.
+// Compare to Luanti, light.h, LightBank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightBank {
+    Day,
+    Night,
+}
+
+impl LightBank {
+    const ALL: [LightBank; 2] = [LightBank::Day, LightBank::Night];
+}
+
+/// Returns the light of a node in the given bank. Nodes that don't store
+/// light only have the light they emit themselves.
+// Compare to Luanti, mapnode.cpp, MapNode::getLight
+pub fn get_light(node: MapNode, bank: LightBank, def: &ContentFeatures) -> u8 {
+    let light = if def.param_type == ParamType::Light {
+        match bank {
+            LightBank::Day => node.param1 & 0x0f,
+            LightBank::Night => node.param1 >> 4,
+        }
+    } else {
+        0
+    };
+    light.max(light_source(def))
+}
+
+/// Sets the light of a node in the given bank. Does nothing for nodes that
+/// don't store light.
+// Compare to Luanti, mapnode.cpp, MapNode::setLight
+fn set_light(node: &mut MapNode, bank: LightBank, light: u8, def: &ContentFeatures) {
+    if def.param_type != ParamType::Light {
+        return;
+    }
+    node.param1 = match bank {
+        LightBank::Day => (node.param1 & 0xf0) | light,
+        LightBank::Night => (node.param1 & 0x0f) | (light << 4),
+    };
+}
+
+fn light_source(def: &ContentFeatures) -> u8 {
+    def.light_source.min(LIGHT_MAX)
+}
+
+/// Brightness of the light levels, from 0 to 255 in gamma space. Roughly a
+/// gamma curve, every level is about 1.25 times as bright as the one below.
+// Compare to Luanti, light.cpp, light_LUT
+const LIGHT_DECODE_TABLE: [u8; LIGHT_MAX as usize + 1] = [
+    8, 11, 14, 18, 22, 29, 37, 47, 60, 76, 97, 123, 157, 200, 255,
+];
+
+/// Returns the brightness of a light level, from 0 to 255 in gamma space.
+/// Sunlight is as bright as LIGHT_MAX.
+fn decode_light(light: u8) -> u8 {
+    LIGHT_DECODE_TABLE[light.min(LIGHT_MAX) as usize]
+}
+
+/// Returns the light of a vertex as the brightness during the day and
+/// during the night, from 0.0 to 1.0 in linear space. The shader mixes them
+/// by daylight.
+///
+/// `emissive` is the light source of the node itself, which makes it
+/// brighter than its light level alone. Light sources are artificial light,
+/// so it goes into the night bank, and the day is never darker than the
+/// night.
+// Compare to Luanti, client/mapblock_mesh.cpp, encode_light
+pub fn vertex_light(day: u8, night: u8, emissive: u8) -> Vec2 {
+    let night =
+        (decode_light(night) as f32 + emissive.min(LIGHT_MAX) as f32 * 2.5).min(255.0) as u8;
+    let day = decode_light(day).max(night);
+    // Luanti multiplies gamma space colors with these, but our shaders work
+    // in linear space
+    Vec2::new(srgb_to_linear(day), srgb_to_linear(night))
+}
+
+/// Returns the light that spreads from a node with `light` to a neighbor,
+/// which is below the node if `downwards`.
+// Compare to Luanti, light.h, diminish_light
+fn spread_light(light: u8, bank: LightBank, downwards: bool, to: &ContentFeatures) -> u8 {
+    if light == LIGHT_SUN && bank == LightBank::Day && downwards && to.sunlight_propagates {
+        LIGHT_SUN
+    } else if light == LIGHT_SUN {
+        LIGHT_MAX
+    } else {
+        light.saturating_sub(1)
+    }
+}
+
+/// Updates the light around a node after it was changed from `old` to the
+/// node that is now in the map. Returns the mapblocks whose light changed,
+/// which includes the mapblock of the node itself.
+///
+/// Light that came through or from the old node is removed first, then the
+/// light of the surrounding nodes is spread again.
+// Compare to Luanti, voxalgo.cpp, update_lighting_nodes
+pub fn update_lighting_node(
+    map: &mut LuantiMap,
+    node_def: &NodeDefManager,
+    pos: MapNodePos,
+    old: MapNode,
+) -> HashSet<MapBlockPos> {
+    let mut modified = HashSet::new();
+    let Some(mut node) = map.get_node(&pos) else {
+        return modified;
+    };
+    modified.insert(pos.split_index().0);
+
+    let old_def = node_def.get_with_fallback(old.content_id);
+    let def = node_def.get_with_fallback(node.content_id);
+
+    for bank in LightBank::ALL {
+        let old_light = get_light(old, bank, old_def);
+        // Light from the neighbors is added back when relighting
+        let new_light = light_source(def);
+        set_light(&mut node, bank, new_light, def);
+        map.set_node(&pos, node);
+
+        let mut relight = Vec::new();
+        if old_light > new_light {
+            unspread_light(
+                map,
+                node_def,
+                bank,
+                pos,
+                old_light,
+                &mut relight,
+                &mut modified,
+            );
+        }
+
+        // The neighbors spread their light into the node again, if it
+        // lets light through
+        relight.push(pos);
+        relight.extend(NEIGHBOR_DIRS.map(|dir| MapNodePos(pos.0 + dir)));
+        spread_light_from(map, node_def, bank, relight, &mut modified);
+
+        node = map.get_node(&pos).unwrap();
+    }
+
+    modified
+}
+
+/// Removes light that originated from `pos`, which had `light` before.
+/// Neighbors that are lit by something else are added to `relight`, so
+/// their light can fill the darkened area again.
+// Compare to Luanti, voxalgo.cpp, unspread_light
+fn unspread_light(
+    map: &mut LuantiMap,
+    node_def: &NodeDefManager,
+    bank: LightBank,
+    pos: MapNodePos,
+    light: u8,
+    relight: &mut Vec<MapNodePos>,
+    modified: &mut HashSet<MapBlockPos>,
+) {
+    let mut queue = VecDeque::from([(pos, light)]);
+    while let Some((pos, light)) = queue.pop_front() {
+        for dir in NEIGHBOR_DIRS {
+            let n_pos = MapNodePos(pos.0 + dir);
+            let Some(mut n_node) = map.get_node(&n_pos) else {
+                continue;
+            };
+            let n_def = node_def.get_with_fallback(n_node.content_id);
+            let n_light = get_light(n_node, bank, n_def);
+            if n_light == 0 {
+                continue;
+            }
+
+            // Direct sunlight below a node with direct sunlight got its
+            // light from it
+            let sunlight_below =
+                bank == LightBank::Day && dir.y < 0 && light == LIGHT_SUN && n_light == LIGHT_SUN;
+            if (n_light < light || sunlight_below) && n_light > light_source(n_def) {
+                set_light(&mut n_node, bank, light_source(n_def), n_def);
+                map.set_node(&n_pos, n_node);
+                modified.insert(n_pos.split_index().0);
+                queue.push_back((n_pos, n_light));
+            } else {
+                relight.push(n_pos);
+            }
+        }
+    }
+}
+
+/// Spreads the light of the given nodes to their neighbors, as far as it
+/// reaches.
+// Compare to Luanti, voxalgo.cpp, spread_light
+fn spread_light_from(
+    map: &mut LuantiMap,
+    node_def: &NodeDefManager,
+    bank: LightBank,
+    sources: Vec<MapNodePos>,
+    modified: &mut HashSet<MapBlockPos>,
+) {
+    let mut queue = VecDeque::from(sources);
+    while let Some(pos) = queue.pop_front() {
+        let Some(node) = map.get_node(&pos) else {
+            continue;
+        };
+        let light = get_light(node, bank, node_def.get_with_fallback(node.content_id));
+        if light == 0 {
+            continue;
+        }
+
+        for dir in NEIGHBOR_DIRS {
+            let n_pos = MapNodePos(pos.0 + dir);
+            let Some(mut n_node) = map.get_node(&n_pos) else {
+                continue;
+            };
+            let n_def = node_def.get_with_fallback(n_node.content_id);
+            if !n_def.light_propagates {
+                continue;
+            }
+            let n_light = spread_light(light, bank, dir.y < 0, n_def);
+            if n_light > get_light(n_node, bank, n_def) {
+                set_light(&mut n_node, bank, n_light, n_def);
+                map.set_node(&n_pos, n_node);
+                modified.insert(n_pos.split_index().0);
+                queue.push_back(n_pos);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Progress of joining a server, reported by the client thread and shown
+//! on the loading screen until the first mapblocks around the player are
+//! meshed.
+
+/// The steps of joining a server, in order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadingStage {
+    /// Waiting for TOCLIENT_HELLO
+    #[default]
+    Connecting,
+    LoggingIn,
+    /// Waiting for the node and item definitions and the media announcement
+    Definitions,
+    DownloadingMedia,
+    /// Meshgen is reserving the node textures and loading the meshes. The
+    /// textures themselves are loaded in the background while meshing.
+    LoadingTextures,
+    /// Waiting for the meshes around the player
+    Meshing,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadingProgress {
+    pub stage: LoadingStage,
+    /// Number of definitions, None until received
+    pub node_defs: Option<usize>,
+    pub item_defs: Option<usize>,
+    /// Media files found in the cache
+    pub media_cached: usize,
+    /// Media files requested from the server, because they weren't cached
+    pub media_requested: usize,
+    pub media_received: usize,
+    /// Node textures, counting each file (and animation) once. Loaded ones
+    /// are counted by the main thread, see ClientToMainEvent::NodeTexture.
+    pub textures_loaded: usize,
+    pub textures_total: usize,
+}
+
+impl LoadingProgress {
+    /// Returns how far the current stage is, from 0.0 to 1.0, or None if
+    /// that isn't known.
+    pub fn stage_fraction(&self) -> Option<f32> {
+        let (done, total) = match self.stage {
+            LoadingStage::DownloadingMedia => (self.media_received, self.media_requested),
+            _ => return None,
+        };
+        (total > 0).then(|| (done as f32 / total as f32).min(1.0))
+    }
+
+    /// Returns the lines describing the progress. `meshed` and `pending`
+    /// are the numbers of finished and unfinished mapblock meshes.
+    // Compare to Luanti, client/game.cpp, Game::getServerContent
+    pub fn lines(&self, meshed: usize, pending: usize) -> Vec<String> {
+        let stage = match self.stage {
+            LoadingStage::Connecting => "Waiting for the server...",
+            LoadingStage::LoggingIn => "Logging in...",
+            LoadingStage::Definitions => "Receiving definitions...",
+            LoadingStage::DownloadingMedia => "Downloading media...",
+            LoadingStage::LoadingTextures => "Loading textures...",
+            LoadingStage::Meshing => "Meshing mapblocks...",
+        };
+        let mut lines = vec![String::from(stage)];
+        if self.stage < LoadingStage::Definitions {
+            return lines;
+        }
+
+        let count = |defs: Option<usize>| match defs {
+            Some(count) => count.to_string(),
+            None => String::from("waiting"),
+        };
+        lines.push(format!(
+            "Node definitions: {}, item definitions: {}",
+            count(self.node_defs),
+            count(self.item_defs)
+        ));
+        if self.stage >= LoadingStage::DownloadingMedia {
+            lines.push(format!(
+                "Media: {} of {} downloaded, {} cached",
+                self.media_received, self.media_requested, self.media_cached
+            ));
+        }
+        if self.stage >= LoadingStage::Meshing {
+            lines.push(format!(
+                "Textures: {} of {} loaded",
+                self.textures_loaded, self.textures_total
+            ));
+            lines.push(format!("Mapblocks: {} meshed, {} pending", meshed, pending));
+        }
+        lines
+    }
+}
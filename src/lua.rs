@@ -1,12 +1,303 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, RwLock, mpsc};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, anyhow};
-use log::info;
-use mlua::Lua;
+use glam::{I16Vec3, Vec3};
+use log::{error, info};
+use luanti_core::MapNodePos;
+use mlua::{ChunkMode, Function, IntoLuaMulti, Lua, Table, Value, Variadic, VmState};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use winit::keyboard::KeyCode;
+
+use crate::camera_controller::PlayerControl;
+use crate::introspection;
+use crate::inventory::ItemStack;
+use crate::item_def::ItemDefManager;
+use crate::lua_storage::{LuaStorageRef, ScriptStorage};
+use crate::map::LuantiMap;
+use crate::media::MediaOrigin;
+use crate::node_def::NodeDefManager;
+use crate::pointing::{self, PointedThing};
+use crate::waypoints::Waypoints;
+use crate::wield::{WieldHand, WieldState};
+
+/// A function scheduled by `cubetonic.after` or `cubetonic.every`.
+struct Timer {
+    id: u64,
+    /// Seconds until the function is called next
+    remaining: f32,
+    /// Some for repeating timers
+    interval: Option<f32>,
+    func: Function,
+    args: Vec<Value>,
+}
+
+/// Stored as Lua app data so the API functions can access it.
+#[derive(Default)]
+struct Timers {
+    next_id: u64,
+    list: Vec<Timer>,
+}
+
+impl Timers {
+    fn add(
+        &mut self,
+        seconds: f32,
+        interval: Option<f32>,
+        func: Function,
+        args: Vec<Value>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Timer {
+            id,
+            remaining: seconds,
+            interval,
+            func,
+            args,
+        });
+        id
+    }
+
+    /// Advances all timers by `dtime`, re-arming repeating timers and removing
+    /// one-shot timers that have expired.
+    /// Returns the functions to call, together with their arguments.
+    fn advance(&mut self, dtime: f32) -> Vec<(Function, Vec<Value>)> {
+        let mut due = Vec::new();
+
+        self.list.retain_mut(|timer| {
+            timer.remaining -= dtime;
+            if timer.remaining > 0.0 {
+                return true;
+            }
+            due.push((timer.func.clone(), timer.args.clone()));
+
+            match timer.interval {
+                Some(interval) => {
+                    // Don't try to catch up if we're lagging behind
+                    timer.remaining = (timer.remaining + interval).max(0.0);
+                    true
+                }
+                None => false,
+            }
+        });
+
+        due
+    }
+}
+
+/// Events scripts can register functions for, with
+/// `cubetonic.register_<name>(func)`.
+// Compare to Luanti, builtin/client/register.lua
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Callback {
+    /// func(), once the player has joined and the world is shown
+    Connect,
+    /// func(message), returning true hides the message
+    ReceivingChatMessage,
+    /// func(message), returning true keeps the message from being sent
+    SendingChatMessage,
+    /// func(hp), returning true suppresses the damage effect
+    HpModification,
+    /// func(dtime), every frame
+    Globalstep,
+    /// func(), when the key is pressed in-game. Returning true keeps the
+    /// built-in action of the key from running.
+    Keypress(KeyCode),
+}
+
+impl Callback {
+    const ALL: [Callback; 5] = [
+        Callback::Connect,
+        Callback::ReceivingChatMessage,
+        Callback::SendingChatMessage,
+        Callback::HpModification,
+        Callback::Globalstep,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Callback::Connect => "on_connect",
+            Callback::ReceivingChatMessage => "on_receiving_chat_message",
+            Callback::SendingChatMessage => "on_sending_chat_message",
+            Callback::HpModification => "on_hp_modification",
+            Callback::Globalstep => "globalstep",
+            Callback::Keypress(_) => "on_keypress",
+        }
+    }
+}
+
+/// Key names for `cubetonic.register_on_keypress`, by their label on a US
+/// keyboard layout.
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("A", KeyCode::KeyA),
+    ("B", KeyCode::KeyB),
+    ("C", KeyCode::KeyC),
+    ("D", KeyCode::KeyD),
+    ("E", KeyCode::KeyE),
+    ("F", KeyCode::KeyF),
+    ("G", KeyCode::KeyG),
+    ("H", KeyCode::KeyH),
+    ("I", KeyCode::KeyI),
+    ("J", KeyCode::KeyJ),
+    ("K", KeyCode::KeyK),
+    ("L", KeyCode::KeyL),
+    ("M", KeyCode::KeyM),
+    ("N", KeyCode::KeyN),
+    ("O", KeyCode::KeyO),
+    ("P", KeyCode::KeyP),
+    ("Q", KeyCode::KeyQ),
+    ("R", KeyCode::KeyR),
+    ("S", KeyCode::KeyS),
+    ("T", KeyCode::KeyT),
+    ("U", KeyCode::KeyU),
+    ("V", KeyCode::KeyV),
+    ("W", KeyCode::KeyW),
+    ("X", KeyCode::KeyX),
+    ("Y", KeyCode::KeyY),
+    ("Z", KeyCode::KeyZ),
+    ("0", KeyCode::Digit0),
+    ("1", KeyCode::Digit1),
+    ("2", KeyCode::Digit2),
+    ("3", KeyCode::Digit3),
+    ("4", KeyCode::Digit4),
+    ("5", KeyCode::Digit5),
+    ("6", KeyCode::Digit6),
+    ("7", KeyCode::Digit7),
+    ("8", KeyCode::Digit8),
+    ("9", KeyCode::Digit9),
+    ("F1", KeyCode::F1),
+    ("F2", KeyCode::F2),
+    ("F3", KeyCode::F3),
+    ("F4", KeyCode::F4),
+    ("F5", KeyCode::F5),
+    ("F6", KeyCode::F6),
+    ("F7", KeyCode::F7),
+    ("F8", KeyCode::F8),
+    ("F9", KeyCode::F9),
+    ("F10", KeyCode::F10),
+    ("F11", KeyCode::F11),
+    ("F12", KeyCode::F12),
+];
+
+/// The registered functions, in registration order.
+/// Stored as Lua app data so the API functions can access it.
+#[derive(Default)]
+struct Callbacks(HashMap<Callback, Vec<Function>>);
+
+/// How `run_callbacks` treats the return values of the functions.
+// Compare to Luanti, builtin/common/register.lua, the RUN_CALLBACKS_MODE_*
+// constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    /// All functions are called, their return values are ignored
+    All,
+    /// The functions are called until one of them returns true
+    OrShortCircuit,
+}
+
+/// Calls the functions registered for `callback` in order. Returns whether
+/// one of them returned true, which stops the others with
+/// RunMode::OrShortCircuit.
+fn run_callbacks(
+    l: &Lua,
+    callback: Callback,
+    mode: RunMode,
+    args: impl IntoLuaMulti + Clone,
+) -> bool {
+    // The app data borrow must end before calling into Lua, as the
+    // functions may register more callbacks.
+    let funcs = l
+        .app_data_ref::<Callbacks>()
+        .unwrap()
+        .0
+        .get(&callback)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut handled = false;
+    for func in funcs {
+        match func.call::<Value>(args.clone()) {
+            Ok(Value::Boolean(true)) => {
+                handled = true;
+                if mode == RunMode::OrShortCircuit {
+                    break;
+                }
+            }
+            Ok(_) => (),
+            Err(err) => error!("Error in Lua {} callback: {err}", callback.name()),
+        }
+    }
+    handled
+}
+
+/// What scripts may do, see LuaController::new_state.
+/// Stored as Lua app data so the API functions can access it.
+struct Sandbox {
+    /// The parts of the standard library that were removed from the globals
+    insecure: Table,
+    /// See Settings::trusted_scripts
+    trusted_scripts: HashSet<String>,
+    /// The script whose main chunk is running, None after loading
+    loading_script: Option<String>,
+    /// Scripts are stopped with an error if they are still running then
+    deadline: Instant,
+    /// How long scripts may run each time they are called
+    time_limit: Duration,
+}
+
+/// Storages opened by `cubetonic.get_storage`, by namespace.
+/// Stored as Lua app data so the API functions can access it.
+#[derive(Default)]
+struct Storages(HashMap<String, Rc<RefCell<ScriptStorage>>>);
+
+/// Game state the API functions can query, kept up to date by the main thread.
+/// Stored as Lua app data. The server scripts' Lua state has its own copy.
+#[derive(Clone)]
+struct GameData {
+    map: Arc<RwLock<LuantiMap>>,
+    node_def: Option<Arc<NodeDefManager>>,
+    item_def: Option<Arc<ItemDefManager>>,
+    media_origins: HashMap<String, MediaOrigin>,
+    camera_pos: Vec3,
+    camera_dir: Vec3,
+    /// Written by `cubetonic.set_wielded_item`, read by the main thread
+    wield: WieldState,
+    /// Shared with the main thread
+    waypoints: Rc<RefCell<Waypoints>>,
+    /// The held controls, updated every frame
+    control: PlayerControl,
+    /// Written by `cubetonic.send_chat_message`, sent by the main thread
+    sent_chat_messages: Vec<String>,
+    /// Written by `cubetonic.display_chat_message`, shown by the main thread
+    displayed_chat_messages: Vec<String>,
+}
+
+/// Converts a Lua position table `{x = ..., y = ..., z = ...}` to a node position.
+fn table_to_node_pos(table: &Table) -> mlua::Result<MapNodePos> {
+    Ok(MapNodePos(I16Vec3::new(
+        table.get("x")?,
+        table.get("y")?,
+        table.get("z")?,
+    )))
+}
 
 pub struct LuaController {
     base_dir: PathBuf,
+    /// Kept for reloading, see Settings::trusted_scripts
+    trusted_scripts: HashSet<String>,
     l: Lua,
+    /// Runs the scripts sent by the server, see Settings::server_scripts.
+    /// Created when the first one arrives.
+    server_l: Option<Lua>,
+    /// Watches `base_dir` for changed scripts, None if that failed. Only
+    /// kept alive, the changes arrive through `script_changes`.
+    _watcher: Option<RecommendedWatcher>,
+    script_changes: mpsc::Receiver<notify::Result<notify::Event>>,
 }
 
 impl LuaController {
@@ -31,13 +322,733 @@ impl LuaController {
         }
     }
 
-    pub fn new() -> anyhow::Result<Self> {
-        let base_dir = Self::get_base_dir()?;
+    /// The main script, without the extension. Also its storage namespace.
+    const MAIN_SCRIPT: &str = "init";
+    /// Globals only trusted scripts get, through
+    /// `cubetonic.request_insecure_environment`. They give access to the
+    /// system, or to the internals of other functions.
+    // Compare to Luanti, script/cpp_api/s_security.cpp, ScriptApiSecurity::initializeSecurity
+    const INSECURE_GLOBALS: [&str; 8] = [
+        "os",
+        "io",
+        "package",
+        "require",
+        "debug",
+        "getfenv",
+        "setfenv",
+        "loadstring",
+    ];
+    /// Memory scripts may allocate, in bytes
+    const MEMORY_LIMIT: usize = 128 * 1024 * 1024;
+    /// How long scripts may run each time they are called, e.g. for loading
+    /// or once per frame
+    const TIME_LIMIT: Duration = Duration::from_secs(1);
+    /// Like TIME_LIMIT, for server scripts. Much shorter, so they can't
+    /// slow down every frame.
+    const SERVER_TIME_LIMIT: Duration = Duration::from_millis(5);
+    /// The `cubetonic` functions server scripts can use. They can't touch
+    /// the disk, send chat messages in the player's name or change what the
+    /// player sees in their hands.
+    // Compare to Luanti, the csm_restriction_flags setting
+    const SERVER_SCRIPT_API: [&str; 9] = [
+        "register_on_receiving_chat_message",
+        "register_on_hp_modification",
+        "register_globalstep",
+        "get_player_control",
+        "display_chat_message",
+        "after",
+        "every",
+        "cancel",
+        "nodeinfo",
+    ];
+    /// Maximum distance for finding the pointed node in `cubetonic.nodeinfo`.
+    const NODEINFO_DISTANCE: f32 = 10.0;
+
+    /// Registers the `cubetonic` namespace table and its functions.
+    fn register_api(l: &Lua) -> mlua::Result<()> {
+        let api = l.create_table()?;
+
+        l.set_app_data(Timers::default());
+        l.set_app_data(Storages::default());
+        l.set_app_data(Callbacks::default());
+
+        // cubetonic.request_insecure_environment()
+        // Returns a table with the globals removed by the sandbox, or nil.
+        // Only works for the scripts in Settings::trusted_scripts, while
+        // their main chunk runs.
+        api.set(
+            "request_insecure_environment",
+            l.create_function(|l, ()| {
+                let sandbox = l.app_data_ref::<Sandbox>().unwrap();
+                Ok(sandbox
+                    .loading_script
+                    .as_ref()
+                    .filter(|script| sandbox.trusted_scripts.contains(*script))
+                    .map(|_| sandbox.insecure.clone()))
+            })?,
+        )?;
+
+        // cubetonic.register_<name>(func)
+        // See Callback for the names and the arguments of `func`.
+        for callback in Callback::ALL {
+            api.set(
+                format!("register_{}", callback.name()),
+                l.create_function(move |l, func: Function| {
+                    let mut callbacks = l.app_data_mut::<Callbacks>().unwrap();
+                    callbacks.0.entry(callback).or_default().push(func);
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        // cubetonic.register_on_keypress(key, func)
+        // Calls `func` when `key` is pressed, e.g. "G", "7" or "F2".
+        api.set(
+            "register_on_keypress",
+            l.create_function(|l, (key, func): (String, Function)| {
+                let Some(&(_, keycode)) = KEY_NAMES
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(&key))
+                else {
+                    return Err(mlua::Error::runtime(format!("unknown key \"{}\"", key)));
+                };
+                let mut callbacks = l.app_data_mut::<Callbacks>().unwrap();
+                callbacks
+                    .0
+                    .entry(Callback::Keypress(keycode))
+                    .or_default()
+                    .push(func);
+                Ok(())
+            })?,
+        )?;
+
+        // cubetonic.get_player_control()
+        // Returns a table of the held controls, with the same keys as
+        // Luanti's get_player_control.
+        api.set(
+            "get_player_control",
+            l.create_function(|l, ()| {
+                let control = l.app_data_ref::<GameData>().unwrap().control;
+                let result = l.create_table()?;
+                result.set("up", control.up)?;
+                result.set("down", control.down)?;
+                result.set("left", control.left)?;
+                result.set("right", control.right)?;
+                result.set("jump", control.jump)?;
+                result.set("sneak", control.sneak)?;
+                result.set("zoom", control.zoom)?;
+                result.set("dig", control.dig)?;
+                result.set("place", control.place)?;
+                Ok(result)
+            })?,
+        )?;
+
+        // cubetonic.send_chat_message(message)
+        // Sends a chat message to the server, unless an
+        // on_sending_chat_message callback returns true.
+        api.set(
+            "send_chat_message",
+            l.create_function(|l, message: String| {
+                if run_callbacks(
+                    l,
+                    Callback::SendingChatMessage,
+                    RunMode::OrShortCircuit,
+                    message.clone(),
+                ) {
+                    return Ok(());
+                }
+                let mut data = l.app_data_mut::<GameData>().unwrap();
+                data.sent_chat_messages.push(message);
+                Ok(())
+            })?,
+        )?;
+
+        // cubetonic.display_chat_message(message)
+        // Shows a message in the chat without sending it.
+        api.set(
+            "display_chat_message",
+            l.create_function(|l, message: String| {
+                let mut data = l.app_data_mut::<GameData>().unwrap();
+                data.displayed_chat_messages.push(message);
+                Ok(())
+            })?,
+        )?;
+
+        // cubetonic.after(seconds, func, ...)
+        // Calls `func` with the remaining arguments once after `seconds`.
+        api.set(
+            "after",
+            l.create_function(
+                |l, (seconds, func, args): (f32, Function, Variadic<Value>)| {
+                    let mut timers = l.app_data_mut::<Timers>().unwrap();
+                    Ok(timers.add(seconds, None, func, args.into_iter().collect()))
+                },
+            )?,
+        )?;
+
+        // cubetonic.every(seconds, func, ...)
+        // Calls `func` with the remaining arguments every `seconds`.
+        api.set(
+            "every",
+            l.create_function(
+                |l, (seconds, func, args): (f32, Function, Variadic<Value>)| {
+                    if seconds <= 0.0 {
+                        return Err(mlua::Error::runtime("interval must be positive"));
+                    }
+                    let mut timers = l.app_data_mut::<Timers>().unwrap();
+                    Ok(timers.add(seconds, Some(seconds), func, args.into_iter().collect()))
+                },
+            )?,
+        )?;
+
+        // cubetonic.cancel(id)
+        // Cancels a timer returned by `after` or `every`.
+        api.set(
+            "cancel",
+            l.create_function(|l, id: u64| {
+                let mut timers = l.app_data_mut::<Timers>().unwrap();
+                timers.list.retain(|timer| timer.id != id);
+                Ok(())
+            })?,
+        )?;
+
+        // cubetonic.get_storage([namespace])
+        // Returns a persistent key-value store. The namespace defaults to the
+        // main script's namespace.
+        api.set(
+            "get_storage",
+            l.create_function(|l, namespace: Option<String>| {
+                let namespace = namespace.unwrap_or_else(|| String::from(Self::MAIN_SCRIPT));
+                // The namespace is used as a file name
+                if namespace.is_empty()
+                    || !namespace
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    return Err(mlua::Error::runtime("invalid storage namespace"));
+                }
+
+                let mut storages = l.app_data_mut::<Storages>().unwrap();
+                if let Some(storage) = storages.0.get(&namespace) {
+                    return Ok(LuaStorageRef(storage.clone()));
+                }
+                let storage = ScriptStorage::load(&namespace).map_err(mlua::Error::external)?;
+                let storage = Rc::new(RefCell::new(storage));
+                storages.0.insert(namespace, storage.clone());
+                Ok(LuaStorageRef(storage))
+            })?,
+        )?;
+
+        // cubetonic.nodeinfo([pos])
+        // Returns a dump of the node at `pos` or the pointed node, including
+        // its ContentFeatures.
+        api.set(
+            "nodeinfo",
+            l.create_function(|l, pos: Option<Table>| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                let Some(node_def) = &data.node_def else {
+                    return Ok(String::from("Node definitions not received yet"));
+                };
+                let map = data.map.read().unwrap();
+
+                if let Some(pos) = pos {
+                    let pos = table_to_node_pos(&pos)?;
+                    return Ok(introspection::describe_node(&map, node_def, pos));
+                }
+                match pointing::raycast(
+                    &map,
+                    node_def,
+                    data.camera_pos,
+                    data.camera_dir,
+                    Self::NODEINFO_DISTANCE,
+                ) {
+                    PointedThing::Node {
+                        under,
+                        above,
+                        intersection,
+                        normal,
+                    } => Ok(format!(
+                        "{}\nPointed at {} from {} (normal {})",
+                        introspection::describe_node(&map, node_def, under),
+                        intersection,
+                        above.0,
+                        normal
+                    )),
+                    PointedThing::Nothing | PointedThing::Object { .. } => {
+                        Ok(String::from("Not pointing at a node"))
+                    }
+                }
+            })?,
+        )?;
+
+        // cubetonic.iteminfo(name)
+        // Returns a dump of the item definition, e.g. for "default:torch".
+        api.set(
+            "iteminfo",
+            l.create_function(|l, name: String| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                let Some(item_def) = &data.item_def else {
+                    return Ok(String::from("Item definitions not received yet"));
+                };
+                Ok(introspection::describe_item(item_def, &name))
+            })?,
+        )?;
+
+        // cubetonic.texinfo(name)
+        // Returns where the texture was resolved from and which nodes use it.
+        api.set(
+            "texinfo",
+            l.create_function(|l, name: String| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                Ok(introspection::describe_texture(
+                    &name,
+                    data.node_def.as_deref(),
+                    &data.media_origins,
+                ))
+            })?,
+        )?;
+
+        // cubetonic.get_wielded_item(hand)
+        // Returns the item string wielded in `hand` ("main" or "offhand"),
+        // or nil if the hand is empty.
+        api.set(
+            "get_wielded_item",
+            l.create_function(|l, hand: String| {
+                let hand: WieldHand = hand.parse().map_err(mlua::Error::external)?;
+                let data = l.app_data_ref::<GameData>().unwrap();
+                Ok(data.wield.slot(hand).item.clone())
+            })?,
+        )?;
+
+        // cubetonic.set_wielded_item(hand, item)
+        // Sets the item string wielded in `hand`, nil or "" empties the hand.
+        // Only the offhand can be set, the main hand follows the server.
+        api.set(
+            "set_wielded_item",
+            l.create_function(|l, (hand, item): (String, Option<String>)| {
+                let hand: WieldHand = hand.parse().map_err(mlua::Error::external)?;
+                if hand != WieldHand::Offhand {
+                    return Err(mlua::Error::runtime(format!("cannot set {} hand", hand)));
+                }
+                let item = item.filter(|item| !item.is_empty());
+                if let Some(item) = &item {
+                    ItemStack::parse(item).map_err(mlua::Error::external)?;
+                }
+                let mut data = l.app_data_mut::<GameData>().unwrap();
+                data.wield.slot_mut(hand).item = item;
+                Ok(())
+            })?,
+        )?;
+
+        // cubetonic.add_waypoint(name, [pos])
+        // Adds a waypoint at `pos` or the camera position, replacing an
+        // existing waypoint with the same name.
+        api.set(
+            "add_waypoint",
+            l.create_function(|l, (name, pos): (String, Option<Table>)| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                let pos = match pos {
+                    Some(pos) => Vec3::new(pos.get("x")?, pos.get("y")?, pos.get("z")?),
+                    None => data.camera_pos,
+                };
+                data.waypoints
+                    .borrow_mut()
+                    .set(name, pos)
+                    .map_err(mlua::Error::external)
+            })?,
+        )?;
+
+        // cubetonic.remove_waypoint(name)
+        // Returns false if there was no waypoint with this name.
+        api.set(
+            "remove_waypoint",
+            l.create_function(|l, name: String| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                data.waypoints
+                    .borrow_mut()
+                    .remove(&name)
+                    .map_err(mlua::Error::external)
+            })?,
+        )?;
+
+        // cubetonic.get_waypoints()
+        // Returns a table of waypoint names to positions.
+        api.set(
+            "get_waypoints",
+            l.create_function(|l, ()| {
+                let data = l.app_data_ref::<GameData>().unwrap();
+                let result = l.create_table()?;
+                for (name, pos) in data.waypoints.borrow().iter() {
+                    let pos_table = l.create_table()?;
+                    pos_table.set("x", pos.x)?;
+                    pos_table.set("y", pos.y)?;
+                    pos_table.set("z", pos.z)?;
+                    result.set(name.as_str(), pos_table)?;
+                }
+                Ok(result)
+            })?,
+        )?;
+
+        l.globals().set("cubetonic", api)?;
+        Ok(())
+    }
+
+    /// Creates a Lua state with the standard library, except for the
+    /// INSECURE_GLOBALS. The memory and time scripts can use are limited.
+    fn new_state(trusted_scripts: HashSet<String>, time_limit: Duration) -> mlua::Result<Lua> {
         let l = Lua::new();
+        l.set_memory_limit(Self::MEMORY_LIMIT)?;
+
+        let globals = l.globals();
+        let insecure = l.create_table()?;
+        for name in Self::INSECURE_GLOBALS {
+            insecure.set(name, globals.get::<Value>(name)?)?;
+            globals.set(name, Value::Nil)?;
+        }
+        l.set_app_data(Sandbox {
+            insecure,
+            trusted_scripts,
+            loading_script: None,
+            deadline: Instant::now(),
+            time_limit,
+        });
+
+        // Called regularly while scripts run, e.g. in loops
+        l.set_interrupt(|l| {
+            if Instant::now() > l.app_data_ref::<Sandbox>().unwrap().deadline {
+                return Err(mlua::Error::runtime("script ran for too long"));
+            }
+            Ok(VmState::Continue)
+        });
+
+        Ok(l)
+    }
 
-        let chunk = l.load(base_dir.join("init.lua"));
-        chunk.exec().with_context(|| "Failed to load main script")?;
+    /// Gives the scripts of a Lua state their time limit from now. Called
+    /// before calling into Lua from the main thread.
+    fn start_time_limit(l: &Lua) {
+        let mut sandbox = l.app_data_mut::<Sandbox>().unwrap();
+        sandbox.deadline = Instant::now() + sandbox.time_limit;
+    }
+
+    /// The Lua state of the local scripts, then the one of the server
+    /// scripts if there is one.
+    fn states(&self) -> impl Iterator<Item = &Lua> {
+        std::iter::once(&self.l).chain(&self.server_l)
+    }
+
+    /// Changes the game data of all Lua states.
+    fn update_data(&mut self, mut f: impl FnMut(&mut GameData)) {
+        for l in self.states() {
+            f(&mut l.app_data_mut::<GameData>().unwrap());
+        }
+    }
+
+    /// `trusted_scripts` may request the insecure environment, see
+    /// Settings::trusted_scripts.
+    pub fn new(
+        map: Arc<RwLock<LuantiMap>>,
+        waypoints: Rc<RefCell<Waypoints>>,
+        trusted_scripts: HashSet<String>,
+    ) -> anyhow::Result<Self> {
+        let base_dir = Self::get_base_dir()?;
+        let l = Self::new_state(trusted_scripts.clone(), Self::TIME_LIMIT)?;
+
+        l.set_app_data(GameData {
+            map,
+            node_def: None,
+            item_def: None,
+            media_origins: HashMap::new(),
+            camera_pos: Vec3::ZERO,
+            camera_dir: Vec3::Z,
+            wield: WieldState::default(),
+            waypoints,
+            control: PlayerControl::default(),
+            sent_chat_messages: Vec::new(),
+            displayed_chat_messages: Vec::new(),
+        });
+
+        Self::load_scripts(&l, &base_dir)?;
+
+        let (tx, script_changes) = mpsc::channel();
+        let watcher = notify::recommended_watcher(tx).and_then(|mut watcher| {
+            watcher.watch(&base_dir, RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                error!("Failed to watch {base_dir:?}, scripts won't be reloaded: {err}");
+                None
+            }
+        };
+
+        Ok(Self {
+            base_dir,
+            trusted_scripts,
+            l,
+            server_l: None,
+            _watcher: watcher,
+            script_changes,
+        })
+    }
+
+    /// Registers the API in a new Lua state and runs the main script.
+    fn load_scripts(l: &Lua, base_dir: &Path) -> anyhow::Result<()> {
+        Self::register_api(l).with_context(|| "Failed to register Lua API")?;
+
+        let mut sandbox = l.app_data_mut::<Sandbox>().unwrap();
+        sandbox.loading_script = Some(String::from(Self::MAIN_SCRIPT));
+        drop(sandbox);
+        Self::start_time_limit(l);
+
+        let chunk = l.load(base_dir.join(format!("{}.lua", Self::MAIN_SCRIPT)));
+        let result = chunk.exec();
+        l.app_data_mut::<Sandbox>().unwrap().loading_script = None;
+        result.with_context(|| "Failed to load main script")?;
+        Ok(())
+    }
+
+    /// Whether any script was changed since the last call.
+    fn scripts_changed(&self) -> bool {
+        let mut changed = false;
+        while let Ok(event) = self.script_changes.try_recv() {
+            match event {
+                // Editors also write temporary files next to the script
+                Ok(event) => {
+                    changed |= !matches!(event.kind, EventKind::Access(_))
+                        && event
+                            .paths
+                            .iter()
+                            .any(|path| path.extension().is_some_and(|ext| ext == "lua"));
+                }
+                Err(err) => error!("Error while watching scripts: {err}"),
+            }
+        }
+        changed
+    }
+
+    /// Runs the scripts again in a new Lua state, which drops all callbacks
+    /// and timers registered by the old scripts. The game data carries
+    /// over. If the new scripts fail to load, the old ones keep running.
+    /// If the player has joined already, the on_connect callbacks of the
+    /// new scripts are run, as they missed the real connect.
+    fn reload_scripts(&mut self, in_game: bool) {
+        // The new scripts may open the same storages
+        self.save_storages();
+
+        let l = match Self::new_state(self.trusted_scripts.clone(), Self::TIME_LIMIT) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("Failed to reload scripts, keeping the old ones: {err:?}");
+                return;
+            }
+        };
+        let data = self.l.remove_app_data::<GameData>().unwrap();
+        l.set_app_data(data);
+        match Self::load_scripts(&l, &self.base_dir) {
+            Ok(()) => {
+                info!("Reloaded scripts");
+                self.l = l;
+                if in_game {
+                    self.on_connect();
+                }
+            }
+            Err(err) => {
+                error!("Failed to reload scripts, keeping the old ones: {err:?}");
+                let data = l.remove_app_data::<GameData>().unwrap();
+                self.l.set_app_data(data);
+            }
+        }
+    }
+
+    fn save_storages(&self) {
+        for (namespace, storage) in &self.l.app_data_ref::<Storages>().unwrap().0 {
+            if let Err(err) = storage.borrow_mut().save_if_dirty() {
+                error!("Failed to save storage \"{namespace}\": {err:?}");
+            }
+        }
+    }
+
+    /// Creates the Lua state for server scripts, with the local game data
+    /// and only the SERVER_SCRIPT_API.
+    fn new_server_state(&self) -> anyhow::Result<Lua> {
+        // No script is trusted
+        let l = Self::new_state(HashSet::new(), Self::SERVER_TIME_LIMIT)?;
+        let mut data = self.l.app_data_ref::<GameData>().unwrap().clone();
+        data.sent_chat_messages.clear();
+        data.displayed_chat_messages.clear();
+        l.set_app_data(data);
+        Self::register_api(&l).with_context(|| "Failed to register Lua API")?;
+
+        let api: Table = l.globals().get("cubetonic")?;
+        let mut removed = Vec::new();
+        for pair in api.pairs::<String, Value>() {
+            let (name, _) = pair?;
+            if !Self::SERVER_SCRIPT_API.contains(&name.as_str()) {
+                removed.push(name);
+            }
+        }
+        for name in removed {
+            api.set(name, Value::Nil)?;
+        }
+        Ok(l)
+    }
+
+    /// Runs a script sent by the server. The message is the name of the
+    /// script, a newline, then its code. Scripts run in the order they are
+    /// received, all in the same Lua state.
+    pub fn run_server_script(&mut self, message: &str) {
+        let Some((name, code)) = message.split_once('\n') else {
+            error!("Received server script without a name");
+            return;
+        };
+        if self.server_l.is_none() {
+            match self.new_server_state() {
+                Ok(l) => self.server_l = Some(l),
+                Err(err) => {
+                    error!("Failed to create Lua state for server scripts: {err:?}");
+                    return;
+                }
+            }
+        }
+        let l = self.server_l.as_ref().unwrap();
+
+        Self::start_time_limit(l);
+        // "=" makes Lua use the name as is in error messages. Only source
+        // code is accepted, Luau doesn't verify bytecode.
+        let chunk = l
+            .load(code)
+            .set_name(format!("=server:{name}"))
+            .set_mode(ChunkMode::Text);
+        match chunk.exec() {
+            Ok(()) => info!("Loaded server script \"{name}\""),
+            Err(err) => error!("Failed to load server script \"{name}\": {err}"),
+        }
+    }
+
+    /// Drops the server scripts with their callbacks and timers, after
+    /// leaving the server.
+    pub fn clear_server_scripts(&mut self) {
+        self.server_l = None;
+    }
+
+    pub fn set_node_def(&mut self, node_def: Arc<NodeDefManager>) {
+        self.update_data(|data| data.node_def = Some(node_def.clone()));
+    }
+
+    pub fn set_item_def(&mut self, item_def: Arc<ItemDefManager>) {
+        self.update_data(|data| data.item_def = Some(item_def.clone()));
+    }
+
+    pub fn set_media_origins(&mut self, origins: HashMap<String, MediaOrigin>) {
+        self.update_data(|data| data.media_origins = origins.clone());
+    }
+
+    pub fn set_camera(&mut self, pos: Vec3, dir: Vec3) {
+        self.update_data(|data| {
+            data.camera_pos = pos;
+            data.camera_dir = dir;
+        });
+    }
+
+    pub fn set_player_control(&mut self, control: PlayerControl) {
+        self.update_data(|data| data.control = control);
+    }
+
+    /// The wielded items, including those set by scripts.
+    pub fn wield(&self) -> WieldState {
+        self.l.app_data_ref::<GameData>().unwrap().wield.clone()
+    }
+
+    pub fn set_wielded_item(&mut self, hand: WieldHand, item: Option<String>) {
+        let mut data = self.l.app_data_mut::<GameData>().unwrap();
+        data.wield.slot_mut(hand).item = item;
+    }
+
+    /// Runs the on_connect callbacks, once the player has joined.
+    pub fn on_connect(&mut self) {
+        Self::start_time_limit(&self.l);
+        run_callbacks(&self.l, Callback::Connect, RunMode::All, ());
+    }
+
+    /// Runs the on_receiving_chat_message callbacks, those of the local
+    /// scripts first. Returns true if the message shouldn't be shown.
+    pub fn on_receiving_chat_message(&mut self, message: &str) -> bool {
+        self.states().any(|l| {
+            Self::start_time_limit(l);
+            run_callbacks(
+                l,
+                Callback::ReceivingChatMessage,
+                RunMode::OrShortCircuit,
+                message.to_owned(),
+            )
+        })
+    }
+
+    /// Runs the on_keypress callbacks of a key. Returns true if the key
+    /// shouldn't do anything else.
+    pub fn on_keypress(&mut self, keycode: KeyCode) -> bool {
+        Self::start_time_limit(&self.l);
+        run_callbacks(
+            &self.l,
+            Callback::Keypress(keycode),
+            RunMode::OrShortCircuit,
+            (),
+        )
+    }
+
+    /// Runs the on_hp_modification callbacks, those of the local scripts
+    /// first. Returns true if the damage effect shouldn't be shown.
+    pub fn on_hp_modification(&mut self, hp: u16) -> bool {
+        self.states().any(|l| {
+            Self::start_time_limit(l);
+            run_callbacks(l, Callback::HpModification, RunMode::OrShortCircuit, hp)
+        })
+    }
+
+    /// Chat messages scripts sent since the last call.
+    pub fn take_sent_chat_messages(&mut self) -> Vec<String> {
+        std::mem::take(
+            &mut self
+                .l
+                .app_data_mut::<GameData>()
+                .unwrap()
+                .sent_chat_messages,
+        )
+    }
+
+    /// Chat messages scripts displayed since the last call.
+    pub fn take_displayed_chat_messages(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        self.update_data(|data| messages.append(&mut data.displayed_chat_messages));
+        messages
+    }
+
+    /// Reloads changed scripts, runs the globalsteps and scheduled timers,
+    /// then saves modified storages. Called once per frame. `in_game` is
+    /// whether the player has joined, see `reload_scripts`.
+    pub fn step(&mut self, dtime: f32, in_game: bool) {
+        if self.scripts_changed() {
+            self.reload_scripts(in_game);
+        }
+
+        for l in self.states() {
+            // Each Lua state has its own budget per frame
+            Self::start_time_limit(l);
+            run_callbacks(l, Callback::Globalstep, RunMode::All, dtime);
+
+            // The app data borrow must end before calling into Lua, as the
+            // callbacks may schedule new timers.
+            let due = l.app_data_mut::<Timers>().unwrap().advance(dtime);
+
+            for (func, args) in due {
+                if let Err(err) = func.call::<()>(Variadic::from_iter(args)) {
+                    error!("Error in Lua timer callback: {err}");
+                }
+            }
+        }
 
-        Ok(Self { base_dir, l })
+        self.save_storages();
     }
 }
@@ -0,0 +1,115 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use anyhow::Context;
+use mlua::{UserData, UserDataMethods};
+
+use crate::settings::Settings;
+
+/// A persistent key-value store for a single script namespace, saved as a
+/// JSON object in the `storage` subdirectory of the config directory.
+/// Like Luanti's mod storage, keys and values are strings.
+pub struct ScriptStorage {
+    path: PathBuf,
+    data: HashMap<String, String>,
+    dirty: bool,
+}
+
+impl ScriptStorage {
+    /// Loads the storage for the given namespace.
+    /// Returns an empty storage if nothing has been saved yet.
+    pub fn load(namespace: &str) -> anyhow::Result<Self> {
+        let mut path = Settings::config_dir()?;
+        path.push("storage");
+        fs::create_dir_all(&path)?;
+        path.push(format!("{}.json", namespace));
+
+        let data = if path.try_exists()? {
+            let text = fs::read_to_string(&path)?;
+            serde_json::from_str(&text).with_context(|| format!("Invalid storage {:?}", path))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            path,
+            data,
+            dirty: false,
+        })
+    }
+
+    /// Writes the storage to disk if it has been modified since the last save.
+    pub fn save_if_dirty(&mut self) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.data)?)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<&String> {
+        self.data.get(key)
+    }
+
+    /// Setting a key to the empty string removes it.
+    fn set(&mut self, key: String, value: String) {
+        if value.is_empty() {
+            self.data.remove(&key);
+        } else {
+            self.data.insert(key, value);
+        }
+        self.dirty = true;
+    }
+}
+
+/// Lua handle to a ScriptStorage, returned by `cubetonic.get_storage()`.
+/// The method names follow Luanti's StorageRef.
+pub struct LuaStorageRef(pub Rc<RefCell<ScriptStorage>>);
+
+impl UserData for LuaStorageRef {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get_string", |_, this, key: String| {
+            Ok(this.0.borrow().get(&key).cloned().unwrap_or_default())
+        });
+        methods.add_method("set_string", |_, this, (key, value): (String, String)| {
+            this.0.borrow_mut().set(key, value);
+            Ok(())
+        });
+
+        methods.add_method("get_int", |_, this, key: String| {
+            let storage = this.0.borrow();
+            Ok(storage
+                .get(&key)
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or(0))
+        });
+        methods.add_method("set_int", |_, this, (key, value): (String, i64)| {
+            this.0.borrow_mut().set(key, value.to_string());
+            Ok(())
+        });
+
+        methods.add_method("get_float", |_, this, key: String| {
+            let storage = this.0.borrow();
+            Ok(storage
+                .get(&key)
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap_or(0.0))
+        });
+        methods.add_method("set_float", |_, this, (key, value): (String, f64)| {
+            this.0.borrow_mut().set(key, value.to_string());
+            Ok(())
+        });
+
+        methods.add_method("contains", |_, this, key: String| {
+            Ok(this.0.borrow().get(&key).is_some())
+        });
+        methods.add_method("get_keys", |_, this, ()| {
+            Ok(this.0.borrow().data.keys().cloned().collect::<Vec<_>>())
+        });
+        methods.add_method("to_table", |_, this, ()| Ok(this.0.borrow().data.clone()));
+    }
+}
@@ -1,35 +1,371 @@
+use std::collections::{HashMap, HashSet};
 use std::f32::consts::PI;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
-use glam::Vec3;
+use glam::{I16Vec3, Vec2, Vec3, Vec4};
 use luanti_core::{ContentId, MapBlockNodes, MapBlockPos, MapNode, MapNodePos};
 use luanti_protocol::LuantiClient;
 use luanti_protocol::commands::client_to_server::{
-    ClientReadySpec, FirstSrpSpec, GotBlocksSpec, Init2Spec, InitSpec, PlayerPosCommand,
-    RequestMediaSpec, ToServerCommand,
+    ChatMessageSpec, ClientReadySpec, FirstSrpSpec, GotBlocksSpec, Init2Spec, InitSpec,
+    InteractSpec, InventoryActionSpec, InventoryFieldsSpec, ModchannelJoinSpec,
+    ModchannelLeaveSpec, NodemetaFieldsSpec, PlayerItemSpec, PlayerPosCommand, RemovedSoundsSpec,
+    RequestMediaSpec, SrpBytesASpec, SrpBytesMSpec, ToServerCommand,
 };
 use luanti_protocol::commands::server_to_client::ToClientCommand;
-use rand::Rng;
+use luanti_protocol::types::{
+    AccessDeniedCode, ActiveObjectCommand, HudSetParam, HudStat, InteractAction, ProtocolContext,
+};
+use luanti_protocol::wire::ser::{MockSerializer, Serialize};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 
-use crate::camera_controller::PlayerPos;
+use crate::active_object::ActiveObject;
+use crate::audio::{SoundLocation, SoundSpec};
+use crate::camera_controller::{CameraMode, EyeOffsets, MovementSettings, PlayerPos};
+use crate::clouds::CloudParams;
+use crate::formspec;
+use crate::hud::HudElement;
+use crate::inventory::Inventory;
+use crate::item_def::ItemDefManager;
+use crate::light;
+use crate::loading::{LoadingProgress, LoadingStage};
 use crate::map::{LuantiMap, NEIGHBOR_DIRS};
-use crate::media::{MediaManager, NodeTextureData};
+use crate::media::{
+    FallbackTexture, MediaIssue, MediaManager, MediaOrigin, NodeTextureData, NodeTextureUpdate,
+    TextureFiltering,
+};
 use crate::meshgen::{MapblockMesh, Meshgen};
 use crate::node_def::NodeDefManager;
+use crate::node_metadata::NodeMetadata;
+use crate::pointing::PointedThing;
+use crate::settings::Settings;
+use crate::sky::{MoonParams, SkyRenderer, StarParams, SunParams};
+use crate::srgb_to_linear;
+use crate::srp::{self, SrpClient};
 
 // Luanti's "BS" factor
-const BS: f32 = 10.0;
+pub const BS: f32 = 10.0;
+
+/// Luanti's NETPROTO_COMPRESSION_NONE. This is the only network compression
+/// mode Luanti defines, the large payloads (mapblocks, nodedef) are always
+/// compressed with zlib/zstd as part of their serialization instead, which
+/// luanti-protocol takes care of.
+const NETPROTO_COMPRESSION_NONE: u16 = 0;
+/// The mod channel servers send scripts on, see Settings::server_scripts
+const SERVER_SCRIPT_CHANNEL: &str = "cubetonic:scripts";
+
+/// Where to connect to and who to log in as.
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    /// A hostname or an IP address, resolved by the client thread
+    pub address: String,
+    pub port: u16,
+    /// Player name, a random one is picked if empty
+    pub name: String,
+    pub password: String,
+}
+
+impl ConnectionConfig {
+    /// Builds the config from the `address`, `port`, `name` and `password`
+    /// settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            address: settings.address.clone(),
+            port: settings.port,
+            name: settings.name.clone(),
+            password: settings.password.clone(),
+        }
+    }
+
+    /// Resolves the address if it's a hostname. The lookup may take a while,
+    /// so it's done by the client thread instead of blocking the event loop.
+    async fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        tokio::net::lookup_host((self.address.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve \"{}\"", self.address))
+    }
+}
+
+/// Settings for the client thread that don't depend on the server.
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// See Settings::deterministic
+    pub deterministic: bool,
+    /// See Settings::greedy_meshing
+    pub greedy_meshing: bool,
+    /// See Settings::retain_meshes
+    pub retain_meshes: bool,
+    /// See Settings::texture_pack
+    pub texture_pack: Option<PathBuf>,
+    pub fallback_texture: FallbackTexture,
+    /// Changed by the main thread when cycling through the filters, so the
+    /// next connection starts with the current filter
+    pub texture_filtering: TextureFiltering,
+    /// See Settings::texture_min_size
+    pub texture_min_size: u32,
+    /// See Settings::server_scripts
+    pub server_scripts: bool,
+}
+
+impl ClientOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            deterministic: settings.deterministic,
+            greedy_meshing: settings.greedy_meshing,
+            retain_meshes: settings.retain_meshes,
+            texture_pack: settings.texture_pack.clone(),
+            fallback_texture: FallbackTexture {
+                size: settings.fallback_texture_size,
+                color: settings.fallback_texture_color,
+            },
+            texture_filtering: TextureFiltering {
+                filter: settings.texture_filter,
+                anisotropy: settings.anisotropic_filter,
+            },
+            texture_min_size: settings.texture_min_size,
+            server_scripts: settings.server_scripts,
+        }
+    }
+}
 
 pub enum ClientToMainEvent {
     PlayerPos(PlayerPos),
     MapblockTextureData(NodeTextureData),
+    /// A node texture was loaded in the background, see
+    /// Meshgen::load_textures
+    NodeTexture(Result<NodeTextureUpdate, MediaIssue>),
     MapblockMesh(MapblockMesh),
+    NodeDef(Arc<NodeDefManager>),
+    ItemDef(Arc<ItemDefManager>),
+    /// Counter of unfinished meshgen tasks, see Meshgen::pending_tasks
+    MeshgenPendingTasks(Arc<AtomicUsize>),
+    MediaOrigins(HashMap<String, MediaOrigin>),
+    /// Problems with media files, for the loading report
+    MediaIssues(Vec<MediaIssue>),
+    Connected(SocketAddr),
+    /// The server address couldn't be resolved, the client thread ended
+    /// without connecting. Not worth retrying, unlike Disconnected.
+    ResolveFailed(String),
+    /// The sorted names of all connected players
+    PlayerList(Vec<String>),
+    /// The player inventory changed
+    Inventory(Inventory),
+    /// Available media, sent once when loading is finished
+    Media(MediaManager),
+    /// Sent whenever joining the server made progress
+    LoadingProgress(LoadingProgress),
+    HudAdd {
+        id: u32,
+        element: HudElement,
+    },
+    HudChange {
+        id: u32,
+        stat: HudStat,
+    },
+    HudRemove(u32),
+    HudSetFlags {
+        flags: u32,
+        mask: u32,
+    },
+    HudSetParam(HudSetParam),
+    /// A detached inventory changed, None if it was removed
+    DetachedInventory {
+        name: String,
+        inventory: Option<Inventory>,
+    },
+    ChatMessage(String),
+    /// A script for LuaController::run_server_script
+    ServerScript(String),
+    MovementSettings(MovementSettings),
+    EyeOffsets(EyeOffsets),
+    /// None if the player may choose the camera mode
+    ForcedCameraMode(Option<CameraMode>),
+    Fov {
+        /// In degrees, 0 restores the user's FOV
+        fov: f32,
+        is_multiplier: bool,
+        /// In seconds
+        transition_time: f32,
+    },
+    Privileges(HashSet<String>),
+    Hp {
+        hp: u16,
+        /// Whether a decrease should show the damage effect
+        damage_effect: bool,
+    },
+    Breath(u16),
+    TimeOfDay {
+        /// 0.0 to 1.0, 0.5 is noon
+        time_of_day: f32,
+        /// Ratio of game time to real time
+        time_speed: f32,
+    },
+    SetSun(SunParams),
+    SetMoon(MoonParams),
+    SetStars(StarParams),
+    CloudParams(CloudParams),
+    /// 0.0 to 1.0, None to follow the time of day again
+    OverrideDayNightRatio(Option<f32>),
+    /// The parts of TOCLIENT_SET_LIGHTING that are supported
+    Lighting {
+        saturation: f32,
+        /// In stops
+        exposure_correction: f32,
+    },
+    ShowFormspec {
+        name: String,
+        formspec: String,
+    },
+    /// The formspec of the player inventory, shown when opening it
+    InventoryFormspec(String),
+    PlaySound {
+        id: i32,
+        spec: SoundSpec,
+    },
+    StopSound(i32),
+    AddActiveObject {
+        id: u16,
+        object: ActiveObject,
+    },
+    RemoveActiveObject(u16),
+    ActiveObjectMessage {
+        id: u16,
+        message: ActiveObjectCommand,
+    },
+    NetworkStats(NetworkStats),
+    Disconnected(DisconnectReason),
+}
+
+/// Statistics for telling network problems apart from rendering problems.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkStats {
+    /// Round-trip time. There's no ping in the protocol, so it's measured
+    /// between TOSERVER_INIT and TOCLIENT_HELLO, and then with commands the
+    /// server answers right away, see LuantiClientRunner::PING_CHANNEL.
+    pub rtt: Option<Duration>,
+    /// Commands, not counting the low-level packets for acks and splitting
+    pub packets_received_per_sec: f32,
+    pub packets_sent_per_sec: f32,
+    /// Serialized size of the commands, without the packet headers
+    pub bytes_received_per_sec: f32,
+    pub bytes_sent_per_sec: f32,
+}
+
+/// Why the connection to the server ended.
+#[derive(Debug, Clone)]
+pub struct DisconnectReason {
+    /// None if the connection ended for another reason than
+    /// TOCLIENT_ACCESS_DENIED, e.g. a network error
+    pub code: Option<AccessDeniedCode>,
+    pub message: String,
+    /// Whether the server suggests reconnecting, e.g. when restarting
+    pub reconnect: bool,
+}
+
+impl DisconnectReason {
+    // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_AccessDenied
+    fn from_access_denied(code: AccessDeniedCode) -> Self {
+        // Compare to Luanti, network/networkprotocol.cpp, accessDeniedStrings
+        let (message, reconnect) = match &code {
+            AccessDeniedCode::WrongPassword => ("Invalid password", false),
+            AccessDeniedCode::UnexpectedData => (
+                "Your client sent something the server didn't expect. \
+                 Try reconnecting or updating your client.",
+                false,
+            ),
+            AccessDeniedCode::Singleplayer => (
+                "The server is running in simple singleplayer mode. You cannot connect.",
+                false,
+            ),
+            AccessDeniedCode::WrongVersion => (
+                "Your client's version is not supported.\n\
+                 Please contact the server administrator.",
+                false,
+            ),
+            AccessDeniedCode::WrongCharsInName => {
+                ("Player name contains disallowed characters", false)
+            }
+            AccessDeniedCode::WrongName => ("Player name not allowed", false),
+            AccessDeniedCode::TooManyUsers => ("Too many users", false),
+            AccessDeniedCode::EmptyPassword => (
+                "Empty passwords are disallowed. Set a password and try again.",
+                false,
+            ),
+            AccessDeniedCode::AlreadyConnected => (
+                "Another client is connected with this name. \
+                 If your client closed unexpectedly, try again in a minute.",
+                false,
+            ),
+            AccessDeniedCode::ServerFail => ("Internal server error", false),
+            AccessDeniedCode::CustomString(reason) => (reason.as_str(), false),
+            AccessDeniedCode::Shutdown(reason, reconnect) => {
+                (Self::or_default(reason, "Server shutting down"), *reconnect)
+            }
+            AccessDeniedCode::Crash(reason, reconnect) => (
+                Self::or_default(
+                    reason,
+                    "The server has experienced an internal error. \
+                     You will now be disconnected.",
+                ),
+                *reconnect,
+            ),
+        };
+        Self {
+            message: String::from(message),
+            code: Some(code),
+            reconnect,
+        }
+    }
+
+    /// Servers can send a custom reason instead of the default message.
+    fn or_default<'a>(reason: &'a str, default: &'a str) -> &'a str {
+        if reason.is_empty() { default } else { reason }
+    }
 }
 
 pub enum MainToClientEvent {
     PlayerPos(PlayerPos),
+    /// The selected hotbar slot changed
+    PlayerItem(u16),
+    /// Fields of a formspec, sent when a button is pressed or the formspec
+    /// is closed
+    InventoryFields {
+        formname: String,
+        fields: Vec<(String, String)>,
+    },
+    /// Fields of a formspec shown from node metadata
+    NodemetaFields {
+        pos: MapNodePos,
+        formname: String,
+        fields: Vec<(String, String)>,
+    },
+    /// A serialized inventory action, e.g. "Move 1 current_player main 0
+    /// current_player craft 0"
+    InventoryAction(String),
+    /// Non-ephemeral sounds that finished playing
+    RemovedSounds(Vec<i32>),
+    /// Digging or placing
+    Interact {
+        action: InteractAction,
+        pointed: PointedThing,
+        /// The selected hotbar slot
+        item: u16,
+        pos: PlayerPos,
+    },
+    /// Sent by a script
+    ChatMessage(String),
+    /// Debugging aid, see Meshgen::set_paused
+    SetMeshgenPaused(bool),
+    /// Debugging aid, see Meshgen::flush
+    FlushMeshgen,
 }
 
 #[derive(Debug, PartialEq)]
@@ -49,26 +385,99 @@ pub struct LuantiClientRunner {
 
     state: ClientState,
     client: LuantiClient,
-    map: LuantiMap,
+    /// Shared with the main thread, which only reads from it.
+    map: Arc<RwLock<LuantiMap>>,
+
+    connection: ConnectionConfig,
+    /// The SRP login in progress
+    srp: Option<SrpClient>,
 
     node_def: Option<NodeDefManager>,
+    item_def: Option<ItemDefManager>,
     media: Option<MediaManager>,
     meshgen: Option<Meshgen>,
+    options: ClientOptions,
+
+    player_list: Vec<String>,
+    /// Kept here too, as the server only sends what changed
+    inventory: Inventory,
+    detached_inventories: HashMap<String, Inventory>,
+    /// All randomness should come from this, so it can be seeded in
+    /// deterministic mode. Except for SRP secrets, which must stay
+    /// unpredictable.
+    rng: StdRng,
+    /// Set when the server denies access, to report the reason to the main
+    /// thread
+    access_denied: Option<DisconnectReason>,
+
+    /// See ClientToMainEvent::LoadingProgress
+    loading: LoadingProgress,
+
+    /// See NetworkStats
+    rtt: Option<Duration>,
+    /// When the last command the server answers right away was sent, while
+    /// waiting for the answer
+    ping_sent_at: Option<Instant>,
+    packets_received: u32,
+    packets_sent: u32,
+    bytes_received: usize,
+    bytes_sent: usize,
+    stats_since: Instant,
 }
 
 impl LuantiClientRunner {
+    /// Also used for other RNGs that are seeded in deterministic mode
+    pub const DETERMINISTIC_SEED: u64 = 0;
+    const NETWORK_STATS_INTERVAL: Duration = Duration::from_secs(1);
+    /// A mod channel the client never joins. Leaving it makes the server
+    /// answer with a TOCLIENT_MODCHANNEL_SIGNAL right away, without any
+    /// side effects, which is used as a ping.
+    // Compare to Luanti, network/serverpackethandler.cpp, handleCommand_ModChannelLeave
+    const PING_CHANNEL: &str = "cubetonic:ping";
+    /// The protocol versions advertised to the server. luanti-protocol
+    /// reads and writes commands in the format of version 46. Versions 44
+    /// and 45 only lack trailing fields added since, which it treats as
+    /// optional. Later versions change the format of some commands, e.g. by
+    /// compressing them.
+    // Compare to Luanti, network/networkprotocol.h, CLIENT_PROTOCOL_VERSION_MIN/MAX
+    const PROTOCOL_VERSION_MIN: u16 = 44;
+    const PROTOCOL_VERSION_MAX: u16 = 46;
+
     pub async fn spawn(
         device: wgpu::Device,
         queue: wgpu::Queue,
         main_tx: mpsc::UnboundedSender<ClientToMainEvent>,
         main_rx: mpsc::UnboundedReceiver<MainToClientEvent>,
+        map: Arc<RwLock<LuantiMap>>,
+        connection: ConnectionConfig,
+        options: ClientOptions,
     ) {
         tokio::spawn(async move {
-            let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+            let addr = match connection.resolve().await {
+                Ok(addr) => addr,
+                Err(err) => {
+                    println!("Could not resolve address: {}", err);
+                    let _ = main_tx.send(ClientToMainEvent::ResolveFailed(err.to_string()));
+                    return;
+                }
+            };
             println!("Connecting to Luanti server at {}...", addr);
-            let client = LuantiClient::connect(addr).await.unwrap();
-
-            let map = LuantiMap::new();
+            let client = match LuantiClient::connect(addr).await {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("Could not connect: {}", err);
+                    let _ = main_tx.send(ClientToMainEvent::Disconnected(DisconnectReason {
+                        code: None,
+                        message: format!("Could not connect: {}", err),
+                        reconnect: false,
+                    }));
+                    return;
+                }
+            };
+            // The main thread may have gone back to the main menu
+            if main_tx.send(ClientToMainEvent::Connected(addr)).is_err() {
+                return;
+            }
 
             let mut runner = LuantiClientRunner {
                 device,
@@ -80,9 +489,34 @@ impl LuantiClientRunner {
                 client,
                 map,
 
+                connection,
+                srp: None,
+
                 node_def: None,
+                item_def: None,
                 media: None,
                 meshgen: None,
+
+                player_list: Vec::new(),
+                inventory: Inventory::default(),
+                detached_inventories: HashMap::new(),
+                rng: if options.deterministic {
+                    StdRng::seed_from_u64(Self::DETERMINISTIC_SEED)
+                } else {
+                    StdRng::from_os_rng()
+                },
+                access_denied: None,
+                options,
+
+                loading: LoadingProgress::default(),
+
+                rtt: None,
+                ping_sent_at: None,
+                packets_received: 0,
+                packets_sent: 0,
+                bytes_received: 0,
+                bytes_sent: 0,
+                stats_since: Instant::now(),
             };
             runner.run().await
         });
@@ -93,22 +527,38 @@ impl LuantiClientRunner {
             Ok(()) => unreachable!(),
             Err(err) => {
                 println!("Disconnected: {}", err);
+                let reason = self
+                    .access_denied
+                    .take()
+                    .unwrap_or_else(|| DisconnectReason {
+                        code: None,
+                        message: err.to_string(),
+                        reconnect: false,
+                    });
+                self.send_to_main(ClientToMainEvent::Disconnected(reason));
             }
         }
     }
 
     async fn run_inner(&mut self) -> anyhow::Result<()> {
-        let mut user_name = String::from("test");
-        user_name.push_str(&rand::rng().random_range(0..1000).to_string());
+        if self.connection.name.is_empty() {
+            self.connection.name = format!("test{}", self.rng.random_range(0..1000));
+        }
 
-        self.client.send(ToServerCommand::Init(Box::new(InitSpec {
+        self.ping_sent_at = Some(Instant::now());
+        self.send(ToServerCommand::Init(Box::new(InitSpec {
             serialization_ver_max: 29,
-            supp_compr_modes: 0, // unused
-            min_net_proto_version: 46,
-            max_net_proto_version: 46, // appears to be the only version supported by luanti-protocol
-            user_name: user_name.clone(),
+            supp_compr_modes: NETPROTO_COMPRESSION_NONE,
+            min_net_proto_version: Self::PROTOCOL_VERSION_MIN,
+            max_net_proto_version: Self::PROTOCOL_VERSION_MAX,
+            user_name: self.connection.name.clone(),
         })))?;
 
+        let mut stats_interval = tokio::time::interval(Self::NETWORK_STATS_INTERVAL);
+        stats_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, there's nothing to report yet
+        stats_interval.tick().await;
+
         loop {
             // println!("Waiting for command...");
 
@@ -116,9 +566,16 @@ impl LuantiClientRunner {
                 command = self.client.recv() => {
                     // println!("Received command from server: {:?}", command);
                     let command = command?;
-                    self.process_network_command(command)?;
+                    self.packets_received += 1;
+                    self.bytes_received += Self::command_size(
+                        &command,
+                        ProtocolContext::latest_for_receive(true),
+                    );
+                    self.process_network_command(command).await?;
                 },
 
+                _ = stats_interval.tick() => self.send_network_stats()?,
+
                 event = self.main_rx.recv() => {
                     let event = event.ok_or_else(|| anyhow!("main_rx is closed"))?;
                     self.process_main_event(event)?;
@@ -127,22 +584,148 @@ impl LuantiClientRunner {
         }
     }
 
-    fn generate_mapblock_with_neighbors(&self, blockpos: MapBlockPos) {
+    /// Sets the loading stage and reports the progress to the main thread.
+    fn set_loading_stage(&mut self, stage: LoadingStage) {
+        self.loading.stage = stage;
+        self.send_loading_progress();
+    }
+
+    fn send_loading_progress(&self) {
+        self.send_to_main(ClientToMainEvent::LoadingProgress(self.loading.clone()));
+    }
+
+    /// Sends an event to the main thread. The main thread may have gone back
+    /// to the main menu, in which case `run_inner` stops once it notices
+    /// that main_rx is closed.
+    fn send_to_main(&self, event: ClientToMainEvent) {
+        let _ = self.main_tx.send(event);
+    }
+
+    fn send(&mut self, command: ToServerCommand) -> anyhow::Result<()> {
+        self.packets_sent += 1;
+        self.bytes_sent += Self::command_size(&command, ProtocolContext::latest_for_send(true));
+        self.client.send(command)
+    }
+
+    /// Returns the serialized size of a command, for NetworkStats, since
+    /// LuantiClient doesn't expose the size of the packets.
+    fn command_size<C: Serialize<Input = C>>(command: &C, context: ProtocolContext) -> usize {
+        let mut ser = MockSerializer::new(context);
+        match C::serialize(command, &mut ser) {
+            Ok(()) => ser.len(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Reports the statistics since the last call to the main thread, and
+    /// measures the RTT again once in game.
+    fn send_network_stats(&mut self) -> anyhow::Result<()> {
+        let elapsed = self.stats_since.elapsed().as_secs_f32();
+        self.stats_since = Instant::now();
+        let stats = NetworkStats {
+            rtt: self.rtt,
+            packets_received_per_sec: std::mem::take(&mut self.packets_received) as f32 / elapsed,
+            packets_sent_per_sec: std::mem::take(&mut self.packets_sent) as f32 / elapsed,
+            bytes_received_per_sec: std::mem::take(&mut self.bytes_received) as f32 / elapsed,
+            bytes_sent_per_sec: std::mem::take(&mut self.bytes_sent) as f32 / elapsed,
+        };
+        self.send_to_main(ClientToMainEvent::NetworkStats(stats));
+
+        // Mod channel commands are only handled in game. A lost answer
+        // can't happen, the command is sent reliably.
+        if self.state == ClientState::ReadySent && self.ping_sent_at.is_none() {
+            self.ping_sent_at = Some(Instant::now());
+            self.send(ToServerCommand::ModchannelLeave(Box::new(
+                ModchannelLeaveSpec {
+                    channel_name: String::from(Self::PING_CHANNEL),
+                },
+            )))?;
+        }
+        Ok(())
+    }
+
+    fn generate_mapblock_with_neighbors(&mut self, blockpos: MapBlockPos) {
         assert!(self.state == ClientState::ReadySent);
-        let meshgen = self.meshgen.as_ref().unwrap();
+        let meshgen = self.meshgen.as_mut().unwrap();
+        let map = self.map.read().unwrap();
 
-        meshgen.submit(&self.map, blockpos, self.map.get_block(&blockpos).unwrap());
+        meshgen.submit(blockpos, map.get_block(&blockpos).unwrap());
 
         for dir in NEIGHBOR_DIRS {
             if let Some(n_blockpos) = blockpos.checked_add(dir)
-                && let Some(n_block) = self.map.get_block(&n_blockpos)
+                && let Some(n_block) = map.get_block(&n_blockpos)
             {
-                meshgen.submit(&self.map, n_blockpos, n_block);
+                meshgen.submit(n_blockpos, n_block);
+            }
+        }
+    }
+
+    /// Sets a node changed by the server, updates the light around it and
+    /// remeshes the affected mapblocks.
+    // Compare to Luanti, client/client.cpp, Client::addNode
+    fn set_node(&mut self, pos: MapNodePos, node: MapNode, keep_metadata: bool) {
+        let node_def = self.meshgen.as_ref().unwrap().node_def().clone();
+        let mut map = self.map.write().unwrap();
+        if !keep_metadata {
+            map.node_metadata_mut().remove(&pos);
+        }
+        let Some(old) = map.get_node(&pos) else {
+            return;
+        };
+        map.set_node(&pos, node);
+        let modified = light::update_lighting_node(&mut map, &node_def, pos, old);
+        drop(map);
+
+        for blockpos in modified {
+            self.generate_mapblock_with_neighbors(blockpos);
+        }
+    }
+
+    /// Replaces a dug node with its "node_dig_prediction" right away, instead
+    /// of waiting for the server. The server corrects wrong predictions.
+    // Compare to Luanti, client/game.cpp, Game::handleDigging
+    fn predict_dug_node(&mut self, pos: &MapNodePos) {
+        let Some(meshgen) = &self.meshgen else {
+            return;
+        };
+        let node_def = meshgen.node_def().clone();
+        let Some(node) = self.map.read().unwrap().get_node(pos) else {
+            return;
+        };
+        let prediction = &node_def
+            .get_with_fallback(node.content_id)
+            .node_dig_prediction;
+        if prediction.is_empty() {
+            return;
+        }
+        let Some(content_id) = node_def.get_id(prediction) else {
+            println!("Unknown node_dig_prediction \"{}\"", prediction);
+            return;
+        };
+        let node = MapNode {
+            content_id,
+            param1: 0,
+            param2: 0,
+        };
+        self.set_node(MapNodePos(pos.0), node, false);
+    }
+
+    /// Returns None for invalid metadata, which is skipped instead of
+    /// ending the connection.
+    fn convert_node_metadata(
+        pos: &MapNodePos,
+        metadata: luanti_protocol::types::NodeMetadata,
+    ) -> Option<NodeMetadata> {
+        match NodeMetadata::from_network(metadata) {
+            Ok(metadata) => Some(metadata),
+            Err(err) => {
+                println!("Invalid node metadata at {}: {:?}", pos.0, err);
+                None
             }
         }
     }
 
-    fn process_network_command(&mut self, command: ToClientCommand) -> anyhow::Result<()> {
+    async fn process_network_command(&mut self, command: ToClientCommand) -> anyhow::Result<()> {
         match command {
             ToClientCommand::Hello(spec) => 'b: {
                 if self.state != ClientState::Connected {
@@ -150,19 +733,85 @@ impl LuantiClientRunner {
                     break 'b;
                 }
 
+                if !(Self::PROTOCOL_VERSION_MIN..=Self::PROTOCOL_VERSION_MAX)
+                    .contains(&spec.proto_ver)
+                {
+                    return Err(anyhow!(
+                        "Server chose unsupported protocol version {}",
+                        spec.proto_ver
+                    ));
+                }
+                println!("Using protocol version {}", spec.proto_ver);
+
+                if let Some(ping_sent_at) = self.ping_sent_at.take() {
+                    self.rtt = Some(ping_sent_at.elapsed());
+                }
+
+                if spec.compression_mode != NETPROTO_COMPRESSION_NONE {
+                    // We only advertised NETPROTO_COMPRESSION_NONE, so this
+                    // shouldn't happen. Compressed serialization formats
+                    // are unaffected by this, so just carry on.
+                    println!(
+                        "Server chose unsupported compression mode {}, ignoring",
+                        spec.compression_mode
+                    );
+                }
+
+                // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_Hello
                 if spec.auth_mechs.first_srp {
-                    // register
-                    self.client
-                        .send(ToServerCommand::FirstSrp(Box::new(FirstSrpSpec {
-                            salt: vec![],
-                            verification_key: vec![],
-                            is_empty: false, // only used for "disallow empty passwords"
-                        })))?;
-                    self.state = ClientState::AuthSent;
+                    // The account doesn't exist yet, register it. Never
+                    // seeded, predictable SRP values would let anyone
+                    // watching the handshake check password guesses.
+                    let (salt, verifier) = srp::create_verifier(
+                        &self.connection.name,
+                        &self.connection.password,
+                        &mut rand::rng(),
+                    );
+                    self.send(ToServerCommand::FirstSrp(Box::new(FirstSrpSpec {
+                        salt,
+                        verification_key: verifier,
+                        // only used for "disallow empty passwords"
+                        is_empty: self.connection.password.is_empty(),
+                    })))?;
+                } else if spec.auth_mechs.srp {
+                    // See above, the secret must not be predictable
+                    let srp = SrpClient::new(
+                        &self.connection.name,
+                        &self.connection.password,
+                        &mut rand::rng(),
+                    );
+                    self.send(ToServerCommand::SrpBytesA(Box::new(SrpBytesASpec {
+                        bytes_a: srp.bytes_a(),
+                        // 1 = SRP, 0 would be the legacy password hash
+                        based_on: 1,
+                    })))?;
+                    self.srp = Some(srp);
                 } else {
-                    // cannot login as that would require actually implementing srp :)
-                    panic!("received unsupported or invalid auth method");
+                    return Err(anyhow!(
+                        "Server doesn't offer a supported auth mechanism (only legacy passwords?)"
+                    ));
                 }
+                self.state = ClientState::AuthSent;
+                self.set_loading_stage(LoadingStage::LoggingIn);
+            }
+
+            ToClientCommand::SrpBytesSB(spec) => 'b: {
+                let Some(srp) = self.srp.take() else {
+                    println!("Received SrpBytesSB, invalid for state {:?}", self.state);
+                    break 'b;
+                };
+
+                let bytes_m = srp.process_challenge(&spec.s, &spec.b)?;
+                self.send(ToServerCommand::SrpBytesM(Box::new(SrpBytesMSpec {
+                    bytes_m,
+                })))?;
+            }
+
+            ToClientCommand::AccessDenied(spec) => {
+                let reason = DisconnectReason::from_access_denied(spec.code);
+                let err = anyhow!("Access denied: {}", reason.message);
+                self.access_denied = Some(reason);
+                return Err(err);
             }
 
             ToClientCommand::AuthAccept(_spec) => 'b: {
@@ -171,11 +820,11 @@ impl LuantiClientRunner {
                     break 'b;
                 }
 
-                self.client
-                    .send(ToServerCommand::Init2(Box::new(Init2Spec {
-                        lang: Some(String::from("en")),
-                    })))?;
+                self.send(ToServerCommand::Init2(Box::new(Init2Spec {
+                    lang: Some(String::from("en")),
+                })))?;
                 self.state = ClientState::Init2Sent;
+                self.set_loading_stage(LoadingStage::Definitions);
             }
 
             // TODO: check state properly
@@ -189,9 +838,24 @@ impl LuantiClientRunner {
                     "Received {} node definitions",
                     spec.node_def.content_features.len()
                 );
+                self.loading.node_defs = Some(spec.node_def.content_features.len());
+                self.send_loading_progress();
                 self.node_def = Some(NodeDefManager::from_network(spec.node_def));
             }
 
+            // TODO: check state properly
+            ToClientCommand::Itemdef(spec) => 'b: {
+                if self.state != ClientState::Init2Sent || self.item_def.is_some() {
+                    println!("Received Itemdef, invalid for state {:?}", self.state);
+                    break 'b;
+                }
+
+                println!("Received {} item definitions", spec.item_def.defs.len());
+                self.loading.item_defs = Some(spec.item_def.defs.len());
+                self.send_loading_progress();
+                self.item_def = Some(ItemDefManager::from_network(spec.item_def));
+            }
+
             // TODO: check state properly
             ToClientCommand::AnnounceMedia(spec) => 'b: {
                 if self.state != ClientState::Init2Sent || self.media.is_some() {
@@ -199,11 +863,17 @@ impl LuantiClientRunner {
                     break 'b;
                 }
 
-                let mut media = MediaManager::new()?;
+                let mut media = MediaManager::new(
+                    self.options.texture_pack.as_deref(),
+                    self.options.fallback_texture,
+                )?;
                 let mut missing = Vec::new();
                 let mut num_found: u32 = 0;
                 for item in spec.files {
-                    match media.try_add_from_cache(&item.name, &item.sha1_base64) {
+                    match media
+                        .try_add_from_cache(&item.name, &item.sha1_base64)
+                        .await
+                    {
                         Ok(found) => {
                             if !found {
                                 missing.push(item.name);
@@ -226,11 +896,14 @@ impl LuantiClientRunner {
                     num_found,
                     missing.len()
                 );
+                self.loading.media_cached = num_found as usize;
+                self.loading.media_requested = missing.len();
                 if missing.len() > 0 {
+                    self.set_loading_stage(LoadingStage::DownloadingMedia);
                     // TODO: try HTTP(S) / remote media servers first
-                    self.client.send(ToServerCommand::RequestMedia(Box::new(
-                        RequestMediaSpec { files: missing },
-                    )))?;
+                    self.send(ToServerCommand::RequestMedia(Box::new(RequestMediaSpec {
+                        files: missing,
+                    })))?;
                     self.state = ClientState::RequestMediaSent;
                 } else {
                     // TODO: properly check whether loading is finished before updating state
@@ -248,12 +921,14 @@ impl LuantiClientRunner {
                     self.media
                         .as_mut()
                         .unwrap()
-                        .add_from_bytes(&file.name, &file.data)?;
+                        .add_from_bytes(&file.name, &file.data);
                 }
                 println!("Received {} media files from the server", spec.files.len());
+                self.loading.media_received += spec.files.len();
+                self.send_loading_progress();
 
                 if spec.bunch_index == spec.num_bunches - 1 {
-                    // TODO: properly check the missing files are now loaded
+                    self.media.as_mut().unwrap().finish_downloads();
                     // TODO: properly check whether loading is finished before updating state
                     self.send_ready()?;
                 }
@@ -265,13 +940,14 @@ impl LuantiClientRunner {
                     break 'b;
                 }
 
-                self.main_tx
-                    .send(ClientToMainEvent::PlayerPos(PlayerPos {
-                        pos: spec.pos / BS,
-                        yaw: -spec.yaw,
-                        pitch: spec.pitch,
-                    }))
-                    .unwrap();
+                if let Some(meshgen) = &mut self.meshgen {
+                    meshgen.set_player_pos(spec.pos / BS);
+                }
+                self.send_to_main(ClientToMainEvent::PlayerPos(PlayerPos {
+                    pos: spec.pos / BS,
+                    yaw: -spec.yaw,
+                    pitch: spec.pitch,
+                }));
             }
 
             ToClientCommand::Blockdata(spec) => 'b: {
@@ -281,14 +957,34 @@ impl LuantiClientRunner {
                 }
 
                 // TODO: Luanti only sends this after meshgen? batching?
-                self.client
-                    .send(ToServerCommand::GotBlocks(Box::new(GotBlocksSpec {
-                        blocks: vec![spec.pos],
-                    })))?;
+                self.send(ToServerCommand::GotBlocks(Box::new(GotBlocksSpec {
+                    blocks: vec![spec.pos],
+                })))?;
 
                 let blockpos = MapBlockPos::new(spec.pos).unwrap();
                 let block = MapBlockNodes(spec.block.nodes.nodes);
-                self.map.insert_block(blockpos, block);
+                let metadata = spec
+                    .block
+                    .node_metadata
+                    .metadata
+                    .into_iter()
+                    .filter_map(|(index, metadata)| {
+                        // Compare to Luanti, nodemetadata.cpp, NodeMetadataList::deSerialize
+                        let size = MapBlockPos::SIZE as u16;
+                        let offset = I16Vec3::new(
+                            (index % size) as i16,
+                            (index / size % size) as i16,
+                            (index / (size * size)) as i16,
+                        );
+                        let pos = MapNodePos(spec.pos * size as i16 + offset);
+                        Self::convert_node_metadata(&pos, metadata).map(|metadata| (pos, metadata))
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut map = self.map.write().unwrap();
+                map.insert_block(blockpos, block);
+                map.node_metadata_mut().set_block(blockpos, metadata);
+                drop(map);
                 self.generate_mapblock_with_neighbors(blockpos);
             }
 
@@ -298,9 +994,7 @@ impl LuantiClientRunner {
                     break 'b;
                 }
 
-                if let Some(blockpos) = self.map.set_node(&MapNodePos(spec.pos), spec.node) {
-                    self.generate_mapblock_with_neighbors(blockpos);
-                }
+                self.set_node(MapNodePos(spec.pos), spec.node, spec.keep_metadata);
             }
 
             ToClientCommand::Removenode(spec) => 'b: {
@@ -314,8 +1008,363 @@ impl LuantiClientRunner {
                     param1: 0,
                     param2: 0,
                 };
-                if let Some(blockpos) = self.map.set_node(&MapNodePos(spec.pos), AIR_NODE) {
-                    self.generate_mapblock_with_neighbors(blockpos);
+                self.set_node(MapNodePos(spec.pos), AIR_NODE, false);
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_NodemetaChanged
+            ToClientCommand::NodemetaChanged(spec) => {
+                let mut map = self.map.write().unwrap();
+                for (pos, metadata) in spec.list.metadata {
+                    let pos = MapNodePos(pos);
+                    if let Some(metadata) = Self::convert_node_metadata(&pos, metadata) {
+                        map.node_metadata_mut().set(&pos, metadata);
+                    }
+                }
+            }
+
+            ToClientCommand::UpdatePlayerList(spec) => {
+                // Compare to Luanti, PlayerListModifer in networkprotocol.h
+                const PLAYER_LIST_INIT: u8 = 0;
+                const PLAYER_LIST_ADD: u8 = 1;
+                const PLAYER_LIST_REMOVE: u8 = 2;
+
+                match spec.typ {
+                    PLAYER_LIST_INIT => self.player_list = spec.players,
+                    PLAYER_LIST_ADD => self.player_list.extend(spec.players),
+                    PLAYER_LIST_REMOVE => {
+                        self.player_list.retain(|name| !spec.players.contains(name))
+                    }
+                    _ => {
+                        println!("Received UpdatePlayerList, invalid type {}", spec.typ);
+                        return Ok(());
+                    }
+                }
+                self.player_list.sort();
+                self.player_list.dedup();
+
+                self.send_to_main(ClientToMainEvent::PlayerList(self.player_list.clone()));
+            }
+
+            ToClientCommand::Inventory(spec) => {
+                // Deserialized into a copy, so a bad update doesn't leave
+                // the inventory half updated
+                let mut inventory = self.inventory.clone();
+                if let Err(err) = inventory.deserialize(&spec.inventory) {
+                    println!("Invalid inventory, ignoring update: {:?}", err);
+                    return Ok(());
+                }
+                self.inventory = inventory;
+                self.send_to_main(ClientToMainEvent::Inventory(self.inventory.clone()));
+            }
+
+            ToClientCommand::InventoryFormspec(spec) => {
+                self.send_to_main(ClientToMainEvent::InventoryFormspec(spec.formspec));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp,
+            // handleCommand_DetachedInventory
+            ToClientCommand::DetachedInventory(spec) => {
+                let inventory = if spec.keep_inv {
+                    let mut inventory = self
+                        .detached_inventories
+                        .get(&spec.name)
+                        .cloned()
+                        .unwrap_or_default();
+                    if let Err(err) = inventory.deserialize(&spec.contents) {
+                        println!(
+                            "Invalid detached inventory \"{}\", ignoring update: {:?}",
+                            spec.name, err
+                        );
+                        return Ok(());
+                    }
+                    self.detached_inventories
+                        .insert(spec.name.clone(), inventory.clone());
+                    Some(inventory)
+                } else {
+                    self.detached_inventories.remove(&spec.name);
+                    None
+                };
+                self.send_to_main(ClientToMainEvent::DetachedInventory {
+                    name: spec.name,
+                    inventory,
+                });
+            }
+
+            ToClientCommand::Hudadd(spec) => {
+                let element = HudElement {
+                    typ: spec.typ,
+                    pos: spec.pos,
+                    name: spec.name,
+                    scale: spec.scale,
+                    text: spec.text,
+                    number: spec.number,
+                    item: spec.item,
+                    dir: spec.dir,
+                    align: spec.align,
+                    offset: spec.offset,
+                    world_pos: spec.world_pos,
+                    size: spec.size,
+                    z_index: spec.z_index,
+                    text2: spec.text2,
+                };
+                self.send_to_main(ClientToMainEvent::HudAdd {
+                    id: spec.server_id,
+                    element,
+                });
+            }
+
+            ToClientCommand::Hudchange(spec) => {
+                self.send_to_main(ClientToMainEvent::HudChange {
+                    id: spec.server_id,
+                    stat: spec.stat,
+                });
+            }
+
+            ToClientCommand::Hudrm(spec) => {
+                self.send_to_main(ClientToMainEvent::HudRemove(spec.server_id));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_Movement
+            ToClientCommand::Movement(spec) => {
+                let movement = MovementSettings {
+                    acceleration_default: spec.acceleration_default,
+                    acceleration_air: spec.acceleration_air,
+                    acceleration_fast: spec.acceleration_fast,
+                    speed_walk: spec.speed_walk,
+                    speed_crouch: spec.speed_crouch,
+                    speed_fast: spec.speed_fast,
+                    speed_climb: spec.speed_climb,
+                    speed_jump: spec.speed_jump,
+                    liquid_fluidity: spec.liquid_fluidity,
+                    liquid_fluidity_smooth: spec.liquid_fluidity_smooth,
+                    liquid_sink: spec.liquid_sink,
+                    gravity: spec.gravity,
+                };
+                self.send_to_main(ClientToMainEvent::MovementSettings(movement));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_EyeOffset
+            ToClientCommand::EyeOffset(spec) => {
+                // Sent in Luanti's internal units
+                let eye_offsets = EyeOffsets {
+                    first: spec.eye_offset_first / BS,
+                    third_back: spec.eye_offset_third / BS,
+                    third_front: spec.eye_offset_third_front / BS,
+                };
+                self.send_to_main(ClientToMainEvent::EyeOffsets(eye_offsets));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_Camera
+            ToClientCommand::Camera(spec) => {
+                self.send_to_main(ClientToMainEvent::ForcedCameraMode(
+                    CameraMode::from_network(spec.mode),
+                ));
+            }
+
+            ToClientCommand::Fov(spec) => {
+                self.send_to_main(ClientToMainEvent::Fov {
+                    fov: spec.fov,
+                    is_multiplier: spec.is_multiplier,
+                    transition_time: spec.transition_time,
+                });
+            }
+
+            ToClientCommand::Privileges(spec) => {
+                let privileges = spec.privileges.into_iter().collect();
+                self.send_to_main(ClientToMainEvent::Privileges(privileges));
+            }
+
+            ToClientCommand::Hp(spec) => {
+                self.send_to_main(ClientToMainEvent::Hp {
+                    hp: spec.hp,
+                    damage_effect: spec.damage_effect,
+                });
+            }
+
+            ToClientCommand::Breath(spec) => {
+                self.send_to_main(ClientToMainEvent::Breath(spec.breath));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_TimeOfDay
+            ToClientCommand::TimeOfDay(spec) => {
+                self.send_to_main(ClientToMainEvent::TimeOfDay {
+                    time_of_day: (spec.time_of_day % 24000) as f32 / 24000.0,
+                    time_speed: spec.time_speed.unwrap_or(SkyRenderer::DEFAULT_TIME_SPEED),
+                });
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_HudSetSun
+            ToClientCommand::SetSun(spec) => {
+                let sun = SunParams {
+                    visible: spec.visible,
+                    texture: spec.texture,
+                    tonemap: spec.tonemap,
+                    sunrise: spec.sunrise,
+                    sunrise_visible: spec.sunrise_visible,
+                    scale: spec.scale,
+                };
+                self.send_to_main(ClientToMainEvent::SetSun(sun));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_HudSetMoon
+            ToClientCommand::SetMoon(spec) => {
+                let moon = MoonParams {
+                    visible: spec.visible,
+                    texture: spec.texture,
+                    tonemap: spec.tonemap,
+                    scale: spec.scale,
+                };
+                self.send_to_main(ClientToMainEvent::SetMoon(moon));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_HudSetStars
+            ToClientCommand::SetStars(spec) => {
+                let color = spec.starcolor;
+                let stars = StarParams {
+                    visible: spec.visible,
+                    count: spec.count,
+                    color: Vec4::new(
+                        srgb_to_linear(color.r),
+                        srgb_to_linear(color.g),
+                        srgb_to_linear(color.b),
+                        color.a as f32 / 255.0,
+                    ),
+                    scale: spec.scale,
+                    day_opacity: spec.day_opacity,
+                };
+                self.send_to_main(ClientToMainEvent::SetStars(stars));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_CloudParams
+            ToClientCommand::CloudParams(spec) => {
+                let (color, ambient) = (spec.color_bright, spec.color_ambient);
+                let params = CloudParams {
+                    density: spec.density,
+                    color: Vec4::new(
+                        srgb_to_linear(color.r),
+                        srgb_to_linear(color.g),
+                        srgb_to_linear(color.b),
+                        color.a as f32 / 255.0,
+                    ),
+                    ambient: Vec3::new(
+                        srgb_to_linear(ambient.r),
+                        srgb_to_linear(ambient.g),
+                        srgb_to_linear(ambient.b),
+                    ),
+                    height: spec.height,
+                    thickness: spec.thickness,
+                    speed: Vec2::new(spec.speed.x, spec.speed.y),
+                };
+                self.send_to_main(ClientToMainEvent::CloudParams(params));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_OverrideDayNightRatio
+            ToClientCommand::OverrideDayNightRatio(spec) => {
+                let ratio = spec
+                    .do_override
+                    .then(|| spec.day_night_ratio as f32 / 65536.0);
+                self.send_to_main(ClientToMainEvent::OverrideDayNightRatio(ratio));
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_SetLighting
+            ToClientCommand::SetLighting(spec) => {
+                // TODO: shadows, automatic exposure, bloom and volumetric
+                // light, once they are implemented
+                let lighting = spec.lighting;
+                self.send_to_main(ClientToMainEvent::Lighting {
+                    saturation: lighting.saturation,
+                    exposure_correction: lighting.exposure.exposure_correction,
+                });
+            }
+
+            ToClientCommand::HudSetFlags(spec) => {
+                self.send_to_main(ClientToMainEvent::HudSetFlags {
+                    flags: spec.flags,
+                    mask: spec.mask,
+                });
+            }
+
+            ToClientCommand::HudSetParam(spec) => {
+                self.send_to_main(ClientToMainEvent::HudSetParam(spec.value));
+            }
+
+            ToClientCommand::ChatMessage(spec) => {
+                self.send_to_main(ClientToMainEvent::ChatMessage(spec.message));
+            }
+
+            ToClientCommand::ModchannelSignal(spec) if spec.channel_name == Self::PING_CHANNEL => {
+                if let Some(ping_sent_at) = self.ping_sent_at.take() {
+                    self.rtt = Some(ping_sent_at.elapsed());
+                }
+            }
+
+            // Sent by the server, not forwarded from other clients
+            ToClientCommand::ModchannelMsg(spec)
+                if spec.channel_name == SERVER_SCRIPT_CHANNEL && spec.sender.is_empty() =>
+            {
+                self.send_to_main(ClientToMainEvent::ServerScript(spec.channel_msg));
+            }
+
+            ToClientCommand::ShowFormspec(spec) => {
+                self.send_to_main(ClientToMainEvent::ShowFormspec {
+                    name: spec.form_name,
+                    formspec: spec.form_spec,
+                });
+            }
+
+            // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_PlaySound
+            ToClientCommand::PlaySound(spec) => {
+                let location = match spec.typ {
+                    0 => SoundLocation::Local,
+                    1 => SoundLocation::Pos(spec.pos / BS),
+                    2 => SoundLocation::Object(spec.object_id),
+                    typ => {
+                        println!("Received sound with unknown type {}", typ);
+                        return Ok(());
+                    }
+                };
+                self.send_to_main(ClientToMainEvent::PlaySound {
+                    id: spec.server_id,
+                    spec: SoundSpec {
+                        name: spec.spec_name,
+                        gain: spec.spec_gain,
+                        pitch: spec.spec_pitch,
+                        looped: spec.spec_loop,
+                        fade: spec.spec_fade,
+                        location,
+                        ephemeral: spec.ephemeral,
+                    },
+                });
+            }
+
+            ToClientCommand::ActiveObjectRemoveAdd(spec) => {
+                for id in spec.removed_object_ids {
+                    self.send_to_main(ClientToMainEvent::RemoveActiveObject(id));
+                }
+                for added in spec.added_objects {
+                    if added.typ != ActiveObject::TYPE_GENERIC {
+                        println!("Ignoring active object with legacy type {}", added.typ);
+                        continue;
+                    }
+                    let object =
+                        ActiveObject::from_init_data(added.init_data, &self.connection.name);
+                    self.send_to_main(ClientToMainEvent::AddActiveObject {
+                        id: added.id,
+                        object,
+                    });
+                }
+            }
+
+            ToClientCommand::StopSound(spec) => {
+                self.send_to_main(ClientToMainEvent::StopSound(spec.server_id));
+            }
+
+            ToClientCommand::ActiveObjectMessages(spec) => {
+                for object in spec.objects {
+                    self.send_to_main(ClientToMainEvent::ActiveObjectMessage {
+                        id: object.id,
+                        message: object.data,
+                    });
                 }
             }
 
@@ -326,51 +1375,169 @@ impl LuantiClientRunner {
     }
 
     fn send_ready(&mut self) -> anyhow::Result<()> {
-        self.meshgen = Some(Meshgen::new(
+        let origins = self.media.as_ref().unwrap().origins().clone();
+        self.send_to_main(ClientToMainEvent::MediaOrigins(origins));
+
+        self.set_loading_stage(LoadingStage::LoadingTextures);
+        let mut media = self.media.take().unwrap();
+        let meshgen = Meshgen::new(
             self.device.clone(),
             self.queue.clone(),
             self.main_tx.clone(),
+            self.map.clone(),
             self.node_def.take().unwrap(),
-            self.media.take().unwrap(),
+            &mut media,
+            &self.options,
+        );
+        self.loading.textures_total = meshgen.textures_to_load();
+        self.set_loading_stage(LoadingStage::Meshing);
+        let issues = media.take_issues();
+        if !issues.is_empty() {
+            self.send_to_main(ClientToMainEvent::MediaIssues(issues));
+        }
+        self.send_to_main(ClientToMainEvent::Media(media));
+        self.send_to_main(ClientToMainEvent::NodeDef(meshgen.node_def().clone()));
+        if let Some(item_def) = self.item_def.take() {
+            self.send_to_main(ClientToMainEvent::ItemDef(Arc::new(item_def)));
+        } else {
+            println!("Didn't receive item definitions");
+        }
+        self.send_to_main(ClientToMainEvent::MeshgenPendingTasks(
+            meshgen.pending_tasks().clone(),
         ));
+        self.meshgen = Some(meshgen);
 
-        self.client
-            .send(ToServerCommand::ClientReady(Box::new(ClientReadySpec {
-                major_ver: 0,
-                minor_ver: 1,
-                patch_ver: 0,
-                reserved: 0,
-                full_ver: String::from("Cubetonic 0.1.0"),
-                formspec_ver: Some(8), // corresponds to proto ver 46
-            })))?;
+        self.send(ToServerCommand::ClientReady(Box::new(ClientReadySpec {
+            major_ver: 0,
+            minor_ver: 1,
+            patch_ver: 0,
+            reserved: 0,
+            full_ver: String::from("Cubetonic 0.1.0"),
+            formspec_ver: Some(formspec::FORMSPEC_API_VERSION),
+        })))?;
         self.state = ClientState::ReadySent;
+        self.meshgen.as_mut().unwrap().load_textures();
+
+        if self.options.server_scripts {
+            self.send(ToServerCommand::ModchannelJoin(Box::new(
+                ModchannelJoinSpec {
+                    channel_name: String::from(SERVER_SCRIPT_CHANNEL),
+                },
+            )))?;
+        }
 
         println!("Client is ready!");
         Ok(())
     }
 
+    // Compare to Luanti, client.cpp, writePlayerPos
+    fn network_player_pos(pos: &PlayerPos) -> luanti_protocol::types::PlayerPos {
+        luanti_protocol::types::PlayerPos {
+            position: pos.pos * BS,
+            speed: Vec3::ZERO,
+            pitch: pos.pitch,
+            yaw: -pos.yaw,
+            keys_pressed: 0,
+            // expected to be max of horizontal and vertical fov
+            // just give a high value so we get much data
+            fov: PI,
+            // just give a high value so we get much data
+            wanted_range: 255,
+            camera_inverted: false,
+            movement_speed: 0.0,
+            movement_direction: 0.0,
+        }
+    }
+
     fn process_main_event(&mut self, event: MainToClientEvent) -> anyhow::Result<()> {
         match event {
             MainToClientEvent::PlayerPos(pos) => {
-                self.client
-                    .send(ToServerCommand::Playerpos(Box::new(PlayerPosCommand {
-                        player_pos: luanti_protocol::types::PlayerPos {
-                            position: pos.pos * BS,
-                            speed: Vec3::ZERO,
-                            pitch: pos.pitch,
-                            yaw: -pos.yaw,
-                            keys_pressed: 0,
-                            // expected to be max of horizontal and vertical fov
-                            // just give a high value so we get much data
-                            fov: PI,
-                            // just give a high value so we get much data
-                            wanted_range: 255,
-                            camera_inverted: false,
-                            movement_speed: 0.0,
-                            movement_direction: 0.0,
-                        },
-                    })))?;
+                if let Some(meshgen) = &mut self.meshgen {
+                    meshgen.set_player_pos(pos.pos);
+                }
+                self.send(ToServerCommand::Playerpos(Box::new(PlayerPosCommand {
+                    player_pos: Self::network_player_pos(&pos),
+                })))?;
+            }
+            MainToClientEvent::PlayerItem(item) => {
+                self.send(ToServerCommand::PlayerItem(Box::new(PlayerItemSpec {
+                    item,
+                })))?;
+            }
+            MainToClientEvent::InventoryFields { formname, fields } => {
+                self.send(ToServerCommand::InventoryFields(Box::new(
+                    InventoryFieldsSpec {
+                        client_formspec_name: formname,
+                        fields,
+                    },
+                )))?;
+            }
+            MainToClientEvent::NodemetaFields {
+                pos,
+                formname,
+                fields,
+            } => {
+                self.send(ToServerCommand::NodemetaFields(Box::new(
+                    NodemetaFieldsSpec {
+                        p: pos.0,
+                        form_name: formname,
+                        fields,
+                    },
+                )))?;
+            }
+            MainToClientEvent::InventoryAction(action) => {
+                self.send(ToServerCommand::InventoryAction(Box::new(
+                    InventoryActionSpec { action },
+                )))?;
+            }
+            MainToClientEvent::RemovedSounds(ids) => {
+                self.send(ToServerCommand::RemovedSounds(Box::new(
+                    RemovedSoundsSpec { ids },
+                )))?;
+            }
+            MainToClientEvent::Interact {
+                action,
+                pointed,
+                item,
+                pos,
+            } => {
+                if action == InteractAction::DiggingCompleted
+                    && let PointedThing::Node { under, .. } = &pointed
+                {
+                    self.predict_dug_node(under);
+                }
+                let pointed_thing = match pointed {
+                    PointedThing::Nothing => luanti_protocol::types::PointedThing::Nothing,
+                    PointedThing::Node { under, above, .. } => {
+                        luanti_protocol::types::PointedThing::Node {
+                            under_surface: under.0,
+                            above_surface: above.0,
+                        }
+                    }
+                    PointedThing::Object { id, .. } => {
+                        luanti_protocol::types::PointedThing::Object { object_id: id }
+                    }
+                };
+                self.send(ToServerCommand::Interact(Box::new(InteractSpec {
+                    action,
+                    item_index: item,
+                    pointed_thing,
+                    player_pos: Self::network_player_pos(&pos),
+                })))?;
+            }
+            MainToClientEvent::ChatMessage(message) => {
+                self.send(ToServerCommand::ChatMessage(Box::new(ChatMessageSpec {
+                    message,
+                })))?;
             }
+            MainToClientEvent::SetMeshgenPaused(paused) => match &mut self.meshgen {
+                Some(meshgen) => meshgen.set_paused(paused),
+                None => println!("Meshgen isn't running yet"),
+            },
+            MainToClientEvent::FlushMeshgen => match &mut self.meshgen {
+                Some(meshgen) => meshgen.flush(),
+                None => println!("Meshgen isn't running yet"),
+            },
         }
 
         Ok(())
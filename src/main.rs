@@ -1,36 +1,130 @@
-use std::collections::HashMap;
-use std::f32::consts::PI;
-use std::sync::Arc;
-use std::time::Instant;
-
-use glam::{I16Vec3, Vec3};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use glam::{Vec2, Vec3, Vec4};
+use luanti_core::MapNodePos;
+use luanti_protocol::types::{ActiveObjectCommand, HudSetParam, InteractAction};
 use tokio::sync::mpsc;
 use wgpu::{FeaturesWGPU, FeaturesWebGPU, SurfaceError};
 use winit::application::ApplicationHandler;
-use winit::event::{DeviceEvent, DeviceId, ElementState, KeyEvent, WindowEvent};
+use winit::event::{DeviceEvent, DeviceId, ElementState, KeyEvent, MouseButton, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::{CursorGrabMode, Fullscreen, Window, WindowId};
 
 use luanti_client::LuantiClientRunner;
 
+use crate::active_object::{ActiveObject, Damage, Nametag};
+use crate::audio::{Audio, SoundLocation};
+use crate::chat::Chat;
+use crate::clouds::CloudRenderer;
+use crate::debug_screen::DebugScreen;
+use crate::digging::{self, DigParams, Digging};
+use crate::entity::EntityRenderer;
+use crate::farmesh::FarMeshRenderer;
+use crate::formspec::{Formspec, FormspecEvent};
 use crate::frustum::Frustum;
+use crate::gamepad::{GamepadInput, Gamepads};
+use crate::hotbar::Hotbar;
+use crate::hud::{
+    HUD_FLAG_BASIC_DEBUG, HUD_FLAG_CHAT_VISIBLE, HUD_FLAG_CROSSHAIR_VISIBLE,
+    HUD_FLAG_HOTBAR_VISIBLE, HUD_FLAG_WIELDITEM_VISIBLE, Hud,
+};
+use crate::inventory::{Inventories, Inventory, ItemStack};
+use crate::item_def::ItemDefManager;
+use crate::join_info::JoinInfo;
+use crate::loading::LoadingProgress;
 use crate::lua::LuaController;
-use crate::luanti_client::{ClientToMainEvent, MainToClientEvent};
-use crate::media::NodeTextureData;
+use crate::luanti_client::{
+    ClientOptions, ClientToMainEvent, ConnectionConfig, DisconnectReason, MainToClientEvent,
+    NetworkStats,
+};
+use crate::main_menu::{MainMenu, MainMenuAction};
+use crate::map::LuantiMap;
+use crate::media::{MediaIssue, MediaManager, NodeTextureData};
 use crate::meshgen::MapblockMesh;
+use crate::node_def::NodeDefManager;
+use crate::overlay::{MediaImages, OverlayRenderer};
+use crate::physics::CollisionWorld;
+use crate::pointing::{self, PointedThing};
+use crate::post_process::PostProcess;
+use crate::profiler::{GpuTimer, Profiler};
+use crate::render_graph::{Attachment, Attachments, RenderGraph};
+use crate::settings::Settings;
+use crate::sky::SkyRenderer;
+use crate::smoke_test::{SmokeTest, SmokeTestResult};
+use crate::text::{self, TextRenderer};
 use crate::texture::MyTexture;
+use crate::waypoints::Waypoints;
+use crate::wield::WieldHand;
+use crate::world::World;
 
+mod active_object;
+mod audio;
 mod camera;
 mod camera_controller;
+mod chat;
+mod clouds;
+mod debug_screen;
+mod digging;
+mod entity;
+mod farmesh;
+mod formspec;
 mod frustum;
+mod gamepad;
+mod gltf_export;
+mod hotbar;
+mod hud;
+mod introspection;
+mod inventory;
+mod item_def;
+mod join_info;
+mod light;
+mod loading;
 mod lua;
+mod lua_storage;
 mod luanti_client;
+mod main_menu;
 mod map;
+mod map_image;
 mod media;
 mod meshgen;
+mod model;
 mod node_def;
+mod node_metadata;
+mod overlay;
+mod physics;
+mod pointing;
+mod post_process;
+mod profiler;
+mod render_graph;
+mod settings;
+mod sky;
+mod smoke_test;
+mod sound;
+mod srp;
+mod text;
 mod texture;
+mod waypoints;
+mod wield;
+mod world;
+
+/// What the app is doing. The disconnect screen is shown on top of the
+/// current phase once the connection has ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// The main menu is open and there's no connection
+    Menu,
+    /// Connecting to the server and loading, until the mapblocks around the
+    /// player are meshed
+    Connecting,
+    InGame,
+}
 
 struct State {
     window: Arc<Window>,
@@ -40,36 +134,164 @@ struct State {
     surface: wgpu::Surface<'static>,
     size: winit::dpi::PhysicalSize<u32>,
     surface_format: wgpu::TextureFormat,
+    /// See Settings::render_scale
+    render_scale: f32,
+    present_mode: wgpu::PresentMode,
+    supported_present_modes: Vec<wgpu::PresentMode>,
 
     depth_texture: MyTexture,
+    post_process: PostProcess,
+    entities: EntityRenderer,
+    sky: SkyRenderer,
+    clouds: CloudRenderer,
+    farmesh: FarMeshRenderer,
+    overlay: OverlayRenderer,
+    text: TextRenderer,
 
     camera: camera::Camera,
     camera_controller: camera_controller::CameraController,
+    hotbar: Hotbar,
+    hud: Hud,
+    /// Inventory images of the items in the wield slots
+    wield_images: MediaImages,
 
     last_frame: Instant,
     last_send: Instant,
+    /// The position sent last, to avoid sending unchanged positions
+    last_sent_pos: Option<camera_controller::PlayerPos>,
+    /// The earliest time the next frame may start, see `frame_interval`
+    next_frame: Instant,
+    fps_max: u32,
+    fps_max_unfocused: u32,
+    /// See Settings::deterministic
+    deterministic: bool,
+    focused: bool,
+    occluded: bool,
+
+    phase: Phase,
+    main_menu: MainMenu,
+    /// Set by the main menu, the connection is started in about_to_wait
+    connect_to: Option<ConnectionConfig>,
+    /// Set by the main menu, the app exits in about_to_wait
+    exit_requested: bool,
 
     client_tx: mpsc::UnboundedSender<MainToClientEvent>,
     client_rx: mpsc::UnboundedReceiver<ClientToMainEvent>,
+    /// Kept for reconnecting, None while in the main menu
+    connection: Option<ConnectionConfig>,
+    client_options: ClientOptions,
+    /// Failed reconnection attempts in a row, for the backoff
+    reconnect_attempts: u32,
+    /// When to reconnect after the connection was lost
+    reconnect_at: Option<Instant>,
+
+    map: Arc<RwLock<LuantiMap>>,
+    node_def: Option<Arc<NodeDefManager>>,
+    item_def: Option<Arc<ItemDefManager>>,
+    meshgen_pending_tasks: Option<Arc<AtomicUsize>>,
+    /// Debugging aid, see Meshgen::set_paused
+    meshgen_paused: bool,
+    /// Received from the client thread once per second
+    network_stats: Option<NetworkStats>,
+    /// Debugging aid, toggled with F5
+    show_network_stats: bool,
+    /// Debugging aid, toggled with F3
+    debug_screen: DebugScreen,
+    /// Debugging aid, toggled with F4
+    profiler: Profiler,
+    /// Time since startup, for animations
+    anim_time: f32,
+
+    server_address: Option<SocketAddr>,
+    /// Sorted by name
+    player_list: Vec<String>,
+    /// Shown while Tab is held
+    show_player_list: bool,
+    /// The player inventory, the selected slot of its "main" list is wielded
+    inventory: Inventory,
+    /// Detached inventories by name, used by server mods for custom UIs
+    detached_inventories: HashMap<String, Inventory>,
+    /// Received from the client thread once loading is finished
+    media: Option<MediaManager>,
+    /// Renders the map image started by export_map_image, returns the
+    /// message to show
+    map_image_export: Option<std::thread::JoinHandle<String>>,
+    /// Shown on the loading screen
+    loading: LoadingProgress,
+    /// When the client started waiting for the first meshes, see
+    /// `update_loading`
+    meshing_since: Option<Instant>,
+    join_info: JoinInfo,
+    chat: Chat,
+    /// The formspec shown by the server, takes all input while open
+    formspec: Option<Formspec>,
+    /// Set by the server, shown when opening the inventory
+    inventory_formspec: String,
+    /// Shown on the disconnect screen once the connection has ended
+    disconnect_reason: Option<DisconnectReason>,
+    audio: Audio,
+    /// Alpha of the red damage flash, 0 to 255, fading out over time
+    damage_flash: f32,
+    /// What the player points at, updated every frame
+    pointed: PointedThing,
+    /// Whether the dig button is held
+    dig_pressed: bool,
+    /// Whether the place button is held
+    place_pressed: bool,
+    gamepads: Gamepads,
+    /// The gamepad state of the last frame, for detecting button presses
+    gamepad_input: GamepadInput,
+    digging: Digging,
+    /// Set by the server to replace the light from the time of day, 0.0
+    /// to 1.0
+    day_night_ratio_override: Option<f32>,
+    /// Media problems to show in the loading report, with the time they
+    /// were received
+    media_issues: Option<(Vec<MediaIssue>, Instant)>,
+    /// Shared with the Lua API, replaced when connecting to a server
+    waypoints: Rc<RefCell<Waypoints>>,
 
     mapblock_texture_data: Option<NodeTextureData>,
     render_pipeline: Option<wgpu::RenderPipeline>,
 
-    remesh_counter_total: u32,
-    remesh_counter: HashMap<I16Vec3, u32>,
-    mapblock_meshes: HashMap<I16Vec3, MapblockMesh>,
+    world: World,
 
     frustum: Frustum,
     frustum_frozen: bool,
+    /// See Settings::disabled_render_passes
+    disabled_render_passes: HashSet<String>,
 
     lua: LuaController,
 }
 
 impl State {
+    const TITLE: &str = "Cubetonic";
+    /// Scale factor for HUD images
+    const HUD_SCALE: f32 = 2.0;
+    /// Font size in pixels
+    const FONT_SIZE: f32 = 20.0;
     const BG_COLOR: Vec3 = Vec3::new(0.262250658, 0.491020850, 0.955973353);
     const VIEW_DISTANCE: f32 = 200.0;
-
-    async fn new(window: Arc<Window>) -> State {
+    /// Fixed timestep used in deterministic mode, in seconds
+    const DETERMINISTIC_DTIME: f32 = 1.0 / 60.0;
+    /// Minimum interval between position updates sent to the server, in seconds
+    const POS_SEND_INTERVAL: f32 = 0.1;
+    /// Interval for sending the position even if it didn't change, in seconds
+    const POS_KEEPALIVE_INTERVAL: f32 = 2.0;
+    /// How long the loading screen waits for the meshes around the player
+    /// at most, e.g. if the server doesn't send the mapblock they're in
+    const MESHING_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Fog distance used while the camera is inside a node with a post effect color.
+    const POST_EFFECT_FOG_DISTANCE: f32 = 16.0;
+    /// Delay before the first reconnection attempt, doubled for every
+    /// further attempt
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+    const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+    /// Handled by the client, never sent to the server
+    // Compare to Luanti, client/game_formspec.cpp, GameFormSpec::showPauseMenu
+    const PAUSE_MENU: &str = "MT_PAUSE_MENU";
+
+    async fn new(window: Arc<Window>, settings: &Settings) -> State {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
 
         let surface = instance.create_surface(window.clone()).unwrap();
@@ -95,19 +317,30 @@ impl State {
             );
         }
 
+        // Optional, for the profiler
+        let timestamps = adapter
+            .features()
+            .features_webgpu
+            .contains(FeaturesWebGPU::TIMESTAMP_QUERY)
+            && avail_features.contains(FeaturesWGPU::TIMESTAMP_QUERY_INSIDE_ENCODERS);
+        let (timestamp_features_wgpu, timestamp_features_webgpu) = if timestamps {
+            (
+                FeaturesWGPU::TIMESTAMP_QUERY_INSIDE_ENCODERS,
+                FeaturesWebGPU::TIMESTAMP_QUERY,
+            )
+        } else {
+            (FeaturesWGPU::empty(), FeaturesWebGPU::empty())
+        };
+
         let mut limits = wgpu::Limits::defaults();
         let the_limit = avail_limits.max_binding_array_elements_per_shader_stage;
         limits.max_binding_array_elements_per_shader_stage = the_limit;
-        println!(
-            "max_binding_array_elements_per_shader_stage = {}",
-            the_limit
-        );
 
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
                 required_features: wgpu::Features {
-                    features_wgpu: bindless_features,
-                    features_webgpu: FeaturesWebGPU::empty(),
+                    features_wgpu: bindless_features | timestamp_features_wgpu,
+                    features_webgpu: timestamp_features_webgpu,
                 },
                 required_limits: limits,
                 ..wgpu::DeviceDescriptor::default()
@@ -117,31 +350,81 @@ impl State {
 
         let size = window.inner_size();
         let cap = surface.get_capabilities(&adapter);
-        let surface_format = cap.formats[0];
+        let surface_format = Self::choose_surface_format(&cap.formats, settings.hdr_output);
+
+        let present_mode = Self::validate_present_mode(settings.present_mode, &cap.present_modes);
+
+        let info = adapter.get_info();
+        println!("GPU info:");
+        println!("    adapter: {} ({:?})", info.name, info.device_type);
+        println!("    backend: {:?}", info.backend);
+        println!("    driver: {} {}", info.driver, info.driver_info);
+        println!(
+            "    max_binding_array_elements_per_shader_stage: {}",
+            the_limit
+        );
+        println!("    timestamp queries: {}", timestamps);
+        println!(
+            "    surface format: {:?} (available: {:?})",
+            surface_format, cap.formats
+        );
 
+        // The far terrain is drawn beyond the view distance
+        let draw_distance = Self::VIEW_DISTANCE.max(settings.farmesh_range as f32);
         let camera = camera::Camera::new(
             &device,
             camera::CameraParams {
                 // These will be overwritten by the CameraController anyway
                 pos: Vec3::ZERO,
                 dir: Vec3::ZERO,
-                fov_y: PI * 0.4,
+                fov_y: settings.fov.to_radians(),
                 size,
                 fog_color: Self::BG_COLOR,
+                fog_end: draw_distance,
                 z_near: 0.1,
-                z_far: Self::VIEW_DISTANCE,
+                z_far: draw_distance,
+                time: 0.0,
+                waving: settings.waving_nodes,
+                daylight: 1.0,
             },
         );
-        let camera_controller = camera_controller::CameraController::new();
+        let camera_controller = camera_controller::CameraController::new(settings);
 
-        let depth_texture = MyTexture::new_depth(&device, size);
+        let render_scale = settings.render_scale;
+        let render_size = Self::scaled_size(size, render_scale);
+        let depth_texture = MyTexture::new_depth(&device, render_size);
+        let post_process = PostProcess::new(
+            &device,
+            render_size,
+            surface_format.add_srgb_suffix(),
+            settings.display_gamma,
+        );
+        let entities = EntityRenderer::new(&device, camera.bind_group_layout());
+        let sky = SkyRenderer::new(&device, &queue, camera.bind_group_layout());
+        let clouds = CloudRenderer::new(&device, camera.bind_group_layout());
+        let farmesh = FarMeshRenderer::new(
+            &device,
+            camera.bind_group_layout(),
+            Self::VIEW_DISTANCE,
+            settings.farmesh_range as f32,
+        );
+        let mut overlay = OverlayRenderer::new(&device, &queue, surface_format.add_srgb_suffix());
+        let text = TextRenderer::new(&device, &queue, &mut overlay, Self::FONT_SIZE);
+        let profiler = Profiler::new(timestamps.then(|| GpuTimer::new(&device, &queue)));
 
-        let (client_tx, main_rx) = mpsc::unbounded_channel();
-        let (main_tx, client_rx) = mpsc::unbounded_channel();
-        LuantiClientRunner::spawn(device.clone(), queue.clone(), main_tx, main_rx).await;
+        let map = Arc::new(RwLock::new(LuantiMap::new()));
+        let (client_tx, client_rx) = Self::idle_channels();
 
         let frustum = Frustum::new(&camera.params);
 
+        let waypoints = Rc::new(RefCell::new(Waypoints::default()));
+        let lua = LuaController::new(
+            map.clone(),
+            waypoints.clone(),
+            settings.trusted_scripts.clone(),
+        )
+        .unwrap();
+
         let state = State {
             window,
             device,
@@ -150,34 +433,314 @@ impl State {
             surface,
             size,
             surface_format,
+            render_scale,
+            present_mode,
+            supported_present_modes: cap.present_modes,
 
             depth_texture,
+            post_process,
+            entities,
+            sky,
+            clouds,
+            farmesh,
+            overlay,
+            text,
 
             camera,
             camera_controller,
+            hotbar: Hotbar::new(settings),
+            wield_images: MediaImages::default(),
+            hud: Hud::new(),
 
             last_frame: Instant::now(),
             last_send: Instant::now(),
+            last_sent_pos: None,
+            next_frame: Instant::now(),
+            fps_max: settings.fps_max,
+            fps_max_unfocused: settings.fps_max_unfocused,
+            deterministic: settings.deterministic,
+            focused: true,
+            occluded: false,
+
+            phase: Phase::Menu,
+            main_menu: MainMenu::new(settings),
+            connect_to: None,
+            exit_requested: false,
 
             client_tx,
             client_rx,
+            connection: None,
+            client_options: ClientOptions::from_settings(settings),
+            reconnect_attempts: 0,
+            reconnect_at: None,
+
+            map,
+            node_def: None,
+            item_def: None,
+            meshgen_pending_tasks: None,
+            meshgen_paused: false,
+            network_stats: None,
+            show_network_stats: false,
+            debug_screen: DebugScreen::default(),
+            profiler,
+            anim_time: 0.0,
+
+            server_address: None,
+            player_list: Vec::new(),
+            show_player_list: false,
+            inventory: Inventory::default(),
+            detached_inventories: HashMap::new(),
+            media: None,
+            map_image_export: None,
+            join_info: JoinInfo::default(),
+            chat: Chat::default(),
+            formspec: None,
+            inventory_formspec: String::new(),
+            disconnect_reason: None,
+            loading: LoadingProgress::default(),
+            meshing_since: None,
+            audio: Audio::new(settings.deterministic),
+            damage_flash: 0.0,
+            pointed: PointedThing::Nothing,
+            dig_pressed: false,
+            place_pressed: false,
+            gamepads: Gamepads::new(settings),
+            gamepad_input: GamepadInput::default(),
+            digging: Digging::default(),
+            day_night_ratio_override: None,
+            media_issues: None,
+            waypoints,
 
             mapblock_texture_data: None,
             render_pipeline: None,
 
-            remesh_counter_total: 0,
-            remesh_counter: HashMap::new(),
-            mapblock_meshes: HashMap::new(),
+            world: World::new(),
 
             frustum,
             frustum_frozen: false,
+            disabled_render_passes: settings.disabled_render_passes.clone(),
 
-            lua: LuaController::new().unwrap(),
+            lua,
         };
         state.configure_surface();
         state
     }
 
+    /// Spawns the client thread, which connects to the server. Returns the
+    /// channels for communicating with it.
+    async fn spawn_client(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        map: &Arc<RwLock<LuantiMap>>,
+        connection: ConnectionConfig,
+        options: ClientOptions,
+    ) -> (
+        mpsc::UnboundedSender<MainToClientEvent>,
+        mpsc::UnboundedReceiver<ClientToMainEvent>,
+    ) {
+        let (client_tx, main_rx) = mpsc::unbounded_channel();
+        let (main_tx, client_rx) = mpsc::unbounded_channel();
+        LuantiClientRunner::spawn(
+            device.clone(),
+            queue.clone(),
+            main_tx,
+            main_rx,
+            map.clone(),
+            connection,
+            options,
+        )
+        .await;
+        (client_tx, client_rx)
+    }
+
+    /// Returns channels without a client thread on the other end, for while
+    /// there's no connection. Replacing the channels of a running client
+    /// thread makes it exit.
+    fn idle_channels() -> (
+        mpsc::UnboundedSender<MainToClientEvent>,
+        mpsc::UnboundedReceiver<ClientToMainEvent>,
+    ) {
+        let (client_tx, _) = mpsc::unbounded_channel();
+        let (_, client_rx) = mpsc::unbounded_channel();
+        (client_tx, client_rx)
+    }
+
+    /// Sends an event to the client thread. After disconnecting, the client
+    /// thread is gone and events are dropped until reconnecting.
+    fn send_to_client(&self, event: MainToClientEvent) {
+        let _ = self.client_tx.send(event);
+    }
+
+    /// Handles the end of the connection: shows the reason and schedules a
+    /// reconnection attempt if the connection was lost or the server asked
+    /// for it.
+    fn disconnected(&mut self, reason: DisconnectReason) {
+        self.left_server();
+
+        // Access denied for other reasons, e.g. a wrong password, would just
+        // happen again
+        if reason.code.is_none() || reason.reconnect {
+            let delay = Self::RECONNECT_DELAY
+                .saturating_mul(1 << self.reconnect_attempts.min(16))
+                .min(Self::RECONNECT_DELAY_MAX);
+            println!("Reconnecting in {:?}", delay);
+            self.reconnect_at = Some(Instant::now() + delay);
+            self.reconnect_attempts += 1;
+        }
+        self.disconnect_reason = Some(reason);
+    }
+
+    /// Stops what belongs to the current server and releases the cursor.
+    /// The world is kept, so it's still visible behind the disconnect
+    /// screen.
+    fn left_server(&mut self) {
+        self.save_farmesh();
+        self.audio.clear();
+        self.world.clear_active_objects();
+        self.server_address = None;
+        self.player_list.clear();
+        self.update_title();
+
+        self.formspec = None;
+        self.chat.close_console();
+        self.set_cursor_grabbed(false);
+    }
+
+    /// Saves the far terrain seen on the current server, if any.
+    fn save_farmesh(&self) {
+        if let Some(node_def) = &self.node_def
+            && let Err(err) = self.farmesh.save(node_def)
+        {
+            println!("Failed to save farmesh columns: {:?}", err);
+        }
+    }
+
+    /// Leaves the server, if connected, and shows the main menu.
+    fn show_main_menu(&mut self) {
+        // Ends the client thread
+        (self.client_tx, self.client_rx) = Self::idle_channels();
+        self.left_server();
+        self.clear_world();
+        self.connection = None;
+        self.reconnect_at = None;
+        self.reconnect_attempts = 0;
+        self.disconnect_reason = None;
+
+        self.phase = Phase::Menu;
+        self.show_formspec("", &self.main_menu.formspec(), "");
+    }
+
+    /// Asks whether to leave the game. Loading and the disconnect screen
+    /// have nothing to continue, so they go back to the main menu directly.
+    // Compare to Luanti, client/game_formspec.cpp, GameFormSpec::showPauseMenu
+    fn show_pause_menu(&mut self) {
+        const FORMSPEC: &str = "size[5,3.5]\
+            button_exit[1,0.5;3,0.5;btn_continue;Continue]\
+            button_exit[1,1.5;3,0.5;btn_exit_menu;Exit to Menu]\
+            button_exit[1,2.5;3,0.5;btn_exit_os;Exit to OS]";
+
+        if self.phase != Phase::InGame || self.disconnect_reason.is_some() {
+            self.show_main_menu();
+            return;
+        }
+        self.show_formspec(Self::PAUSE_MENU, FORMSPEC, "");
+    }
+
+    /// Leaves the loading screen once the mapblock the player is in has been
+    /// meshed and meshgen is idle, so the world doesn't pop in around them.
+    fn update_loading(&mut self) {
+        let Some(since) = self.meshing_since else {
+            return;
+        };
+        if self.phase != Phase::Connecting || self.disconnect_reason.is_some() {
+            return;
+        }
+        let pending_tasks = self
+            .meshgen_pending_tasks
+            .as_ref()
+            .map_or(0, |counter| counter.load(Ordering::Relaxed));
+        let player_node = (self.camera_controller.get_pos().pos + 0.5).floor();
+        let (blockpos, _) = MapNodePos(player_node.as_i16vec3()).split_index();
+        let meshed = self.world.has_mapblock(blockpos.vec()) && pending_tasks == 0;
+        if !meshed && since.elapsed() < Self::MESHING_TIMEOUT {
+            return;
+        }
+
+        self.meshing_since = None;
+        self.phase = Phase::InGame;
+        self.set_cursor_grabbed(true);
+        self.lua.on_connect();
+    }
+
+    /// Whether a scheduled reconnection attempt is due.
+    fn should_reconnect(&self) -> bool {
+        self.reconnect_at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Connects to the same server again.
+    async fn reconnect(&mut self) {
+        if let Some(connection) = self.connection.clone() {
+            self.connect(connection).await;
+        }
+    }
+
+    /// Throws away everything received from the previous connection, if
+    /// any, and connects to a server.
+    async fn connect(&mut self, connection: ConnectionConfig) {
+        self.reconnect_at = None;
+        self.clear_world();
+        self.phase = Phase::Connecting;
+
+        (self.client_tx, self.client_rx) = Self::spawn_client(
+            &self.device,
+            &self.queue,
+            &self.map,
+            connection.clone(),
+            self.client_options.clone(),
+        )
+        .await;
+        self.connection = Some(connection);
+    }
+
+    /// Throws away everything received from the server.
+    fn clear_world(&mut self) {
+        *self.map.write().unwrap() = LuantiMap::new();
+        self.world = World::new();
+        self.mapblock_texture_data = None;
+        self.render_pipeline = None;
+        self.node_def = None;
+        self.item_def = None;
+        self.meshgen_pending_tasks = None;
+        self.media = None;
+        self.loading = LoadingProgress::default();
+        self.meshing_since = None;
+        self.inventory = Inventory::default();
+        self.detached_inventories.clear();
+        self.inventory_formspec.clear();
+        self.hud = Hud::new();
+        self.wield_images = MediaImages::default();
+        self.join_info = JoinInfo::default();
+        self.sky.reset(&self.device, &self.queue);
+        self.clouds.reset();
+        self.farmesh.reset();
+        self.lua.clear_server_scripts();
+        self.day_night_ratio_override = None;
+        self.camera_controller.set_privileges(&HashSet::new());
+        self.camera_controller.set_forced_camera_mode(None);
+        self.camera_controller
+            .set_movement_settings(camera_controller::MovementSettings::default());
+        self.camera_controller
+            .set_eye_offsets(camera_controller::EyeOffsets::default());
+        self.camera_controller.set_server_fov(0.0, false, 0.0);
+        self.camera_controller.set_zoom_fov(0.0);
+        self.camera_controller
+            .set_physics_override(camera_controller::PhysicsOverride::default());
+        self.digging = Digging::default();
+        self.post_process.params.saturation = 1.0;
+        self.post_process.params.exposure = 0.0;
+        self.last_sent_pos = None;
+    }
+
     fn configure_surface(&self) {
         self.surface.configure(
             &self.device,
@@ -187,22 +750,194 @@ impl State {
                 view_formats: vec![self.surface_format.add_srgb_suffix()],
                 width: self.size.width,
                 height: self.size.height,
-                present_mode: wgpu::PresentMode::AutoVsync,
+                present_mode: self.present_mode,
                 alpha_mode: wgpu::CompositeAlphaMode::Auto,
                 desired_maximum_frame_latency: 2,
             },
         );
         println!(
-            "Surface configured, size: {:?}, format: {:?}",
-            self.size, self.surface_format
+            "Surface configured, size: {:?}, format: {:?}, present mode: {:?}",
+            self.size, self.surface_format, self.present_mode
+        );
+    }
+
+    /// Chooses the surface format by preference, instead of whatever the
+    /// platform happens to list first, as the choice affects colors.
+    ///
+    /// Order: 10-bit if `hdr_output` is enabled, then 8-bit sRGB formats,
+    /// then 8-bit formats that can be viewed as sRGB, then the first
+    /// available format. For non-sRGB formats, the post-processing pass
+    /// encodes to sRGB itself.
+    fn choose_surface_format(
+        available: &[wgpu::TextureFormat],
+        hdr_output: bool,
+    ) -> wgpu::TextureFormat {
+        use wgpu::TextureFormat as F;
+
+        let mut preferred = Vec::new();
+        if hdr_output {
+            preferred.push(F::Rgb10a2Unorm);
+        }
+        preferred.extend([
+            F::Bgra8UnormSrgb,
+            F::Rgba8UnormSrgb,
+            F::Bgra8Unorm,
+            F::Rgba8Unorm,
+        ]);
+
+        preferred
+            .into_iter()
+            .find(|format| available.contains(format))
+            .unwrap_or(available[0])
+    }
+
+    /// Returns `wanted` if the surface supports it, AutoVsync otherwise.
+    fn validate_present_mode(
+        wanted: wgpu::PresentMode,
+        supported: &[wgpu::PresentMode],
+    ) -> wgpu::PresentMode {
+        // The Auto* modes are always supported, they fall back internally
+        if matches!(
+            wanted,
+            wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync
+        ) || supported.contains(&wanted)
+        {
+            return wanted;
+        }
+        println!(
+            "Present mode {:?} not supported (supported: {:?}), using AutoVsync",
+            wanted, supported
         );
+        wgpu::PresentMode::AutoVsync
+    }
+
+    /// Pauses or resumes mesh generation, to tell whether frame hitches come
+    /// from meshing or from uploading and rendering.
+    fn toggle_meshgen_paused(&mut self) {
+        self.meshgen_paused = !self.meshgen_paused;
+        self.send_to_client(MainToClientEvent::SetMeshgenPaused(self.meshgen_paused));
+    }
+
+    /// Switches to the next supported present mode at runtime.
+    fn cycle_present_mode(&mut self) {
+        let index = self
+            .supported_present_modes
+            .iter()
+            .position(|mode| *mode == self.present_mode);
+        let next = match index {
+            Some(index) => (index + 1) % self.supported_present_modes.len(),
+            // Currently using an Auto* mode
+            None => 0,
+        };
+        self.present_mode = self.supported_present_modes[next];
+        self.configure_surface();
+    }
+
+    /// Switches to the next node texture filter, for comparing them in game.
+    fn cycle_texture_filter(&mut self) {
+        let filtering = &mut self.client_options.texture_filtering;
+        filtering.filter = filtering.filter.next();
+        println!("Texture filter: {:?}", filtering.filter);
+        if let Some(data) = &mut self.mapblock_texture_data {
+            data.set_filtering(&self.device, *filtering);
+        }
+    }
+
+    /// Exports the loaded mapblock meshes to a .glb file in the `exports`
+    /// subdirectory of the config directory, see gltf_export.
+    fn export_world(&mut self) {
+        let (Some(media), Some(textures)) = (&self.media, &self.mapblock_texture_data) else {
+            return;
+        };
+        let result = Settings::config_dir().and_then(|mut path| {
+            path.push("exports");
+            std::fs::create_dir_all(&path)?;
+            path.push(format!(
+                "world-{}.glb",
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            ));
+            let count = gltf_export::export(&self.world, textures, media, &path)?;
+            Ok((path, count))
+        });
+        let message = match result {
+            Ok((path, count)) => format!("Exported {} mapblocks to {}", count, path.display()),
+            Err(err) => format!("Could not export the world: {}", err),
+        };
+        println!("{}", message);
+        self.chat.add_message(&message);
+    }
+
+    /// Saves a top-down image of the loaded map to a .png file in the
+    /// `exports` subdirectory of the config directory, see map_image.
+    fn export_map_image(&mut self) {
+        let (Some(media), Some(node_def)) = (&self.media, &self.node_def) else {
+            return;
+        };
+        if self.map_image_export.is_some() {
+            self.chat.add_message("The map image is still being saved");
+            return;
+        }
+        // Rendering takes a while, the client thread must not wait for the
+        // map lock meanwhile
+        let snapshot =
+            map_image::MapSnapshot::new(&self.map.read().unwrap(), node_def.clone(), media);
+        self.map_image_export = Some(std::thread::spawn(move || {
+            let result = Settings::config_dir().and_then(|mut path| {
+                path.push("exports");
+                std::fs::create_dir_all(&path)?;
+                path.push(format!(
+                    "map-{}.png",
+                    chrono::Local::now().format("%Y%m%d-%H%M%S")
+                ));
+                let img = map_image::render(&snapshot)?;
+                img.save(&path)?;
+                Ok((path, img.dimensions()))
+            });
+            match result {
+                Ok((path, (width, height))) => {
+                    format!("Saved {}x{} map image to {}", width, height, path.display())
+                }
+                Err(err) => format!("Could not save the map image: {}", err),
+            }
+        }));
+    }
+
+    /// Reports the result of export_map_image once the image is saved.
+    fn poll_map_image_export(&mut self) {
+        if !self
+            .map_image_export
+            .as_ref()
+            .is_some_and(|thread| thread.is_finished())
+        {
+            return;
+        }
+        let message = match self.map_image_export.take().unwrap().join() {
+            Ok(message) => message,
+            Err(_) => String::from("Could not save the map image: the thread panicked"),
+        };
+        println!("{}", message);
+        self.chat.add_message(&message);
+    }
+
+    fn scaled_size(
+        size: winit::dpi::PhysicalSize<u32>,
+        scale: f32,
+    ) -> winit::dpi::PhysicalSize<u32> {
+        winit::dpi::PhysicalSize::new(
+            ((size.width as f32 * scale).round() as u32).max(1),
+            ((size.height as f32 * scale).round() as u32).max(1),
+        )
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.size = new_size;
         self.configure_surface();
 
-        self.depth_texture = MyTexture::new_depth(&self.device, new_size);
+        // The world is rendered at a scaled resolution, the post-processing
+        // pass scales it to the window size.
+        let render_size = Self::scaled_size(new_size, self.render_scale);
+        self.depth_texture = MyTexture::new_depth(&self.device, render_size);
+        self.post_process.resize(&self.device, render_size);
 
         self.camera.params.size = new_size;
         // camera update will happen before rendering either way
@@ -210,21 +945,65 @@ impl State {
 
     fn render(&mut self) {
         let now = Instant::now();
-        let dtime = (now - self.last_frame).as_secs_f32();
+        let dtime = if self.deterministic {
+            Self::DETERMINISTIC_DTIME
+        } else {
+            (now - self.last_frame).as_secs_f32()
+        };
         self.last_frame = now;
+        self.next_frame = now + self.frame_interval();
+        self.anim_time += dtime;
+
+        self.lua
+            .set_camera(self.camera.params.pos, self.camera.params.dir);
+        self.lua.set_player_control(self.player_control());
+        self.lua.step(dtime, self.phase == Phase::InGame);
+        for message in self.lua.take_sent_chat_messages() {
+            self.send_to_client(MainToClientEvent::ChatMessage(message));
+        }
+        for message in self.lua.take_displayed_chat_messages() {
+            self.chat.add_message(&message);
+        }
+
+        self.update_gamepad();
+        let map = self.map.read().unwrap();
+        let world = self.node_def.as_deref().map(|node_def| CollisionWorld {
+            map: &map,
+            node_def,
+        });
+        self.camera_controller
+            .step(dtime, &mut self.camera.params, world.as_ref());
+        drop(map);
+        let removed_sounds = self.audio.step(dtime, self.camera.params.pos, |id| {
+            self.world.object_pos(id)
+        });
+        if !removed_sounds.is_empty() {
+            self.send_to_client(MainToClientEvent::RemovedSounds(removed_sounds));
+        }
+        self.camera.params.time = self.anim_time;
+        self.camera.params.daylight = self
+            .day_night_ratio_override
+            .unwrap_or_else(|| self.sky.day_night_ratio());
+        self.update_post_effect_color();
+        self.update_pointed();
+        self.update_digging(dtime);
+        self.camera.update(&self.queue);
+        self.post_process.update(&self.queue);
 
+        // Only send the position if it changed, plus a keepalive at a lower
+        // rate. We don't send pressed keys yet, so they don't matter here.
         let send_dtime = (now - self.last_send).as_secs_f32();
-        if send_dtime >= 0.1 {
+        if send_dtime >= Self::POS_SEND_INTERVAL {
             let pos = self.camera_controller.get_pos();
-            self.client_tx
-                .send(MainToClientEvent::PlayerPos(pos.clone()))
-                .unwrap();
-            self.last_send = now;
+            if self.last_sent_pos.as_ref() != Some(pos)
+                || send_dtime >= Self::POS_KEEPALIVE_INTERVAL
+            {
+                self.send_to_client(MainToClientEvent::PlayerPos(pos.clone()));
+                self.last_send = now;
+                self.last_sent_pos = Some(pos.clone());
+            }
         }
 
-        self.camera_controller.step(dtime, &mut self.camera.params);
-        self.camera.update(&self.queue);
-
         let mut output = self.surface.get_current_texture();
         // Fixes a crash when pressing F11 (toggle fullscreen) on one of my systems with Wayland
         // TODO: this shouldn't be necessary, winit bug?
@@ -242,236 +1021,1574 @@ impl State {
             ..wgpu::TextureViewDescriptor::default()
         });
 
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
-
-        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                depth_slice: None,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: Self::BG_COLOR.x as f64,
-                        g: Self::BG_COLOR.y as f64,
-                        b: Self::BG_COLOR.z as f64,
-                        a: 1.0,
-                    }),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                view: &self.depth_texture.view,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: wgpu::StoreOp::Store,
-                }),
-                stencil_ops: None,
-            }),
-            ..wgpu::RenderPassDescriptor::default()
-        });
-
-        if self.render_pipeline.is_some() {
-            let render_pipeline = self.render_pipeline.as_ref().unwrap();
-            let mapblock_texture_data = self.mapblock_texture_data.as_ref().unwrap();
-
-            pass.set_pipeline(render_pipeline);
-            pass.set_bind_group(0, self.camera.bind_group(), &[]);
-            pass.set_bind_group(1, &mapblock_texture_data.bind_group, &[]);
+        if !self.frustum_frozen {
+            self.frustum = Frustum::new(&self.camera.params);
+        }
 
-            if !self.frustum_frozen {
-                self.frustum = Frustum::new(&self.camera.params);
+        // The overlay is built on the CPU before recording any passes
+        if self.phase == Phase::InGame {
+            self.draw_hud(dtime);
+        }
+        self.draw_loading_screen();
+        self.draw_network_stats();
+        self.draw_formspec();
+        self.draw_disconnect_screen();
+
+        let (drawn, culled) =
+            self.world
+                .cull(self.camera.params.pos, &self.frustum, Self::VIEW_DISTANCE);
+        let drawlist = self.world.extract_draws();
+        self.draw_debug_screen(drawn, culled);
+        self.profiler.draw(&mut self.overlay, &self.text, self.size);
+        self.world
+            .step_active_objects(dtime, self.camera_controller.get_pos());
+        if let Some(node_def) = &self.node_def {
+            self.world
+                .update_object_light(&self.map.read().unwrap(), node_def);
+        }
+        self.sky.step(dtime);
+        self.clouds.step(dtime);
+        self.clouds.prepare(&self.camera.params);
+        if let Some(media) = &self.media {
+            if let Some(node_def) = &self.node_def {
+                self.farmesh.prepare(
+                    &self.device,
+                    &self.queue,
+                    &self.camera.params,
+                    node_def,
+                    media,
+                );
             }
-            let mut drawlist = Vec::new();
-
-            let mut drawn: u32 = 0;
-            // TODO: drop meshes that are continuously culled for 30s or so
-            let mut culled: u32 = 0;
+            self.sky
+                .prepare(&self.device, &self.queue, media, &self.camera.params);
+            self.entities.prepare(
+                &self.device,
+                &self.queue,
+                media,
+                &self.world,
+                &self.camera.params,
+                self.camera_controller.is_first_person(),
+            );
+        }
+        self.add_crack();
+        if let Some(data) = &mut self.mapblock_texture_data {
+            data.prepare(&self.device);
+        }
 
-            for (_, mesh) in &self.mapblock_meshes {
-                if mesh.num_indices == 0 {
-                    continue;
+        let mut graph = RenderGraph::new();
+
+        graph.add_pass(
+            "world",
+            &[],
+            &[Attachment::SceneColor, Attachment::SceneDepth],
+            |encoder, attachments| {
+                let fog_color = self.camera.params.fog_color;
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("World pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: attachments.view(Attachment::SceneColor),
+                        depth_slice: None,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color {
+                                r: fog_color.x as f64,
+                                g: fog_color.y as f64,
+                                b: fog_color.z as f64,
+                                a: 1.0,
+                            }),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: attachments.view(Attachment::SceneDepth),
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: wgpu::StoreOp::Store,
+                        }),
+                        stencil_ops: None,
+                    }),
+                    ..wgpu::RenderPassDescriptor::default()
+                });
+
+                let (Some(render_pipeline), Some(mapblock_texture_data)) = (
+                    self.render_pipeline.as_ref(),
+                    self.mapblock_texture_data.as_ref(),
+                ) else {
+                    return;
+                };
+
+                pass.set_pipeline(render_pipeline);
+                pass.set_bind_group(0, self.camera.bind_group(), &[]);
+                pass.set_bind_group(1, &mapblock_texture_data.bind_group, &[]);
+                pass.set_bind_group(2, self.farmesh.bind_group(), &[]);
+
+                pass.push_debug_group("Opaque mapblocks");
+                for mesh in drawlist {
+                    pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
                 }
+                pass.pop_debug_group();
+            },
+        );
 
-                let sphere = mesh.bounding_sphere.as_ref().unwrap();
+        graph.add_pass(
+            "farmesh",
+            &[],
+            &[Attachment::SceneColor, Attachment::SceneDepth],
+            |encoder, attachments| {
+                self.farmesh.render(
+                    encoder,
+                    attachments.view(Attachment::SceneColor),
+                    attachments.view(Attachment::SceneDepth),
+                    self.camera.bind_group(),
+                );
+            },
+        );
 
-                // TODO: this filters out some blocks the frustum culling doesn't,
-                // but there are no visible glitches.
-                // is the frustum culling buggy / too conservative?
-                let distance_sq = self.camera.params.pos.distance_squared(sphere.center);
-                let max_distance = Self::VIEW_DISTANCE + sphere.radius;
-                if distance_sq > max_distance * max_distance {
-                    culled += 1;
-                    continue;
-                }
+        graph.add_pass(
+            "sky",
+            &[],
+            &[Attachment::SceneColor, Attachment::SceneDepth],
+            |encoder, attachments| {
+                self.sky.render(
+                    &self.device,
+                    encoder,
+                    attachments.view(Attachment::SceneColor),
+                    attachments.view(Attachment::SceneDepth),
+                    self.camera.bind_group(),
+                );
+            },
+        );
 
-                if !sphere.is_on_frustum(&self.frustum) {
-                    culled += 1;
-                    continue;
-                }
+        graph.add_pass(
+            "clouds",
+            &[],
+            &[Attachment::SceneColor, Attachment::SceneDepth],
+            |encoder, attachments| {
+                self.clouds.render(
+                    &self.device,
+                    encoder,
+                    attachments.view(Attachment::SceneColor),
+                    attachments.view(Attachment::SceneDepth),
+                    self.camera.bind_group(),
+                );
+            },
+        );
 
-                drawn += 1;
-                drawlist.push(mesh);
-            }
+        graph.add_pass(
+            "entities",
+            &[],
+            &[Attachment::SceneColor, Attachment::SceneDepth],
+            |encoder, attachments| {
+                self.entities.render(
+                    &self.device,
+                    encoder,
+                    attachments.view(Attachment::SceneColor),
+                    attachments.view(Attachment::SceneDepth),
+                    self.camera.bind_group(),
+                );
+            },
+        );
 
-            for mesh in drawlist {
-                let index_buffer = mesh.index_buffer.as_ref().unwrap();
-                let vertex_buffer = mesh.vertex_buffer.as_ref().unwrap();
+        graph.add_pass(
+            "post_process",
+            &[Attachment::SceneColor],
+            &[Attachment::Surface],
+            |encoder, attachments| {
+                self.post_process
+                    .render(encoder, attachments.view(Attachment::Surface));
+            },
+        );
 
-                pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-                pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
-            }
+        graph.add_pass(
+            "overlay",
+            &[],
+            &[Attachment::Surface],
+            |encoder, attachments| {
+                self.overlay.render(
+                    &self.device,
+                    &self.queue,
+                    encoder,
+                    attachments.view(Attachment::Surface),
+                    self.size,
+                );
+            },
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Frame command encoder"),
+            });
+        let attachments = Attachments {
+            scene_color: self.post_process.color_view(),
+            scene_depth: &self.depth_texture.view,
+            surface: &view,
+        };
+        let timings = graph.execute(
+            &mut encoder,
+            &attachments,
+            &self.disabled_render_passes,
+            self.profiler.gpu_timer(),
+        );
 
+        if self.render_pipeline.is_some() {
+            let timings: Vec<String> = timings
+                .iter()
+                .map(|(name, time)| format!("{} {:.2}ms", name, time.as_secs_f64() * 1000.0))
+                .collect();
             println!(
-                "dtime: {:.4}; drawn = {}; culled = {}",
-                dtime, drawn, culled
+                "dtime: {:.4}; drawn = {}; culled = {}; passes: {}",
+                dtime,
+                drawn,
+                culled,
+                timings.join(", ")
             );
         }
 
-        drop(pass);
-
         self.queue.submit([encoder.finish()]);
+        self.profiler.end_frame(dtime, &timings);
         self.window.pre_present_notify();
         output.present();
     }
 
-    fn setup_mapblock_rendering(&mut self, data: NodeTextureData) {
-        assert!(self.mapblock_texture_data.is_none());
-        assert!(self.render_pipeline.is_none());
+    /// Draws everything shown on top of the world while playing.
+    fn draw_hud(&mut self, dtime: f32) {
+        if let Some(media) = &self.media {
+            self.hud.load_textures(
+                &self.device,
+                &self.queue,
+                &mut self.overlay,
+                media,
+                &self.inventory,
+            );
+            if let (Some(item_def), Some(list)) = (&self.item_def, self.inventory.list("main")) {
+                self.hotbar.load_textures(
+                    &self.device,
+                    &self.queue,
+                    &mut self.overlay,
+                    media,
+                    item_def,
+                    list,
+                );
+            }
+        }
+        if self.hud.is_visible(HUD_FLAG_CROSSHAIR_VISIBLE) {
+            self.overlay.crosshair(self.size, Self::HUD_SCALE);
+        }
+        if self.hud.is_visible(HUD_FLAG_HOTBAR_VISIBLE) {
+            let (image, selected_image) = self.hud.hotbar_images();
+            self.hotbar.draw(
+                &mut self.overlay,
+                self.size,
+                Self::HUD_SCALE,
+                image,
+                selected_image,
+            );
+            if let (Some(item_def), Some(list)) = (&self.item_def, self.inventory.list("main")) {
+                self.hotbar.draw_items(
+                    &mut self.overlay,
+                    &self.text,
+                    self.size,
+                    Self::HUD_SCALE,
+                    item_def,
+                    list,
+                );
+            }
+        }
+        self.hud.draw(
+            &mut self.overlay,
+            &self.text,
+            &self.camera.params,
+            &self.inventory,
+            self.size,
+            Self::HUD_SCALE,
+        );
+        self.draw_damage_flash(dtime);
+        self.draw_loading_indicator();
+        self.draw_media_issues();
+        self.draw_player_list();
+        self.draw_infotext();
+        self.draw_wield_slots();
+        self.draw_nametags();
+        self.draw_waypoints();
+        self.join_info
+            .draw(&mut self.overlay, &self.text, self.size);
+        if self.hud.is_visible(HUD_FLAG_CHAT_VISIBLE) {
+            self.chat.draw(&mut self.overlay, &self.text, self.size);
+        }
+    }
 
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Mapblock pipeline layout"),
-                bind_group_layouts: &[&self.camera.bind_group_layout(), &data.bind_group_layout],
-                push_constant_ranges: &[],
-            });
+    /// Covers the screen while connecting and loading, showing the
+    /// progress.
+    fn draw_loading_screen(&mut self) {
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.8);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+        const HINT_COLOR: Vec4 = Vec4::new(0.7, 0.7, 0.7, 1.0);
+        const BAR_SIZE: Vec2 = Vec2::new(400.0, 8.0);
+        const BAR_BACKGROUND_COLOR: Vec4 = Vec4::new(0.3, 0.3, 0.3, 1.0);
+        const BAR_COLOR: Vec4 = Vec4::new(0.3, 0.6, 1.0, 1.0);
+
+        // The disconnect screen replaces it
+        if self.phase != Phase::Connecting || self.disconnect_reason.is_some() {
+            return;
+        }
+        let screen = Vec2::new(self.size.width as f32, self.size.height as f32);
+        self.overlay.rect(Vec2::ZERO, screen, BACKGROUND_COLOR);
+
+        let title = match &self.connection {
+            Some(connection) => format!(
+                "Connecting to {}:{}...",
+                connection.address, connection.port
+            ),
+            None => String::from("Connecting..."),
+        };
+        let pending_tasks = self
+            .meshgen_pending_tasks
+            .as_ref()
+            .map_or(0, |counter| counter.load(Ordering::Relaxed));
+        let progress = self
+            .loading
+            .lines(self.world.mapblock_count(), pending_tasks);
+        let fraction = self.loading.stage_fraction();
+
+        let mut lines = vec![(title, TEXT_COLOR)];
+        lines.extend(progress.into_iter().map(|line| (line, HINT_COLOR)));
+        let line_height = self.text.line_height();
+        // The bar takes up a line below the progress, empty if there's none
+        let height = (lines.len() + 2) as f32 * line_height;
+        let mut y = (screen.y - height) / 2.0;
+        for (line, color) in &lines {
+            let x = (screen.x - self.text.width(line)) / 2.0;
+            self.text
+                .draw(&mut self.overlay, line, Vec2::new(x, y), *color);
+            y += line_height;
+        }
 
-        let shader = self
-            .device
-            .create_shader_module(wgpu::include_wgsl!("mapblock_shader.wgsl"));
+        if let Some(fraction) = fraction {
+            let min = Vec2::new(
+                (screen.x - BAR_SIZE.x) / 2.0,
+                y + (line_height - BAR_SIZE.y) / 2.0,
+            );
+            self.overlay.rect(min, min + BAR_SIZE, BAR_BACKGROUND_COLOR);
+            self.overlay.rect(
+                min,
+                min + Vec2::new(BAR_SIZE.x * fraction, BAR_SIZE.y),
+                BAR_COLOR,
+            );
+        }
+        y += line_height;
 
-        let render_pipeline = self
-            .device
-            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("Mapblock render pipeline"),
-                layout: Some(&pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: Some("vs_main"),
-                    compilation_options: wgpu::PipelineCompilationOptions::default(),
-                    buffers: &[meshgen::Vertex::layout()],
-                },
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    // Irrlicht's fault
-                    front_face: wgpu::FrontFace::Cw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    ..wgpu::PrimitiveState::default()
-                },
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: MyTexture::DEPTH_FORMAT,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                }),
-                multisample: wgpu::MultisampleState::default(),
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: Some("fs_main"),
-                    compilation_options: wgpu::PipelineCompilationOptions::default(),
-                    targets: &[Some(wgpu::ColorTargetState {
-                        format: self.surface_format,
-                        blend: Some(wgpu::BlendState::REPLACE),
-                        write_mask: wgpu::ColorWrites::ALL,
-                    })],
-                }),
-                multiview: None,
-                cache: None,
-            });
+        let hint = "Press Escape to cancel.";
+        let x = (screen.x - self.text.width(hint)) / 2.0;
+        self.text
+            .draw(&mut self.overlay, hint, Vec2::new(x, y), HINT_COLOR);
+    }
 
-        self.mapblock_texture_data = Some(data);
-        self.render_pipeline = Some(render_pipeline);
+    /// Returns the minimum time between the start of two frames, based on the
+    /// frame rate limits.
+    fn frame_interval(&self) -> Duration {
+        // Deterministic mode doesn't adapt to the window state
+        let fps_max = if self.deterministic || (self.focused && !self.occluded) {
+            self.fps_max
+        } else {
+            self.fps_max_unfocused
+        };
+        if fps_max == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(1.0 / fps_max as f64)
+        }
     }
 
-    fn insert_mapblock_mesh(&mut self, mesh: MapblockMesh) {
-        assert!(self.mapblock_texture_data.is_some());
-        assert!(self.render_pipeline.is_some());
+    /// Draws a small indicator in the bottom left corner while mapblocks are
+    /// still waiting for meshgen, so incomplete terrain can be told apart from
+    /// rendering bugs. The bars show the number of unfinished meshgen tasks
+    /// and of mapblocks that haven't got their first mesh yet.
+    fn draw_loading_indicator(&mut self) {
+        const MARGIN: f32 = 16.0;
+        const DOT_SIZE: f32 = 8.0;
+        const BAR_HEIGHT: f32 = 6.0;
+        const BAR_SPACING: f32 = 4.0;
+        // Bar length in pixels per doubling of the count
+        const BAR_UNIT: f32 = 24.0;
+        const TASKS_COLOR: Vec4 = Vec4::new(1.0, 0.5, 0.05, 0.9);
+        const BLOCKS_COLOR: Vec4 = Vec4::new(0.2, 0.5, 1.0, 0.9);
+
+        let pending_tasks = self
+            .meshgen_pending_tasks
+            .as_ref()
+            .map_or(0, |counter| counter.load(Ordering::Relaxed));
+        let pending_blocks = self
+            .map
+            .read()
+            .unwrap()
+            .block_count()
+            .saturating_sub(self.world.mapblock_count());
+        if pending_tasks == 0 && pending_blocks == 0 && !self.meshgen_paused {
+            return;
+        }
 
-        self.remesh_counter_total += 1;
+        let origin = Vec2::new(MARGIN, self.size.height as f32 - MARGIN);
 
-        let counter = self.remesh_counter.entry(mesh.blockpos.vec()).or_insert(0);
-        *counter += 1;
+        // Logarithmic length so both small and large counts are visible
+        for (row, (count, color)) in [(pending_tasks, TASKS_COLOR), (pending_blocks, BLOCKS_COLOR)]
+            .into_iter()
+            .enumerate()
+        {
+            let length = (count as f32 + 1.0).log2() * BAR_UNIT;
+            let y = origin.y - (row + 1) as f32 * (BAR_HEIGHT + BAR_SPACING);
+            self.overlay.rect(
+                Vec2::new(origin.x, y),
+                Vec2::new(origin.x + length, y + BAR_HEIGHT),
+                color,
+            );
+        }
 
-        let prev_mesh = self.mapblock_meshes.get_mut(&mesh.blockpos.vec());
+        // Pulsing dots, so it's obvious that things are still happening
+        let dots_y = origin.y - 2.0 * (BAR_HEIGHT + BAR_SPACING) - DOT_SIZE - BAR_SPACING;
+        for index in 0..3 {
+            let phase = self.anim_time * 4.0 - index as f32 * 0.8;
+            let alpha = 0.3 + 0.7 * phase.sin().max(0.0);
+            let min = Vec2::new(origin.x + index as f32 * (DOT_SIZE + BAR_SPACING), dots_y);
+            self.overlay
+                .rect(min, min + DOT_SIZE, Vec4::new(1.0, 1.0, 1.0, alpha));
+        }
+
+        if self.meshgen_paused {
+            let text_y = dots_y - BAR_SPACING - self.text.line_height();
+            self.text.draw(
+                &mut self.overlay,
+                "Meshgen paused (F6: resume, F7: flush)",
+                Vec2::new(origin.x, text_y),
+                TASKS_COLOR,
+            );
+        }
+    }
+
+    /// Lists media files that had problems in the top left corner for a
+    /// while after loading, so missing textures don't go unnoticed.
+    fn draw_media_issues(&mut self) {
+        const DURATION: Duration = Duration::from_secs(30);
+        const MAX_LINES: usize = 5;
+        const MARGIN: f32 = 16.0;
+        const PADDING: f32 = 8.0;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+        const TEXT_COLOR: Vec4 = Vec4::new(1.0, 0.8, 0.3, 1.0);
+
+        let Some((issues, time)) = &self.media_issues else {
+            return;
+        };
+        if time.elapsed() > DURATION {
+            self.media_issues = None;
+            return;
+        }
+
+        let mut lines = vec![format!(
+            "{} media file(s) had problems, see the log:",
+            issues.len()
+        )];
+        lines.extend(issues.iter().take(MAX_LINES).map(|issue| issue.to_string()));
+        if issues.len() > MAX_LINES {
+            lines.push(format!("... and {} more", issues.len() - MAX_LINES));
+        }
+
+        let width = lines
+            .iter()
+            .map(|line| self.text.width(line))
+            .fold(0.0, f32::max);
+        let min = Vec2::splat(MARGIN);
+        let size = Vec2::new(
+            width + 2.0 * PADDING,
+            lines.len() as f32 * self.text.line_height() + 2.0 * PADDING,
+        );
+        self.overlay.rect(min, min + size, BACKGROUND_COLOR);
+        for (index, line) in lines.iter().enumerate() {
+            let pos = min + PADDING + Vec2::new(0.0, index as f32 * self.text.line_height());
+            self.text.draw(&mut self.overlay, line, pos, TEXT_COLOR);
+        }
+    }
+
+    /// Shows the network statistics in the top right corner, to tell
+    /// whether stutter comes from the network or the renderer.
+    fn draw_network_stats(&mut self) {
+        const MARGIN: f32 = 16.0;
+        const PADDING: f32 = 8.0;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+
+        if !self.show_network_stats {
+            return;
+        }
+        let lines = match &self.network_stats {
+            Some(stats) => vec![
+                match stats.rtt {
+                    Some(rtt) => format!("RTT: {:.0} ms", rtt.as_secs_f64() * 1000.0),
+                    None => String::from("RTT: unknown"),
+                },
+                format!(
+                    "Received: {:.1} packets/s, {:.1} KiB/s",
+                    stats.packets_received_per_sec,
+                    stats.bytes_received_per_sec / 1024.0
+                ),
+                format!(
+                    "Sent: {:.1} packets/s, {:.1} KiB/s",
+                    stats.packets_sent_per_sec,
+                    stats.bytes_sent_per_sec / 1024.0
+                ),
+            ],
+            None => vec![String::from("No network statistics yet")],
+        };
+
+        let width = lines
+            .iter()
+            .map(|line| self.text.width(line))
+            .fold(0.0, f32::max);
+        let size = Vec2::new(
+            width + 2.0 * PADDING,
+            lines.len() as f32 * self.text.line_height() + 2.0 * PADDING,
+        );
+        let min = Vec2::new(self.size.width as f32 - MARGIN - size.x, MARGIN);
+        self.overlay.rect(min, min + size, BACKGROUND_COLOR);
+        for (index, line) in lines.iter().enumerate() {
+            let pos = min + PADDING + Vec2::new(0.0, index as f32 * self.text.line_height());
+            self.text.draw(&mut self.overlay, line, pos, TEXT_COLOR);
+        }
+    }
+
+    /// Shows the F3 debug screen, with statistics about the frame rate,
+    /// the camera and the world. `drawn` and `culled` are the mapblock
+    /// counts from culling.
+    fn draw_debug_screen(&mut self, drawn: u32, culled: u32) {
+        if !self.debug_screen.visible() {
+            return;
+        }
+
+        let pos = self.camera.params.pos;
+        let player = self.camera_controller.get_pos();
+        let pointed = match &self.pointed {
+            PointedThing::Nothing => String::from("nothing"),
+            PointedThing::Node { under, .. } => {
+                let node = self.map.read().unwrap().get_node(under);
+                match (node, &self.node_def) {
+                    (Some(node), Some(node_def)) => format!(
+                        "{} at {}",
+                        node_def.get_with_fallback(node.content_id).name,
+                        under.0
+                    ),
+                    _ => format!("unknown node at {}", under.0),
+                }
+            }
+            PointedThing::Object { id } => format!("object {}", id),
+        };
+        let pending_tasks = self
+            .meshgen_pending_tasks
+            .as_ref()
+            .map_or(0, |counter| counter.load(Ordering::Relaxed));
+
+        let mut lines = vec![format!(
+            "FPS: {:.0}, max frame time: {:.1} ms",
+            self.profiler.fps(),
+            self.profiler.max_frame_time() * 1000.0
+        )];
+        // Compare to Luanti, client/gameui.cpp, GameUI::update, show_basic_debug
+        if self.hud.is_visible(HUD_FLAG_BASIC_DEBUG) {
+            lines.extend([
+                format!("Camera: ({:.1}, {:.1}, {:.1})", pos.x, pos.y, pos.z),
+                format!("Yaw: {:.1}, pitch: {:.1}", player.yaw, player.pitch),
+                format!("Pointed: {}", pointed),
+            ]);
+        }
+        lines.extend([
+            format!(
+                "Blocks loaded: {}, meshes: {}",
+                self.map.read().unwrap().block_count(),
+                self.world.mapblock_count()
+            ),
+            format!("Meshes drawn: {}, culled: {}", drawn, culled),
+            format!("Vertices: {}", self.world.vertex_count()),
+            format!(
+                "Remeshes: {}, meshgen tasks: {}",
+                self.world.remesh_count_total, pending_tasks
+            ),
+        ]);
+        self.debug_screen
+            .draw(&mut self.overlay, &self.text, &lines, &self.profiler);
+    }
+
+    /// Draws the names of all connected players at the top of the screen,
+    /// in columns if there are many.
+    fn draw_player_list(&mut self) {
+        const MARGIN: f32 = 16.0;
+        const PADDING: f32 = 8.0;
+        const COLUMN_GAP: f32 = 24.0;
+        const MAX_ROWS: usize = 20;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+        const TITLE_COLOR: Vec4 = Vec4::new(1.0, 1.0, 0.5, 1.0);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+
+        if !self.show_player_list {
+            return;
+        }
+
+        let title = format!("Players online: {}", self.player_list.len());
+        let line_height = self.text.line_height();
+        let columns: Vec<&[String]> = self.player_list.chunks(MAX_ROWS).collect();
+        let column_widths: Vec<f32> = columns
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|name| self.text.width(name))
+                    .fold(0.0, f32::max)
+            })
+            .collect();
+        let names_width = column_widths.iter().sum::<f32>()
+            + COLUMN_GAP * column_widths.len().saturating_sub(1) as f32;
+        let rows = columns.first().map_or(0, |column| column.len());
+
+        let size = Vec2::new(
+            names_width.max(self.text.width(&title)) + 2.0 * PADDING,
+            (rows + 1) as f32 * line_height + 2.0 * PADDING,
+        );
+        let min = Vec2::new((self.size.width as f32 - size.x) / 2.0, MARGIN).round();
+        self.overlay.rect(min, min + size, BACKGROUND_COLOR);
+        self.text
+            .draw(&mut self.overlay, &title, min + PADDING, TITLE_COLOR);
+
+        let mut x = min.x + PADDING;
+        for (column, width) in columns.iter().zip(&column_widths) {
+            for (row, name) in column.iter().enumerate() {
+                let pos = Vec2::new(x, min.y + PADDING + (row + 1) as f32 * line_height);
+                self.text.draw(&mut self.overlay, name, pos, TEXT_COLOR);
+            }
+            x += width + COLUMN_GAP;
+        }
+    }
+
+    /// Draws the wield slots in the bottom corners, main hand on the right,
+    /// offhand on the left, with the item's inventory image and count like in
+    /// the hotbar. Only slots that hold an item are drawn.
+    fn draw_wield_slots(&mut self) {
+        const SLOT_SIZE: f32 = 32.0;
+        const MARGIN: f32 = 16.0;
+        const BORDER: f32 = 2.0;
+        const BORDER_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.6);
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.5);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+
+        let wield = self.lua.wield();
+        let slot_size = SLOT_SIZE * Self::HUD_SCALE;
+        let y = self.size.height as f32 - MARGIN - slot_size;
+
+        for hand in [WieldHand::Main, WieldHand::Offhand] {
+            let Some(item) = &wield.slot(hand).item else {
+                continue;
+            };
+            if hand == WieldHand::Main && !self.hud.is_visible(HUD_FLAG_WIELDITEM_VISIBLE) {
+                continue;
+            }
+            let x = match hand {
+                WieldHand::Main => self.size.width as f32 - MARGIN - slot_size,
+                // Leave room for the loading indicator
+                WieldHand::Offhand => MARGIN + slot_size,
+            };
+            let min = Vec2::new(x, y);
+            let max = min + slot_size;
+            self.overlay.rect(min, max, BORDER_COLOR);
+            self.overlay
+                .rect(min + BORDER, max - BORDER, BACKGROUND_COLOR);
+
+            // Checked when set from Lua
+            let Ok(stack) = ItemStack::parse(item) else {
+                continue;
+            };
+            if let (Some(media), Some(item_def)) = (&self.media, &self.item_def)
+                && let Some(image) = item_def.inventory_image(&stack.name)
+            {
+                self.wield_images
+                    .load(&self.device, &self.queue, &mut self.overlay, media, image);
+                if let Some(image) = self.wield_images.get(image) {
+                    self.overlay.image(
+                        image.id,
+                        min + BORDER,
+                        max - BORDER,
+                        Vec2::ZERO,
+                        Vec2::ONE,
+                        Vec4::ONE,
+                    );
+                }
+            }
+            if stack.count > 1 {
+                let count = stack.count.to_string();
+                let text_pos = max - Vec2::new(self.text.width(&count), self.text.line_height());
+                self.text
+                    .draw(&mut self.overlay, &count, text_pos, TEXT_COLOR);
+            }
+        }
+    }
+
+    // Compare to Luanti, client/clientpackethandler.cpp, handleCommand_HudSetParam
+    fn set_hud_param(&mut self, param: HudSetParam) {
+        match param {
+            HudSetParam::SetHotBarItemCount(count) => {
+                if self.hotbar.set_item_count(count) {
+                    self.send_to_client(MainToClientEvent::PlayerItem(self.hotbar.selected()));
+                    self.update_main_hand();
+                }
+            }
+            HudSetParam::SetHotBarImage(name) => self.hud.hotbar_image = name,
+            HudSetParam::SetHotBarSelectedImage(name) => self.hud.hotbar_selected_image = name,
+        }
+    }
+
+    /// Makes the main hand wield the item in the selected hotbar slot.
+    // Compare to Luanti, client/client.cpp, Client::getPlayerItem
+    fn update_main_hand(&mut self) {
+        let item = self.wielded_item().map(ToString::to_string);
+        self.lua.set_wielded_item(WieldHand::Main, item);
+    }
+
+    /// Shows a formspec, replacing the current one. The cursor is released
+    /// so the player can click its elements. `context` is the inventory
+    /// location of lists in "context", see Formspec::parse.
+    // Compare to Luanti, client/game.cpp, Game::handleClientEvent_ShowFormSpec
+    fn show_formspec(&mut self, name: &str, formspec: &str, context: &str) {
+        // An empty formspec closes the formspec with the same name
+        if formspec.is_empty() {
+            if self
+                .formspec
+                .as_ref()
+                .is_some_and(|current| current.name == name)
+            {
+                self.close_formspec();
+            }
+            return;
+        }
+
+        self.formspec = Some(Formspec::parse(name, formspec, context));
+        self.set_cursor_grabbed(false);
+    }
+
+    /// Shows the "formspec" metadata field of a node, e.g. a chest.
+    // Compare to Luanti, client/game.cpp, Game::handlePointingAtNode
+    fn show_node_formspec(&mut self, pos: MapNodePos, formspec: &str) {
+        let context = format!("nodemeta:{},{},{}", pos.0.x, pos.0.y, pos.0.z);
+        self.show_formspec("", formspec, &context);
+        if let Some(formspec) = &mut self.formspec {
+            formspec.node = Some(pos);
+        }
+    }
+
+    /// Opens the player inventory, using a minimal layout with the main
+    /// and crafting lists if the server didn't set a formspec.
+    // Compare to Luanti, client/game.cpp, Game::openInventory
+    fn open_inventory(&mut self) {
+        const FALLBACK_FORMSPEC: &str = "size[8,7.5]\
+            list[current_player;craft;2,0;3,3;]\
+            list[current_player;craftpreview;6,1;1,1;]\
+            list[current_player;main;0,3.5;8,4;]";
+
+        if self.formspec.is_some()
+            || self.disconnect_reason.is_some()
+            || self.phase != Phase::InGame
+        {
+            return;
+        }
+        let formspec = if self.inventory_formspec.is_empty() {
+            String::from(FALLBACK_FORMSPEC)
+        } else {
+            self.inventory_formspec.clone()
+        };
+        // The player inventory is the formspec without a name
+        self.show_formspec("", &formspec, "current_player");
+    }
+
+    fn close_formspec(&mut self) {
+        self.formspec = None;
+        self.set_cursor_grabbed(true);
+    }
+
+    /// Locks and hides the cursor for looking around, or releases it for
+    /// clicking on UI elements.
+    fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        if !grabbed {
+            self.camera_controller.discard_mouse_delta();
+        }
+        self.window.set_cursor_visible(!grabbed);
+        if grabbed {
+            if let Err(err) = self.window.set_cursor_grab(CursorGrabMode::Locked) {
+                println!("Could not lock cursor: {:?}", err);
+            }
+        } else if let Err(err) = self.window.set_cursor_grab(CursorGrabMode::None) {
+            println!("Could not release cursor: {:?}", err);
+        }
+    }
+
+    /// Opens the chat console, see Chat. Not while the server hides the
+    /// chat.
+    fn open_console(&mut self) {
+        if self.formspec.is_some()
+            || self.disconnect_reason.is_some()
+            || self.phase != Phase::InGame
+            || !self.hud.is_visible(HUD_FLAG_CHAT_VISIBLE)
+        {
+            return;
+        }
+        self.chat.open_console();
+        self.dig_pressed = false;
+        self.place_pressed = false;
+        self.set_cursor_grabbed(false);
+    }
+
+    /// Passes a window event to the open chat console, grabbing the cursor
+    /// again once it's closed.
+    fn process_console_event(&mut self, event: &WindowEvent) {
+        self.chat.process_window_event(event);
+        if !self.chat.console_open() {
+            self.set_cursor_grabbed(true);
+        }
+    }
+
+    /// Covers the screen with the reason the connection ended, like the
+    /// error dialog Luanti shows in the main menu.
+    fn draw_disconnect_screen(&mut self) {
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.8);
+        const TITLE_COLOR: Vec4 = Vec4::new(1.0, 0.3, 0.3, 1.0);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+        const HINT_COLOR: Vec4 = Vec4::new(0.7, 0.7, 0.7, 1.0);
+
+        let Some(reason) = &self.disconnect_reason else {
+            return;
+        };
+        let screen = Vec2::new(self.size.width as f32, self.size.height as f32);
+        self.overlay.rect(Vec2::ZERO, screen, BACKGROUND_COLOR);
+
+        let title = match reason.code {
+            Some(_) => "Access denied",
+            None => "Disconnected",
+        };
+        let hint = match self.reconnect_at {
+            Some(at) => format!(
+                "Reconnecting in {} s (attempt {}). Press Escape to return to the main menu.",
+                at.saturating_duration_since(Instant::now())
+                    .as_secs_f32()
+                    .ceil(),
+                self.reconnect_attempts
+            ),
+            None if self.server_address.is_some() => String::from("Reconnecting..."),
+            None => String::from("Press Escape to return to the main menu."),
+        };
+        let message = text::strip_escapes(&reason.message);
+        let mut lines = vec![(title, TITLE_COLOR)];
+        lines.extend(message.lines().map(|line| (line, TEXT_COLOR)));
+        lines.push((hint.as_str(), HINT_COLOR));
+
+        let line_height = self.text.line_height();
+        let mut y = (screen.y - lines.len() as f32 * line_height) / 2.0;
+        for (line, color) in lines {
+            let x = (screen.x - self.text.width(line)) / 2.0;
+            self.text
+                .draw(&mut self.overlay, line, Vec2::new(x, y), color);
+            y += line_height;
+        }
+    }
+
+    /// Passes a window event to the open formspec and sends the resulting
+    /// fields or inventory action to the server.
+    fn process_formspec_event(&mut self, event: &WindowEvent) {
+        let Some(formspec) = &mut self.formspec else {
+            return;
+        };
+        let map = self.map.read().unwrap();
+        let inventories = Inventories {
+            player: &self.inventory,
+            detached: &self.detached_inventories,
+            node_metadata: map.node_metadata(),
+        };
+        let event = formspec.process_window_event(event, &inventories);
+        drop(map);
+        let Some(event) = event else {
+            return;
+        };
+        if self.phase == Phase::Menu {
+            self.process_main_menu_event(event);
+            return;
+        }
+        if formspec.name == Self::PAUSE_MENU {
+            self.process_pause_menu_event(event);
+            return;
+        }
+        let formname = formspec.name.clone();
+        let node = formspec.node.as_ref().map(|pos| MapNodePos(pos.0));
+        let fields_event = |fields| match node {
+            Some(pos) => MainToClientEvent::NodemetaFields {
+                pos,
+                formname,
+                fields,
+            },
+            None => MainToClientEvent::InventoryFields { formname, fields },
+        };
+
+        match event {
+            FormspecEvent::Fields(fields) => self.send_to_client(fields_event(fields)),
+            FormspecEvent::Close(fields) => {
+                self.send_to_client(fields_event(fields));
+                self.close_formspec();
+            }
+            FormspecEvent::InventoryAction(action) => {
+                self.send_to_client(MainToClientEvent::InventoryAction(action))
+            }
+        }
+    }
+
+    /// Handles the fields of the main menu formspec. It's shown again after
+    /// closing, e.g. with Escape, since there's nothing behind it.
+    fn process_main_menu_event(&mut self, event: FormspecEvent) {
+        let fields = match event {
+            FormspecEvent::Fields(fields) | FormspecEvent::Close(fields) => fields,
+            FormspecEvent::InventoryAction(_) => return,
+        };
+        match self.main_menu.process_fields(&fields) {
+            Some(MainMenuAction::Connect(connection)) => {
+                self.close_formspec();
+                self.connect_to = Some(connection);
+            }
+            Some(MainMenuAction::Quit) => self.exit_requested = true,
+            None => self.show_formspec("", &self.main_menu.formspec(), ""),
+        }
+    }
 
-        if let Some(prev_mesh) = prev_mesh {
-            // A meshgen task for the same mapblock might have started
-            // later, but finished earlier than this one.
-            // Don't replace the new data with our outdated data in that case.
-            if mesh.timestamp_task_spawned > prev_mesh.timestamp_task_spawned {
-                /*
-                println!(
-                    "Received mapblock mesh for {} [UPDATED] [#{}]",
-                    mesh.blockpos.vec(),
-                    counter,
+    /// Handles the buttons of the pause menu. All of them close it.
+    fn process_pause_menu_event(&mut self, event: FormspecEvent) {
+        let FormspecEvent::Close(fields) = event else {
+            return;
+        };
+        self.close_formspec();
+        let pressed = |name: &str| fields.iter().any(|(field, _)| field == name);
+        if pressed("btn_exit_menu") {
+            self.show_main_menu();
+        } else if pressed("btn_exit_os") {
+            self.exit_requested = true;
+        }
+    }
+
+    /// Updates the health shown in the HUD. A decrease flashes the screen
+    /// red and plays the damage sound, unless the server disabled the effect.
+    // Compare to Luanti, client/game.cpp, Game::handleClientEvent_PlayerDamage
+    fn set_hp(&mut self, hp: u16, damage_effect: bool) {
+        let old_hp = self.hud.hp.replace(hp);
+        let Some(old_hp) = old_hp else {
+            return;
+        };
+        if hp >= old_hp || !damage_effect {
+            return;
+        }
+
+        let amount = (old_hp - hp) as f32;
+        self.damage_flash = (self.damage_flash + 95.0 + 3.2 * amount).min(127.0);
+        // Compare to Luanti, client/sound/sound_maker.h, SoundMaker::playerDamage
+        if let Some(media) = &self.media
+            && let Err(err) =
+                self.audio
+                    .play_local(media, "player_damage", 0.5, SoundLocation::Local)
+        {
+            println!("Error while playing sound: {:?}", err);
+        }
+    }
+
+    /// Applies the zoom FOV of the local player's object, which the server
+    /// sends with its properties.
+    fn update_zoom_fov(&mut self) {
+        if let Some(zoom_fov) = self.world.local_player_zoom_fov() {
+            self.camera_controller.set_zoom_fov(zoom_fov);
+        }
+    }
+
+    /// Applies the physics override of the local player's object, which the
+    /// server sends with its initialization data and when mods change it.
+    fn update_physics_override(&mut self) {
+        if let Some(physics_override) = self.world.local_player_physics_override() {
+            self.camera_controller
+                .set_physics_override(physics_override);
+        }
+    }
+
+    /// Plays the damage sound of players at their position. Entities have
+    /// no damage sound on the client, mods play them from the server.
+    // Compare to Luanti, client/sound/sound_maker.h, SoundMaker::playerDamage
+    fn object_damaged(&mut self, id: u16, damage: Damage) {
+        if !damage.is_player {
+            return;
+        }
+        if let Some(media) = &self.media
+            && let Some(pos) = self.world.object_pos(id)
+            && let Err(err) =
+                self.audio
+                    .play_local(media, "player_damage", 0.5, SoundLocation::Pos(pos))
+        {
+            println!("Error while playing sound: {:?}", err);
+        }
+    }
+
+    fn draw_damage_flash(&mut self, dtime: f32) {
+        if self.damage_flash <= 0.0 {
+            return;
+        }
+        let color = Vec4::new(srgb_to_linear(180), 0.0, 0.0, self.damage_flash / 255.0);
+        let screen = Vec2::new(self.size.width as f32, self.size.height as f32);
+        self.overlay.rect(Vec2::ZERO, screen, color);
+        self.damage_flash -= 384.0 * dtime;
+    }
+
+    fn draw_formspec(&mut self) {
+        let Some(formspec) = &mut self.formspec else {
+            return;
+        };
+        let map = self.map.read().unwrap();
+        let inventories = Inventories {
+            player: &self.inventory,
+            detached: &self.detached_inventories,
+            node_metadata: map.node_metadata(),
+        };
+        let item_def = self.item_def.as_deref();
+        if let Some(media) = &self.media {
+            formspec.load_textures(
+                &self.device,
+                &self.queue,
+                &mut self.overlay,
+                media,
+                &inventories,
+                item_def,
+            );
+        }
+        formspec.draw(
+            &mut self.overlay,
+            &self.text,
+            &inventories,
+            item_def,
+            self.size,
+        );
+    }
+
+    /// Drops a waypoint at the current position.
+    fn add_waypoint(&mut self) {
+        let mut waypoints = self.waypoints.borrow_mut();
+        let name = waypoints.unused_name();
+        let pos = self.camera_controller.get_pos().pos;
+        println!("Adding waypoint \"{}\" at {}", name, pos);
+        if let Err(err) = waypoints.set(name, pos) {
+            println!("Failed to save waypoints: {:?}", err);
+        }
+    }
+
+    /// Draws the nametags of objects above them, fading out with the
+    /// distance.
+    // Compare to Luanti, client/camera.cpp, Camera::drawNametags
+    fn draw_nametags(&mut self) {
+        /// Distance where nametags start fading out, in nodes
+        const FADE_START: f32 = 16.0;
+        const MAX_DISTANCE: f32 = 48.0;
+        const PADDING: f32 = 2.0;
+
+        let camera_pos = self.camera.params.pos;
+        let mut objects = self.world.ecs.query::<&ActiveObject>();
+        let mut nametags: Vec<(f32, Nametag)> = objects
+            .iter()
+            .filter_map(|(_, object)| object.nametag())
+            .map(|nametag| (nametag.pos.distance(camera_pos), nametag))
+            .filter(|(distance, _)| *distance < MAX_DISTANCE)
+            .collect();
+        // Farthest first, so closer nametags are drawn on top
+        nametags.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let line_height = self.text.line_height();
+        for (distance, nametag) in nametags {
+            let Some(anchor) = self.camera.params.world_to_screen(nametag.pos) else {
+                continue;
+            };
+            let anchor = anchor.round();
+            let alpha = ((MAX_DISTANCE - distance) / (MAX_DISTANCE - FADE_START)).min(1.0);
+
+            let lines: Vec<(Vec<(String, Vec4)>, f32)> = nametag
+                .text
+                .lines()
+                .map(|line| {
+                    let runs = text::colorize(line, nametag.color);
+                    let width = runs.iter().map(|(run, _)| self.text.width(run)).sum();
+                    (runs, width)
+                })
+                .collect();
+            let width = lines.iter().map(|(_, width)| *width).fold(0.0, f32::max);
+            let height = lines.len() as f32 * line_height;
+            let min = anchor - Vec2::new((width / 2.0).round(), height);
+            self.overlay.rect(
+                min - PADDING,
+                min + Vec2::new(width, height) + PADDING,
+                nametag.background.with_w(nametag.background.w * alpha),
+            );
+            for (index, (runs, line_width)) in lines.iter().enumerate() {
+                let pos = Vec2::new(
+                    (anchor.x - line_width / 2.0).round(),
+                    min.y + index as f32 * line_height,
                 );
-                */
-                *prev_mesh = mesh;
-            }
-            /* else {
-                println!(
-                    "Received mapblock mesh for {} [UPDATED, OBSOLETE] [#{}]",
-                    mesh.blockpos.vec(),
-                    counter,
+                self.text.draw_colored(&mut self.overlay, runs, pos, alpha);
+            }
+        }
+    }
+
+    /// Draws a marker and a vertical beam for each waypoint, with its name
+    /// and distance below the marker.
+    // Compare to Luanti, client/hud.cpp, Hud::drawLuaElements, HUD_ELEM_WAYPOINT
+    fn draw_waypoints(&mut self) {
+        const BEAM_HEIGHT: f32 = 100.0;
+        const BEAM_WIDTH: f32 = 4.0;
+        const MARKER_SIZE: f32 = 12.0;
+        const BEAM_COLOR: Vec4 = Vec4::new(1.0, 0.85, 0.2, 0.4);
+        const MARKER_COLOR: Vec4 = Vec4::new(1.0, 0.85, 0.2, 0.9);
+        const TEXT_COLOR: Vec4 = Vec4::new(1.0, 0.85, 0.2, 1.0);
+
+        for (name, pos) in self.waypoints.borrow().iter() {
+            let Some(base) = self.camera.params.world_to_screen(*pos) else {
+                continue;
+            };
+            // The camera never rolls, so the beam is always vertical on screen
+            let top = self
+                .camera
+                .params
+                .world_to_screen(*pos + Vec3::Y * BEAM_HEIGHT)
+                .map_or(0.0, |top| top.y.min(base.y));
+            self.overlay.rect(
+                Vec2::new(base.x - BEAM_WIDTH / 2.0, top),
+                Vec2::new(base.x + BEAM_WIDTH / 2.0, base.y),
+                BEAM_COLOR,
+            );
+            self.overlay.rect(
+                base - MARKER_SIZE / 2.0,
+                base + MARKER_SIZE / 2.0,
+                MARKER_COLOR,
+            );
+            let distance = pos.distance(self.camera.params.pos);
+            let label = format!("{} ({}m)", name, distance.round());
+            let label_pos = Vec2::new(
+                (base.x - self.text.width(&label) / 2.0).round(),
+                base.y + MARKER_SIZE,
+            );
+            self.text
+                .draw(&mut self.overlay, &label, label_pos, TEXT_COLOR);
+        }
+    }
+
+    /// Shows the server address and player count in the window title while
+    /// connected.
+    fn update_title(&self) {
+        let title = match self.server_address {
+            Some(address) => format!(
+                "{} - {} ({} players)",
+                Self::TITLE,
+                address,
+                self.player_list.len()
+            ),
+            None => String::from(Self::TITLE),
+        };
+        self.window.set_title(&title);
+    }
+
+    /// The item in the selected hotbar slot, None if the slot is empty.
+    fn wielded_item(&self) -> Option<&ItemStack> {
+        self.inventory
+            .list("main")
+            .and_then(|list| list.get(self.hotbar.selected() as usize))
+            .filter(|stack| !stack.is_empty())
+    }
+
+    /// Finds what the player points at, within the range of the wielded
+    /// item. Objects in front of a node are pointed at instead of it.
+    // Compare to Luanti, client/game.cpp, Game::updatePointedThing
+    fn update_pointed(&mut self) {
+        let (Some(node_def), Some(item_def), Some((pos, dir))) = (
+            &self.node_def,
+            &self.item_def,
+            self.camera_controller.shootline(),
+        ) else {
+            self.pointed = PointedThing::Nothing;
+            return;
+        };
+        let item = self
+            .wielded_item()
+            .map_or(ItemDefManager::HAND, |stack| &stack.name);
+        let range = item_def.range(item);
+
+        let object = pointing::raycast_objects(&self.world, pos, dir, range);
+        let node_range = object.as_ref().map_or(range, |(distance, _)| *distance);
+        let node = pointing::raycast(&self.map.read().unwrap(), node_def, pos, dir, node_range);
+        self.pointed = match (node, object) {
+            (PointedThing::Nothing, Some((_, object))) => object,
+            (node, _) => node,
+        };
+    }
+
+    /// Punches (StartDigging) or right-clicks (Place) the pointed object.
+    /// Nodes are dug by `update_digging` instead.
+    // Compare to Luanti, client/game.cpp, Game::handlePointingAtObject
+    fn interact_with_object(&mut self, action: InteractAction) {
+        if !matches!(self.pointed, PointedThing::Object { .. }) {
+            return;
+        }
+        if action == InteractAction::StartDigging {
+            self.camera_controller.punch();
+        }
+        self.send_interact(action, self.pointed.clone());
+    }
+
+    /// Right-clicks the pointed node or object. Nodes with a "formspec"
+    /// metadata field show it instead, unless the player sneaks.
+    // Compare to Luanti, client/game.cpp, Game::handlePointingAtNode
+    fn place(&mut self) {
+        let PointedThing::Node { under, .. } = &self.pointed else {
+            self.interact_with_object(InteractAction::Place);
+            return;
+        };
+        let under = MapNodePos(under.0);
+        let formspec = self
+            .map
+            .read()
+            .unwrap()
+            .get_node_metadata(&under)
+            .and_then(|metadata| metadata.fields.get("formspec"))
+            .filter(|formspec| !formspec.is_empty())
+            .cloned();
+        match formspec {
+            Some(formspec) if !self.camera_controller.control().sneak => {
+                self.show_node_formspec(under, &formspec);
+            }
+            _ => {
+                self.camera_controller.punch();
+                self.send_interact(InteractAction::Place, self.pointed.clone());
+            }
+        }
+    }
+
+    /// Reads the gamepad. Movement goes to the camera controller, presses of
+    /// the dig and place buttons interact with the pointed object. Holding
+    /// the dig button digs nodes, see `update_digging`.
+    fn update_gamepad(&mut self) {
+        let input = self.gamepads.poll();
+        let previous = std::mem::replace(&mut self.gamepad_input, input);
+        // An open formspec takes all input
+        if self.formspec.is_some() {
+            self.camera_controller
+                .set_gamepad_input(GamepadInput::default());
+            return;
+        }
+        self.camera_controller.set_gamepad_input(input);
+        if input.dig && !previous.dig {
+            self.interact_with_object(InteractAction::StartDigging);
+        }
+        if input.place && !previous.place {
+            self.place();
+        }
+    }
+
+    fn send_interact(&self, action: InteractAction, pointed: PointedThing) {
+        self.send_to_client(MainToClientEvent::Interact {
+            action,
+            pointed,
+            item: self.hotbar.selected(),
+            pos: self.camera_controller.get_pos().clone(),
+        });
+    }
+
+    /// The controls the player holds, including the mouse buttons.
+    fn player_control(&self) -> camera_controller::PlayerControl {
+        camera_controller::PlayerControl {
+            dig: self.dig_pressed || self.gamepad_input.dig,
+            place: self.place_pressed || self.gamepad_input.place,
+            ..self.camera_controller.control()
+        }
+    }
+
+    /// Digs the pointed node while the dig button is held and sends the
+    /// progress to the server.
+    // Compare to Luanti, client/game.cpp, Game::handleDigging
+    fn update_digging(&mut self, dtime: f32) {
+        let (Some(node_def), Some(item_def)) = (&self.node_def, &self.item_def) else {
+            return;
+        };
+        // Releasing the button isn't seen while a formspec takes the input
+        let pressed = (self.dig_pressed || self.gamepad_input.dig) && self.formspec.is_none();
+        let pointed = if pressed {
+            &self.pointed
+        } else {
+            &PointedThing::Nothing
+        };
+        let item = self.wielded_item().map(|stack| stack.name.clone());
+        let item = item.as_deref().unwrap_or(ItemDefManager::HAND);
+        let map = self.map.read().unwrap();
+
+        let actions = self.digging.step(dtime, pointed, |pointed| {
+            let PointedThing::Node { under, .. } = pointed else {
+                return DigParams::NOT_DIGGABLE;
+            };
+            let Some(node) = map.get_node(under) else {
+                return DigParams::NOT_DIGGABLE;
+            };
+            let def = node_def.get_with_fallback(node.content_id);
+            let caps = |name: &str| item_def.tool_capabilities(name);
+            // Nodes the tool can't dig may still be diggable by hand
+            let params = caps(item).map_or(DigParams::NOT_DIGGABLE, |caps| {
+                digging::dig_params(def, caps)
+            });
+            if params.diggable {
+                params
+            } else {
+                caps(ItemDefManager::HAND).map_or(DigParams::NOT_DIGGABLE, |caps| {
+                    digging::dig_params(def, caps)
+                })
+            }
+        });
+        drop(map);
+
+        for (action, pointed) in actions {
+            if action == InteractAction::StartDigging {
+                self.camera_controller.punch();
+            }
+            if action == InteractAction::DiggingCompleted {
+                self.play_dug_sound(&pointed);
+            }
+            // The client thread predicts the dug node
+            self.send_interact(action, pointed);
+        }
+    }
+
+    /// Plays the "sound_dug" of a node that has been dug.
+    // Compare to Luanti, client/sound/sound_maker.cpp, SoundMaker::nodeDug
+    fn play_dug_sound(&mut self, pointed: &PointedThing) {
+        let (Some(media), Some(node_def)) = (&self.media, &self.node_def) else {
+            return;
+        };
+        let PointedThing::Node { under, .. } = pointed else {
+            return;
+        };
+        let Some(node) = self.map.read().unwrap().get_node(under) else {
+            return;
+        };
+        let sound = &node_def.get_with_fallback(node.content_id).sound_dug;
+        if sound.name.is_empty() {
+            return;
+        }
+        if let Err(err) =
+            self.audio
+                .play_local(media, &sound.name, sound.gain, SoundLocation::Local)
+        {
+            println!("Error while playing sound: {:?}", err);
+        }
+    }
+
+    /// Draws the crack on the node being dug.
+    fn add_crack(&mut self) {
+        let (Some(media), Some(node_def)) = (&self.media, &self.node_def) else {
+            return;
+        };
+        let Some((PointedThing::Node { under, .. }, progress)) = self.digging.progress() else {
+            return;
+        };
+        let Some(node) = self.map.read().unwrap().get_node(under) else {
+            return;
+        };
+        let center = under.0.as_vec3();
+        let boxes: Vec<(Vec3, Vec3)> =
+            pointing::selection_boxes(node_def.get_with_fallback(node.content_id), node)
+                .into_iter()
+                .map(|(min, max)| (center + min, center + max))
+                .collect();
+        self.entities
+            .add_crack(&self.device, &self.queue, media, &boxes, progress);
+    }
+
+    /// Draws the "infotext" metadata field of the pointed node in the top
+    /// left corner, e.g. the owner of a chest.
+    // Compare to Luanti, client/game.cpp, Game::handlePointingAtNode
+    fn draw_infotext(&mut self) {
+        const POS: Vec2 = Vec2::new(16.0, 16.0);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+
+        let PointedThing::Node { under, .. } = &self.pointed else {
+            return;
+        };
+        let map = self.map.read().unwrap();
+        let Some(infotext) = map
+            .get_node_metadata(under)
+            .and_then(|metadata| metadata.fields.get("infotext"))
+        else {
+            return;
+        };
+        for (index, line) in infotext.lines().enumerate() {
+            let pos = POS + Vec2::new(0.0, index as f32 * self.text.line_height());
+            self.text.draw(&mut self.overlay, line, pos, TEXT_COLOR);
+        }
+    }
+
+    /// Applies the post effect color of the node containing the camera, e.g.
+    /// water or lava, by tinting the screen in the post-processing pass and
+    /// shortening the fog distance.
+    fn update_post_effect_color(&mut self) {
+        let mut color = Vec4::ZERO;
+
+        if let Some(node_def) = &self.node_def {
+            let pos = MapNodePos((self.camera.params.pos + 0.5).floor().as_i16vec3());
+            if let Some(node) = self.map.read().unwrap().get_node(&pos) {
+                let c = node_def
+                    .get_with_fallback(node.content_id)
+                    .post_effect_color;
+                // Node colors are sRGB, but we render in linear color space
+                color = Vec4::new(
+                    srgb_to_linear(c.r),
+                    srgb_to_linear(c.g),
+                    srgb_to_linear(c.b),
+                    c.a as f32 / 255.0,
                 );
             }
-            */
+        }
+
+        self.post_process.params.tint = color;
+
+        let params = &mut self.camera.params;
+        if color.w > 0.0 {
+            params.fog_color = (Self::BG_COLOR * params.daylight).lerp(color.truncate(), color.w);
+            params.fog_end = Self::POST_EFFECT_FOG_DISTANCE;
         } else {
-            /*
-            println!(
-                "Received mapblock mesh for {} [NEW] [#{}]",
-                mesh.blockpos.vec(),
-                counter
-            );
-            */
-            self.mapblock_meshes.insert(mesh.blockpos.vec(), mesh);
+            // The sky gets darker with the light
+            params.fog_color = Self::BG_COLOR * params.daylight;
+            params.fog_end = params.z_far;
         }
     }
+
+    fn setup_mapblock_rendering(&mut self, data: NodeTextureData) {
+        assert!(self.mapblock_texture_data.is_none());
+        assert!(self.render_pipeline.is_none());
+
+        let pipeline_layout = self
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Mapblock pipeline layout"),
+                bind_group_layouts: &[
+                    &self.camera.bind_group_layout(),
+                    &data.bind_group_layout,
+                    self.farmesh.bind_group_layout(),
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let shader = self
+            .device
+            .create_shader_module(wgpu::include_wgsl!("mapblock_shader.wgsl"));
+
+        let render_pipeline = self
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Mapblock render pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: Some("vs_main"),
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                    buffers: &[meshgen::Vertex::layout()],
+                },
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    // Irrlicht's fault
+                    front_face: wgpu::FrontFace::Cw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    ..wgpu::PrimitiveState::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: MyTexture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: Some("fs_main"),
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: PostProcess::COLOR_FORMAT,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                multiview: None,
+                cache: None,
+            });
+
+        self.mapblock_texture_data = Some(data);
+        self.render_pipeline = Some(render_pipeline);
+    }
+
+    fn insert_mapblock_mesh(&mut self, mesh: MapblockMesh) {
+        assert!(self.mapblock_texture_data.is_some());
+        assert!(self.render_pipeline.is_some());
+
+        self.profiler.add_meshgen_time(mesh.generation_time);
+        self.farmesh.set_surface(mesh.blockpos, mesh.surface);
+        self.world.insert_mapblock_mesh(mesh);
+    }
 }
 
 struct App {
     rt: tokio::runtime::Runtime,
+    settings: Settings,
+    smoke_test: Option<SmokeTest>,
+    /// Set when the smoke test is finished
+    smoke_test_result: Option<SmokeTestResult>,
     state: Option<State>,
 }
 
 impl App {
-    fn new() -> Self {
+    fn new(settings: Settings, smoke_test: Option<SmokeTest>) -> Self {
         let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
 
-        App { rt, state: None }
+        App {
+            rt,
+            settings,
+            smoke_test,
+            smoke_test_result: None,
+            state: None,
+        }
+    }
+
+    fn finish_smoke_test(&mut self, event_loop: &ActiveEventLoop, result: SmokeTestResult) {
+        self.smoke_test_result = Some(result);
+        event_loop.exit();
     }
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let attr = Window::default_attributes().with_title("Cubetonic");
+        let attr = Window::default_attributes().with_title(State::TITLE);
         let window = Arc::new(event_loop.create_window(attr).unwrap());
 
-        let state = self.rt.block_on(State::new(window.clone()));
-        self.state = Some(state);
-
-        window.set_cursor_visible(false);
-        if let Err(err) = window.set_cursor_grab(CursorGrabMode::Locked) {
-            println!("Could not lock cursor: {:?}", err);
+        let mut state = self.rt.block_on(State::new(window.clone(), &self.settings));
+        // The smoke test can't click through the menu
+        if self.smoke_test.is_some() || !self.settings.main_menu {
+            let mut connection = ConnectionConfig::from_settings(&self.settings);
+            if let Some(smoke_test) = &self.smoke_test {
+                connection.address = smoke_test.address.ip().to_string();
+                connection.port = smoke_test.address.port();
+            }
+            state.connect_to = Some(connection);
+        } else {
+            state.show_main_menu();
         }
+        self.state = Some(state);
 
         window.request_redraw();
     }
@@ -484,21 +2601,102 @@ impl ApplicationHandler for App {
     ) {
         let state = self.state.as_mut().unwrap();
 
+        // An open formspec takes all input, only window management events
+        // are handled below
+        if state.formspec.is_some()
+            && matches!(
+                event,
+                WindowEvent::CursorMoved { .. }
+                    | WindowEvent::MouseInput { .. }
+                    | WindowEvent::MouseWheel { .. }
+                    | WindowEvent::KeyboardInput { .. }
+                    | WindowEvent::ModifiersChanged(_)
+            )
+        {
+            state.process_formspec_event(&event);
+            return;
+        }
+        // The same for the chat console
+        if state.chat.console_open()
+            && matches!(
+                event,
+                WindowEvent::CursorMoved { .. }
+                    | WindowEvent::MouseInput { .. }
+                    | WindowEvent::MouseWheel { .. }
+                    | WindowEvent::KeyboardInput { .. }
+                    | WindowEvent::ModifiersChanged(_)
+            )
+        {
+            state.process_console_event(&event);
+            return;
+        }
+        // Keys registered by scripts come before the built-in ones
+        if let WindowEvent::KeyboardInput {
+            event:
+                KeyEvent {
+                    state: ElementState::Pressed,
+                    physical_key: PhysicalKey::Code(keycode),
+                    repeat: false,
+                    ..
+                },
+            ..
+        } = event
+            && state.phase == Phase::InGame
+            && state.lua.on_keypress(keycode)
+        {
+            return;
+        }
         if state.camera_controller.process_window_event(&event) {
             return;
         }
+        if state.hotbar.process_window_event(&event) {
+            state.send_to_client(MainToClientEvent::PlayerItem(state.hotbar.selected()));
+            state.update_main_hand();
+            return;
+        }
 
         match event {
             WindowEvent::CloseRequested => {
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
+                // The next redraw is requested by about_to_wait
                 state.render();
-                state.window.request_redraw();
+                if let Some(smoke_test) = &self.smoke_test
+                    && let Some(result) = smoke_test.check_frame(state.world.mapblock_count())
+                {
+                    self.finish_smoke_test(event_loop, result);
+                }
+            }
+            WindowEvent::Focused(focused) => {
+                state.focused = focused;
+            }
+            WindowEvent::Occluded(occluded) => {
+                state.occluded = occluded;
             }
             WindowEvent::Resized(new_size) => {
                 state.resize(new_size);
             }
+            WindowEvent::MouseInput {
+                state: button_state,
+                button: MouseButton::Left,
+                ..
+            } => {
+                state.dig_pressed = button_state == ElementState::Pressed;
+                if state.dig_pressed {
+                    state.interact_with_object(InteractAction::StartDigging);
+                }
+            }
+            WindowEvent::MouseInput {
+                state: button_state,
+                button: MouseButton::Right,
+                ..
+            } => {
+                state.place_pressed = button_state == ElementState::Pressed;
+                if state.place_pressed {
+                    state.place();
+                }
+            }
             WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
@@ -508,7 +2706,23 @@ impl ApplicationHandler for App {
                     },
                 ..
             } => match keycode {
-                KeyCode::Escape => event_loop.exit(),
+                KeyCode::Escape => {
+                    if key_state == ElementState::Pressed {
+                        state.show_pause_menu();
+                    }
+                }
+                KeyCode::F8 => {
+                    if key_state == ElementState::Pressed {
+                        // Culling stays at the player, to look at it from
+                        // outside
+                        state.frustum_frozen = state.camera_controller.toggle_detached();
+                    }
+                }
+                KeyCode::F10 => {
+                    if key_state == ElementState::Pressed {
+                        state.open_console();
+                    }
+                }
                 KeyCode::F11 => {
                     if key_state == ElementState::Pressed {
                         state
@@ -525,6 +2739,69 @@ impl ApplicationHandler for App {
                         state.frustum_frozen = !state.frustum_frozen;
                     }
                 }
+                KeyCode::Enter => {
+                    if key_state == ElementState::Pressed {
+                        state.join_info.dismiss();
+                    }
+                }
+                KeyCode::KeyB => {
+                    if key_state == ElementState::Pressed {
+                        state.add_waypoint();
+                    }
+                }
+                KeyCode::KeyI | KeyCode::KeyE => {
+                    if key_state == ElementState::Pressed {
+                        state.open_inventory();
+                    }
+                }
+                KeyCode::Tab => {
+                    state.show_player_list = key_state == ElementState::Pressed;
+                }
+                KeyCode::F3 => {
+                    if key_state == ElementState::Pressed {
+                        state.debug_screen.toggle();
+                    }
+                }
+                KeyCode::F4 => {
+                    if key_state == ElementState::Pressed {
+                        state.profiler.toggle();
+                    }
+                }
+                KeyCode::F5 => {
+                    if key_state == ElementState::Pressed {
+                        state.show_network_stats = !state.show_network_stats;
+                    }
+                }
+                KeyCode::F6 => {
+                    if key_state == ElementState::Pressed {
+                        state.toggle_meshgen_paused();
+                    }
+                }
+                KeyCode::F7 => {
+                    if key_state == ElementState::Pressed {
+                        state.send_to_client(MainToClientEvent::FlushMeshgen);
+                    }
+                }
+                KeyCode::KeyV => {
+                    if key_state == ElementState::Pressed {
+                        state.cycle_present_mode();
+                    }
+                }
+                KeyCode::F9 => {
+                    if key_state == ElementState::Pressed {
+                        state.cycle_texture_filter();
+                    }
+                }
+                KeyCode::F12 => {
+                    if key_state == ElementState::Pressed {
+                        state.export_world();
+                    }
+                }
+                KeyCode::KeyM => {
+                    if key_state == ElementState::Pressed {
+                        state.export_map_image();
+                    }
+                }
                 _ => (),
             },
 
@@ -540,10 +2817,18 @@ impl ApplicationHandler for App {
     ) {
         let state = self.state.as_mut().unwrap();
 
-        state.camera_controller.process_device_event(&event);
+        // Mouse movement is for the cursor while a formspec, the console or
+        // the disconnect screen is open, or outside of the game
+        if state.phase == Phase::InGame
+            && state.formspec.is_none()
+            && !state.chat.console_open()
+            && state.disconnect_reason.is_none()
+        {
+            state.camera_controller.process_device_event(&event);
+        }
     }
 
-    fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
         let state = self.state.as_mut().unwrap();
 
         while let Ok(event) = state.client_rx.try_recv() {
@@ -552,9 +2837,246 @@ impl ApplicationHandler for App {
                 ClientToMainEvent::MapblockTextureData(data) => {
                     state.setup_mapblock_rendering(data)
                 }
+                ClientToMainEvent::NodeTexture(result) => {
+                    state.loading.textures_loaded += 1;
+                    match result {
+                        Ok(update) => {
+                            if let Some(data) = &mut state.mapblock_texture_data {
+                                data.update(&state.queue, update);
+                            }
+                        }
+                        Err(issue) => match &mut state.media_issues {
+                            Some((issues, _)) => issues.push(issue),
+                            None => state.media_issues = Some((vec![issue], Instant::now())),
+                        },
+                    }
+                }
                 ClientToMainEvent::MapblockMesh(mesh) => state.insert_mapblock_mesh(mesh),
+                ClientToMainEvent::NodeDef(node_def) => {
+                    state.lua.set_node_def(node_def.clone());
+                    if let Some(address) = state.server_address
+                        && let Err(err) = state.farmesh.load(address, &node_def)
+                    {
+                        println!("Failed to load farmesh columns: {:?}", err);
+                    }
+                    state.node_def = Some(node_def);
+                }
+                ClientToMainEvent::ItemDef(item_def) => {
+                    state.lua.set_item_def(item_def.clone());
+                    state.hud.item_def = Some(item_def.clone());
+                    state.item_def = Some(item_def);
+                }
+                ClientToMainEvent::MeshgenPendingTasks(counter) => {
+                    state.meshgen_pending_tasks = Some(counter)
+                }
+                ClientToMainEvent::MediaOrigins(origins) => state.lua.set_media_origins(origins),
+                ClientToMainEvent::MediaIssues(issues) => {
+                    state.media_issues = Some((issues, Instant::now()))
+                }
+                ClientToMainEvent::Connected(address) => {
+                    state.server_address = Some(address);
+                    state.join_info.joined();
+                    match Waypoints::load(address) {
+                        Ok(waypoints) => *state.waypoints.borrow_mut() = waypoints,
+                        Err(err) => println!("Failed to load waypoints: {:?}", err),
+                    }
+                    state.update_title();
+                }
+                ClientToMainEvent::PlayerList(list) => {
+                    state.player_list = list;
+                    state.update_title();
+                }
+                ClientToMainEvent::Inventory(inventory) => {
+                    state.inventory = inventory;
+                    state.update_main_hand();
+                }
+                ClientToMainEvent::DetachedInventory { name, inventory } => match inventory {
+                    Some(inventory) => {
+                        state.detached_inventories.insert(name, inventory);
+                    }
+                    None => {
+                        state.detached_inventories.remove(&name);
+                    }
+                },
+                ClientToMainEvent::Media(media) => {
+                    state.media = Some(media);
+                    // Loading finished, so connecting worked
+                    state.reconnect_attempts = 0;
+                    state.disconnect_reason = None;
+                    state.meshing_since = Some(Instant::now());
+                }
+                ClientToMainEvent::LoadingProgress(progress) => state.loading = progress,
+                ClientToMainEvent::HudAdd { id, element } => state.hud.add(id, element),
+                ClientToMainEvent::HudChange { id, stat } => state.hud.change(id, stat),
+                ClientToMainEvent::HudRemove(id) => state.hud.remove(id),
+                ClientToMainEvent::HudSetFlags { flags, mask } => {
+                    state.hud.set_flags(flags, mask);
+                    if state.chat.console_open() && !state.hud.is_visible(HUD_FLAG_CHAT_VISIBLE) {
+                        state.chat.close_console();
+                        state.set_cursor_grabbed(true);
+                    }
+                }
+                ClientToMainEvent::HudSetParam(param) => state.set_hud_param(param),
+                ClientToMainEvent::ChatMessage(message) => {
+                    println!("Chat: {}", message);
+                    state.join_info.add_chat_message(&message);
+                    if !state.lua.on_receiving_chat_message(&message) {
+                        state.chat.add_message(&message);
+                    }
+                }
+                ClientToMainEvent::ServerScript(message) => state.lua.run_server_script(&message),
+                ClientToMainEvent::NetworkStats(stats) => state.network_stats = Some(stats),
+                ClientToMainEvent::MovementSettings(movement) => {
+                    state.camera_controller.set_movement_settings(movement)
+                }
+                ClientToMainEvent::EyeOffsets(eye_offsets) => {
+                    state.camera_controller.set_eye_offsets(eye_offsets)
+                }
+                ClientToMainEvent::ForcedCameraMode(mode) => {
+                    state.camera_controller.set_forced_camera_mode(mode)
+                }
+                ClientToMainEvent::Fov {
+                    fov,
+                    is_multiplier,
+                    transition_time,
+                } => state
+                    .camera_controller
+                    .set_server_fov(fov, is_multiplier, transition_time),
+                ClientToMainEvent::Privileges(privileges) => {
+                    state.camera_controller.set_privileges(&privileges)
+                }
+                ClientToMainEvent::Hp { hp, damage_effect } => {
+                    let handled = state.lua.on_hp_modification(hp);
+                    state.set_hp(hp, damage_effect && !handled);
+                }
+                ClientToMainEvent::Breath(breath) => state.hud.breath = Some(breath),
+                ClientToMainEvent::TimeOfDay {
+                    time_of_day,
+                    time_speed,
+                } => state.sky.set_time_of_day(time_of_day, time_speed),
+                ClientToMainEvent::SetSun(sun) => state.sky.set_sun(sun),
+                ClientToMainEvent::SetMoon(moon) => state.sky.set_moon(moon),
+                ClientToMainEvent::SetStars(stars) => state.sky.set_stars(stars),
+                ClientToMainEvent::CloudParams(params) => state.clouds.set_params(params),
+                ClientToMainEvent::OverrideDayNightRatio(ratio) => {
+                    state.day_night_ratio_override = ratio;
+                }
+                ClientToMainEvent::Lighting {
+                    saturation,
+                    exposure_correction,
+                } => {
+                    state.post_process.params.saturation = saturation;
+                    state.post_process.params.exposure = exposure_correction;
+                }
+                ClientToMainEvent::ShowFormspec { name, formspec } => {
+                    println!("Received formspec \"{}\"", name);
+                    state.join_info.add_formspec(&formspec);
+                    state.show_formspec(&name, &formspec, "");
+                }
+                ClientToMainEvent::InventoryFormspec(formspec) => {
+                    state.inventory_formspec = formspec;
+                }
+                ClientToMainEvent::PlaySound { id, spec } => {
+                    // Sounds are only sent after loading is finished
+                    if let Some(media) = &state.media
+                        && let Err(err) = state
+                            .audio
+                            .play_server(media, id, spec, |id| state.world.object_pos(id))
+                    {
+                        println!("Error while playing sound: {:?}", err);
+                    }
+                }
+                ClientToMainEvent::StopSound(id) => state.audio.stop(id),
+                ClientToMainEvent::AddActiveObject { id, object } => {
+                    state.world.add_active_object(id, object);
+                    state.update_zoom_fov();
+                    state.update_physics_override();
+                }
+                ClientToMainEvent::RemoveActiveObject(id) => state.world.remove_active_object(id),
+                ClientToMainEvent::ActiveObjectMessage { id, message } => {
+                    let set_properties = matches!(message, ActiveObjectCommand::SetProperties(_));
+                    let set_physics_override =
+                        matches!(message, ActiveObjectCommand::SetPhysicsOverride(_));
+                    if let Some(damage) = state.world.process_object_message(id, message) {
+                        state.object_damaged(id, damage);
+                    }
+                    if set_properties {
+                        state.update_zoom_fov();
+                    }
+                    if set_physics_override {
+                        state.update_physics_override();
+                    }
+                }
+                ClientToMainEvent::ResolveFailed(message) => {
+                    if let Some(smoke_test) = &self.smoke_test {
+                        self.smoke_test_result = Some(smoke_test.disconnected(&message));
+                        event_loop.exit();
+                        return;
+                    }
+                    // Also without the main_menu setting, so the address
+                    // can be fixed
+                    state.show_main_menu();
+                    state.main_menu.set_error(message);
+                    state.show_formspec("", &state.main_menu.formspec(), "");
+                    break;
+                }
+                ClientToMainEvent::Disconnected(reason) => {
+                    if let Some(smoke_test) = &self.smoke_test {
+                        self.smoke_test_result = Some(smoke_test.disconnected(&reason.message));
+                        event_loop.exit();
+                        return;
+                    }
+                    state.disconnected(reason);
+                    // The client thread is gone, the remaining events are
+                    // from the previous connection
+                    break;
+                }
             }
         }
+        state.update_loading();
+        state.poll_map_image_export();
+
+        if state.exit_requested {
+            event_loop.exit();
+            return;
+        }
+        if let Some(connection) = state.connect_to.take() {
+            self.rt.block_on(state.connect(connection));
+        }
+        if state.should_reconnect() {
+            self.rt.block_on(state.reconnect());
+        }
+
+        if let Some(smoke_test) = &self.smoke_test
+            && let Some(result) = smoke_test.check_timeout()
+        {
+            self.finish_smoke_test(event_loop, result);
+            return;
+        }
+
+        // Frame pacing: sleep until the next frame is due instead of
+        // rendering as fast as possible
+        if Instant::now() >= state.next_frame {
+            state.window.request_redraw();
+            event_loop.set_control_flow(ControlFlow::Wait);
+        } else {
+            event_loop.set_control_flow(ControlFlow::WaitUntil(state.next_frame));
+        }
+    }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        if let Some(state) = &self.state {
+            state.save_farmesh();
+        }
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
     }
 }
 
@@ -564,6 +3086,16 @@ fn main() {
     let event_loop = EventLoop::with_user_event().build().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut app = App::new();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let smoke_test = SmokeTest::from_args(&mut args).unwrap();
+
+    let mut settings = Settings::load().unwrap();
+    settings.apply_args(args.into_iter()).unwrap();
+
+    let mut app = App::new(settings, smoke_test);
     event_loop.run_app(&mut app).unwrap();
+
+    if let Some(result) = app.smoke_test_result {
+        std::process::exit(result.exit_code());
+    }
 }
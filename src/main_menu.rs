@@ -0,0 +1,100 @@
+//! The main menu shown at startup, where the player enters the server to
+//! connect to. Like Luanti's main menu, it is a formspec, built on the
+//! client instead of received from a server.
+
+use crate::formspec::escape;
+use crate::luanti_client::ConnectionConfig;
+use crate::settings::Settings;
+
+/// What the player chose in the main menu.
+pub enum MainMenuAction {
+    Connect(ConnectionConfig),
+    Quit,
+}
+
+/// The values of the fields, kept while connected so the menu shows them
+/// again after leaving the server.
+pub struct MainMenu {
+    address: String,
+    port: String,
+    name: String,
+    password: String,
+    /// Shown below the fields, e.g. why the last connection attempt failed
+    error: Option<String>,
+}
+
+impl MainMenu {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            address: settings.address.clone(),
+            port: settings.port.to_string(),
+            name: settings.name.clone(),
+            password: settings.password.clone(),
+            error: None,
+        }
+    }
+
+    /// Shows an error below the fields until the next connection attempt.
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    // Compare to Luanti, builtin/mainmenu/tab_online.lua, get_formspec
+    pub fn formspec(&self) -> String {
+        let mut formspec = format!(
+            "formspec_version[2]size[10,7.5]\
+            label[0.5,0.6;Join a server]\
+            field[0.5,1.6;6.5,0.8;address;Address;{}]\
+            field[7.25,1.6;2.25,0.8;port;Port;{}]\
+            field[0.5,3.1;4.25,0.8;name;Name;{}]\
+            pwdfield[5.25,3.1;4.25,0.8;password;Password;{}]\
+            button[0.5,6.2;4.25,0.8;exit;Quit]\
+            button[5.25,6.2;4.25,0.8;connect;Connect]",
+            escape(&self.address),
+            escape(&self.port),
+            escape(&self.name),
+            escape(&self.password),
+        );
+        if let Some(error) = &self.error {
+            // Long errors, e.g. from resolving the address, are cut off
+            for (index, line) in error.lines().take(3).enumerate() {
+                formspec += &format!("label[0.5,{};{}]", 4.4 + 0.4 * index as f32, escape(line));
+            }
+        }
+        formspec
+    }
+
+    /// Handles the fields sent by the menu formspec. Returns None if the
+    /// menu should stay open, e.g. to show an error.
+    pub fn process_fields(&mut self, fields: &[(String, String)]) -> Option<MainMenuAction> {
+        let mut connect = false;
+        for (name, value) in fields {
+            match name.as_str() {
+                "address" => self.address = String::from(value.trim()),
+                "port" => self.port = String::from(value.trim()),
+                "name" => self.name = String::from(value.trim()),
+                "password" => self.password = value.clone(),
+                "connect" | "key_enter" => connect = true,
+                "exit" => return Some(MainMenuAction::Quit),
+                _ => (),
+            }
+        }
+        if !connect {
+            return None;
+        }
+
+        // The address is resolved by the client thread, which comes back
+        // to the menu if it can't be resolved
+        let Ok(port) = self.port.parse() else {
+            self.error = Some(format!("Invalid port \"{}\"", self.port));
+            return None;
+        };
+        self.error = None;
+        Some(MainMenuAction::Connect(ConnectionConfig {
+            address: self.address.clone(),
+            port,
+            name: self.name.clone(),
+            password: self.password.clone(),
+        }))
+    }
+}
@@ -3,9 +3,12 @@ use std::collections::HashMap;
 use glam::I16Vec3;
 use luanti_core::{MapBlockNodes, MapBlockPos, MapNode, MapNodePos};
 
+use crate::node_metadata::{NodeMetadata, NodeMetadataStore};
+
 /// A Luanti map. Consists of "mapblocks", which are 16³ chunks of "nodes".
 pub struct LuantiMap {
     blocks: HashMap<MapBlockPos, MapBlockNodes>,
+    metadata: NodeMetadataStore,
 }
 
 impl LuantiMap {
@@ -13,6 +16,7 @@ impl LuantiMap {
     pub fn new() -> Self {
         Self {
             blocks: HashMap::new(),
+            metadata: NodeMetadataStore::new(),
         }
     }
 
@@ -28,6 +32,39 @@ impl LuantiMap {
         self.blocks.get(blockpos)
     }
 
+    /// Returns all mapblocks in the map, in no particular order.
+    pub fn blocks(&self) -> impl Iterator<Item = (&MapBlockPos, &MapBlockNodes)> {
+        self.blocks.iter()
+    }
+
+    /// Returns the number of mapblocks in the map.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Gets a node from the map.
+    /// Returns None if the mapblock that would contain the node doesn't exist.
+    pub fn get_node(&self, pos: &MapNodePos) -> Option<MapNode> {
+        let (blockpos, index) = pos.split_index();
+
+        let block = self.blocks.get(&blockpos)?;
+        Some(block[index])
+    }
+
+    /// Gets the metadata of a node.
+    /// Returns None if the node has no metadata.
+    pub fn get_node_metadata(&self, pos: &MapNodePos) -> Option<&NodeMetadata> {
+        self.metadata.get(pos)
+    }
+
+    pub fn node_metadata(&self) -> &NodeMetadataStore {
+        &self.metadata
+    }
+
+    pub fn node_metadata_mut(&mut self) -> &mut NodeMetadataStore {
+        &mut self.metadata
+    }
+
     /// Sets a node in the map.
     /// Returns the modified mapblock's position.
     /// Returns None and does nothing if the mapblock that would contain the
@@ -0,0 +1,184 @@
+//! Renders the loaded map as seen from above into an image, like a minimal
+//! mapper built into the client. Each pixel is the highest visible node of
+//! its column, colored with the average color of its top texture.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use glam::IVec3;
+use image::{Rgba, RgbaImage};
+use luanti_core::{ContentId, MapBlockNodes, MapBlockPos};
+use luanti_protocol::types::DrawType;
+
+use crate::map::LuantiMap;
+use crate::media::{MediaManager, MediaSource};
+use crate::node_def::NodeDefManager;
+
+/// Images are at most this large in either direction, in pixels
+const MAX_SIZE: u32 = 16384;
+/// Columns without visible nodes
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 0]);
+/// For nodes whose texture can't be loaded
+const DEFAULT_COLOR: [u8; 3] = [128, 128, 128];
+
+/// A copy of the loaded map and of the top textures of all nodes, so the
+/// image can be rendered on another thread without holding the map lock.
+pub struct MapSnapshot {
+    blocks: Vec<(MapBlockPos, MapBlockNodes)>,
+    node_def: Arc<NodeDefManager>,
+    /// None for nodes whose top texture is unknown
+    textures: HashMap<ContentId, Option<MediaSource>>,
+}
+
+impl MapSnapshot {
+    pub fn new(map: &LuantiMap, node_def: Arc<NodeDefManager>, media: &MediaManager) -> Self {
+        let blocks = map
+            .blocks()
+            .map(|(blockpos, block)| (*blockpos, block.clone()))
+            .collect();
+        let textures = node_def
+            .map
+            .iter()
+            .map(|(content_id, def)| {
+                let source = def.tiledef.first().and_then(|tile| media.get(&tile.name));
+                (*content_id, source.cloned())
+            })
+            .collect();
+        Self {
+            blocks,
+            node_def,
+            textures,
+        }
+    }
+}
+
+/// Renders one pixel per node column, with north (+Z) up. Slopes facing
+/// north are drawn brighter and slopes facing south darker, so the terrain
+/// height is visible.
+pub fn render(snapshot: &MapSnapshot) -> anyhow::Result<RgbaImage> {
+    const SIZE: i32 = MapBlockPos::SIZE as i32;
+
+    let node_def = &snapshot.node_def;
+    let mut bounds: Option<(IVec3, IVec3)> = None;
+    for (blockpos, _) in &snapshot.blocks {
+        let pos = blockpos.vec().as_ivec3();
+        bounds = Some(match bounds {
+            Some((min, max)) => (min.min(pos), max.max(pos)),
+            None => (pos, pos),
+        });
+    }
+    let Some((min, max)) = bounds else {
+        return Err(anyhow!("No mapblocks loaded"));
+    };
+    let width = ((max.x - min.x + 1) * SIZE) as u32;
+    let height = ((max.z - min.z + 1) * SIZE) as u32;
+    if width > MAX_SIZE || height > MAX_SIZE {
+        return Err(anyhow!(
+            "The loaded map is too large ({}x{})",
+            width,
+            height
+        ));
+    }
+
+    // The highest visible node of each column
+    let mut top: Vec<Option<(i32, ContentId)>> = vec![None; (width * height) as usize];
+    for (blockpos, block) in &snapshot.blocks {
+        let origin = blockpos.vec().as_ivec3() * SIZE;
+        let mut index = 0;
+        for z in 0..SIZE {
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    let node = block.0[index];
+                    index += 1;
+                    if node.content_id == ContentId::AIR
+                        || node_def.get_with_fallback(node.content_id).drawtype == DrawType::AirLike
+                    {
+                        continue;
+                    }
+                    let px = origin.x + x - min.x * SIZE;
+                    let py = (max.z + 1) * SIZE - 1 - (origin.z + z);
+                    let column = &mut top[(py * width as i32 + px) as usize];
+                    let y = origin.y + y;
+                    if column.is_none_or(|(top_y, _)| y > top_y) {
+                        *column = Some((y, node.content_id));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut colors = HashMap::new();
+    let mut img = RgbaImage::from_pixel(width, height, BACKGROUND);
+    for (index, column) in top.iter().enumerate() {
+        let Some((y, content_id)) = *column else {
+            continue;
+        };
+        let color = *colors.entry(content_id).or_insert_with(|| {
+            let def = node_def.get_with_fallback(content_id);
+            let source = snapshot.textures.get(&content_id).and_then(Option::as_ref);
+            match def.tiledef.first() {
+                Some(tile) => texture_color(&tile.name, source),
+                None => DEFAULT_COLOR,
+            }
+        });
+
+        // Compare to the column north of this one, which is the row above
+        let north = index
+            .checked_sub(width as usize)
+            .and_then(|index| top[index]);
+        let shade = match north {
+            Some((north_y, _)) => 1.0 + (y - north_y).clamp(-3, 3) as f32 * 0.08,
+            None => 1.0,
+        };
+        let color = color.map(|c| (c as f32 * shade).round().clamp(0.0, 255.0) as u8);
+        img.put_pixel(
+            index as u32 % width,
+            index as u32 / width,
+            Rgba([color[0], color[1], color[2], 255]),
+        );
+    }
+    Ok(img)
+}
+
+/// Returns the average color of the top texture of a node, ignoring
+/// transparent pixels.
+// Compare to Luanti, nodedef.cpp, ContentFeatures::updateTextures, minimap_color
+pub fn node_color(
+    node_def: &NodeDefManager,
+    media: &MediaManager,
+    content_id: ContentId,
+) -> [u8; 3] {
+    let def = node_def.get_with_fallback(content_id);
+    let Some(tile) = def.tiledef.first() else {
+        return DEFAULT_COLOR;
+    };
+    texture_color(&tile.name, media.get(&tile.name))
+}
+
+/// Returns the average color of a texture, ignoring transparent pixels.
+/// `source` is None if the texture is unknown.
+fn texture_color(name: &str, source: Option<&MediaSource>) -> [u8; 3] {
+    let img = match source.map(MediaSource::load_image) {
+        Some(Ok(img)) => img.to_rgba8(),
+        None => return DEFAULT_COLOR,
+        Some(Err(err)) => {
+            println!("Error while loading texture \"{}\": {:?}", name, err);
+            return DEFAULT_COLOR;
+        }
+    };
+
+    let mut sum = [0u64; 3];
+    let mut weight = 0u64;
+    for pixel in img.pixels() {
+        let alpha = pixel[3] as u64;
+        for (sum, c) in sum.iter_mut().zip(pixel.0) {
+            *sum += c as u64 * alpha;
+        }
+        weight += alpha;
+    }
+    if weight == 0 {
+        return DEFAULT_COLOR;
+    }
+    sum.map(|sum| (sum / weight) as u8)
+}
@@ -4,22 +4,59 @@ struct CameraUniform {
     // order of the following two is intentional to avoid needing additional
     // alignment
     fog_color: vec3<f32>,
-    z_far: f32,
+    fog_end: f32,
+    time: f32,
+    // non-zero if waving nodes are enabled
+    waving: u32,
+    // 0.0 to 1.0, brightness of sunlight
+    daylight: f32,
 }
 @group(0) @binding(0)
 var<uniform> camera: CameraUniform;
 
+// Animated textures have one layer per frame
 @group(1) @binding(0)
-var textures: binding_array<texture_2d<f32>>;
+var textures: binding_array<texture_2d_array<f32>>;
 
 @group(1) @binding(1)
 var the_sampler: sampler;
 
+struct TextureAnimation {
+    frame_count: u32,
+    // in seconds
+    frame_length: f32,
+}
+// Same indices as `textures`
+@group(1) @binding(2)
+var<storage, read> animations: array<TextureAnimation>;
+
+// See farmesh.rs
+struct FarMeshUniform {
+    // The mapblock column of the first texel of `farmesh_coverage`
+    origin: vec2<i32>,
+    // Distance from the camera up to which mapblocks are drawn, in nodes
+    near: f32,
+}
+@group(2) @binding(0)
+var<uniform> farmesh: FarMeshUniform;
+
+// 1.0 for the mapblock columns around the camera that the far terrain
+// covers, 0.0 elsewhere
+@group(2) @binding(1)
+var farmesh_coverage: texture_2d<f32>;
+
+@group(2) @binding(2)
+var farmesh_sampler: sampler;
+
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) uv: vec2<f32>,
     @location(2) normal: vec3<f32>,
     @location(3) texture_index: u32,
+    // ContentFeatures waving type
+    @location(4) flags: u32,
+    // Brightness during the day and during the night
+    @location(5) light: vec2<f32>,
 }
 
 struct VertexOutput {
@@ -29,6 +66,64 @@ struct VertexOutput {
     @location(2) normal: vec3<f32>,
     @location(3) texture_index: u32,
     @location(4) view_position: vec3<f32>,
+    @location(5) light: f32,
+}
+
+// Compare to Luanti, nodes_shader/opengl_vertex.glsl
+const WAVING_PLANTS: u32 = 1u;
+const WAVING_LEAVES: u32 = 2u;
+const WAVING_LIQUID: u32 = 3u;
+
+// Luanti's shader works in units of BS
+const BS: f32 = 10.0;
+const WATER_WAVE_HEIGHT: f32 = 1.0;
+const WATER_WAVE_LENGTH: f32 = 20.0;
+const WATER_WAVE_SPEED: f32 = 5.0;
+
+fn smooth_curve(x: f32) -> f32 {
+    return x * x * (3.0 - 2.0 * x);
+}
+
+fn triangle_wave(x: f32) -> f32 {
+    return abs(fract(x + 0.5) * 2.0 - 1.0);
+}
+
+fn smooth_triangle_wave(x: f32) -> f32 {
+    return smooth_curve(triangle_wave(x)) * 2.0 - 1.0;
+}
+
+fn wave(position: vec3<f32>, uv: vec2<f32>, flags: u32) -> vec3<f32> {
+    if camera.waving == 0u || flags == 0u {
+        return position;
+    }
+
+    var pos = position * BS;
+    // Luanti's animation timer wraps every 100 seconds, the frequencies below
+    // are relative to that
+    let timer = camera.time / 100.0;
+
+    if flags == WAVING_LIQUID {
+        pos.y -= 2.0;
+        let offset = pos.z / WATER_WAVE_LENGTH + timer * WATER_WAVE_SPEED * WATER_WAVE_LENGTH;
+        pos.y -= sin(offset) * WATER_WAVE_HEIGHT + sin(offset / 7.0) * WATER_WAVE_HEIGHT;
+        return pos / BS;
+    }
+
+    let t_offset = (pos.x + pos.y) * 0.001 + pos.z * 0.002;
+    let disp_x = (smooth_triangle_wave(timer * 23.0 + t_offset)
+        + smooth_triangle_wave(timer * 11.0 + t_offset)) * 0.4;
+    let disp_z = (smooth_triangle_wave(timer * 31.0 + t_offset)
+        + smooth_triangle_wave(timer * 29.0 + t_offset)
+        + smooth_triangle_wave(timer * 13.0 + t_offset)) * 0.5;
+
+    if flags == WAVING_LEAVES {
+        pos += vec3<f32>(disp_x, disp_z * 0.1, disp_z);
+    } else if flags == WAVING_PLANTS && uv.y < 0.05 {
+        // Only the top of plants moves
+        pos.x += disp_x;
+        pos.z += disp_z;
+    }
+    return pos / BS;
 }
 
 @vertex
@@ -36,12 +131,16 @@ fn vs_main(
     model: VertexInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
-    out.position = model.position;
+    let position = wave(model.position, model.uv, model.flags);
+    out.clip_position = camera.view_proj * vec4<f32>(position, 1.0);
+    out.position = position;
     out.uv = model.uv;
     out.normal = model.normal;
     out.texture_index = model.texture_index;
-    out.view_position = (camera.view * vec4<f32>(model.position, 1.0)).xyz;
+    out.view_position = (camera.view * vec4<f32>(position, 1.0)).xyz;
+    // Compare to Luanti, nodes_shader/opengl_vertex.glsl, the day/night
+    // mixing of the vertex color
+    out.light = mix(model.light.y, model.light.x, camera.daylight);
     return out;
 }
 
@@ -60,7 +159,9 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     return vec4<f32>(material_color * light, 1.0);
     */
 
-    var tex_color: vec4<f32> = textureSample(textures[in.texture_index], the_sampler, in.uv);
+    let animation = animations[in.texture_index];
+    let frame = u32(camera.time / animation.frame_length) % animation.frame_count;
+    var tex_color: vec4<f32> = textureSample(textures[in.texture_index], the_sampler, in.uv, frame);
     // TODO: this is probably not the proper way to do this
     if tex_color.a == 0.0 {
         discard;
@@ -80,8 +181,15 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     }
     // +y = 1.0
 
+    color *= in.light;
+
+    // Without far terrain behind them, the mapblocks have to fade out
+    // before they end
+    let side = vec2<f32>(textureDimensions(farmesh_coverage));
+    let column = in.position.xz / 16.0 - vec2<f32>(farmesh.origin);
+    let covered = textureSampleLevel(farmesh_coverage, farmesh_sampler, column / side, 0.0).r;
     let fog_color = camera.fog_color;
-    let fog_end = camera.z_far;
+    let fog_end = mix(min(farmesh.near, camera.fog_end), camera.fog_end, covered);
     let fog_start = fog_end * 0.8;
 
     let distance = length(in.view_position);
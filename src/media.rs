@@ -1,29 +1,161 @@
-use std::{collections::HashMap, fs, num::NonZero, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, fs,
+    io::Cursor,
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use base64::{Engine as _, engine::DecodePaddingMode};
+use image::ImageReader;
+use luanti_protocol::types::{TileAnimationParams, TileDef};
 use sha1::{Digest as _, Sha1};
+use wgpu::util::DeviceExt;
 
+use crate::model::Model;
+use crate::settings::Settings;
 use crate::texture::MyTexture;
 
+#[derive(Clone)]
 pub enum MediaSource {
     Path(PathBuf),
-    Bytes(&'static [u8]),
+    /// Generated builtin textures, and downloaded files that couldn't be
+    /// written to the media cache
+    Memory(Vec<u8>),
+}
+
+impl MediaSource {
+    /// Loads the file as an image.
+    /// Returns Err(err) for image loading errors.
+    pub fn load_image(&self) -> anyhow::Result<image::DynamicImage> {
+        let img = match self {
+            MediaSource::Path(path) => ImageReader::open(path)?.with_guessed_format()?.decode()?,
+            MediaSource::Memory(data) => image::load_from_memory(data)?,
+        };
+        if img.width() == 0 || img.height() == 0 {
+            return Err(anyhow!("Empty image"));
+        }
+        Ok(img)
+    }
+}
+
+/// A generated checkerboard texture, see Settings::fallback_texture_size.
+#[derive(Debug, Clone, Copy)]
+pub struct FallbackTexture {
+    /// In pixels
+    pub size: u32,
+    pub color: [u8; 3],
+}
+
+impl FallbackTexture {
+    /// Cells per side of the checkerboard
+    const CELLS: u32 = 4;
+
+    /// Generates the texture as a PNG file. Every other cell has the color
+    /// multiplied by `dark`.
+    fn generate(&self, dark: f32) -> anyhow::Result<Vec<u8>> {
+        let cell_size = (self.size / Self::CELLS).max(1);
+        let img = image::RgbaImage::from_fn(self.size, self.size, |x, y| {
+            let [r, g, b] = if (x / cell_size + y / cell_size) % 2 == 0 {
+                self.color
+            } else {
+                self.color.map(|c| (c as f32 * dark) as u8)
+            };
+            image::Rgba([r, g, b, 0xff])
+        });
+        let mut data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+        Ok(data)
+    }
+}
+
+/// Generates the puff shown when an object dies, a soft gray disc, as a
+/// PNG file. Luanti ships it with the client, so servers don't send it.
+fn generate_smoke_puff() -> anyhow::Result<Vec<u8>> {
+    const SIZE: u32 = 16;
+    let img = image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        let center = (SIZE as f32 - 1.0) / 2.0;
+        let distance = (x as f32 - center).hypot(y as f32 - center) / (SIZE as f32 / 2.0);
+        let alpha = (1.0 - distance).clamp(0.0, 1.0).sqrt();
+        image::Rgba([0xc0, 0xc0, 0xc0, (alpha * 255.0) as u8])
+    });
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+    Ok(data)
+}
+
+/// A problem with a media file, summarized to the user after loading.
+#[derive(Debug, Clone)]
+pub struct MediaIssue {
+    pub name: String,
+    pub problem: String,
+}
+
+impl fmt::Display for MediaIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.problem)
+    }
+}
+
+/// Where a media file was resolved from, for debugging purposes.
+#[derive(Debug, Clone)]
+pub enum MediaOrigin {
+    /// Embedded into the executable
+    Builtin,
+    /// Found in the media cache
+    Cache(PathBuf),
+    /// Downloaded from the server, then written to the media cache
+    Downloaded(PathBuf),
+    /// Downloaded from the server, but only kept in memory
+    Memory,
+    /// Replaced by a file from the texture pack
+    TexturePack(PathBuf),
+}
+
+impl fmt::Display for MediaOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaOrigin::Builtin => write!(f, "builtin"),
+            MediaOrigin::Cache(path) => write!(f, "cache ({})", path.display()),
+            MediaOrigin::Downloaded(path) => write!(f, "downloaded ({})", path.display()),
+            MediaOrigin::Memory => write!(f, "downloaded (not cached)"),
+            MediaOrigin::TexturePack(path) => write!(f, "texture pack ({})", path.display()),
+        }
+    }
 }
 
 /// A media manager. Media is identified by file name. To use a file, it must be
 /// "added" to the media manager first. Then it can be "gotten" by file name.
+/// Files in the texture pack take precedence over added files.
 pub struct MediaManager {
     base64: base64::engine::GeneralPurpose,
     cache_dir: PathBuf,
     /// File name -> path or bytes
     map: HashMap<String, MediaSource>,
+    /// File name -> file in the texture pack
+    overrides: HashMap<String, MediaSource>,
+    /// File name -> announced SHA1, for files that weren't found in the
+    /// cache and haven't been downloaded yet
+    missing: HashMap<String, Vec<u8>>,
+    /// File name -> origin
+    origins: HashMap<String, MediaOrigin>,
+    issues: Vec<MediaIssue>,
 }
 
 impl MediaManager {
     /// A fallback texture that is guaranteed to always be available.
     pub const FALLBACK_TEXTURE: &str = "no_texture.png";
+    /// The texture of nodes with unknown content ids, also always available.
+    pub const UNKNOWN_NODE_TEXTURE: &str = "unknown_node.png";
+    /// Shown when an object dies, may be replaced by the server.
+    // Compare to Luanti, client/content_cso.cpp, SmokePuffCSO
+    pub const SMOKE_PUFF_TEXTURE: &str = "smoke_puff.png";
 
-    pub fn new() -> anyhow::Result<Self> {
+    /// `texture_pack` is the directory of the texture pack to use, if any.
+    pub fn new(texture_pack: Option<&Path>, fallback: FallbackTexture) -> anyhow::Result<Self> {
         let base64 = base64::engine::GeneralPurpose::new(
             &base64::alphabet::STANDARD,
             base64::engine::GeneralPurposeConfig::new()
@@ -35,86 +167,545 @@ impl MediaManager {
         cache_dir.push(".minetest/cache/media");
         fs::create_dir_all(&cache_dir)?;
 
+        // Magenta and black, so unknown nodes stand out from missing media
+        let unknown_node = FallbackTexture {
+            size: fallback.size,
+            color: [0xff, 0x00, 0xff],
+        };
+        let builtin = [
+            (Self::FALLBACK_TEXTURE, fallback.generate(0.6)?),
+            (Self::UNKNOWN_NODE_TEXTURE, unknown_node.generate(0.0)?),
+            (Self::SMOKE_PUFF_TEXTURE, generate_smoke_puff()?),
+        ];
         let mut map = HashMap::new();
-        map.insert(
-            String::from(Self::FALLBACK_TEXTURE),
-            MediaSource::Bytes(include_bytes!("no_texture.png")),
-        );
+        let mut origins = HashMap::new();
+        for (name, data) in builtin {
+            map.insert(String::from(name), MediaSource::Memory(data));
+            origins.insert(String::from(name), MediaOrigin::Builtin);
+        }
+
+        let mut overrides = HashMap::new();
+        if let Some(dir) = texture_pack {
+            match Self::scan_texture_pack(dir) {
+                Ok(files) => {
+                    println!(
+                        "Using {} files from texture pack {}",
+                        files.len(),
+                        dir.display()
+                    );
+                    for (name, path) in files {
+                        origins.insert(name.clone(), MediaOrigin::TexturePack(path.clone()));
+                        overrides.insert(name, MediaSource::Path(path));
+                    }
+                }
+                Err(err) => println!(
+                    "Could not read texture pack {}, ignoring it: {:?}",
+                    dir.display(),
+                    err
+                ),
+            }
+        }
 
         Ok(Self {
             base64,
             cache_dir,
             map,
+            overrides,
+            missing: HashMap::new(),
+            origins,
+            issues: Vec::new(),
         })
     }
 
+    /// Returns the files in a texture pack directory and its subdirectories
+    /// by name. Hidden files and directories are skipped. If several files
+    /// have the same name, the one closest to the top wins.
+    // Compare to Luanti, client/tile.cpp, getTextureDirs
+    fn scan_texture_pack(dir: &Path) -> anyhow::Result<HashMap<String, PathBuf>> {
+        let mut files = HashMap::new();
+        let mut dirs = VecDeque::from([dir.to_path_buf()]);
+        while let Some(dir) = dirs.pop_front() {
+            let mut entries = fs::read_dir(&dir)?.collect::<Result<Vec<_>, _>>()?;
+            // Deterministic order for files with the same name
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if name.starts_with('.') {
+                    continue;
+                }
+                if entry.file_type()?.is_dir() {
+                    dirs.push_back(entry.path());
+                } else {
+                    files.entry(name).or_insert_with(|| entry.path());
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Adds a file received from the server. Its origin is only recorded if
+    /// the texture pack doesn't replace it.
+    fn insert(&mut self, name: &str, source: MediaSource, origin: MediaOrigin) {
+        self.map.insert(String::from(name), source);
+        if !self.overrides.contains_key(name) {
+            self.origins.insert(String::from(name), origin);
+        }
+    }
+
+    /// How often reading a cache file is attempted before giving up on it
+    const CACHE_READ_ATTEMPTS: u32 = 3;
+
     /// Tries to find a file with the given sha1 in the existing Luanti media
     /// cache, and adds it to the media manager as `name`.
     /// Returns Ok(true) on success.
-    /// Returns Ok(false) if there is no such file in the cache.
+    /// Returns Ok(false) if there is no such file in the cache, or if it is
+    /// unreadable or corrupted. Such files are moved out of the cache, so they
+    /// are downloaded again. The file is expected to be downloaded then, see
+    /// `add_from_bytes`.
     /// Returns Err(err) for unexpected errors (bad base64, IO error).
-    pub fn try_add_from_cache(&mut self, name: &str, sha1_base64: &str) -> anyhow::Result<bool> {
+    pub async fn try_add_from_cache(
+        &mut self,
+        name: &str,
+        sha1_base64: &str,
+    ) -> anyhow::Result<bool> {
         // The encoding choices made here are very curious
         let sha1_raw = self.base64.decode(&sha1_base64)?;
-        let sha1_hex = hex::encode(sha1_raw);
+        let found = self.add_from_cache(name, &sha1_raw).await?;
+        if !found {
+            self.missing.insert(String::from(name), sha1_raw);
+        }
+        Ok(found)
+    }
 
-        let path = self.cache_dir.join(sha1_hex);
-        let exists = path.try_exists()?;
-        if exists {
-            self.map.insert(String::from(name), MediaSource::Path(path));
-        }
-        Ok(exists)
-    }
-
-    /// Adds the given file to the media manager, and to the Luanti media cache.
-    /// Returns Ok(()) on success.
-    /// Returns Err(err) for IO errors.
-    pub fn add_from_bytes(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
-        // TODO: check the file is in the missing list (was announced, was not
-        // found in cache, hash matches announced hash)
-        let mut hasher = Sha1::new();
-        hasher.update(data);
-        let sha1_raw = hasher.finalize();
+    async fn add_from_cache(&mut self, name: &str, sha1_raw: &[u8]) -> anyhow::Result<bool> {
         let sha1_hex = hex::encode(sha1_raw);
 
         let path = self.cache_dir.join(sha1_hex);
-        fs::write(&path, data)?;
-        self.map.insert(String::from(name), MediaSource::Path(path));
-        Ok(())
+        if !path.try_exists()? {
+            return Ok(false);
+        }
+
+        let data = match Self::read_with_retry(&path).await {
+            Ok(data) => data,
+            Err(err) => {
+                self.quarantine(name, &path, format!("unreadable cache file: {}", err));
+                return Ok(false);
+            }
+        };
+        if Sha1::digest(&data).as_slice() != sha1_raw {
+            self.quarantine(name, &path, String::from("corrupted cache file"));
+            return Ok(false);
+        }
+
+        self.insert(
+            name,
+            MediaSource::Path(path.clone()),
+            MediaOrigin::Cache(path),
+        );
+        Ok(true)
+    }
+
+    async fn read_with_retry(path: &Path) -> std::io::Result<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            match fs::read(path) {
+                Ok(data) => return Ok(data),
+                Err(err) if attempt >= Self::CACHE_READ_ATTEMPTS => return Err(err),
+                Err(_) => {
+                    attempt += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        }
+    }
+
+    /// Moves a bad cache file to the quarantine directory (or deletes it if
+    /// that fails), and records the issue.
+    fn quarantine(&mut self, name: &str, path: &Path, problem: String) {
+        println!(
+            "Media file \"{}\" ({}): {}, will download again",
+            name,
+            path.display(),
+            problem
+        );
+
+        let moved = Settings::config_dir()
+            .map(|dir| dir.join("media_quarantine"))
+            .and_then(|dir| {
+                fs::create_dir_all(&dir)?;
+                fs::rename(path, dir.join(path.file_name().unwrap()))?;
+                Ok(())
+            });
+        if moved.is_err() {
+            let _ = fs::remove_file(path);
+        }
+
+        self.add_issue(name, problem);
+    }
+
+    /// Adds a downloaded file to the media manager, and to the Luanti media
+    /// cache. Files that weren't announced or were already found in the cache
+    /// are ignored, files that don't match the announced SHA1 are rejected.
+    /// If writing to the cache fails, the file is kept in memory instead.
+    // Compare to Luanti, client/clientmedia.cpp, ClientMediaDownloader::checkAndLoad
+    pub fn add_from_bytes(&mut self, name: &str, data: &[u8]) {
+        let Some(sha1_raw) = self.missing.remove(name) else {
+            println!("Ignoring media file \"{}\", it wasn't requested", name);
+            return;
+        };
+        let sha1 = Sha1::digest(data);
+        if sha1.as_slice() != sha1_raw {
+            println!(
+                "Media file \"{}\" doesn't match the announced hash, ignoring it",
+                name
+            );
+            self.add_issue(name, String::from("downloaded file is corrupted"));
+            return;
+        }
+
+        let path = self.cache_dir.join(hex::encode(sha1));
+        match Self::write_to_cache(&path, data) {
+            Ok(()) => {
+                self.insert(
+                    name,
+                    MediaSource::Path(path.clone()),
+                    MediaOrigin::Downloaded(path),
+                );
+            }
+            Err(err) => {
+                println!("Could not write media file \"{}\" to cache: {}", name, err);
+                self.insert(
+                    name,
+                    MediaSource::Memory(data.to_vec()),
+                    MediaOrigin::Memory,
+                );
+                self.add_issue(name, format!("not cached: {}", err));
+            }
+        }
+    }
+
+    /// Writes under a temporary name first, so an interrupted write doesn't
+    /// leave a truncated file in the cache.
+    fn write_to_cache(path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Records an issue for each requested file that wasn't downloaded.
+    /// Called once the server has sent all files.
+    pub fn finish_downloads(&mut self) {
+        let mut names: Vec<String> = self.missing.drain().map(|(name, _)| name).collect();
+        names.sort();
+        for name in names {
+            println!("Media file \"{}\" wasn't sent by the server", name);
+            self.add_issue(&name, String::from("not sent by the server"));
+        }
+    }
+
+    pub fn add_issue(&mut self, name: &str, problem: String) {
+        self.issues.push(MediaIssue {
+            name: String::from(name),
+            problem,
+        });
+    }
+
+    /// Returns the problems encountered so far, clearing the list.
+    pub fn take_issues(&mut self) -> Vec<MediaIssue> {
+        std::mem::take(&mut self.issues)
     }
 
-    /// Gets a file from the media manager.
+    /// Returns where each file was resolved from.
+    pub fn origins(&self) -> &HashMap<String, MediaOrigin> {
+        &self.origins
+    }
+
+    /// Gets a file from the media manager, preferring the texture pack.
     /// Returns None if the file name is unknown.
     pub fn get(&self, name: &str) -> Option<&MediaSource> {
-        self.map.get(name)
+        self.overrides.get(name).or_else(|| self.map.get(name))
+    }
+
+    /// Returns the names of all added files. Files only in the texture pack
+    /// aren't included.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.map.keys().map(String::as_str)
+    }
+
+    /// Reads a file from the media manager into memory.
+    /// Returns Ok(None) if the file name is unknown.
+    pub fn load_bytes(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(source) = self.get(name) else {
+            return Ok(None);
+        };
+        let data = match source {
+            MediaSource::Path(path) => fs::read(path)?,
+            MediaSource::Memory(data) => data.clone(),
+        };
+        Ok(Some(data))
+    }
+
+    /// Loads an image from the media manager.
+    /// Returns Ok(None) if the file name is unknown.
+    /// Returns Err(err) for image loading errors.
+    pub fn load_image(&self, name: &str) -> anyhow::Result<Option<image::DynamicImage>> {
+        self.get(name).map(MediaSource::load_image).transpose()
+    }
+
+    /// Loads a mesh file from the media manager.
+    /// Returns Ok(None) if the file name is unknown.
+    /// Returns Err(err) for parsing errors.
+    pub fn load_model(&self, name: &str) -> anyhow::Result<Option<Arc<Model>>> {
+        let Some(data) = self.load_bytes(name)? else {
+            return Ok(None);
+        };
+        Ok(Some(Arc::new(Model::parse(name, &data)?)))
     }
 }
 
 pub struct NodeTextureData {
     pub bind_group_layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
+    /// The file name and animation of each texture, same indices as the
+    /// binding array
+    pub files: Vec<(String, Option<VerticalFrames>)>,
+    // Kept for recreating the bind group
+    views: Vec<wgpu::TextureView>,
+    animation_buffer: wgpu::Buffer,
+    filtering: TextureFiltering,
+    /// Set when a texture was loaded since the bind group was created
+    dirty: bool,
+}
+
+impl NodeTextureData {
+    /// Recreates the sampler and the bind group, e.g. after the texture
+    /// filter was changed.
+    pub fn set_filtering(&mut self, device: &wgpu::Device, filtering: TextureFiltering) {
+        self.filtering = filtering;
+        self.dirty = true;
+        self.prepare(device);
+    }
+
+    /// Replaces the placeholder of a texture that was loaded in the
+    /// background. Takes effect with the next call to `prepare`.
+    pub fn update(&mut self, queue: &wgpu::Queue, update: NodeTextureUpdate) {
+        queue.write_buffer(
+            &self.animation_buffer,
+            (update.index * size_of::<TextureAnimationUniform>()) as wgpu::BufferAddress,
+            bytemuck::bytes_of(&update.uniform),
+        );
+        self.views[update.index] = update.view;
+        self.dirty = true;
+    }
+
+    /// Recreates the bind group if textures were updated. Called once per
+    /// frame, so that many textures coming in at once only cause a single
+    /// rebuild.
+    pub fn prepare(&mut self, device: &wgpu::Device) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        self.bind_group = NodeTextureManager::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.views,
+            &self.animation_buffer,
+            self.filtering,
+        );
+    }
+}
+
+/// How node textures are sampled when they are drawn smaller than their
+/// size. They are magnified with nearest filtering, so pixels stay sharp up
+/// close, except with anisotropic filtering, see TextureFiltering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureFilter {
+    Nearest,
+    /// Linear filtering within the nearest mip level
+    Bilinear,
+    /// Linear filtering between mip levels as well
+    Trilinear,
+}
+
+impl TextureFilter {
+    pub fn next(self) -> Self {
+        match self {
+            TextureFilter::Nearest => TextureFilter::Bilinear,
+            TextureFilter::Bilinear => TextureFilter::Trilinear,
+            TextureFilter::Trilinear => TextureFilter::Nearest,
+        }
+    }
+}
+
+/// See Settings::texture_filter and Settings::anisotropic_filter.
+/// Anisotropy is only applied with trilinear filtering, and then
+/// magnification is linear as well: wgpu only allows anisotropy if all
+/// filters are linear.
+#[derive(Debug, Clone, Copy)]
+pub struct TextureFiltering {
+    pub filter: TextureFilter,
+    pub anisotropy: u16,
+}
+
+impl TextureFiltering {
+    fn create_sampler(&self, device: &wgpu::Device) -> wgpu::Sampler {
+        let (min_filter, mipmap_filter) = match self.filter {
+            TextureFilter::Nearest => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest),
+            TextureFilter::Bilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest),
+            TextureFilter::Trilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear),
+        };
+        let anisotropic = self.filter == TextureFilter::Trilinear && self.anisotropy > 1;
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Node texture sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: if anisotropic {
+                wgpu::FilterMode::Linear
+            } else {
+                wgpu::FilterMode::Nearest
+            },
+            min_filter,
+            mipmap_filter,
+            anisotropy_clamp: if anisotropic { self.anisotropy } else { 1 },
+            ..wgpu::SamplerDescriptor::default()
+        })
+    }
+}
+
+/// A "vertical_frames" tile animation: the frames are stacked vertically in
+/// the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VerticalFrames {
+    pub aspect_w: u32,
+    pub aspect_h: u32,
+    /// Length of the whole animation in milliseconds
+    pub length_ms: u32,
+}
+
+impl VerticalFrames {
+    /// Returns None if the tile isn't animated.
+    pub fn from_tile(tile: &TileDef) -> Option<Self> {
+        match tile.animation {
+            TileAnimationParams::VerticalFrames {
+                aspect_w,
+                aspect_h,
+                length,
+            } if aspect_w > 0 && aspect_h > 0 && length > 0.0 => Some(Self {
+                aspect_w: aspect_w as u32,
+                aspect_h: aspect_h as u32,
+                length_ms: (length * 1000.0) as u32,
+            }),
+            // TODO: support sheet_2d animations
+            _ => None,
+        }
+    }
+
+    /// Splits the image into its frames.
+    pub fn split(&self, img: &image::RgbaImage) -> Vec<image::RgbaImage> {
+        let frame_height = (img.width() * self.aspect_h / self.aspect_w).max(1);
+        let frame_count = (img.height() / frame_height).max(1);
+        (0..frame_count)
+            .map(|frame| {
+                image::imageops::crop_imm(img, 0, frame * frame_height, img.width(), frame_height)
+                    .to_image()
+            })
+            .collect()
+    }
+}
+
+/// Per-texture animation parameters, read by the mapblock shader.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextureAnimationUniform {
+    frame_count: u32,
+    /// In seconds
+    frame_length: f32,
+}
+
+/// A node texture loaded into memory, but not uploaded yet. See
+/// NodeTextureManager::decode_texture.
+pub struct DecodedTexture {
+    /// The mip levels of each frame
+    frames: Vec<Vec<image::RgbaImage>>,
+    uniform: TextureAnimationUniform,
+}
+
+/// A node texture that was uploaded after NodeTextureManager::finish, see
+/// NodeTextureManager::upload_texture and NodeTextureData::update.
+pub struct NodeTextureUpdate {
+    index: usize,
+    view: wgpu::TextureView,
+    uniform: TextureAnimationUniform,
 }
 
 /// A node texture manager using "bindless" textures (yay!)
+///
+/// Every texture is a 2D array texture. Animated textures have one layer per
+/// frame, the mapblock shader selects the layer based on the time. This way,
+/// an animation only takes up a single slot in the binding array.
+///
+/// Indices can be reserved before the texture is loaded. Until then, the
+/// fallback texture takes its place in the binding array, so most textures
+/// can be loaded in the background after `finish`.
 pub struct NodeTextureManager {
-    texture_vec: Vec<MyTexture>,
+    // None for textures that were reserved, but not loaded yet
+    texture_vec: Vec<Option<MyTexture>>,
+    // same indices as texture_vec
+    animation_vec: Vec<TextureAnimationUniform>,
     // contains indices into texture_vec
-    texture_map: HashMap<String, usize>,
+    texture_map: HashMap<(String, Option<VerticalFrames>), usize>,
+    /// See Settings::texture_min_size
+    min_size: u32,
 
     finished: bool,
 }
 
 impl NodeTextureManager {
-    pub fn new() -> Self {
+    /// Upscaled textures are at most this large
+    const MAX_UPSCALED_SIZE: u32 = 4096;
+
+    /// Animation parameters of textures that aren't animated, including the
+    /// placeholders of textures that haven't been loaded yet
+    const NOT_ANIMATED: TextureAnimationUniform = TextureAnimationUniform {
+        frame_count: 1,
+        frame_length: 1.0,
+    };
+
+    pub fn new(min_size: u32) -> Self {
         Self {
             texture_vec: Vec::new(),
+            animation_vec: Vec::new(),
             texture_map: HashMap::new(),
+            min_size,
             finished: false,
         }
     }
 
-    /// Adds the texture with the given file name if it hasn't been added already,
-    /// allocating an index for it.
+    /// Allocates an index for the texture with the given file name and
+    /// animation without loading it, unless it has been added already.
+    /// Returns the index.
+    ///
+    /// `finish` must not have been called yet.
+    pub fn reserve_texture(&mut self, name: &str, animation: Option<VerticalFrames>) -> usize {
+        assert!(!self.finished);
+
+        let key = (String::from(name), animation);
+        if let Some(&index) = self.texture_map.get(&key) {
+            return index;
+        }
+        self.texture_vec.push(None);
+        self.animation_vec.push(Self::NOT_ANIMATED);
+        let index = self.texture_vec.len() - 1;
+        self.texture_map.insert(key, index);
+        index
+    }
+
+    /// Adds the texture with the given file name and animation and loads it
+    /// right away, unless it has been loaded already.
     /// Returns Ok(true) on success.
     /// Returns Ok(false) if the file name is unknown.
     /// Returns Err(err) for texture loading errors.
@@ -126,63 +717,139 @@ impl NodeTextureManager {
         queue: &wgpu::Queue,
         media: &MediaManager,
         name: &str,
+        animation: Option<VerticalFrames>,
     ) -> anyhow::Result<bool> {
-        assert!(!self.finished);
-
-        if self.texture_map.contains_key(name) {
-            return Ok(true);
-        }
-
         let Some(source) = media.get(name) else {
             return Ok(false);
         };
-        let texture = match source {
-            MediaSource::Path(path) => MyTexture::from_path(device, queue, name, path),
-            MediaSource::Bytes(bytes) => MyTexture::from_bytes(device, queue, name, bytes),
-        }?;
-        self.texture_vec.push(texture);
-        let index = self.texture_vec.len() - 1;
-        self.texture_map.insert(String::from(name), index);
+        let index = self.reserve_texture(name, animation);
+        if self.texture_vec[index].is_some() {
+            return Ok(true);
+        }
+        let decoded = Self::decode_texture(source, animation, self.min_size)?;
+        self.texture_vec[index] = Some(MyTexture::from_layers(
+            device,
+            queue,
+            name,
+            &decoded.frames,
+        )?);
+        self.animation_vec[index] = decoded.uniform;
         Ok(true)
     }
 
-    /// Returns the index allocated for the texture with the given file name.
-    /// Returns None if the file name is unknown.
+    /// Loads the image of a texture, splits it into its animation frames and
+    /// generates the mip levels. Textures smaller than `min_size` are
+    /// upscaled first, see Settings::texture_min_size.
+    /// Doesn't touch the NodeTextureManager or the GPU, so textures can be
+    /// decoded in parallel and uploaded afterwards.
+    /// Returns Err(err) for texture loading errors.
+    // Compare to Luanti, client/imagesource.cpp, the [applyfiltersformesh modifier
+    pub fn decode_texture(
+        source: &MediaSource,
+        animation: Option<VerticalFrames>,
+        min_size: u32,
+    ) -> anyhow::Result<DecodedTexture> {
+        let mut img = source.load_image()?.to_rgba8();
+
+        // Nearest neighbor by an integer factor, so pixels stay sharp in the
+        // mip levels
+        let (width, height) = img.dimensions();
+        let factor = min_size
+            .div_ceil(width.min(height))
+            .min(Self::MAX_UPSCALED_SIZE / width.max(height));
+        if factor > 1 {
+            img = image::imageops::resize(
+                &img,
+                width * factor,
+                height * factor,
+                image::imageops::FilterType::Nearest,
+            );
+        }
+
+        let decoded = match animation {
+            Some(animation) => {
+                let frames: Vec<_> = animation.split(&img).into_iter().map(mip_chain).collect();
+                let frame_count = frames.len() as u32;
+                let uniform = TextureAnimationUniform {
+                    frame_count,
+                    frame_length: animation.length_ms as f32 / 1000.0 / frame_count as f32,
+                };
+                DecodedTexture { frames, uniform }
+            }
+            None => DecodedTexture {
+                frames: vec![mip_chain(img)],
+                uniform: Self::NOT_ANIMATED,
+            },
+        };
+        Ok(decoded)
+    }
+
+    /// Uploads a decoded texture for a reserved index after `finish`. The
+    /// result goes to NodeTextureData::update.
+    pub fn upload_texture(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        index: usize,
+        name: &str,
+        decoded: DecodedTexture,
+    ) -> anyhow::Result<NodeTextureUpdate> {
+        let texture = MyTexture::from_layers(device, queue, name, &decoded.frames)?;
+        Ok(NodeTextureUpdate {
+            index,
+            view: texture.view,
+            uniform: decoded.uniform,
+        })
+    }
+
+    /// Returns the index allocated for the texture with the given file name
+    /// and animation.
+    /// Returns None if the texture is unknown.
     ///
     /// `finish` must have been called.
-    pub fn get_texture_index(&self, name: &str) -> Option<usize> {
+    pub fn get_texture_index(
+        &self,
+        name: &str,
+        animation: Option<VerticalFrames>,
+    ) -> Option<usize> {
         assert!(self.finished);
 
-        self.texture_map.get(name).copied()
+        self.texture_map
+            .get(&(String::from(name), animation))
+            .copied()
     }
 
     /// Finishes the NodeTextureManager, preventing further modification.
     /// Creates the bind group (layout) so the textures can be used for
-    /// rendering.
-    pub fn finish(&mut self, device: &wgpu::Device) -> NodeTextureData {
+    /// rendering. The fallback texture must have been added, it stands in
+    /// for the textures that haven't been loaded yet.
+    pub fn finish(
+        &mut self,
+        device: &wgpu::Device,
+        filtering: TextureFiltering,
+    ) -> NodeTextureData {
         assert!(!self.finished);
         self.finished = true;
 
-        let texture_view_vec: Vec<&wgpu::TextureView> = self
+        let fallback = &self.texture_map[&(String::from(MediaManager::FALLBACK_TEXTURE), None)];
+        let fallback_view = self.texture_vec[*fallback].as_ref().unwrap().view.clone();
+        let views: Vec<wgpu::TextureView> = self
             .texture_vec
             .iter()
-            .map(|texture| &texture.view)
+            .map(|texture| match texture {
+                Some(texture) => texture.view.clone(),
+                None => fallback_view.clone(),
+            })
             .collect();
 
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("Node texture sampler"),
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Linear,
-            ..wgpu::SamplerDescriptor::default()
+        let animation_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Node texture animation buffer"),
+            contents: bytemuck::cast_slice(&self.animation_vec),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
 
         // TODO: check if we are within limits (but we almost definitely are if
         // the bindless features are available)
-        let count = NonZero::new(self.texture_vec.len() as u32).unwrap();
+        let count = NonZero::new(views.len() as u32).unwrap();
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Node texture bind group layout"),
@@ -192,7 +859,7 @@ impl NodeTextureManager {
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
                         multisampled: false,
                     },
                     count: Some(count),
@@ -203,27 +870,86 @@ impl NodeTextureManager {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &views,
+            &animation_buffer,
+            filtering,
+        );
+
+        let mut files = vec![(String::new(), None); self.texture_vec.len()];
+        for ((name, animation), &index) in &self.texture_map {
+            files[index] = (name.clone(), *animation);
+        }
+
+        NodeTextureData {
+            bind_group_layout,
+            bind_group,
+            files,
+            views,
+            animation_buffer,
+            filtering,
+            dirty: false,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        views: &[wgpu::TextureView],
+        animation_buffer: &wgpu::Buffer,
+        filtering: TextureFiltering,
+    ) -> wgpu::BindGroup {
+        let view_refs: Vec<&wgpu::TextureView> = views.iter().collect();
+        let sampler = filtering.create_sampler(device);
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Node texture bind group"),
-            layout: &bind_group_layout,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureViewArray(&texture_view_vec),
+                    resource: wgpu::BindingResource::TextureViewArray(&view_refs),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
                     resource: wgpu::BindingResource::Sampler(&sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: animation_buffer.as_entire_binding(),
+                },
             ],
-        });
+        })
+    }
+}
 
-        NodeTextureData {
-            bind_group_layout,
-            bind_group,
-        }
+/// Returns the image followed by its mip levels, down to 1x1.
+fn mip_chain(img: image::RgbaImage) -> Vec<image::RgbaImage> {
+    let levels = 32 - img.width().max(img.height()).leading_zeros();
+    let mut chain = vec![img];
+    for _ in 1..levels {
+        let last = chain.last().unwrap();
+        let next = image::imageops::resize(
+            last,
+            (last.width() / 2).max(1),
+            (last.height() / 2).max(1),
+            image::imageops::FilterType::Triangle,
+        );
+        chain.push(next);
     }
+    chain
 }
@@ -1,16 +1,22 @@
-use std::sync::Arc;
-use std::time::Instant;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use glam::{I16Vec3, Vec2, Vec3};
+use glam::{I16Vec3, Quat, Vec2, Vec3};
 use luanti_core::{ContentId, MapBlockNodes, MapBlockPos, MapNode, MapNodePos};
-use luanti_protocol::types::DrawType;
+use luanti_protocol::types::{ContentFeatures, DrawType, NodeBox, ParamType2, TileAnimationParams};
 use tokio::sync::mpsc;
 use wgpu::util::DeviceExt;
 
+use crate::farmesh::{self, BlockSurface};
 use crate::frustum::BoundingSphere;
-use crate::luanti_client::ClientToMainEvent;
+use crate::light::{self, LightBank};
+use crate::luanti_client::{ClientOptions, ClientToMainEvent};
 use crate::map::{LuantiMap, MeshgenMapData, NEIGHBOR_DIRS};
-use crate::media::{MediaManager, NodeTextureManager};
+use crate::media::{MediaIssue, MediaManager, MediaSource, NodeTextureManager, VerticalFrames};
+use crate::model::ModelManager;
 use crate::node_def::NodeDefManager;
 
 pub struct Meshgen {
@@ -18,20 +24,47 @@ pub struct Meshgen {
     queue: wgpu::Queue,
     main_tx: mpsc::UnboundedSender<ClientToMainEvent>,
     pool: rayon::ThreadPool,
+    /// Read by the worker tasks when they start generating a mesh
+    map: Arc<RwLock<LuantiMap>>,
 
     node_def: Arc<NodeDefManager>,
     textures: Arc<NodeTextureManager>,
+    models: Arc<ModelManager>,
+    /// Node textures that have an index, but haven't been loaded yet
+    texture_jobs: Vec<TextureJob>,
+    /// See Settings::texture_min_size
+    min_texture_size: u32,
+    /// Number of submitted tasks that haven't finished yet, including the
+    /// queued ones
+    pending_tasks: Arc<AtomicUsize>,
+    /// See Settings::greedy_meshing
+    greedy_meshing: bool,
+    /// See Settings::retain_meshes
+    retain_meshes: bool,
+    /// Mapblocks waiting for a free thread, shared with the worker tasks
+    queue: Arc<Mutex<MeshgenQueue>>,
+
+    /// While paused, submitted mapblocks are held back instead of being
+    /// spawned on the thread pool. Debugging aid, see Meshgen::set_paused.
+    paused: bool,
+    /// Mapblocks submitted while paused, with the time of the first
+    /// submission
+    held: HashMap<MapBlockPos, Instant>,
 }
 
 /// A thread pool for generating mapblock meshes and uploading them to the GPU.
 impl Meshgen {
-    /// Creates the meshgen, setting up the thread pool.
+    /// Creates the meshgen, setting up the thread pool. Indices for the node
+    /// textures are reserved in a fixed order, but only the fallback texture
+    /// is loaded here. See Meshgen::load_textures for the others.
     pub fn new(
         device: wgpu::Device,
         queue: wgpu::Queue,
         main_tx: mpsc::UnboundedSender<ClientToMainEvent>,
+        map: Arc<RwLock<LuantiMap>>,
         mut node_def: NodeDefManager,
-        media: MediaManager,
+        media: &mut MediaManager,
+        options: &ClientOptions,
     ) -> Self {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(0)
@@ -39,86 +72,454 @@ impl Meshgen {
             .build()
             .unwrap();
 
-        let mut textures = NodeTextureManager::new();
-
-        for (_, def) in &mut node_def.map {
+        // Strip texture modifiers, and collect the textures to load. Tiles
+        // using the same texture share a single job.
+        let mut jobs = Vec::new();
+        let mut seen = HashSet::new();
+        for def in node_def.map.values_mut() {
             for tile in &mut def.tiledef {
-                // strip texture modifiers
                 let name_simple = tile.name.split('^').next().unwrap();
                 tile.name = String::from(name_simple);
-
-                match textures.add_texture(&device, &queue, &media, &tile.name) {
-                    Ok(exists) => {
-                        if exists {
-                            continue;
-                        } else {
-                            println!(
-                                "Missing texture \"{}\" for node \"{}\"",
-                                tile.name, def.name
-                            );
-                        }
-                    }
-                    Err(err) => {
-                        println!("Error while loading texture \"{}\": {:?}", tile.name, err);
-                    }
+                let key = (tile.name.clone(), VerticalFrames::from_tile(tile));
+                if seen.insert(key.clone()) {
+                    jobs.push(key);
                 }
+            }
+        }
+        // The node definitions are a HashMap, sort so that the indices don't
+        // depend on its order
+        jobs.sort();
 
-                // normally skipped by `continue`
-                tile.name = String::from(MediaManager::FALLBACK_TEXTURE);
-                assert!(
-                    textures
-                        .add_texture(&device, &queue, &media, &tile.name)
-                        .unwrap()
+        // The fallback texture stands in for all the others until they are
+        // loaded, so it's needed right away
+        let mut textures = NodeTextureManager::new(options.texture_min_size);
+        assert!(
+            textures
+                .add_texture(&device, &queue, media, MediaManager::FALLBACK_TEXTURE, None)
+                .unwrap()
+        );
+
+        let mut texture_jobs = Vec::new();
+        let mut missing = HashSet::new();
+        for (name, animation) in jobs {
+            if name == MediaManager::FALLBACK_TEXTURE && animation.is_none() {
+                continue;
+            }
+            let Some(source) = media.get(&name) else {
+                missing.insert((name, animation));
+                continue;
+            };
+            texture_jobs.push(TextureJob {
+                index: textures.reserve_texture(&name, animation),
+                source: source.clone(),
+                name,
+                animation,
+            });
+        }
+
+        // Tiles whose texture doesn't exist use the fallback texture
+        for def in node_def.map.values_mut() {
+            for tile in &mut def.tiledef {
+                let key = (tile.name.clone(), VerticalFrames::from_tile(tile));
+                if !missing.contains(&key) {
+                    continue;
+                }
+                println!(
+                    "Missing texture \"{}\" for node \"{}\"",
+                    tile.name, def.name
                 );
+                tile.name = String::from(MediaManager::FALLBACK_TEXTURE);
+                tile.animation = TileAnimationParams::None;
             }
         }
 
-        let data = textures.finish(&device);
-        main_tx
-            .send(ClientToMainEvent::MapblockTextureData(data))
-            .unwrap();
+        // Parsed once here, the tasks share the models
+        let mut models = ModelManager::default();
+        for def in node_def.map.values() {
+            if def.drawtype != DrawType::Mesh || def.mesh.is_empty() {
+                continue;
+            }
+            match models.load(media, &def.mesh) {
+                Ok(Some(_)) => (),
+                Ok(None) => println!(
+                    "Missing or invalid mesh \"{}\" for node \"{}\"",
+                    def.mesh, def.name
+                ),
+                Err(err) => {
+                    println!("Error while loading mesh \"{}\": {:?}", def.mesh, err);
+                    media.add_issue(&def.mesh, format!("could not load mesh: {}", err));
+                }
+            }
+        }
+
+        let data = textures.finish(&device, options.texture_filtering);
+        let _ = main_tx.send(ClientToMainEvent::MapblockTextureData(data));
 
         Self {
             device,
             queue,
             main_tx,
             pool,
+            map,
             node_def: Arc::new(node_def),
             textures: Arc::new(textures),
+            models: Arc::new(models),
+            texture_jobs,
+            min_texture_size: options.texture_min_size,
+            pending_tasks: Arc::new(AtomicUsize::new(0)),
+            greedy_meshing: options.greedy_meshing,
+            retain_meshes: options.retain_meshes,
+            queue: Arc::new(Mutex::new(MeshgenQueue::new())),
+            paused: false,
+            held: HashMap::new(),
         }
     }
 
-    /// Submits a mapblock for mesh generation.
+    /// Returns the number of node textures that haven't been loaded yet.
+    pub fn textures_to_load(&self) -> usize {
+        self.texture_jobs.len()
+    }
+
+    /// Loads the remaining node textures on the thread pool, in the
+    /// background. Each one is sent to the main thread as a
+    /// ClientToMainEvent::NodeTexture when it's done, replacing the fallback
+    /// texture. They count as pending tasks, so the loading screen waits
+    /// for them.
+    pub fn load_textures(&mut self) {
+        self.pending_tasks
+            .fetch_add(self.texture_jobs.len(), Ordering::Relaxed);
+        for job in self.texture_jobs.drain(..) {
+            let device = self.device.clone();
+            let queue = self.queue.clone();
+            let main_tx = self.main_tx.clone();
+            let pending_tasks = self.pending_tasks.clone();
+            let min_size = self.min_texture_size;
+
+            self.pool.spawn(move || {
+                let result =
+                    NodeTextureManager::decode_texture(&job.source, job.animation, min_size)
+                        .and_then(|decoded| {
+                            NodeTextureManager::upload_texture(
+                                &device, &queue, job.index, &job.name, decoded,
+                            )
+                        });
+                let result = result.map_err(|err| {
+                    println!("Error while loading texture \"{}\": {:?}", job.name, err);
+                    MediaIssue {
+                        name: job.name,
+                        problem: format!("could not load texture: {}", err),
+                    }
+                });
+                let _ = main_tx.send(ClientToMainEvent::NodeTexture(result));
+                pending_tasks.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+
+    /// Returns the counter of submitted tasks that haven't finished yet.
+    pub fn pending_tasks(&self) -> &Arc<AtomicUsize> {
+        &self.pending_tasks
+    }
+
+    /// Returns the node definitions used by meshgen.
+    pub fn node_def(&self) -> &Arc<NodeDefManager> {
+        &self.node_def
+    }
+
+    /// Submits a mapblock for mesh generation. If the mapblock is already
+    /// queued, the submissions are merged into one, as the map data is only
+    /// read when generating starts.
     /// The finished MapblockMesh is returned using the UnboundedSender given to Meshgen::new.
-    pub fn submit(&self, map: &LuantiMap, blockpos: MapBlockPos, block: &MapBlockNodes) {
-        MeshgenTask::spawn(
-            self.device.clone(),
-            self.main_tx.clone(),
-            self.node_def.clone(),
-            self.textures.clone(),
-            &self.pool,
-            map,
+    pub fn submit(&mut self, blockpos: MapBlockPos, block: &MapBlockNodes) {
+        let t = Instant::now();
+        if self.paused {
+            self.held.entry(blockpos).or_insert(t);
+            return;
+        }
+
+        let mut empty = true;
+        for node in &block.0 {
+            // Quick check, not exhaustive (other nodes can have DrawType::Airlike as well).
+            if node.content_id != ContentId::AIR {
+                empty = false;
+            }
+        }
+
+        // If the mapblock is empty, we can skip cloning 7 mapblocks and queueing
+        // the task. A queued task for it would be outdated now.
+        if empty {
+            // println!("Skipped spawning meshgen task for empty {}", blockpos.vec());
+
+            if self.queue.lock().unwrap().remove(blockpos) {
+                self.pending_tasks.fetch_sub(1, Ordering::Relaxed);
+            }
+
+            // The main thread may have left the server already
+            let _ = self
+                .main_tx
+                .send(ClientToMainEvent::MapblockMesh(MapblockMesh {
+                    blockpos: blockpos,
+                    num_indices: 0,
+                    num_vertices: 0,
+                    index_buffer: None,
+                    vertex_buffer: None,
+                    bounding_sphere: None,
+                    mesh: None,
+                    surface: None,
+                    timestamp_task_spawned: t,
+                    generation_time: Duration::ZERO,
+                }));
+            return;
+        }
+
+        // println!("Queueing meshgen task for {}", blockpos.vec());
+
+        self.enqueue(blockpos, t);
+    }
+
+    /// Updates the player position, so that mapblocks near the player are
+    /// meshed first.
+    pub fn set_player_pos(&mut self, pos: Vec3) {
+        let (blockpos, _) = MapNodePos((pos + 0.5).floor().as_i16vec3()).split_index();
+        self.queue.lock().unwrap().set_center(blockpos);
+    }
+
+    /// Queues a mapblock unless it is queued already, and spawns a worker on
+    /// the thread pool if not all threads are busy yet.
+    fn enqueue(&self, blockpos: MapBlockPos, t: Instant) {
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.push(blockpos, t) {
+            return;
+        }
+        self.pending_tasks.fetch_add(1, Ordering::Relaxed);
+        if queue.workers < self.pool.current_num_threads() {
+            queue.workers += 1;
+            self.spawn_worker();
+        }
+    }
+
+    /// Spawns a task on the thread pool that generates meshes for queued
+    /// mapblocks until the queue is empty.
+    fn spawn_worker(&self) {
+        let device = self.device.clone();
+        let main_tx = self.main_tx.clone();
+        let node_def = self.node_def.clone();
+        let textures = self.textures.clone();
+        let models = self.models.clone();
+        let pending_tasks = self.pending_tasks.clone();
+        let greedy_meshing = self.greedy_meshing;
+        let retain_meshes = self.retain_meshes;
+        let queue = self.queue.clone();
+        let map = self.map.clone();
+
+        self.pool.spawn(move || {
+            while let Some(task) = MeshgenQueue::pop(&queue) {
+                // Snapshot the newest map data. The mapblock can be gone if it
+                // was unloaded in the meantime.
+                let data = {
+                    let map = map.read().unwrap();
+                    map.get_block(&task.blockpos)
+                        .map(|block| MeshgenMapData::new(&map, task.blockpos, block))
+                };
+                let Some(data) = data else {
+                    pending_tasks.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                };
+
+                MeshgenTask {
+                    device: device.clone(),
+                    main_tx: main_tx.clone(),
+                    node_def: node_def.clone(),
+                    textures: textures.clone(),
+                    models: models.clone(),
+                    data,
+                    timestamp_task_spawned: task.timestamp,
+                    greedy_meshing,
+                    retain_mesh: retain_meshes,
+                }
+                .generate();
+                pending_tasks.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Pauses or resumes mesh generation. Tasks that are already running are
+    /// not affected. Resuming flushes the mapblocks held back while paused.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if !paused {
+            self.flush();
+        }
+        println!("Meshgen {}", if paused { "paused" } else { "resumed" });
+    }
+
+    /// Queues all mapblocks held back while paused, without
+    /// resuming. Useful for producing a single burst of meshgen work.
+    pub fn flush(&mut self) {
+        let count = self.held.len();
+        for (blockpos, t) in std::mem::take(&mut self.held) {
+            self.enqueue(blockpos, t);
+        }
+        println!("Flushed {} held meshgen tasks", count);
+    }
+}
+
+/// Mapblocks waiting for meshgen. Tasks are taken nearest to the player
+/// first, so the terrain around the player appears before the terrain far
+/// away. Each mapblock is queued at most once.
+// Compare to Luanti, client/mesh_generator_thread.cpp, MeshUpdateQueue
+struct MeshgenQueue {
+    /// Can contain outdated entries for mapblocks that were removed or
+    /// re-queued, those are skipped when popping
+    tasks: BinaryHeap<QueuedTask>,
+    /// The queued mapblocks, with the seq of their current entry in `tasks`
+    queued: HashMap<MapBlockPos, u64>,
+    /// The mapblock containing the player, distances are relative to it
+    center: MapBlockPos,
+    /// Number of worker tasks on the thread pool
+    workers: usize,
+    /// Increases with every queued task, so equally distant tasks are taken
+    /// in submission order
+    next_seq: u64,
+}
+
+impl MeshgenQueue {
+    fn new() -> Self {
+        Self {
+            tasks: BinaryHeap::new(),
+            queued: HashMap::new(),
+            center: MapBlockPos::new(I16Vec3::ZERO).unwrap(),
+            workers: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Returns the squared distance between `blockpos` and the center, in
+    /// mapblocks.
+    fn distance(&self, blockpos: MapBlockPos) -> i32 {
+        (blockpos.vec().as_ivec3() - self.center.vec().as_ivec3()).length_squared()
+    }
+
+    /// Queues a mapblock. Returns false if it is queued already, in which
+    /// case it keeps its place.
+    fn push(&mut self, blockpos: MapBlockPos, timestamp: Instant) -> bool {
+        if self.queued.contains_key(&blockpos) {
+            return false;
+        }
+        let task = QueuedTask {
+            distance: self.distance(blockpos),
+            seq: self.next_seq,
             blockpos,
-            block,
-        );
+            timestamp,
+        };
+        self.queued.insert(blockpos, task.seq);
+        self.next_seq += 1;
+        self.tasks.push(task);
+        true
+    }
+
+    /// Removes a mapblock from the queue. Returns false if it wasn't queued.
+    fn remove(&mut self, blockpos: MapBlockPos) -> bool {
+        self.queued.remove(&blockpos).is_some()
+    }
+
+    /// Whether the entry is the current one for its mapblock.
+    fn is_current(&self, task: &QueuedTask) -> bool {
+        self.queued.get(&task.blockpos) == Some(&task.seq)
+    }
+
+    /// Takes the nearest task. If there is none, the calling worker is done
+    /// and no longer counted. Locks the queue only for the duration of the
+    /// call, so it isn't held while generating.
+    fn pop(queue: &Mutex<Self>) -> Option<QueuedTask> {
+        let mut queue = queue.lock().unwrap();
+        while let Some(task) = queue.tasks.pop() {
+            if queue.is_current(&task) {
+                queue.queued.remove(&task.blockpos);
+                return Some(task);
+            }
+        }
+        queue.workers -= 1;
+        None
+    }
+
+    /// Moves the center, re-sorting the queued tasks if it changed.
+    fn set_center(&mut self, center: MapBlockPos) {
+        if center == self.center {
+            return;
+        }
+        self.center = center;
+        let mut tasks = std::mem::take(&mut self.tasks).into_vec();
+        tasks.retain(|task| self.is_current(task));
+        for task in &mut tasks {
+            task.distance = self.distance(task.blockpos);
+        }
+        self.tasks = BinaryHeap::from(tasks);
+    }
+}
+
+struct QueuedTask {
+    /// Squared distance to the player, in mapblocks
+    distance: i32,
+    seq: u64,
+    blockpos: MapBlockPos,
+    timestamp: Instant,
+}
+
+// Ordered so that the BinaryHeap (a max-heap) pops the nearest, then the
+// oldest task
+impl Ord for QueuedTask {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .distance
+            .cmp(&self.distance)
+            .then(other.seq.cmp(&self.seq))
     }
 }
 
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for QueuedTask {}
+
+/// A node texture to load in the background, see Meshgen::load_textures
+struct TextureJob {
+    /// Reserved in the NodeTextureManager
+    index: usize,
+    name: String,
+    animation: Option<VerticalFrames>,
+    source: MediaSource,
+}
+
 /// The representation of a vertex, used by the CPU-side mesh representation,
 /// and byte-serializable for uploading to GPU buffers.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
-    position: Vec3,
-    uv: Vec2,
-    normal: Vec3,
-    texture_index: u32,
+    pub position: Vec3,
+    pub uv: Vec2,
+    pub normal: Vec3,
+    pub texture_index: u32,
+    /// Material flags, currently only the ContentFeatures waving type
+    flags: u32,
+    /// Brightness during the day and during the night, see light::vertex_light
+    light: Vec2,
 }
 
 impl Vertex {
     pub fn layout() -> wgpu::VertexBufferLayout<'static> {
-        const ATTRIBS: [wgpu::VertexAttribute; 4] =
-            wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3, 3 => Uint32];
+        const ATTRIBS: [wgpu::VertexAttribute; 6] = wgpu::vertex_attr_array![
+            0 => Float32x3, 1 => Float32x2, 2 => Float32x3, 3 => Uint32, 4 => Uint32, 5 => Float32x2
+        ];
 
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
@@ -129,24 +530,32 @@ impl Vertex {
 }
 
 /// The CPU-side representation of a mesh. Usually dropped after uploading
-/// the data to GPU buffers.
+/// the data to GPU buffers, see Settings::retain_meshes.
 #[derive(Default)]
-struct Mesh {
-    vertices: Vec<Vertex>,
-    indices: Vec<u32>,
+pub struct Mesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
 }
 
 /// A finished mapblock mesh that has been uploaded to the GPU.
 pub struct MapblockMesh {
     pub blockpos: MapBlockPos,
     pub num_indices: u32,
+    /// For debugging
+    pub num_vertices: u32,
     /// None if num_indices == 0
     pub index_buffer: Option<wgpu::Buffer>,
     /// None if num_indices == 0
     pub vertex_buffer: Option<wgpu::Buffer>,
     /// None if num_indices == 0
     pub bounding_sphere: Option<BoundingSphere>,
+    /// None if num_indices == 0 or Settings::retain_meshes is disabled
+    pub mesh: Option<Mesh>,
+    /// For the far terrain, None if the mapblock has no sunlit surface
+    pub surface: Option<BlockSurface>,
     pub timestamp_task_spawned: Instant,
+    /// How long generating and uploading the mesh took, for the profiler
+    pub generation_time: Duration,
 }
 
 /// A task for generating a single mapblock mesh and uploading it to the GPU.
@@ -155,83 +564,45 @@ struct MeshgenTask {
     main_tx: mpsc::UnboundedSender<ClientToMainEvent>,
     node_def: Arc<NodeDefManager>,
     textures: Arc<NodeTextureManager>,
+    models: Arc<ModelManager>,
     data: MeshgenMapData,
     timestamp_task_spawned: Instant,
+    greedy_meshing: bool,
+    /// Send the CPU-side mesh along, see Settings::retain_meshes
+    retain_mesh: bool,
 }
 
 impl MeshgenTask {
-    /// Spawns the meshgen task on the thread pool.
-    fn spawn(
-        device: wgpu::Device,
-        main_tx: mpsc::UnboundedSender<ClientToMainEvent>,
-        node_def: Arc<NodeDefManager>,
-        textures: Arc<NodeTextureManager>,
-        pool: &rayon::ThreadPool,
-        map: &LuantiMap,
-        blockpos: MapBlockPos,
-        block: &MapBlockNodes,
-    ) {
-        let t = Instant::now();
-
-        let mut empty = true;
-        for node in &block.0 {
-            // Quick check, not exhaustive (other nodes can have DrawType::Airlike as well).
-            if node.content_id != ContentId::AIR {
-                empty = false;
-            }
-        }
-
-        // If the mapblock is empty, we can skip cloning 7 mapblocks and spawning
-        // the task.
-        if empty {
-            // println!("Skipped spawning meshgen task for empty {}", blockpos.vec());
-
-            main_tx
-                .send(ClientToMainEvent::MapblockMesh(MapblockMesh {
-                    blockpos: blockpos,
-                    num_indices: 0,
-                    index_buffer: None,
-                    vertex_buffer: None,
-                    bounding_sphere: None,
-                    timestamp_task_spawned: t,
-                }))
-                .unwrap();
-        } else {
-            // println!("Spawning meshgen task for {}", blockpos.vec());
-
-            let data = MeshgenMapData::new(map, blockpos, block);
-
-            pool.install(move || {
-                MeshgenTask {
-                    device,
-                    node_def,
-                    textures,
-                    main_tx,
-                    data,
-                    timestamp_task_spawned: t,
-                }
-                .generate();
-            });
-        }
-    }
-
     /// Generates the mapblock mesh and uploads it to GPU buffers.
     fn generate(&self) {
-        // let begin = Instant::now();
+        let begin = Instant::now();
 
         let mut mesh = Mesh::default();
+        let mut greedy = self.greedy_meshing.then(GreedyFaces::new);
 
         let block = self.data.get_block();
         let mut index: usize = 0;
         for z in 0..MapBlockPos::SIZE as i16 {
             for y in 0..MapBlockPos::SIZE as i16 {
                 for x in 0..MapBlockPos::SIZE as i16 {
-                    self.generate_single(&mut mesh, I16Vec3::new(x, y, z), block.0[index]);
+                    self.generate_single(
+                        &mut mesh,
+                        greedy.as_mut(),
+                        I16Vec3::new(x, y, z),
+                        block.0[index],
+                    );
                     index += 1;
                 }
             }
         }
 
+        if let Some(greedy) = greedy {
+            let block_offset = MapNodePos::from(self.data.get_blockpos()).0.as_vec3();
+            greedy.merge_into(&mut mesh, block_offset);
+        }
+
+        let surface = farmesh::block_surface(&self.data, &self.node_def);
+
         if mesh.indices.len() == 0 {
             // This can still happen even though we attempt to skip empty mapblocks
             // earlier: A mapblock may be non-empty, but not render any faces due to
@@ -243,23 +614,31 @@ impl MeshgenTask {
             );
             */
 
-            self.main_tx
+            // The main thread may have left the server, a panic here
+            // would abort
+            let _ = self
+                .main_tx
                 .send(ClientToMainEvent::MapblockMesh(MapblockMesh {
                     blockpos: self.data.get_blockpos(),
                     num_indices: 0,
+                    num_vertices: 0,
                     index_buffer: None,
                     vertex_buffer: None,
                     bounding_sphere: None,
+                    mesh: None,
+                    surface,
                     timestamp_task_spawned: self.timestamp_task_spawned,
-                }))
-                .unwrap();
+                    generation_time: begin.elapsed(),
+                }));
             return;
         }
 
+        // Labels make GPU captures navigable
+        let blockpos = self.data.get_blockpos().vec();
         let vertex_buffer = self
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
+                label: Some(&format!("Mapblock {} vertex buffer", blockpos)),
                 contents: bytemuck::cast_slice(&mesh.vertices),
                 usage: wgpu::BufferUsages::VERTEX,
             });
@@ -267,7 +646,7 @@ impl MeshgenTask {
         let index_buffer = self
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
+                label: Some(&format!("Mapblock {} index buffer", blockpos)),
                 contents: bytemuck::cast_slice(&mesh.indices),
                 usage: wgpu::BufferUsages::INDEX,
             });
@@ -278,18 +657,21 @@ impl MeshgenTask {
             radius: ((3 * MapBlockPos::SIZE.pow(2)) as f32).sqrt(),
         };
 
-        self.main_tx
+        // See above
+        let _ = self
+            .main_tx
             .send(ClientToMainEvent::MapblockMesh(MapblockMesh {
                 blockpos: self.data.get_blockpos(),
                 num_indices: mesh.indices.len() as u32,
+                num_vertices: mesh.vertices.len() as u32,
                 index_buffer: Some(index_buffer),
                 vertex_buffer: Some(vertex_buffer),
                 bounding_sphere: Some(bounding_sphere),
+                mesh: self.retain_mesh.then_some(mesh),
+                surface,
                 timestamp_task_spawned: self.timestamp_task_spawned,
-            }))
-            .unwrap();
-
-        // println!("Meshgen took: {}", begin.elapsed().as_millis());
+                generation_time: begin.elapsed(),
+            }));
     }
 }
 
@@ -299,50 +681,154 @@ impl MeshgenTask {
 #[cfg_attr(rustfmt, rustfmt_skip)]
 const CUBE_VERTICES: &[Vertex] = &[
     // Top
-    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0 },
+    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
     // Bottom
-    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0 },
+    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, -1.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
     // Right
-    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0 },
+    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
     // Left
-    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0 },
+    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(-1.0, 0.0, 0.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
     // Back
-    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0 },
+    Vertex { position: Vec3::new(0.5, 0.5, 0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, 0.5, 0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, -0.5, 0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, 0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 0.0, 1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
     // Front
-    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0 },
-    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0 },
+    Vertex { position: Vec3::new(-0.5, 0.5, -0.5), uv: Vec2::new(0.0, 0.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, 0.5, -0.5), uv: Vec2::new(1.0, 0.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(0.5, -0.5, -0.5), uv: Vec2::new(1.0, 1.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
+    Vertex { position: Vec3::new(-0.5, -0.5, -0.5), uv: Vec2::new(0.0, 1.0), normal: Vec3::new(0.0, 0.0, -1.0), texture_index: 0, flags: 0, light: Vec2::ZERO },
 ];
 
+/// Returns the facedir rotation (0-23) of a node, or 0 if its param2 isn't
+/// a facedir/4dir type.
+pub fn node_facedir(def: &ContentFeatures, node: MapNode) -> u8 {
+    let facedir = match def.param_type_2 {
+        ParamType2::FaceDir | ParamType2::ColorFaceDir => node.param2 & 0x1f,
+        // 4dir is facedir limited to rotations around the Y axis
+        ParamType2::FourDir | ParamType2::ColorFourDir => node.param2 & 0x03,
+        _ => 0,
+    };
+    // Compare to Luanti, mapnode.cpp, MapNode::getFaceDir
+    facedir % 24
+}
+
+/// Returns the rotation around the Y axis in degrees of a plantlike or mesh
+/// node, or 0 if its param2 isn't a degrotate type.
+// Compare to Luanti, mapnode.cpp, MapNode::getDegRotate
+fn node_degrotate(def: &ContentFeatures, node: MapNode) -> f32 {
+    match def.param_type_2 {
+        ParamType2::DegRotate => (node.param2 % 240) as f32 * 1.5,
+        ParamType2::ColorDegRotate => ((node.param2 & 0x1f) % 24) as f32 * 15.0,
+        _ => 0.0,
+    }
+}
+
+/// 90-degree multiples as (cos, sin), exact to avoid cracks between nodes.
+fn cos_sin(degrees: i32) -> (f32, f32) {
+    match degrees.rem_euclid(360) {
+        0 => (1.0, 0.0),
+        90 => (0.0, 1.0),
+        180 => (-1.0, 0.0),
+        270 => (0.0, -1.0),
+        _ => unreachable!(),
+    }
+}
+
+// Compare to Irrlicht's vector3d::rotateXZBy, rotateXYBy and rotateYZBy
+fn rotate_xz(v: Vec3, degrees: i32) -> Vec3 {
+    let (cs, sn) = cos_sin(degrees);
+    Vec3::new(v.x * cs - v.z * sn, v.y, v.x * sn + v.z * cs)
+}
+
+fn rotate_xy(v: Vec3, degrees: i32) -> Vec3 {
+    let (cs, sn) = cos_sin(degrees);
+    Vec3::new(v.x * cs - v.y * sn, v.x * sn + v.y * cs, v.z)
+}
+
+fn rotate_yz(v: Vec3, degrees: i32) -> Vec3 {
+    let (cs, sn) = cos_sin(degrees);
+    Vec3::new(v.x, v.y * cs - v.z * sn, v.y * sn + v.z * cs)
+}
+
+/// Rotates a vector relative to the node center by a facedir value (0-23).
+/// Works for both positions and normals.
+// Compare to Luanti, mesh.cpp, rotateMeshBy6dFacedir
+pub fn facedir_rotate(v: Vec3, facedir: u8) -> Vec3 {
+    let axis = facedir >> 2;
+    let rotation = (facedir & 0x03) as i32;
+    // Rotation around the axis, in degrees, for rotation = 0..3
+    let angle = |degrees: [i32; 4]| degrees[rotation as usize];
+
+    match axis {
+        // y+
+        0 => rotate_xz(v, angle([0, -90, 180, 90])),
+        // z+
+        1 => rotate_xy(rotate_yz(v, 90), angle([0, 90, 180, -90])),
+        // z-
+        2 => rotate_xy(rotate_yz(v, -90), angle([0, -90, 180, 90])),
+        // x+
+        3 => rotate_yz(rotate_xy(v, -90), angle([0, 90, 180, -90])),
+        // x-
+        4 => rotate_yz(rotate_xy(v, 90), angle([0, -90, 180, 90])),
+        // y-
+        5 => rotate_xz(rotate_xy(v, -180), angle([0, 90, 180, -90])),
+        _ => v,
+    }
+}
+
 // Compare to Luanti, content_mapblock.cpp, quad_indices
 // Note: Winding order is clockwise
 const QUAD_INDICES: &[u32] = &[0, 1, 2, 2, 3, 0];
+/// The same quad with the opposite winding, for faces visible from both sides
+const QUAD_INDICES_BACK: &[u32] = &[0, 3, 2, 2, 1, 0];
 
 impl MeshgenTask {
     /// Generates the mesh for a single node within the mapblock.
-    fn generate_single(&self, mesh: &mut Mesh, pos: I16Vec3, node: MapNode) {
+    /// Unrotated cube faces are added to `greedy` instead of the mesh, if given.
+    fn generate_single(
+        &self,
+        mesh: &mut Mesh,
+        mut greedy: Option<&mut GreedyFaces>,
+        pos: I16Vec3,
+        node: MapNode,
+    ) {
         let def = self.node_def.get_with_fallback(node.content_id);
-        if def.drawtype == DrawType::AirLike {
-            return;
+        match def.drawtype {
+            DrawType::AirLike => return,
+            DrawType::PlantLike => {
+                self.generate_plantlike(mesh, pos, node, def);
+                return;
+            }
+            DrawType::NodeBox => {
+                self.generate_nodebox(mesh, pos, node, def);
+                return;
+            }
+            DrawType::Mesh => {
+                self.generate_mesh(mesh, pos, node, def);
+                return;
+            }
+            _ => (),
         }
 
-        for (face_index, dir) in NEIGHBOR_DIRS.iter().enumerate() {
+        let facedir = node_facedir(def, node);
+
+        for face_index in 0..NEIGHBOR_DIRS.len() {
+            // The direction the face points to after rotation
+            let normal = facedir_rotate(CUBE_VERTICES[face_index * 4].normal, facedir);
+            let dir = normal.round().as_i16vec3();
             let n_pos = pos + dir;
 
             // Faces to non-existent mapblocks are not generated, as we don't know if the
@@ -351,19 +837,35 @@ impl MeshgenTask {
             let Some(n_node) = self.data.get_node(MapNodePos(n_pos)) else {
                 continue;
             };
-            // Some funny heuristics for now
-            if n_node.content_id == node.content_id
-                && (def.drawtype == DrawType::Liquid || def.drawtype == DrawType::FlowingLiquid)
-            {
-                continue;
-            }
             let n_def = self.node_def.get_with_fallback(n_node.content_id);
-            if n_def.drawtype == DrawType::Normal {
+            if !face_visible(node, def, n_node, n_def) {
                 continue;
             }
+            let light = face_light(node, def, n_node, n_def);
+
+            // The tile belongs to the unrotated face, so it rotates with the node
+            let tile = &def.tiledef[face_index];
+            let texture_index = self
+                .textures
+                .get_texture_index(&tile.name, VerticalFrames::from_tile(tile))
+                .unwrap() as u32;
 
-            let texture_name = &def.tiledef[face_index].name;
-            let texture_index = self.textures.get_texture_index(&texture_name).unwrap() as u32;
+            // Waving faces can't be merged, they move per vertex
+            if facedir == 0
+                && def.waving == 0
+                && let Some(greedy) = greedy.as_deref_mut()
+            {
+                greedy.add(
+                    face_index,
+                    pos,
+                    FaceAttributes {
+                        texture_index,
+                        flags: 0,
+                        light,
+                    },
+                );
+                continue;
+            }
 
             let index_offset = mesh.vertices.len() as u32;
             let vertex_offset =
@@ -374,8 +876,11 @@ impl MeshgenTask {
             let vertices = CUBE_VERTICES[from_vertex..to_vertex]
                 .iter()
                 .map(|vertex| Vertex {
-                    position: vertex_offset + vertex.position,
+                    position: vertex_offset + facedir_rotate(vertex.position, facedir),
+                    normal,
                     texture_index,
+                    flags: def.waving as u32,
+                    light,
                     ..*vertex
                 });
             mesh.vertices.extend(vertices);
@@ -384,4 +889,707 @@ impl MeshgenTask {
             mesh.indices.extend(indices);
         }
     }
+
+    /// Generates the crossed quads of a plantlike node.
+    // Compare to Luanti, content_mapblock.cpp, drawPlantlike
+    fn generate_plantlike(
+        &self,
+        mesh: &mut Mesh,
+        pos: I16Vec3,
+        node: MapNode,
+        def: &ContentFeatures,
+    ) {
+        let world_pos = MapNodePos::from(self.data.get_blockpos()).0 + pos;
+
+        let mut plant = PlantOptions {
+            style: PlantStyle::Cross,
+            scale: 0.5 * def.visual_scale,
+            offset: Vec3::ZERO,
+            rotation: 0.0,
+            random_offset_y: false,
+        };
+        match def.param_type_2 {
+            ParamType2::MeshOptions => {
+                plant.style = PlantStyle::from_param2(node.param2);
+                if node.param2 & MESHOPTIONS_SCALE_SQRT2 != 0 {
+                    plant.scale *= 1.41421;
+                }
+                if node.param2 & MESHOPTIONS_RANDOM_OFFSET != 0 {
+                    let mut rng = PseudoRandom::new(
+                        (world_pos.x as i32) << 8 | world_pos.z as i32 | (world_pos.y as i32) << 16,
+                    );
+                    plant.offset.x = (rng.next() % 16) as f32 / 16.0 * 0.29 - 0.145;
+                    plant.offset.z = (rng.next() % 16) as f32 / 16.0 * 0.29 - 0.145;
+                }
+                plant.random_offset_y = node.param2 & MESHOPTIONS_RANDOM_OFFSET_Y != 0;
+            }
+            ParamType2::DegRotate | ParamType2::ColorDegRotate => {
+                plant.rotation = node_degrotate(def, node)
+            }
+            _ => (),
+        }
+
+        let tile = &def.tiledef[0];
+        let texture_index = self
+            .textures
+            .get_texture_index(&tile.name, VerticalFrames::from_tile(tile))
+            .unwrap() as u32;
+        let light = interior_light(node, def);
+
+        // (rotation, offset of the quad from the center, only offset the top)
+        let quads: &[(f32, f32, bool)] = match plant.style {
+            PlantStyle::Cross => &[(46.0, 0.0, false), (-44.0, 0.0, false)],
+            PlantStyle::Cross2 => &[(91.0, 0.0, false), (1.0, 0.0, false)],
+            PlantStyle::Star => &[(121.0, 0.0, false), (241.0, 0.0, false), (1.0, 0.0, false)],
+            PlantStyle::Hash => &[
+                (1.0, 0.25, false),
+                (91.0, 0.25, false),
+                (181.0, 0.25, false),
+                (271.0, 0.25, false),
+            ],
+            PlantStyle::Hash2 => &[
+                (1.0, -0.5, true),
+                (91.0, -0.5, true),
+                (181.0, -0.5, true),
+                (271.0, -0.5, true),
+            ],
+        };
+
+        for (face_num, &(rotation, quad_offset, offset_top_only)) in quads.iter().enumerate() {
+            let scale = plant.scale;
+            let mut corners = [
+                Vec3::new(-scale, -0.5 + 2.0 * scale, 0.0),
+                Vec3::new(scale, -0.5 + 2.0 * scale, 0.0),
+                Vec3::new(scale, -0.5, 0.0),
+                Vec3::new(-scale, -0.5, 0.0),
+            ];
+
+            let mut offset = plant.offset;
+            if plant.random_offset_y {
+                let mut rng = PseudoRandom::new(
+                    face_num as i32
+                        | (world_pos.x as i32) << 16
+                        | (world_pos.z as i32) << 8
+                        | (world_pos.y as i32) << 24,
+                );
+                offset.y = -((rng.next() % 16) as f32 / 16.0 * 0.125);
+            }
+
+            let offset_count = if offset_top_only { 2 } else { 4 };
+            for corner in &mut corners[..offset_count] {
+                corner.z += quad_offset;
+            }
+            let rotation = glam::Quat::from_rotation_y(-(rotation + plant.rotation).to_radians());
+            let node_offset = world_pos.as_vec3();
+
+            let index_offset = mesh.vertices.len() as u32;
+            let uvs = [
+                Vec2::new(0.0, 0.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(1.0, 1.0),
+                Vec2::new(0.0, 1.0),
+            ];
+            for (corner, uv) in corners.into_iter().zip(uvs) {
+                mesh.vertices.push(Vertex {
+                    position: node_offset + rotation * corner + offset,
+                    uv,
+                    // Plants are lit like top faces
+                    normal: Vec3::Y,
+                    texture_index,
+                    flags: def.waving as u32,
+                    light,
+                });
+            }
+
+            // Visible from both sides
+            let indices = QUAD_INDICES
+                .iter()
+                .chain(QUAD_INDICES_BACK)
+                .map(|index| index_offset + index);
+            mesh.indices.extend(indices);
+        }
+    }
+
+    /// Generates the boxes of a nodebox node.
+    // Compare to Luanti, content_mapblock.cpp, drawNodeboxNode, and
+    // nodedef.cpp, getNodeBoxes
+    fn generate_nodebox(
+        &self,
+        mesh: &mut Mesh,
+        pos: I16Vec3,
+        node: MapNode,
+        def: &ContentFeatures,
+    ) {
+        let node_offset = (MapNodePos::from(self.data.get_blockpos()).0 + pos).as_vec3();
+        let light = interior_light(node, def);
+
+        let (boxes, facedir) = match &def.node_box {
+            NodeBox::Fixed(nodebox) => (vec![&nodebox.fixed], node_facedir(def, node)),
+            // Connected nodeboxes are never rotated
+            NodeBox::Connected(nodebox) => {
+                let neighbors = self.connected_neighbors(pos, node);
+                let mut boxes = vec![&nodebox.fixed];
+                let sides = [
+                    (CONNECT_TOP, &nodebox.connect_top, &nodebox.disconnected_top),
+                    (
+                        CONNECT_BOTTOM,
+                        &nodebox.connect_bottom,
+                        &nodebox.disconnected_bottom,
+                    ),
+                    (
+                        CONNECT_FRONT,
+                        &nodebox.connect_front,
+                        &nodebox.disconnected_front,
+                    ),
+                    (
+                        CONNECT_LEFT,
+                        &nodebox.connect_left,
+                        &nodebox.disconnected_left,
+                    ),
+                    (
+                        CONNECT_BACK,
+                        &nodebox.connect_back,
+                        &nodebox.disconnected_back,
+                    ),
+                    (
+                        CONNECT_RIGHT,
+                        &nodebox.connect_right,
+                        &nodebox.disconnected_right,
+                    ),
+                ];
+                for (side, connected, disconnected) in sides {
+                    boxes.push(if neighbors & side != 0 {
+                        connected
+                    } else {
+                        disconnected
+                    });
+                }
+                if neighbors == 0 {
+                    boxes.push(&nodebox.disconnected);
+                }
+                // Top and bottom don't count here
+                if neighbors & CONNECT_SIDES == 0 {
+                    boxes.push(&nodebox.disconnected_sides);
+                }
+                (boxes, 0)
+            }
+            // TODO: wallmounted and leveled nodeboxes, drawn as a full box for now
+            _ => {
+                self.add_box(
+                    mesh,
+                    node_offset,
+                    def,
+                    light,
+                    0,
+                    (Vec3::splat(-0.5), Vec3::splat(0.5)),
+                );
+                return;
+            }
+        };
+
+        for aabb in boxes.into_iter().flatten() {
+            self.add_box(
+                mesh,
+                node_offset,
+                def,
+                light,
+                facedir,
+                (aabb.min_edge, aabb.max_edge),
+            );
+        }
+    }
+
+    /// Generates a mesh node from its model, scaled by visual_scale.
+    // Compare to Luanti, content_mapblock.cpp, drawMeshNode
+    fn generate_mesh(&self, mesh: &mut Mesh, pos: I16Vec3, node: MapNode, def: &ContentFeatures) {
+        // Missing models were reported by Meshgen::new
+        let Some(model) = self.models.get(&def.mesh) else {
+            return;
+        };
+        let node_offset = (MapNodePos::from(self.data.get_blockpos()).0 + pos).as_vec3();
+
+        // TODO: wallmounted meshes
+        let facedir = node_facedir(def, node);
+        let degrees = node_degrotate(def, node);
+        let rotation = Quat::from_rotation_y(-degrees.to_radians());
+        let light = interior_light(node, def);
+
+        for (index, buffer) in model.buffers.iter().enumerate() {
+            // Buffers beyond the last tile use the last tile
+            let Some(tile) = def.tiledef.get(index).or(def.tiledef.last()) else {
+                return;
+            };
+            let texture_index = self
+                .textures
+                .get_texture_index(&tile.name, VerticalFrames::from_tile(tile))
+                .unwrap() as u32;
+
+            let index_offset = mesh.vertices.len() as u32;
+            mesh.vertices
+                .extend(buffer.vertices.iter().map(|vertex| Vertex {
+                    position: node_offset
+                        + facedir_rotate(rotation * vertex.position * def.visual_scale, facedir),
+                    uv: vertex.uv,
+                    normal: facedir_rotate(rotation * vertex.normal, facedir),
+                    texture_index,
+                    flags: def.waving as u32,
+                    light,
+                }));
+            mesh.indices
+                .extend(buffer.indices.iter().map(|index| index_offset + index));
+        }
+    }
+
+    /// Adds the six faces of a box given in node coordinates.
+    fn add_box(
+        &self,
+        mesh: &mut Mesh,
+        node_offset: Vec3,
+        def: &ContentFeatures,
+        light: Vec2,
+        facedir: u8,
+        (min, max): (Vec3, Vec3),
+    ) {
+        for (face_index, tile) in def.tiledef.iter().enumerate() {
+            let texture_index = self
+                .textures
+                .get_texture_index(&tile.name, VerticalFrames::from_tile(tile))
+                .unwrap() as u32;
+            let attributes = FaceAttributes {
+                texture_index,
+                flags: def.waving as u32,
+                light,
+            };
+            add_box_face(mesh, node_offset, face_index, attributes, facedir, min, max);
+        }
+    }
+
+    /// Returns the CONNECT_* bits of the neighbors a connected nodebox
+    /// connects to.
+    // Compare to Luanti, content_mapblock.cpp, getNeighborConnectingFace
+    fn connected_neighbors(&self, pos: I16Vec3, node: MapNode) -> u8 {
+        let mut neighbors = 0;
+        for (side, dir) in CONNECT_DIRS {
+            // Unknown neighbors don't connect, the mesh is re-generated once
+            // the neighboring mapblock arrives
+            let Some(n_node) = self.data.get_node(MapNodePos(pos + dir)) else {
+                continue;
+            };
+            if self.nodebox_connects(node, n_node, side) {
+                neighbors |= side;
+            }
+        }
+        neighbors
+    }
+
+    /// Whether the connected nodebox `from` connects to the node `to` on the
+    /// given side.
+    // Compare to Luanti, nodedef.cpp, NodeDefManager::nodeboxConnects
+    fn nodebox_connects(&self, from: MapNode, to: MapNode, side: u8) -> bool {
+        let from_def = self.node_def.get_with_fallback(from.content_id);
+        if !from_def.connects_to_ids.contains(&to.content_id.0) {
+            return false;
+        }
+
+        let to_def = self.node_def.get_with_fallback(to.content_id);
+        if to_def.drawtype == DrawType::NodeBox
+            && let NodeBox::Connected(_) = to_def.node_box
+        {
+            // Luanti doesn't check which side the back connection is on
+            return to_def.connects_to_ids.contains(&from.content_id.0);
+        }
+
+        // Does the target declare which of its sides can be connected to?
+        if to_def.connect_sides > 0 {
+            let side = if side & CONNECT_SIDES != 0 {
+                rotate_connect_side(side, node_facedir(to_def, to))
+            } else {
+                side
+            };
+            return to_def.connect_sides & side != 0;
+        }
+
+        // Regular nodes are always connected to
+        true
+    }
+}
+
+/// How much a node hides the faces of its neighbors. 2 for opaque cubes,
+/// 1 for liquid sources, 0 for everything else.
+// Compare to Luanti, nodedef.cpp, ContentFeatures::updateTextures
+fn solidness(def: &ContentFeatures) -> u8 {
+    match def.drawtype {
+        DrawType::Normal | DrawType::PlantLikeRooted => 2,
+        DrawType::Liquid => 1,
+        _ => 0,
+    }
+}
+
+/// Solidness used against other non-solid nodes. Glass and leaves hide the
+/// faces of other see-through nodes.
+fn visual_solidness(def: &ContentFeatures) -> u8 {
+    match def.drawtype {
+        DrawType::GlassLike
+        | DrawType::GlassLikeFramed
+        | DrawType::GlassLikeFramedOptional
+        | DrawType::AllFaces
+        | DrawType::AllFacesOptional => 1,
+        _ => 0,
+    }
+}
+
+fn is_liquid(def: &ContentFeatures) -> bool {
+    def.drawtype == DrawType::Liquid || def.drawtype == DrawType::FlowingLiquid
+}
+
+/// Whether two nodes are forms of the same liquid, e.g. a source and its
+/// flowing variant. No faces are drawn between them.
+// Compare to Luanti, nodedef.h, ContentFeatures::sameLiquidRender
+fn same_liquid(a: &ContentFeatures, b: &ContentFeatures) -> bool {
+    is_liquid(a)
+        && is_liquid(b)
+        && !a.liquid_alternative_source.is_empty()
+        && a.liquid_alternative_source == b.liquid_alternative_source
+}
+
+/// Whether the cube face of `node` towards its neighbor `n_node` is visible.
+// Compare to Luanti, content_mapblock.cpp, which draws the see-through
+// drawtypes with their own rules, and mapblock_mesh.cpp, which draws the
+// faces between the remaining nodes
+fn face_visible(
+    node: MapNode,
+    def: &ContentFeatures,
+    n_node: MapNode,
+    n_def: &ContentFeatures,
+) -> bool {
+    if n_node.content_id == ContentId::IGNORE {
+        return false;
+    }
+
+    match def.drawtype {
+        DrawType::Normal | DrawType::Liquid | DrawType::PlantLikeRooted => {
+            face_contents(node, def, n_node, n_def)
+        }
+        DrawType::FlowingLiquid => !same_liquid(def, n_def) && solidness(n_def) < 2,
+        DrawType::GlassLike | DrawType::GlassLikeFramed | DrawType::GlassLikeFramedOptional => {
+            n_node.content_id != node.content_id
+        }
+        DrawType::AllFaces | DrawType::AllFacesOptional => true,
+        // Drawtypes that aren't supported yet and are drawn as cubes
+        _ => solidness(n_def) < 2,
+    }
+}
+
+/// Whether the face between two nodes belongs to `node`. At most one of the
+/// two nodes draws the face, the one that is "more solid".
+// Compare to Luanti, mapblock_mesh.cpp, face_contents
+fn face_contents(
+    node: MapNode,
+    def: &ContentFeatures,
+    n_node: MapNode,
+    n_def: &ContentFeatures,
+) -> bool {
+    if node.content_id == n_node.content_id || same_liquid(def, n_def) {
+        return false;
+    }
+
+    let (mut own, mut other) = (solidness(def), solidness(n_def));
+    if own == other {
+        return false;
+    }
+    if own == 0 {
+        own = visual_solidness(def);
+    } else if other == 0 {
+        other = visual_solidness(n_def);
+    }
+    // With the same solidness, liquids take precedence
+    if own == other {
+        return is_liquid(def);
+    }
+    own > other
+}
+
+/// Returns the vertex light of a cube face, which is the light of the
+/// brighter one of the node and its neighbor.
+// Compare to Luanti, client/mapblock_mesh.cpp, getFaceLight
+fn face_light(
+    node: MapNode,
+    def: &ContentFeatures,
+    n_node: MapNode,
+    n_def: &ContentFeatures,
+) -> Vec2 {
+    let light = |bank| light::get_light(node, bank, def).max(light::get_light(n_node, bank, n_def));
+    light::vertex_light(
+        light(LightBank::Day),
+        light(LightBank::Night),
+        def.light_source,
+    )
+}
+
+/// Returns the vertex light of drawtypes other than cubes, which are lit by
+/// the light inside the node.
+// Compare to Luanti, content_mapblock.cpp, MapblockMeshGenerator::getInteriorLight
+fn interior_light(node: MapNode, def: &ContentFeatures) -> Vec2 {
+    light::vertex_light(
+        light::get_light(node, LightBank::Day, def),
+        light::get_light(node, LightBank::Night, def),
+        def.light_source,
+    )
+}
+
+/// Adds a single face of a box given in node coordinates. The texture is
+/// cropped to the box, like in Luanti. Boxes larger than a node repeat it.
+// Compare to Luanti, content_mapblock.cpp, drawAutoLightedCuboid
+fn add_box_face(
+    mesh: &mut Mesh,
+    node_offset: Vec3,
+    face_index: usize,
+    attributes: FaceAttributes,
+    facedir: u8,
+    min: Vec3,
+    max: Vec3,
+) {
+    let (t1, t2) = (min + 0.5, max + 0.5);
+    // (min, max) texture coordinates, faces in CUBE_VERTICES order
+    let (uv_min, uv_max) = match face_index {
+        0 => (Vec2::new(t1.x, 1.0 - t2.z), Vec2::new(t2.x, 1.0 - t1.z)),
+        1 => (Vec2::new(t1.x, t1.z), Vec2::new(t2.x, t2.z)),
+        2 => (Vec2::new(t1.z, 1.0 - t2.y), Vec2::new(t2.z, 1.0 - t1.y)),
+        3 => (
+            Vec2::new(1.0 - t2.z, 1.0 - t2.y),
+            Vec2::new(1.0 - t1.z, 1.0 - t1.y),
+        ),
+        4 => (
+            Vec2::new(1.0 - t2.x, 1.0 - t2.y),
+            Vec2::new(1.0 - t1.x, 1.0 - t1.y),
+        ),
+        5 => (Vec2::new(t1.x, 1.0 - t2.y), Vec2::new(t2.x, 1.0 - t1.y)),
+        _ => unreachable!(),
+    };
+    let normal = facedir_rotate(CUBE_VERTICES[face_index * 4].normal, facedir);
+
+    let index_offset = mesh.vertices.len() as u32;
+    let from_vertex = face_index * 4;
+    let to_vertex = from_vertex + 4;
+    let vertices = CUBE_VERTICES[from_vertex..to_vertex].iter().map(|vertex| {
+        // CUBE_VERTICES are at +-0.5, pick the matching box corner
+        let position = Vec3::select(vertex.position.cmpgt(Vec3::ZERO), max, min);
+        Vertex {
+            position: node_offset + facedir_rotate(position, facedir),
+            uv: uv_min + (uv_max - uv_min) * vertex.uv,
+            normal,
+            texture_index: attributes.texture_index,
+            flags: attributes.flags,
+            light: attributes.light,
+        }
+    });
+    mesh.vertices.extend(vertices);
+
+    let indices = QUAD_INDICES.iter().map(|index| index_offset + index);
+    mesh.indices.extend(indices);
+}
+
+/// The vertex attributes that are the same for the whole face.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FaceAttributes {
+    texture_index: u32,
+    flags: u32,
+    light: Vec2,
+}
+
+/// Unrotated cube faces of a mapblock, collected to be merged into larger
+/// quads. Faces can be merged if they have the same direction, lie in the
+/// same plane and have the same texture and light.
+struct GreedyFaces {
+    /// Attributes per face direction, layer along the normal and position
+    /// within the layer, None where there is no face
+    faces: Vec<Option<FaceAttributes>>,
+}
+
+impl GreedyFaces {
+    const SIZE: usize = MapBlockPos::SIZE as usize;
+
+    fn new() -> Self {
+        Self {
+            faces: vec![None; NEIGHBOR_DIRS.len() * Self::SIZE.pow(3)],
+        }
+    }
+
+    /// Returns the (normal, first, second) axes for a face, the latter two
+    /// spanning its plane.
+    fn axes(face_index: usize) -> (usize, usize, usize) {
+        let normal = CUBE_VERTICES[face_index * 4].normal;
+        let normal_axis = normal.abs().max_position();
+        (normal_axis, (normal_axis + 1) % 3, (normal_axis + 2) % 3)
+    }
+
+    fn index(face_index: usize, layer: usize, a: usize, b: usize) -> usize {
+        ((face_index * Self::SIZE + layer) * Self::SIZE + b) * Self::SIZE + a
+    }
+
+    /// Adds the face of the node at `pos` (relative to the mapblock).
+    fn add(&mut self, face_index: usize, pos: I16Vec3, attributes: FaceAttributes) {
+        let (n, a, b) = Self::axes(face_index);
+        let pos = pos.as_usizevec3();
+        self.faces[Self::index(face_index, pos[n], pos[a], pos[b])] = Some(attributes);
+    }
+
+    /// Merges the collected faces into as few rectangles as possible and
+    /// adds them to the mesh.
+    fn merge_into(mut self, mesh: &mut Mesh, block_offset: Vec3) {
+        const SIZE: usize = GreedyFaces::SIZE;
+
+        for face_index in 0..NEIGHBOR_DIRS.len() {
+            let (n, a, b) = Self::axes(face_index);
+            for layer in 0..SIZE {
+                for start_b in 0..SIZE {
+                    let mut start_a = 0;
+                    while start_a < SIZE {
+                        let Some(attributes) =
+                            self.faces[Self::index(face_index, layer, start_a, start_b)]
+                        else {
+                            start_a += 1;
+                            continue;
+                        };
+
+                        let same = |faces: &[Option<FaceAttributes>], a: usize, b: usize| {
+                            faces[Self::index(face_index, layer, a, b)] == Some(attributes)
+                        };
+
+                        // Grow along the first axis, then along the second
+                        // axis as long as the whole row matches
+                        let mut width = 1;
+                        while start_a + width < SIZE && same(&self.faces, start_a + width, start_b)
+                        {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        while start_b + height < SIZE
+                            && (start_a..start_a + width)
+                                .all(|a| same(&self.faces, a, start_b + height))
+                        {
+                            height += 1;
+                        }
+
+                        for b in start_b..start_b + height {
+                            for a in start_a..start_a + width {
+                                self.faces[Self::index(face_index, layer, a, b)] = None;
+                            }
+                        }
+
+                        let mut node_pos = Vec3::ZERO;
+                        node_pos[n] = layer as f32;
+                        node_pos[a] = start_a as f32;
+                        node_pos[b] = start_b as f32;
+                        let mut extent = Vec3::ONE;
+                        extent[a] = width as f32;
+                        extent[b] = height as f32;
+
+                        add_box_face(
+                            mesh,
+                            block_offset + node_pos,
+                            face_index,
+                            attributes,
+                            0,
+                            Vec3::splat(-0.5),
+                            Vec3::splat(-0.5) + extent,
+                        );
+
+                        start_a += width;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Compare to Luanti, nodedef.h, NodeBoxConnected / connect_sides bits
+const CONNECT_TOP: u8 = 0x01;
+const CONNECT_BOTTOM: u8 = 0x02;
+const CONNECT_FRONT: u8 = 0x04;
+const CONNECT_LEFT: u8 = 0x08;
+const CONNECT_BACK: u8 = 0x10;
+const CONNECT_RIGHT: u8 = 0x20;
+const CONNECT_SIDES: u8 = CONNECT_FRONT | CONNECT_LEFT | CONNECT_BACK | CONNECT_RIGHT;
+
+const CONNECT_DIRS: [(u8, I16Vec3); 6] = [
+    (CONNECT_TOP, I16Vec3::new(0, 1, 0)),
+    (CONNECT_BOTTOM, I16Vec3::new(0, -1, 0)),
+    (CONNECT_FRONT, I16Vec3::new(0, 0, -1)),
+    (CONNECT_LEFT, I16Vec3::new(-1, 0, 0)),
+    (CONNECT_BACK, I16Vec3::new(0, 0, 1)),
+    (CONNECT_RIGHT, I16Vec3::new(1, 0, 0)),
+];
+
+/// Rotates a horizontal CONNECT_* side by a facedir. Like in Luanti, only
+/// rotations around the Y axis are supported, other rotations never connect.
+fn rotate_connect_side(side: u8, facedir: u8) -> u8 {
+    const ORDER: [u8; 4] = [CONNECT_FRONT, CONNECT_LEFT, CONNECT_BACK, CONNECT_RIGHT];
+
+    if facedir >= 4 {
+        return 0;
+    }
+    let index = ORDER.iter().position(|&s| s == side).unwrap();
+    ORDER[(index + 4 - facedir as usize) % 4]
+}
+
+// Compare to Luanti, nodedef.h, MO_MASK_STYLE etc.
+const MESHOPTIONS_MASK_STYLE: u8 = 0x07;
+const MESHOPTIONS_RANDOM_OFFSET: u8 = 0x08;
+const MESHOPTIONS_SCALE_SQRT2: u8 = 0x10;
+const MESHOPTIONS_RANDOM_OFFSET_Y: u8 = 0x20;
+
+/// Compare to Luanti, nodedef.h, PlantlikeStyle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlantStyle {
+    /// "x" shape
+    Cross,
+    /// "+" shape
+    Cross2,
+    /// "*" shape
+    Star,
+    /// "#" shape
+    Hash,
+    /// "#" shape, leaning outwards
+    Hash2,
+}
+
+impl PlantStyle {
+    fn from_param2(param2: u8) -> Self {
+        match param2 & MESHOPTIONS_MASK_STYLE {
+            1 => Self::Cross2,
+            2 => Self::Star,
+            3 => Self::Hash,
+            4 => Self::Hash2,
+            // 5-7 are reserved
+            _ => Self::Cross,
+        }
+    }
+}
+
+struct PlantOptions {
+    style: PlantStyle,
+    /// Half the width of the quads
+    scale: f32,
+    offset: Vec3,
+    /// Additional rotation around the Y axis, in degrees
+    rotation: f32,
+    random_offset_y: bool,
+}
+
+/// Luanti's PseudoRandom, so random plant offsets match Luanti.
+// Compare to Luanti, noise.h, PseudoRandom
+struct PseudoRandom {
+    next: u32,
+}
+
+impl PseudoRandom {
+    fn new(seed: i32) -> Self {
+        Self { next: seed as u32 }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.next = self.next.wrapping_mul(1103515245).wrapping_add(12345);
+        (self.next / 65536) % 32768
+    }
 }
@@ -0,0 +1,490 @@
+//! Mesh files (.obj, .b3d, .gltf and .glb) used by mesh drawtype nodes and
+//! "mesh" visuals of active objects, parsed into a common representation.
+//! Only the static geometry is loaded, bones and animations are ignored.
+//!
+//! Like in Luanti (which uses Irrlicht's loaders), each model consists of
+//! mesh buffers, and the n-th buffer is drawn with the n-th texture of the
+//! node or object. Positions are converted to Luanti's left-handed
+//! coordinate system, and triangles are wound clockwise like mapblock faces.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use base64::Engine as _;
+use glam::{Mat4, Quat, Vec2, Vec3};
+
+use crate::media::MediaManager;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModelVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+}
+
+/// Triangles drawn with the same texture.
+#[derive(Default)]
+pub struct ModelBuffer {
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+pub struct Model {
+    pub buffers: Vec<ModelBuffer>,
+}
+
+impl Model {
+    /// Parses a mesh file, the format is chosen by the file extension.
+    pub fn parse(name: &str, data: &[u8]) -> anyhow::Result<Self> {
+        let extension = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+        let mut buffers = match extension.to_ascii_lowercase().as_str() {
+            "obj" => parse_obj(data)?,
+            "b3d" => parse_b3d(data)?,
+            "gltf" | "glb" => parse_gltf(data)?,
+            _ => bail!("Unsupported mesh format"),
+        };
+        for buffer in &mut buffers {
+            if let Some(index) = buffer
+                .indices
+                .iter()
+                .find(|index| **index as usize >= buffer.vertices.len())
+            {
+                bail!("Vertex index {} out of range", index);
+            }
+            if buffer
+                .vertices
+                .iter()
+                .all(|vertex| vertex.normal == Vec3::ZERO)
+            {
+                buffer.recalculate_normals();
+            }
+        }
+        Ok(Self { buffers })
+    }
+}
+
+impl ModelBuffer {
+    /// Smooth normals for files without normals.
+    // Compare to Irrlicht, CMeshManipulator::recalculateNormals
+    fn recalculate_normals(&mut self) {
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| self.vertices[triangle[i] as usize].position);
+            // Clockwise in a left-handed coordinate system
+            let normal = (b - a).cross(c - a).normalize_or_zero();
+            for index in triangle {
+                self.vertices[*index as usize].normal += normal;
+            }
+        }
+        for vertex in &mut self.vertices {
+            vertex.normal = vertex.normal.normalize_or_zero();
+        }
+    }
+}
+
+/// Loaded models by file name, including the ones that failed to load.
+#[derive(Default)]
+pub struct ModelManager {
+    models: HashMap<String, Option<Arc<Model>>>,
+}
+
+impl ModelManager {
+    /// Loads a model from the media manager, unless it was loaded before.
+    /// Returns Ok(None) if the file name is unknown or the model failed to
+    /// load before. Errors are only returned the first time.
+    pub fn load(&mut self, media: &MediaManager, name: &str) -> anyhow::Result<Option<Arc<Model>>> {
+        if let Some(model) = self.models.get(name) {
+            return Ok(model.clone());
+        }
+        let result = media.load_model(name);
+        let model = result.as_ref().ok().cloned().flatten();
+        self.models.insert(String::from(name), model);
+        result
+    }
+
+    /// Returns a model that was loaded before.
+    pub fn get(&self, name: &str) -> Option<&Model> {
+        self.models.get(name)?.as_deref()
+    }
+}
+
+/// Parses a Wavefront OBJ file. Every "usemtl" material becomes a buffer,
+/// .mtl files aren't used.
+// Compare to Irrlicht, COBJMeshFileLoader::createMesh
+fn parse_obj(data: &[u8]) -> anyhow::Result<Vec<ModelBuffer>> {
+    let text = std::str::from_utf8(data)?;
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    // By material name, in the order of first use
+    let mut materials: Vec<(
+        String,
+        ModelBuffer,
+        HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    )> = vec![(String::new(), ModelBuffer::default(), HashMap::new())];
+    let mut current = 0;
+
+    let floats = |parts: std::str::SplitWhitespace| -> Result<Vec<f32>, _> {
+        parts.map(str::parse).collect::<Result<Vec<f32>, _>>()
+    };
+
+    for line in text.lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            // X is mirrored for the left-handed coordinate system
+            Some("v") => match floats(parts)?[..] {
+                [x, y, z, ..] => positions.push(Vec3::new(-x, y, z)),
+                _ => bail!("Invalid vertex position"),
+            },
+            Some("vn") => match floats(parts)?[..] {
+                [x, y, z, ..] => normals.push(Vec3::new(-x, y, z)),
+                _ => bail!("Invalid vertex normal"),
+            },
+            // Texture coordinates start at the bottom
+            Some("vt") => match floats(parts)?[..] {
+                [u, v, ..] => uvs.push(Vec2::new(u, 1.0 - v)),
+                [u] => uvs.push(Vec2::new(u, 1.0)),
+                _ => bail!("Invalid texture coordinate"),
+            },
+            Some("usemtl") => {
+                let name = parts.next().unwrap_or_default();
+                current = match materials.iter().position(|(mtl, _, _)| mtl == name) {
+                    Some(index) => index,
+                    None => {
+                        materials.push((
+                            String::from(name),
+                            ModelBuffer::default(),
+                            HashMap::new(),
+                        ));
+                        materials.len() - 1
+                    }
+                };
+            }
+            Some("f") => {
+                let (_, buffer, vertex_map) = &mut materials[current];
+                let mut corners = Vec::new();
+                for corner in parts {
+                    // "v", "v/vt", "v//vn" or "v/vt/vn", 1-based or negative
+                    // for relative to the end
+                    let mut refs = corner.split('/');
+                    let mut next_index = |len: usize| -> anyhow::Result<Option<usize>> {
+                        let Some(index) = refs.next().filter(|index| !index.is_empty()) else {
+                            return Ok(None);
+                        };
+                        let index: isize = index.parse()?;
+                        let index = if index < 0 {
+                            len.checked_add_signed(index)
+                        } else {
+                            (index as usize).checked_sub(1)
+                        };
+                        match index {
+                            Some(index) if index < len => Ok(Some(index)),
+                            _ => Err(anyhow!("Invalid face index \"{}\"", corner)),
+                        }
+                    };
+                    let position = next_index(positions.len())?
+                        .ok_or_else(|| anyhow!("Face without vertex position"))?;
+                    let uv = next_index(uvs.len())?;
+                    let normal = next_index(normals.len())?;
+
+                    // Shared corners become shared vertices
+                    let vertex = *vertex_map.entry((position, uv, normal)).or_insert_with(|| {
+                        buffer.vertices.push(ModelVertex {
+                            position: positions[position],
+                            normal: normal.map_or(Vec3::ZERO, |normal| normals[normal]),
+                            uv: uv.map_or(Vec2::ZERO, |uv| uvs[uv]),
+                        });
+                        buffer.vertices.len() as u32 - 1
+                    });
+                    corners.push(vertex);
+                }
+                // A fan, reversed since X is mirrored
+                for i in 1..corners.len().saturating_sub(1) {
+                    buffer
+                        .indices
+                        .extend([corners[i + 1], corners[i], corners[0]]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // The default material is only a buffer if it's used
+    Ok(materials
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (_, buffer, _))| *index > 0 || !buffer.indices.is_empty())
+        .map(|(_, (_, buffer, _))| buffer)
+        .collect())
+}
+
+/// Reads the little-endian chunks of a .b3d file.
+struct B3dReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl B3dReader<'_> {
+    fn bytes<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| anyhow!("Unexpected end of file"))?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+
+    fn vec3(&mut self) -> anyhow::Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    /// Skips a null-terminated string.
+    fn skip_string(&mut self) -> anyhow::Result<()> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| anyhow!("Unterminated string"))?;
+        self.pos += len + 1;
+        Ok(())
+    }
+
+    /// Reads a chunk header. Returns the tag and the end of the chunk.
+    fn chunk(&mut self) -> anyhow::Result<([u8; 4], usize)> {
+        let tag = self.bytes()?;
+        let len = self.i32()?;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("Invalid chunk length"))?;
+        Ok((tag, end))
+    }
+}
+
+/// Parses a Blitz3D file. Every TRIS chunk becomes a buffer, static meshes
+/// are placed with the transforms of their nodes.
+// Compare to Irrlicht, CB3DMeshFileLoader::createMesh
+fn parse_b3d(data: &[u8]) -> anyhow::Result<Vec<ModelBuffer>> {
+    let mut reader = B3dReader { data, pos: 0 };
+    let (tag, end) = reader.chunk()?;
+    if &tag != b"BB3D" {
+        bail!("Not a B3D file");
+    }
+    let _version = reader.i32()?;
+
+    let mut buffers = Vec::new();
+    while reader.pos < end {
+        let (tag, chunk_end) = reader.chunk()?;
+        if &tag == b"NODE" {
+            read_b3d_node(&mut reader, chunk_end, Mat4::IDENTITY, &mut buffers)?;
+        }
+        reader.pos = chunk_end;
+    }
+    Ok(buffers)
+}
+
+fn read_b3d_node(
+    reader: &mut B3dReader,
+    end: usize,
+    parent: Mat4,
+    buffers: &mut Vec<ModelBuffer>,
+) -> anyhow::Result<()> {
+    reader.skip_string()?;
+    let position = reader.vec3()?;
+    let scale = reader.vec3()?;
+    // Stored as w, x, y, z
+    let w = reader.f32()?;
+    let rotation = Quat::from_xyzw(reader.f32()?, reader.f32()?, reader.f32()?, w);
+    let transform =
+        parent * Mat4::from_scale_rotation_translation(scale, rotation.normalize(), position);
+
+    while reader.pos < end {
+        let (tag, chunk_end) = reader.chunk()?;
+        match &tag {
+            b"MESH" => read_b3d_mesh(reader, chunk_end, transform, buffers)?,
+            b"NODE" => read_b3d_node(reader, chunk_end, transform, buffers)?,
+            // Bones, keys and animations
+            _ => (),
+        }
+        reader.pos = chunk_end;
+    }
+    Ok(())
+}
+
+fn read_b3d_mesh(
+    reader: &mut B3dReader,
+    end: usize,
+    transform: Mat4,
+    buffers: &mut Vec<ModelBuffer>,
+) -> anyhow::Result<()> {
+    let _brush_id = reader.i32()?;
+
+    let mut vertices = Vec::new();
+    while reader.pos < end {
+        let (tag, chunk_end) = reader.chunk()?;
+        match &tag {
+            b"VRTS" => {
+                let flags = reader.i32()?;
+                let tex_coord_sets = reader.i32()?.max(0) as usize;
+                let tex_coord_size = reader.i32()?.max(0) as usize;
+                while reader.pos < chunk_end {
+                    let position = transform.transform_point3(reader.vec3()?);
+                    let mut normal = Vec3::ZERO;
+                    if flags & 1 != 0 {
+                        normal = transform
+                            .transform_vector3(reader.vec3()?)
+                            .normalize_or_zero();
+                    }
+                    if flags & 2 != 0 {
+                        // Vertex colors aren't used
+                        reader.bytes::<16>()?;
+                    }
+                    let mut uv = Vec2::ZERO;
+                    for set in 0..tex_coord_sets {
+                        for component in 0..tex_coord_size {
+                            let value = reader.f32()?;
+                            if set == 0 && component < 2 {
+                                uv[component] = value;
+                            }
+                        }
+                    }
+                    vertices.push(ModelVertex {
+                        position,
+                        normal,
+                        uv,
+                    });
+                }
+            }
+            b"TRIS" => {
+                let _brush_id = reader.i32()?;
+                // Only the vertices used by the triangles are copied
+                let mut buffer = ModelBuffer::default();
+                let mut vertex_map = HashMap::new();
+                while reader.pos < chunk_end {
+                    for _ in 0..3 {
+                        let index = reader.i32()?;
+                        let vertex = usize::try_from(index)
+                            .ok()
+                            .and_then(|index| vertices.get(index))
+                            .ok_or_else(|| anyhow!("Vertex index {} out of range", index))?;
+                        let index = *vertex_map.entry(index).or_insert_with(|| {
+                            buffer.vertices.push(*vertex);
+                            buffer.vertices.len() as u32 - 1
+                        });
+                        buffer.indices.push(index);
+                    }
+                }
+                buffers.push(buffer);
+            }
+            _ => (),
+        }
+        reader.pos = chunk_end;
+    }
+    Ok(())
+}
+
+/// Parses a glTF file, either .gltf with embedded base64 buffers or binary
+/// .glb. Every triangle primitive becomes a buffer.
+// Compare to Luanti, irr/src/CGLTFMeshFileLoader.cpp
+fn parse_gltf(data: &[u8]) -> anyhow::Result<Vec<ModelBuffer>> {
+    let gltf = gltf::Gltf::from_slice(data)?;
+    let buffers = gltf
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Bin => gltf
+                .blob
+                .clone()
+                .ok_or_else(|| anyhow!("Missing binary buffer")),
+            // Like in Luanti, other files can't be referenced
+            gltf::buffer::Source::Uri(uri) => {
+                let (_, encoded) = uri
+                    .strip_prefix("data:")
+                    .and_then(|uri| uri.split_once(";base64,"))
+                    .ok_or_else(|| anyhow!("Unsupported buffer URI"))?;
+                Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?)
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or_else(|| anyhow!("No scene"))?;
+    let mut model_buffers = Vec::new();
+    for node in scene.nodes() {
+        read_gltf_node(&node, Mat4::IDENTITY, &buffers, &mut model_buffers)?;
+    }
+    Ok(model_buffers)
+}
+
+fn read_gltf_node(
+    node: &gltf::Node,
+    parent: Mat4,
+    buffers: &[Vec<u8>],
+    model_buffers: &mut Vec<ModelBuffer>,
+) -> anyhow::Result<()> {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+    // glTF is right-handed, Z is mirrored
+    let mirror = Vec3::new(1.0, 1.0, -1.0);
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+            let positions: Vec<Vec3> = reader
+                .read_positions()
+                .ok_or_else(|| anyhow!("Primitive without positions"))?
+                .map(|position| transform.transform_point3(Vec3::from(position)) * mirror)
+                .collect();
+            let mut normals = reader.read_normals().map(|normals| {
+                normals.map(|normal| {
+                    (transform.transform_vector3(Vec3::from(normal)) * mirror).normalize_or_zero()
+                })
+            });
+            let mut uvs = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().map(Vec2::from));
+
+            let vertices = positions
+                .into_iter()
+                .map(|position| ModelVertex {
+                    position,
+                    normal: normals
+                        .as_mut()
+                        .and_then(|normals| normals.next())
+                        .unwrap_or(Vec3::ZERO),
+                    uv: uvs
+                        .as_mut()
+                        .and_then(|uvs| uvs.next())
+                        .unwrap_or(Vec2::ZERO),
+                })
+                .collect::<Vec<_>>();
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+            // Reversed since Z is mirrored
+            let indices = indices
+                .chunks_exact(3)
+                .flat_map(|triangle| [triangle[2], triangle[1], triangle[0]])
+                .collect();
+            model_buffers.push(ModelBuffer { vertices, indices });
+        }
+    }
+
+    for child in node.children() {
+        read_gltf_node(&child, transform, buffers, model_buffers)?;
+    }
+    Ok(())
+}
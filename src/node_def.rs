@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use luanti_core::ContentId;
 use luanti_protocol::types::{ContentFeatures, DrawType, ParamType, TileDef};
 
+use crate::media::MediaManager;
+
 pub struct NodeDefManager {
     // TODO: should be private
     pub map: HashMap<ContentId, ContentFeatures>,
@@ -19,7 +21,7 @@ impl NodeDefManager {
             ContentFeatures {
                 name: String::from("unknown"),
                 tiledef: std::array::from_fn(|_| TileDef {
-                    name: String::from("unknown_node.png"),
+                    name: String::from(MediaManager::UNKNOWN_NODE_TEXTURE),
                     ..TileDef::default()
                 }),
                 ..ContentFeatures::default()
@@ -69,6 +71,15 @@ impl NodeDefManager {
         self.map.get(&content_id)
     }
 
+    /// Looks up a node by its name. Slow, but only needed rarely, e.g. for
+    /// dig predictions.
+    pub fn get_id(&self, name: &str) -> Option<ContentId> {
+        self.map
+            .iter()
+            .find(|(_, def)| def.name == name)
+            .map(|(id, _)| *id)
+    }
+
     pub fn get_with_fallback(&self, content_id: ContentId) -> &ContentFeatures {
         self.get(content_id)
             .unwrap_or_else(|| self.map.get(&ContentId::UNKNOWN).unwrap())
@@ -0,0 +1,87 @@
+//! Node metadata, e.g. the inventory of a chest or the text of a sign. Sent
+//! with TOCLIENT_BLOCKDATA and updated through TOCLIENT_NODEMETA_CHANGED.
+
+use std::collections::HashMap;
+
+use glam::I16Vec3;
+use luanti_core::{MapBlockPos, MapNodePos};
+use luanti_protocol::types::NodeMetadata as NetworkNodeMetadata;
+
+use crate::inventory::Inventory;
+
+/// The metadata of a single node. Private fields aren't sent to clients.
+// Compare to Luanti, nodemetadata.h, NodeMetadata
+#[derive(Debug, Clone, Default)]
+pub struct NodeMetadata {
+    pub fields: HashMap<String, String>,
+    pub inventory: Inventory,
+}
+
+impl NodeMetadata {
+    // Compare to Luanti, nodemetadata.cpp, NodeMetadata::deSerialize
+    pub fn from_network(metadata: NetworkNodeMetadata) -> anyhow::Result<Self> {
+        let mut inventory = Inventory::default();
+        inventory.deserialize(&metadata.inventory)?;
+        Ok(Self {
+            fields: metadata
+                .stringvars
+                .into_iter()
+                .map(|var| (var.name, var.value))
+                .collect(),
+            inventory,
+        })
+    }
+
+    /// Nodes without metadata are sent with empty metadata.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.inventory.lists.is_empty()
+    }
+}
+
+/// The metadata of all nodes in the map that have some.
+#[derive(Default)]
+pub struct NodeMetadataStore {
+    /// By node position
+    metadata: HashMap<I16Vec3, NodeMetadata>,
+}
+
+impl NodeMetadataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the metadata of a node.
+    /// Returns None if the node has no metadata.
+    pub fn get(&self, pos: &MapNodePos) -> Option<&NodeMetadata> {
+        self.metadata.get(&pos.0)
+    }
+
+    /// Sets the metadata of a node, replacing the existing metadata. Empty
+    /// metadata removes it.
+    pub fn set(&mut self, pos: &MapNodePos, metadata: NodeMetadata) {
+        if metadata.is_empty() {
+            self.remove(pos);
+        } else {
+            self.metadata.insert(pos.0, metadata);
+        }
+    }
+
+    /// Removes the metadata of a node, e.g. when it is removed.
+    pub fn remove(&mut self, pos: &MapNodePos) {
+        self.metadata.remove(&pos.0);
+    }
+
+    /// Replaces the metadata of all nodes in a mapblock, since a mapblock
+    /// is always sent with all of its metadata.
+    pub fn set_block(
+        &mut self,
+        blockpos: MapBlockPos,
+        metadata: impl IntoIterator<Item = (MapNodePos, NodeMetadata)>,
+    ) {
+        self.metadata
+            .retain(|pos, _| MapNodePos(*pos).split_index().0 != blockpos);
+        for (pos, metadata) in metadata {
+            self.set(&pos, metadata);
+        }
+    }
+}
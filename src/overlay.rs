@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use glam::{Mat4, Vec2, Vec4};
+use wgpu::util::DeviceExt;
+
+use crate::media::MediaManager;
+use crate::texture::MyTexture;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct OverlayVertex {
+    /// In pixels, origin is the top left corner of the screen
+    position: Vec2,
+    uv: Vec2,
+    /// Multiplied with the texture color
+    color: Vec4,
+}
+
+impl OverlayVertex {
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: [wgpu::VertexAttribute; 3] =
+            wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32x4];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<OverlayVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct OverlayUniform {
+    proj: [f32; 16],
+}
+
+/// A texture added to the OverlayRenderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlayTextureId(usize);
+
+/// An image from a media file, uploaded to the OverlayRenderer.
+pub struct MediaImage {
+    pub id: OverlayTextureId,
+    /// In pixels
+    pub size: Vec2,
+}
+
+/// Images from media files by name, uploaded on first use.
+#[derive(Default)]
+pub struct MediaImages {
+    /// None if the image couldn't be loaded
+    images: HashMap<String, Option<MediaImage>>,
+}
+
+impl MediaImages {
+    /// Uploads the image if it hasn't been uploaded yet.
+    pub fn load(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        overlay: &mut OverlayRenderer,
+        media: &MediaManager,
+        name: &str,
+    ) {
+        if name.is_empty() || self.images.contains_key(name) {
+            return;
+        }
+
+        // TODO: texture modifiers, only the base image is used for now
+        let base_name = name.split('^').next().unwrap_or_default();
+        let image = match media.load_image(base_name) {
+            Ok(Some(img)) => Some(MediaImage {
+                size: Vec2::new(img.width() as f32, img.height() as f32),
+                id: overlay.add_texture(device, queue, name, &img),
+            }),
+            Ok(None) => {
+                println!("Image \"{}\" not found", name);
+                None
+            }
+            Err(err) => {
+                println!("Error while loading image \"{}\": {:?}", name, err);
+                None
+            }
+        };
+        self.images.insert(String::from(name), image);
+    }
+
+    /// Returns the image if it was uploaded successfully.
+    pub fn get(&self, name: &str) -> Option<&MediaImage> {
+        self.images.get(name).and_then(Option::as_ref)
+    }
+}
+
+struct OverlayTexture {
+    // Kept alive for the bind group
+    _texture: MyTexture,
+    bind_group: wgpu::BindGroup,
+}
+
+/// A range of indices drawn with the same texture.
+struct Batch {
+    texture: OverlayTextureId,
+    indices: Range<u32>,
+}
+
+/// The 2D overlay pass. Draws screen-space quads over the finished frame,
+/// using an orthographic projection in pixel coordinates. Quads are collected
+/// during the frame and drawn (and cleared) by `render`, in the order they
+/// were added.
+pub struct OverlayRenderer {
+    pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+
+    textures: Vec<OverlayTexture>,
+    /// A 1x1 white texture, used for untextured shapes
+    white_texture: OverlayTextureId,
+    crosshair_texture: OverlayTextureId,
+
+    vertices: Vec<OverlayVertex>,
+    indices: Vec<u32>,
+    batches: Vec<Batch>,
+}
+
+impl OverlayRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target_format: wgpu::TextureFormat,
+    ) -> Self {
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Overlay buffer"),
+            size: std::mem::size_of::<OverlayUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Overlay bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Overlay bind group"),
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Overlay texture bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        // Nearest filtering, HUD images are usually pixel art
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Overlay sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Overlay pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("overlay_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Overlay render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[OverlayVertex::layout()],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        let mut renderer = Self {
+            pipeline,
+            uniform_buffer,
+            bind_group,
+            texture_bind_group_layout,
+            sampler,
+
+            textures: Vec::new(),
+            white_texture: OverlayTextureId(0),
+            crosshair_texture: OverlayTextureId(0),
+
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            batches: Vec::new(),
+        };
+
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        renderer.white_texture =
+            renderer.add_texture(device, queue, "Overlay white texture", &white.into());
+        let crosshair = Self::generate_crosshair();
+        renderer.crosshair_texture = renderer.add_texture(
+            device,
+            queue,
+            "Overlay crosshair texture",
+            &crosshair.into(),
+        );
+
+        renderer
+    }
+
+    /// Generates a simple crosshair image, similar to Luanti's default one.
+    fn generate_crosshair() -> image::RgbaImage {
+        const SIZE: u32 = 17;
+        const CENTER: u32 = SIZE / 2;
+        image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+            if x == CENTER || y == CENTER {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        })
+    }
+
+    /// Uploads an image so it can be drawn with `image`.
+    pub fn add_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: &str,
+        img: &image::DynamicImage,
+    ) -> OverlayTextureId {
+        // Only fails for invalid images, which DynamicImage can't be
+        let texture = MyTexture::from_image(device, queue, name, img).unwrap();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(name),
+            layout: &self.texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+        self.textures.push(OverlayTexture {
+            _texture: texture,
+            bind_group,
+        });
+        OverlayTextureId(self.textures.len() - 1)
+    }
+
+    /// Adds a textured quad, coordinates are in pixels.
+    /// The texture color is multiplied with `color`.
+    pub fn image(
+        &mut self,
+        texture: OverlayTextureId,
+        min: Vec2,
+        max: Vec2,
+        uv_min: Vec2,
+        uv_max: Vec2,
+        color: Vec4,
+    ) {
+        let index_offset = self.vertices.len() as u32;
+        let corners = [
+            (min, uv_min),
+            (Vec2::new(max.x, min.y), Vec2::new(uv_max.x, uv_min.y)),
+            (max, uv_max),
+            (Vec2::new(min.x, max.y), Vec2::new(uv_min.x, uv_max.y)),
+        ];
+        for (position, uv) in corners {
+            self.vertices.push(OverlayVertex {
+                position,
+                uv,
+                color,
+            });
+        }
+
+        let first_index = self.indices.len() as u32;
+        self.indices
+            .extend([0, 1, 2, 2, 3, 0].map(|index| index_offset + index));
+        let last_index = self.indices.len() as u32;
+
+        // Consecutive quads with the same texture are drawn together
+        match self.batches.last_mut() {
+            Some(batch) if batch.texture == texture => batch.indices.end = last_index,
+            _ => self.batches.push(Batch {
+                texture,
+                indices: first_index..last_index,
+            }),
+        }
+    }
+
+    /// Adds a filled rectangle, coordinates are in pixels.
+    pub fn rect(&mut self, min: Vec2, max: Vec2, color: Vec4) {
+        self.image(self.white_texture, min, max, Vec2::ZERO, Vec2::ONE, color);
+    }
+
+    /// Adds the crosshair in the center of the screen.
+    pub fn crosshair(&mut self, screen_size: winit::dpi::PhysicalSize<u32>, scale: f32) {
+        const COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.8);
+
+        let center = Vec2::new(screen_size.width as f32, screen_size.height as f32) / 2.0;
+        let half_size = Vec2::splat(Self::generate_crosshair().width() as f32 * scale / 2.0);
+        // Snap to whole pixels, otherwise the 1-pixel lines get blurry
+        let min = (center - half_size).round();
+        self.image(
+            self.crosshair_texture,
+            min,
+            min + half_size * 2.0,
+            Vec2::ZERO,
+            Vec2::ONE,
+            COLOR,
+        );
+    }
+
+    /// Draws all shapes added since the last call over `target`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        if self.indices.is_empty() {
+            return;
+        }
+
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[OverlayUniform {
+                proj: Mat4::orthographic_rh(
+                    0.0,
+                    screen_size.width as f32,
+                    screen_size.height as f32,
+                    0.0,
+                    -1.0,
+                    1.0,
+                )
+                .to_cols_array(),
+            }]),
+        );
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Overlay vertex buffer"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Overlay index buffer"),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Overlay pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        for batch in &self.batches {
+            pass.set_bind_group(1, &self.textures[batch.texture.0].bind_group, &[]);
+            pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+        }
+
+        self.vertices.clear();
+        self.indices.clear();
+        self.batches.clear();
+    }
+}
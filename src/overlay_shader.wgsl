@@ -0,0 +1,40 @@
+struct OverlayUniform {
+    // orthographic projection, pixels (origin top left) to clip space
+    proj: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> overlay: OverlayUniform;
+
+@group(1) @binding(0)
+var the_texture: texture_2d<f32>;
+
+@group(1) @binding(1)
+var the_sampler: sampler;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = overlay.proj * vec4<f32>(model.position, 0.0, 1.0);
+    out.uv = model.uv;
+    out.color = model.color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(the_texture, the_sampler, in.uv) * in.color;
+}
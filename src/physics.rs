@@ -0,0 +1,170 @@
+//! Player physics: moving an axis-aligned box through the map without
+//! passing through walkable nodes.
+
+use glam::{IVec3, Vec3};
+use luanti_core::{ContentId, MapNodePos};
+
+use crate::map::LuantiMap;
+use crate::node_def::NodeDefManager;
+use crate::pointing;
+
+/// Tolerance for touching boxes, in nodes
+const EPSILON: f32 = 0.001;
+
+/// What the player collides with.
+pub struct CollisionWorld<'a> {
+    pub map: &'a LuantiMap,
+    pub node_def: &'a NodeDefManager,
+}
+
+impl CollisionWorld<'_> {
+    /// Returns the collision boxes of the walkable nodes in the area
+    /// between `min` and `max`, in world coordinates.
+    fn boxes(&self, min: Vec3, max: Vec3) -> Vec<(Vec3, Vec3)> {
+        // Nodeboxes can reach into the neighboring nodes
+        let min_cell = (min + 0.5).floor().as_ivec3() - 1;
+        let max_cell = (max + 0.5).floor().as_ivec3() + 1;
+
+        let mut boxes = Vec::new();
+        for x in min_cell.x..=max_cell.x {
+            for y in min_cell.y..=max_cell.y {
+                for z in min_cell.z..=max_cell.z {
+                    let cell = IVec3::new(x, y, z);
+                    let center = cell.as_vec3();
+                    match self.map.get_node(&MapNodePos(cell.as_i16vec3())) {
+                        Some(node) if node.content_id != ContentId::IGNORE => {
+                            let def = self.node_def.get_with_fallback(node.content_id);
+                            if !def.walkable {
+                                continue;
+                            }
+                            boxes.extend(
+                                pointing::node_boxes(&def.collision_box, def, node)
+                                    .into_iter()
+                                    .map(|(min, max)| (center + min, center + max)),
+                            );
+                        }
+                        // Unloaded nodes are solid, so the player doesn't
+                        // fall through the world while it loads
+                        _ => boxes.push((center - 0.5, center + 0.5)),
+                    }
+                }
+            }
+        }
+        boxes
+    }
+}
+
+/// Moves the box `min..max` by up to `distance` along `axis`, stopping at
+/// the first box in the way. Returns the distance actually moved. Boxes the
+/// box is already stuck in are ignored, so it can get out of them.
+fn sweep_axis(boxes: &[(Vec3, Vec3)], min: Vec3, max: Vec3, axis: usize, distance: f32) -> f32 {
+    let mut distance = distance;
+    for (box_min, box_max) in boxes {
+        // Only boxes overlapping on the other axes are in the way
+        let in_the_way = (0..3).filter(|&other| other != axis).all(|other| {
+            min[other] < box_max[other] - EPSILON && max[other] > box_min[other] + EPSILON
+        });
+        if !in_the_way {
+            continue;
+        }
+        if distance > 0.0 && max[axis] <= box_min[axis] + EPSILON {
+            distance = distance.min((box_min[axis] - max[axis]).max(0.0));
+        } else if distance < 0.0 && min[axis] >= box_max[axis] - EPSILON {
+            distance = distance.max((box_max[axis] - min[axis]).min(0.0));
+        }
+    }
+    distance
+}
+
+pub struct MoveResult {
+    pub pos: Vec3,
+    /// The velocity with the components that were blocked set to 0
+    pub velocity: Vec3,
+    /// Whether the box is standing on something
+    pub touching_ground: bool,
+}
+
+/// Moves a box (`collisionbox` relative to `pos`) by `velocity * dtime`,
+/// sliding along walkable nodes. While on the ground, it steps up onto
+/// obstacles up to `stepheight` high, e.g. slabs.
+// Compare to Luanti, collision.cpp, collisionMoveSimple
+pub fn collision_move(
+    world: &CollisionWorld,
+    collisionbox: (Vec3, Vec3),
+    pos: Vec3,
+    velocity: Vec3,
+    dtime: f32,
+    stepheight: f32,
+    on_ground: bool,
+) -> MoveResult {
+    let (box_min, box_max) = collisionbox;
+    let delta = velocity * dtime;
+    let mut pos = pos;
+    let mut velocity = velocity;
+
+    // Everything the box can touch during this step, including when
+    // stepping up
+    let reach = delta.abs() + Vec3::Y * stepheight;
+    let boxes = world.boxes(pos + box_min - reach, pos + box_max + reach);
+    let sweep = |pos: Vec3, axis, distance| {
+        sweep_axis(&boxes, pos + box_min, pos + box_max, axis, distance)
+    };
+
+    // Vertical first, so we know whether we're standing on something
+    let moved = sweep(pos, 1, delta.y);
+    let mut touching_ground = false;
+    if moved != delta.y {
+        touching_ground = delta.y < 0.0;
+        velocity.y = 0.0;
+    }
+    pos.y += moved;
+    let can_step_up = stepheight > 0.0 && (on_ground || touching_ground);
+
+    for axis in [0, 2] {
+        let moved = sweep(pos, axis, delta[axis]);
+        if moved == delta[axis] {
+            pos[axis] += moved;
+            continue;
+        }
+
+        if can_step_up {
+            // Move up, then forward, then back down onto the obstacle
+            let up = sweep(pos, 1, stepheight);
+            let mut raised = pos + Vec3::Y * up;
+            let stepped = sweep(raised, axis, delta[axis]);
+            if stepped.abs() > moved.abs() + EPSILON {
+                raised[axis] += stepped;
+                raised.y += sweep(raised, 1, -up);
+                pos = raised;
+                if stepped != delta[axis] {
+                    velocity[axis] = 0.0;
+                }
+                touching_ground = true;
+                continue;
+            }
+        }
+
+        pos[axis] += moved;
+        velocity[axis] = 0.0;
+    }
+
+    MoveResult {
+        pos,
+        velocity,
+        touching_ground,
+    }
+}
+
+/// Returns whether the box (`collisionbox` relative to `pos`) would land on
+/// something within `max_drop` nodes below it.
+// Compare to Luanti, client/localplayer.cpp, LocalPlayer::move (sneak)
+pub fn has_ground(
+    world: &CollisionWorld,
+    collisionbox: (Vec3, Vec3),
+    pos: Vec3,
+    max_drop: f32,
+) -> bool {
+    let (min, max) = (pos + collisionbox.0, pos + collisionbox.1);
+    let boxes = world.boxes(min - Vec3::Y * max_drop, max);
+    sweep_axis(&boxes, min, max, 1, -max_drop) > -max_drop
+}
@@ -0,0 +1,220 @@
+//! Finding what the player points at, by casting a ray from their head
+//! through the map and testing the selection boxes of pointable nodes and
+//! active objects.
+
+use std::collections::HashSet;
+
+use glam::{I16Vec3, IVec3, Vec3};
+use luanti_core::{MapNode, MapNodePos};
+use luanti_protocol::types::{ContentFeatures, NodeBox};
+
+use crate::active_object::ActiveObject;
+use crate::map::LuantiMap;
+use crate::meshgen::{facedir_rotate, node_facedir};
+use crate::node_def::NodeDefManager;
+use crate::world::{ObjectId, World};
+
+/// The result of a raycast.
+// Compare to Luanti, util/pointedthing.h, PointedThing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum PointedThing {
+    #[default]
+    Nothing,
+    Node {
+        /// The pointed node
+        under: MapNodePos,
+        /// The neighbor on the pointed side, where placed nodes go
+        above: MapNodePos,
+        /// Where the ray hit the selection box
+        intersection: Vec3,
+        /// Normal of the pointed side of the selection box
+        normal: I16Vec3,
+    },
+    Object {
+        id: u16,
+    },
+}
+
+/// Luanti's range for items without a range, in nodes
+// Compare to Luanti, itemdef.h, ItemDefinition::range
+pub const DEFAULT_RANGE: f32 = 4.0;
+
+/// Returns the selection boxes of a node, relative to the node center.
+pub fn selection_boxes(def: &ContentFeatures, node: MapNode) -> Vec<(Vec3, Vec3)> {
+    node_boxes(&def.selection_box, def, node)
+}
+
+/// Returns the boxes of one of the nodeboxes of a node (selection or
+/// collision), relative to the node center.
+// Compare to Luanti, nodedef.cpp, getNodeBoxes
+pub fn node_boxes(nodebox: &NodeBox, def: &ContentFeatures, node: MapNode) -> Vec<(Vec3, Vec3)> {
+    const FULL_BOX: (Vec3, Vec3) = (Vec3::splat(-0.5), Vec3::splat(0.5));
+
+    match nodebox {
+        NodeBox::Fixed(nodebox) => {
+            let facedir = node_facedir(def, node);
+            nodebox
+                .fixed
+                .iter()
+                .map(|aabb| {
+                    let a = facedir_rotate(aabb.min_edge, facedir);
+                    let b = facedir_rotate(aabb.max_edge, facedir);
+                    (a.min(b), a.max(b))
+                })
+                .collect()
+        }
+        // TODO: the connected parts, which depend on the neighbors
+        NodeBox::Connected(nodebox) => nodebox
+            .fixed
+            .iter()
+            .map(|aabb| (aabb.min_edge, aabb.max_edge))
+            .collect(),
+        // TODO: wallmounted and leveled boxes, a full box for now
+        _ => vec![FULL_BOX],
+    }
+}
+
+/// Intersects a ray with a box. Returns the distance along the ray and the
+/// normal of the side that was hit, or None if the ray misses the box or
+/// starts inside of it.
+fn intersect_box(pos: Vec3, dir: Vec3, min: Vec3, max: Vec3) -> Option<(f32, I16Vec3)> {
+    // Slab test, axes the ray is parallel to give infinities
+    let t1 = (min - pos) / dir;
+    let t2 = (max - pos) / dir;
+    let t_near = t1.min(t2);
+    let t_far = t1.max(t2);
+
+    let enter = t_near.max_element();
+    let exit = t_far.min_element();
+    if enter > exit || enter < 0.0 || enter.is_nan() {
+        return None;
+    }
+
+    let axis = t_near.max_position();
+    let mut normal = I16Vec3::ZERO;
+    normal[axis] = if dir[axis] > 0.0 { -1 } else { 1 };
+    Some((enter, normal))
+}
+
+/// Intersects a ray with the selection boxes of the node at `cell`, if it
+/// is pointable. Returns the distance and normal like `intersect_box`.
+fn intersect_node(
+    map: &LuantiMap,
+    node_def: &NodeDefManager,
+    cell: IVec3,
+    pos: Vec3,
+    dir: Vec3,
+) -> Option<(f32, I16Vec3)> {
+    let node = map.get_node(&MapNodePos(cell.as_i16vec3()))?;
+    let def = node_def.get_with_fallback(node.content_id);
+    if !def.pointable {
+        return None;
+    }
+    let center = cell.as_vec3();
+    selection_boxes(def, node)
+        .into_iter()
+        .filter_map(|(min, max)| intersect_box(pos, dir, center + min, center + max))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+/// Finds the first pointable node within `range` of `pos` in direction
+/// `dir`. Visits the nodes along the ray in order, using the voxel traversal
+/// algorithm by Amanatides and Woo. Selection boxes may extend up to one
+/// node into the neighbors, e.g. of doors, so the neighbors of every visited
+/// node are tested as well.
+// Compare to Luanti, raycast.cpp, RaycastState, and
+// environment.cpp, Environment::continueRaycast
+pub fn raycast(
+    map: &LuantiMap,
+    node_def: &NodeDefManager,
+    pos: Vec3,
+    dir: Vec3,
+    range: f32,
+) -> PointedThing {
+    let dir = dir.normalize_or_zero();
+    if dir == Vec3::ZERO {
+        return PointedThing::Nothing;
+    }
+
+    // Nodes are centered on integer coordinates
+    let mut cell = (pos + 0.5).floor().as_ivec3();
+    let step = IVec3::new(
+        dir.x.signum() as i32,
+        dir.y.signum() as i32,
+        dir.z.signum() as i32,
+    );
+    let boundary = cell.as_vec3() + step.as_vec3() * 0.5;
+    // Distance along the ray to the next cell boundary on each axis
+    let mut t_max = Vec3::select(
+        dir.cmpeq(Vec3::ZERO),
+        Vec3::INFINITY,
+        (boundary - pos) / dir,
+    );
+    let t_delta = Vec3::select(dir.cmpeq(Vec3::ZERO), Vec3::INFINITY, dir.recip().abs());
+
+    let mut tested = HashSet::new();
+    // Distance, node and normal of the closest hit so far
+    let mut closest: Option<(f32, IVec3, I16Vec3)> = None;
+    let mut t = 0.0;
+    // A node whose box is hit closer than the closest hit so far is a
+    // neighbor of a node the ray enters before that
+    while t <= closest.map_or(range, |(distance, ..)| distance) {
+        for index in 0..27 {
+            let neighbor = cell + IVec3::new(index % 3 - 1, index / 3 % 3 - 1, index / 9 - 1);
+            if !tested.insert(neighbor) {
+                continue;
+            }
+            if let Some((distance, normal)) = intersect_node(map, node_def, neighbor, pos, dir)
+                && distance <= range
+                && closest.is_none_or(|(closest, ..)| distance < closest)
+            {
+                closest = Some((distance, neighbor, normal));
+            }
+        }
+
+        let axis = t_max.min_position();
+        t = t_max[axis];
+        cell[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+    }
+
+    let Some((distance, cell, normal)) = closest else {
+        return PointedThing::Nothing;
+    };
+    let under = cell.as_i16vec3();
+    PointedThing::Node {
+        under: MapNodePos(under),
+        above: MapNodePos(under + normal),
+        intersection: pos + dir * distance,
+        normal,
+    }
+}
+
+/// Finds the closest pointable active object within `range` of `pos` in
+/// direction `dir`. Returns the distance along the ray as well.
+// Compare to Luanti, environment.cpp, Environment::continueRaycast and
+// client/clientenvironment.cpp, ClientEnvironment::getSelectedActiveObjects
+pub fn raycast_objects(
+    world: &World,
+    pos: Vec3,
+    dir: Vec3,
+    range: f32,
+) -> Option<(f32, PointedThing)> {
+    let dir = dir.normalize_or_zero();
+    if dir == Vec3::ZERO {
+        return None;
+    }
+
+    world
+        .ecs
+        .query::<(&ObjectId, &ActiveObject)>()
+        .iter()
+        .filter_map(|(_, (id, object))| {
+            let (min, max) = object.selection_box()?;
+            let (distance, _) = intersect_box(pos, dir, min, max)?;
+            Some((distance, id.0))
+        })
+        .filter(|(distance, _)| *distance <= range)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(distance, id)| (distance, PointedThing::Object { id }))
+}
@@ -0,0 +1,255 @@
+use glam::Vec4;
+use wgpu::util::DeviceExt;
+
+use crate::texture::MyTexture;
+
+/// Parameters for the post-processing pass.
+#[derive(Debug)]
+pub struct PostProcessParams {
+    /// Color blended over the whole screen, alpha is the blend factor.
+    /// Used for the post_effect_color of the node containing the camera.
+    pub tint: Vec4,
+    /// Display gamma, values above 1 brighten dark scenes
+    pub gamma: f32,
+    /// 1.0 leaves colors unchanged, 0.0 is grayscale. Set by the server.
+    pub saturation: f32,
+    /// Exposure correction in stops, 0.0 leaves colors unchanged. Set by
+    /// the server.
+    pub exposure: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostProcessUniform {
+    tint: [f32; 4],
+    /// Non-zero if the target isn't an sRGB format, so the shader has to
+    /// encode to sRGB itself
+    encode_srgb: u32,
+    gamma: f32,
+    saturation: f32,
+    /// Factor to multiply colors with
+    exposure_factor: f32,
+}
+
+impl PostProcessUniform {
+    fn new(params: &PostProcessParams, encode_srgb: bool) -> Self {
+        Self {
+            tint: params.tint.to_array(),
+            encode_srgb: encode_srgb as u32,
+            gamma: params.gamma,
+            saturation: params.saturation,
+            exposure_factor: params.exposure.exp2(),
+        }
+    }
+}
+
+/// The post-processing framework. The world is rendered into an offscreen
+/// color texture, which is then drawn to the surface by a fullscreen pass
+/// applying screen-space effects. The offscreen texture may have a different
+/// size than the surface, it is scaled with linear filtering.
+pub struct PostProcess {
+    pub params: PostProcessParams,
+    encode_srgb: bool,
+
+    color_texture: MyTexture,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl PostProcess {
+    /// The format of the offscreen color texture. Pipelines rendering the
+    /// world must use this as their color target format.
+    pub const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(
+        device: &wgpu::Device,
+        size: winit::dpi::PhysicalSize<u32>,
+        target_format: wgpu::TextureFormat,
+        gamma: f32,
+    ) -> Self {
+        let params = PostProcessParams {
+            tint: Vec4::ZERO,
+            gamma,
+            saturation: 1.0,
+            exposure: 0.0,
+        };
+        let encode_srgb = !target_format.is_srgb();
+
+        let color_texture = MyTexture::new_render_target(device, size, Self::COLOR_FORMAT);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post-processing sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post-processing buffer"),
+            contents: bytemuck::cast_slice(&[PostProcessUniform::new(&params, encode_srgb)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post-processing bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &uniform_buffer,
+            &color_texture,
+            &sampler,
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post-processing pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("post_process_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Post-processing render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            params,
+            encode_srgb,
+            color_texture,
+            sampler,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &wgpu::Buffer,
+        color_texture: &MyTexture,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Post-processing bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&color_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+        })
+    }
+
+    /// Recreates the offscreen color texture with the new render size.
+    pub fn resize(&mut self, device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) {
+        self.color_texture = MyTexture::new_render_target(device, size, Self::COLOR_FORMAT);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buffer,
+            &self.color_texture,
+            &self.sampler,
+        );
+    }
+
+    /// The view the world should be rendered into.
+    pub fn color_view(&self) -> &wgpu::TextureView {
+        &self.color_texture.view
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[PostProcessUniform::new(&self.params, self.encode_srgb)]),
+        );
+    }
+
+    /// Draws the offscreen color texture to `target`, applying the effects.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Post-processing pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        // Fullscreen triangle, see post_process_shader.wgsl
+        pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,62 @@
+struct PostProcessUniform {
+    tint: vec4<f32>,
+    // non-zero if the target isn't an sRGB format
+    encode_srgb: u32,
+    gamma: f32,
+    saturation: f32,
+    exposure_factor: f32,
+}
+@group(0) @binding(0)
+var<uniform> params: PostProcessUniform;
+
+@group(0) @binding(1)
+var scene: texture_2d<f32>;
+
+@group(0) @binding(2)
+var scene_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Generates a single triangle covering the whole screen, no vertex buffer needed.
+@vertex
+fn vs_main(
+    @builtin(vertex_index) index: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.clip_position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color: vec3<f32> = textureSample(scene, scene_sampler, in.uv).rgb;
+
+    color = mix(color, params.tint.rgb, params.tint.a);
+
+    // Compare to Luanti, second_stage/opengl_fragment.glsl, applySaturation
+    color *= params.exposure_factor;
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    color = max(mix(vec3<f32>(luminance), color, params.saturation), vec3<f32>(0.0));
+
+    // Applied in linear space, before encoding, so it doesn't depend on the
+    // surface format
+    color = pow(color, vec3<f32>(1.0 / params.gamma));
+
+    if params.encode_srgb != 0u {
+        color = linear_to_srgb(color);
+    }
+
+    return vec4<f32>(color, 1.0);
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let cutoff = color <= vec3<f32>(0.0031308);
+    let lower = color * 12.92;
+    let higher = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(higher, lower, cutoff);
+}
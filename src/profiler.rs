@@ -0,0 +1,436 @@
+//! The profiler graph toggled with F4: the history of frame times, meshgen
+//! times and the time spent in each render pass. Pass times are measured on
+//! the GPU with timestamp queries if the adapter supports them, otherwise
+//! the time spent recording them on the CPU is shown instead.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use glam::{Vec2, Vec4};
+
+use crate::overlay::OverlayRenderer;
+use crate::text::TextRenderer;
+
+type MapResult = Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>;
+
+enum Readback {
+    /// The buffers are free, the next frame can be timed
+    Idle,
+    /// The timestamps of these passes are copied to the readback buffer by
+    /// the frame that is about to be submitted
+    Resolved(Vec<&'static str>),
+    /// Waiting for the readback buffer to be mapped
+    Mapping(Vec<&'static str>, MapResult),
+}
+
+/// Measures how long render passes take on the GPU, using timestamps
+/// written before and after each pass. The results arrive a few frames
+/// late, frames recorded while waiting for them aren't timed.
+pub struct GpuTimer {
+    device: wgpu::Device,
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    /// The passes timed in the frame being recorded
+    passes: Vec<&'static str>,
+    readback: Readback,
+}
+
+impl GpuTimer {
+    /// Passes beyond this aren't timed
+    const MAX_PASSES: u32 = 16;
+
+    /// The device needs the TIMESTAMP_QUERY and
+    /// TIMESTAMP_QUERY_INSIDE_ENCODERS features.
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let count = 2 * Self::MAX_PASSES;
+        let size = count as u64 * wgpu::QUERY_SIZE as u64;
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Render pass timestamps"),
+            ty: wgpu::QueryType::Timestamp,
+            count,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp resolve buffer"),
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp readback buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            device: device.clone(),
+            query_set,
+            resolve_buffer,
+            readback_buffer,
+            period: queue.get_timestamp_period(),
+            passes: Vec::new(),
+            readback: Readback::Idle,
+        }
+    }
+
+    /// Records a pass with timestamps around it, if this frame is timed.
+    pub fn time_pass(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        name: &'static str,
+        record: impl FnOnce(&mut wgpu::CommandEncoder),
+    ) {
+        let index = self.passes.len() as u32;
+        if !matches!(self.readback, Readback::Idle) || index == Self::MAX_PASSES {
+            record(encoder);
+            return;
+        }
+        encoder.write_timestamp(&self.query_set, 2 * index);
+        record(encoder);
+        encoder.write_timestamp(&self.query_set, 2 * index + 1);
+        self.passes.push(name);
+    }
+
+    /// Copies the timestamps of this frame to the readback buffer. Called
+    /// after recording all passes.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.passes.is_empty() {
+            return;
+        }
+        let count = 2 * self.passes.len() as u32;
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            &self.readback_buffer,
+            0,
+            count as u64 * wgpu::QUERY_SIZE as u64,
+        );
+        self.readback = Readback::Resolved(std::mem::take(&mut self.passes));
+    }
+
+    /// Starts reading back the resolved timestamps, or returns the pass
+    /// times once they have arrived. Called after submitting the frame.
+    fn poll(&mut self) -> Option<Vec<(&'static str, Duration)>> {
+        match std::mem::replace(&mut self.readback, Readback::Idle) {
+            Readback::Idle => None,
+            Readback::Resolved(passes) => {
+                // Mapping can only start after the copy was submitted
+                let result = MapResult::default();
+                let callback_result = result.clone();
+                self.readback_buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Read, move |res| {
+                        *callback_result.lock().unwrap() = Some(res);
+                    });
+                self.readback = Readback::Mapping(passes, result);
+                None
+            }
+            Readback::Mapping(passes, result) => {
+                let _ = self.device.poll(wgpu::PollType::Poll);
+                let Some(res) = result.lock().unwrap().take() else {
+                    self.readback = Readback::Mapping(passes, result);
+                    return None;
+                };
+                if let Err(err) = res {
+                    println!("Could not read back GPU timestamps: {:?}", err);
+                    return None;
+                }
+
+                let data = self.readback_buffer.slice(..).get_mapped_range();
+                let ticks: &[u64] = bytemuck::cast_slice(&data);
+                let times = passes
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, name)| {
+                        let elapsed = ticks[2 * index + 1].saturating_sub(ticks[2 * index]);
+                        let nanos = elapsed as f64 * self.period as f64;
+                        (name, Duration::from_nanos(nanos as u64))
+                    })
+                    .collect();
+                drop(data);
+                self.readback_buffer.unmap();
+                Some(times)
+            }
+        }
+    }
+}
+
+struct Sample {
+    frame_time: f32,
+    /// Summed over all meshgen threads, so it can exceed the frame time
+    meshgen_time: f32,
+    pass_times: Vec<(&'static str, f32)>,
+}
+
+// Compare to Luanti, client/profilergraph.cpp, ProfilerGraph
+pub struct Profiler {
+    visible: bool,
+    /// Oldest first
+    samples: VecDeque<Sample>,
+    /// Meshgen time of the meshes received since the last frame
+    meshgen_time: Duration,
+    /// None if timestamp queries aren't supported
+    gpu_timer: Option<GpuTimer>,
+    /// The most recent pass times, GPU results are repeated until the next
+    /// ones arrive
+    pass_times: Vec<(&'static str, f32)>,
+}
+
+impl Profiler {
+    /// Number of frames shown in the graphs
+    pub const HISTORY: usize = 240;
+    /// Frame time at the top of the frame graph, in seconds
+    const FRAME_GRAPH_MAX: f32 = 1.0 / 20.0;
+    /// Pass time at the top of the pass graph, in seconds
+    const PASS_GRAPH_MAX: f32 = 1.0 / 60.0;
+    /// Frame time marked by a line in the frame graph, in seconds
+    const TARGET: f32 = 1.0 / 60.0;
+    const PASS_COLORS: [Vec4; 6] = [
+        Vec4::new(0.3, 0.6, 1.0, 0.9),
+        Vec4::new(1.0, 0.8, 0.2, 0.9),
+        Vec4::new(0.7, 0.4, 1.0, 0.9),
+        Vec4::new(0.3, 0.9, 0.6, 0.9),
+        Vec4::new(1.0, 0.4, 0.6, 0.9),
+        Vec4::new(0.6, 0.6, 0.6, 0.9),
+    ];
+
+    pub fn new(gpu_timer: Option<GpuTimer>) -> Self {
+        Self {
+            visible: false,
+            samples: VecDeque::new(),
+            meshgen_time: Duration::ZERO,
+            gpu_timer,
+            pass_times: Vec::new(),
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Returns the timer to pass to RenderGraph::execute.
+    pub fn gpu_timer(&mut self) -> Option<&mut GpuTimer> {
+        self.gpu_timer.as_mut()
+    }
+
+    /// Adds the time it took to generate a mesh received this frame.
+    pub fn add_meshgen_time(&mut self, time: Duration) {
+        self.meshgen_time += time;
+    }
+
+    /// Records a frame. `cpu_pass_times` are the recording times returned
+    /// by RenderGraph::execute, used without a GPU timer. Called after
+    /// submitting the frame, even while hidden.
+    pub fn end_frame(&mut self, frame_time: f32, cpu_pass_times: &[(&'static str, Duration)]) {
+        match &mut self.gpu_timer {
+            Some(timer) => {
+                if let Some(times) = timer.poll() {
+                    self.pass_times = Self::to_secs(&times);
+                }
+            }
+            None => self.pass_times = Self::to_secs(cpu_pass_times),
+        }
+
+        if self.samples.len() == Self::HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            frame_time,
+            meshgen_time: std::mem::take(&mut self.meshgen_time).as_secs_f32(),
+            pass_times: self.pass_times.clone(),
+        });
+    }
+
+    /// Returns the recent frame times in seconds, oldest first. Shared with
+    /// the debug screen, they are recorded even while both are hidden.
+    pub fn frame_times(&self) -> impl ExactSizeIterator<Item = f32> + '_ {
+        self.samples.iter().map(|sample| sample.frame_time)
+    }
+
+    /// Returns the average frames per second over the history.
+    pub fn fps(&self) -> f32 {
+        let total: f32 = self.frame_times().sum();
+        if total > 0.0 {
+            self.samples.len() as f32 / total
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the longest frame time in the history, in seconds.
+    pub fn max_frame_time(&self) -> f32 {
+        self.frame_times().fold(0.0, f32::max)
+    }
+
+    fn to_secs(times: &[(&'static str, Duration)]) -> Vec<(&'static str, f32)> {
+        times
+            .iter()
+            .map(|(name, time)| (*name, time.as_secs_f32()))
+            .collect()
+    }
+
+    /// Passes are colored by their position in the most recent frame.
+    fn pass_color(&self, name: &str) -> Vec4 {
+        self.pass_times
+            .iter()
+            .position(|(other, _)| *other == name)
+            .map_or(Vec4::new(0.4, 0.4, 0.4, 0.9), |index| {
+                Self::PASS_COLORS[index % Self::PASS_COLORS.len()]
+            })
+    }
+
+    /// Returns the average of `value` over the history in milliseconds.
+    fn average_ms(&self, value: impl Fn(&Sample) -> f32) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().map(value).sum::<f32>() / self.samples.len() as f32 * 1000.0
+    }
+
+    /// Draws the graphs in the top right corner.
+    pub fn draw(
+        &self,
+        overlay: &mut OverlayRenderer,
+        text: &TextRenderer,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        const MARGIN: f32 = 16.0;
+        const PADDING: f32 = 8.0;
+        const BAR_WIDTH: f32 = 2.0;
+        const GRAPH_HEIGHT: f32 = 80.0;
+        const BACKGROUND_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+        const TEXT_COLOR: Vec4 = Vec4::ONE;
+        const FRAME_COLOR: Vec4 = Vec4::new(0.8, 0.8, 0.8, 0.9);
+        const MESHGEN_COLOR: Vec4 = Vec4::new(1.0, 0.5, 0.1, 0.9);
+        const TARGET_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.4);
+
+        if !self.visible {
+            return;
+        }
+        let line_height = text.line_height();
+        let width = Self::HISTORY as f32 * BAR_WIDTH;
+        let source = if self.gpu_timer.is_some() {
+            "GPU"
+        } else {
+            "CPU recording"
+        };
+        let header = [
+            (
+                format!(
+                    "Frame: {:.1} ms",
+                    self.average_ms(|sample| sample.frame_time)
+                ),
+                FRAME_COLOR,
+            ),
+            (
+                format!(
+                    "Meshgen: {:.1} ms per frame",
+                    self.average_ms(|sample| sample.meshgen_time)
+                ),
+                MESHGEN_COLOR,
+            ),
+        ];
+        let legend: Vec<(String, Vec4)> = self
+            .pass_times
+            .iter()
+            .map(|(name, _)| {
+                let average = self.average_ms(|sample| {
+                    sample
+                        .pass_times
+                        .iter()
+                        .filter(|(other, _)| other == name)
+                        .map(|(_, time)| time)
+                        .sum()
+                });
+                (
+                    format!("{}: {:.2} ms", name, average),
+                    self.pass_color(name),
+                )
+            })
+            .collect();
+
+        let height = (header.len() + 1 + legend.len()) as f32 * line_height
+            + 2.0 * GRAPH_HEIGHT
+            + 6.0 * PADDING;
+        let min = Vec2::new(
+            screen_size.width as f32 - MARGIN - width - 2.0 * PADDING,
+            MARGIN,
+        );
+        overlay.rect(
+            min,
+            min + Vec2::new(width + 2.0 * PADDING, height),
+            BACKGROUND_COLOR,
+        );
+
+        let left = min.x + PADDING;
+        let right = left + width;
+        let count = self.samples.len();
+        let bar_x = |index: usize| right - (count - index) as f32 * BAR_WIDTH;
+        let mut y = min.y + PADDING;
+
+        for (line, color) in &header {
+            text.draw(overlay, line, Vec2::new(left, y), *color);
+            y += line_height;
+        }
+
+        // Frame times with the meshgen times in front, growing upwards
+        y += PADDING + GRAPH_HEIGHT;
+        for (index, sample) in self.samples.iter().enumerate() {
+            let x = bar_x(index);
+            for (time, color) in [
+                (sample.frame_time, FRAME_COLOR),
+                (sample.meshgen_time, MESHGEN_COLOR),
+            ] {
+                let bar_height = (time / Self::FRAME_GRAPH_MAX).min(1.0) * GRAPH_HEIGHT;
+                overlay.rect(
+                    Vec2::new(x, y - bar_height),
+                    Vec2::new(x + BAR_WIDTH, y),
+                    color,
+                );
+            }
+        }
+        let target_y = y - Self::TARGET / Self::FRAME_GRAPH_MAX * GRAPH_HEIGHT;
+        overlay.rect(
+            Vec2::new(left, target_y),
+            Vec2::new(right, target_y + 1.0),
+            TARGET_COLOR,
+        );
+
+        y += PADDING;
+        text.draw(
+            overlay,
+            &format!("Render passes ({}):", source),
+            Vec2::new(left, y),
+            TEXT_COLOR,
+        );
+        y += line_height;
+
+        // Pass times stacked in execution order
+        y += PADDING + GRAPH_HEIGHT;
+        for (index, sample) in self.samples.iter().enumerate() {
+            let x = bar_x(index);
+            let mut bottom = y;
+            for (name, time) in &sample.pass_times {
+                let bar_height = time / Self::PASS_GRAPH_MAX * GRAPH_HEIGHT;
+                let top = (bottom - bar_height).max(y - GRAPH_HEIGHT);
+                overlay.rect(
+                    Vec2::new(x, top),
+                    Vec2::new(x + BAR_WIDTH, bottom),
+                    self.pass_color(name),
+                );
+                bottom = top;
+            }
+        }
+
+        y += PADDING;
+        for (line, color) in &legend {
+            text.draw(overlay, line, Vec2::new(left, y), *color);
+            y += line_height;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! A minimal render graph. Passes declare the attachments they read and
+//! write, the graph orders them accordingly, wraps each one in a debug group,
+//! skips disabled ones and measures how long recording each one takes. With
+//! a GpuTimer, the time each pass takes on the GPU is measured as well.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::profiler::GpuTimer;
+
+/// Textures shared between passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attachment {
+    /// The offscreen color texture the world is rendered into, see PostProcess
+    SceneColor,
+    SceneDepth,
+    /// The surface texture that is presented to the window
+    Surface,
+}
+
+/// The texture views backing the attachments for the current frame.
+pub struct Attachments<'a> {
+    pub scene_color: &'a wgpu::TextureView,
+    pub scene_depth: &'a wgpu::TextureView,
+    pub surface: &'a wgpu::TextureView,
+}
+
+impl Attachments<'_> {
+    pub fn view(&self, attachment: Attachment) -> &wgpu::TextureView {
+        match attachment {
+            Attachment::SceneColor => self.scene_color,
+            Attachment::SceneDepth => self.scene_depth,
+            Attachment::Surface => self.surface,
+        }
+    }
+}
+
+type RecordFn<'a> = Box<dyn FnOnce(&mut wgpu::CommandEncoder, &Attachments) + 'a>;
+
+struct GraphPass<'a> {
+    name: &'static str,
+    reads: &'static [Attachment],
+    writes: &'static [Attachment],
+    record: RecordFn<'a>,
+}
+
+/// The passes of a single frame. Built every frame, so passes can borrow
+/// whatever they need for recording.
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    passes: Vec<GraphPass<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pass. Passes writing the same attachment run in the order they
+    /// were added, passes only reading an attachment run after all passes
+    /// writing it.
+    pub fn add_pass(
+        &mut self,
+        name: &'static str,
+        reads: &'static [Attachment],
+        writes: &'static [Attachment],
+        record: impl FnOnce(&mut wgpu::CommandEncoder, &Attachments) + 'a,
+    ) {
+        self.passes.push(GraphPass {
+            name,
+            reads,
+            writes,
+            record: Box::new(record),
+        });
+    }
+
+    /// Whether the pass at `index` has to run after the pass at `other`.
+    fn depends_on(&self, index: usize, other: usize) -> bool {
+        let pass = &self.passes[index];
+        self.passes[other].writes.iter().any(|attachment| {
+            if pass.writes.contains(attachment) {
+                other < index
+            } else {
+                pass.reads.contains(attachment)
+            }
+        })
+    }
+
+    /// Returns the pass indices in execution order. Among passes without
+    /// dependencies on each other, the order they were added is kept.
+    fn order(&self) -> Vec<usize> {
+        let count = self.passes.len();
+        let mut done = vec![false; count];
+        let mut order = Vec::with_capacity(count);
+        while order.len() < count {
+            let next = (0..count)
+                .find(|&index| {
+                    !done[index]
+                        && (0..count).all(|other| {
+                            other == index || done[other] || !self.depends_on(index, other)
+                        })
+                })
+                .expect("Render graph has a dependency cycle");
+            done[next] = true;
+            order.push(next);
+        }
+        order
+    }
+
+    /// Records all passes that aren't disabled into `encoder`, with
+    /// timestamps around them if `gpu_timer` is given.
+    /// Returns the time spent recording each pass, in execution order.
+    pub fn execute(
+        self,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        disabled: &HashSet<String>,
+        mut gpu_timer: Option<&mut GpuTimer>,
+    ) -> Vec<(&'static str, Duration)> {
+        let order = self.order();
+        let mut passes: Vec<Option<GraphPass>> = self.passes.into_iter().map(Some).collect();
+
+        let mut timings = Vec::new();
+        for index in order {
+            let pass = passes[index].take().unwrap();
+            if disabled.contains(pass.name) {
+                continue;
+            }
+
+            let begin = Instant::now();
+            encoder.push_debug_group(pass.name);
+            let record = |encoder: &mut wgpu::CommandEncoder| (pass.record)(encoder, attachments);
+            match gpu_timer.as_deref_mut() {
+                Some(timer) => timer.time_pass(encoder, pass.name, record),
+                None => record(encoder),
+            }
+            encoder.pop_debug_group();
+            timings.push((pass.name, begin.elapsed()));
+        }
+        if let Some(timer) = gpu_timer {
+            timer.resolve(encoder);
+        }
+        timings
+    }
+}
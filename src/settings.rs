@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, anyhow};
+
+use crate::media::TextureFilter;
+use crate::text;
+
+/// User settings. Read from `cubetonic.conf` in the config directory, which
+/// uses the same `key = value` format as Luanti's `minetest.conf`.
+/// Missing keys keep their default value.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Vertical field of view in degrees
+    pub fov: f32,
+    /// Scale factor for the resolution the world is rendered at, relative
+    /// to the window size. Below 1 for weak GPUs, above 1 for supersampling.
+    pub render_scale: f32,
+    /// How frames are presented, i.e. vsync behavior.
+    /// Falls back to AutoVsync if not supported by the surface.
+    pub present_mode: wgpu::PresentMode,
+    /// Prefer a 10-bit surface format, for displays with HDR output enabled
+    pub hdr_output: bool,
+    /// Maximum frame rate, 0 for unlimited
+    pub fps_max: u32,
+    /// Maximum frame rate while the window is unfocused or occluded
+    pub fps_max_unfocused: u32,
+    /// Whether the camera bobs up and down while walking
+    pub view_bobbing: bool,
+    /// Multiplier for the view bobbing amplitude
+    pub view_bobbing_amount: f32,
+    /// Apply mouse movement right before rendering a frame, instead of as
+    /// soon as it arrives. Reduces look latency.
+    pub mouse_at_render: bool,
+    /// Camera rotation per mouse count, in degrees. Can be changed at
+    /// runtime with [ and ].
+    pub mouse_sensitivity: f32,
+    /// Moving the mouse up looks down
+    pub invert_mouse: bool,
+    /// How much the camera rotation lags behind the mouse, from 0 (off) to
+    /// 0.99, for smoother recordings
+    pub camera_smoothing: f32,
+    /// Hotbar slots to move per scroll wheel notch
+    pub hotbar_scroll_sensitivity: f32,
+    /// Invert the hotbar scroll direction
+    pub hotbar_scroll_invert: bool,
+    /// Whether to read input from gamepads
+    pub gamepad: bool,
+    /// Fraction of the stick range around the center that is ignored,
+    /// for sticks that don't return to the exact center
+    pub gamepad_deadzone: f32,
+    /// How fast the right stick turns the camera at full deflection, in
+    /// degrees per second
+    pub gamepad_look_sensitivity: f32,
+    /// Display gamma, applied to the whole screen. Values above 1 make dark
+    /// scenes brighter.
+    pub display_gamma: f32,
+    /// Seed all randomness, use a fixed timestep and disable adaptive
+    /// behavior, so runs against a packet replay produce identical frames
+    pub deterministic: bool,
+    /// Comma-separated names of render passes to skip, e.g. "overlay" for
+    /// clean GPU captures. See State::render for the pass names.
+    pub disabled_render_passes: HashSet<String>,
+    /// Comma-separated names of scripts in `scriptsrc`, without ".lua",
+    /// that may use `cubetonic.request_insecure_environment` to get the
+    /// parts of the Lua standard library that are removed by the sandbox.
+    // Compare to Luanti, the secure.trusted_mods setting
+    pub trusted_scripts: HashSet<String>,
+    /// Run scripts the server sends on the "cubetonic:scripts" mod channel.
+    /// They get their own Lua state with a smaller API than the scripts in
+    /// `scriptsrc`: no storage, waypoints, chat sending or wielded items.
+    // Compare to Luanti, the enable_client_modding setting
+    pub server_scripts: bool,
+    /// Merge adjacent faces with the same texture into larger quads when
+    /// generating mapblock meshes. Greatly reduces vertex counts.
+    pub greedy_meshing: bool,
+    /// Keep a copy of the mapblock meshes in memory after uploading them, so
+    /// the loaded world can be exported to glTF with F12. Takes about as
+    /// much memory as the meshes on the GPU.
+    pub retain_meshes: bool,
+    /// Distance up to which a coarse heightfield of the terrain is drawn
+    /// beyond the view distance, in nodes. Also moves the fog that far
+    /// away. 0 to disable.
+    pub farmesh_range: u32,
+    /// Whether plants, leaves and liquids with `waving` set in their node
+    /// definition move in the wind
+    pub waving_nodes: bool,
+    /// Directory of a texture pack. Its files, including those in
+    /// subdirectories, replace the media files with the same name sent by
+    /// servers.
+    pub texture_pack: Option<PathBuf>,
+    /// How node textures are filtered when they are drawn smaller than
+    /// their size: "nearest", "bilinear" or "trilinear". Can be changed at
+    /// runtime with F9.
+    pub texture_filter: TextureFilter,
+    /// Maximum anisotropy for node textures seen at steep angles: 1 (off),
+    /// 2, 4, 8 or 16. Only used with trilinear filtering, and makes
+    /// magnification linear too, which wgpu requires for anisotropy.
+    pub anisotropic_filter: u16,
+    /// Node textures smaller than this are upscaled by an integer factor
+    /// before generating mip levels, so their pixels stay sharp when
+    /// filtered. 0 to disable.
+    pub texture_min_size: u32,
+    /// Size in pixels of the generated textures shown for missing media
+    /// and unknown nodes
+    pub fallback_texture_size: u32,
+    /// Color of the checkerboard shown for missing media, e.g. "#808080" or
+    /// a color name, alpha is ignored. Unknown nodes are always magenta and
+    /// black.
+    pub fallback_texture_color: [u8; 3],
+    /// Show the main menu at startup. If disabled, the client connects to
+    /// `address` right away.
+    pub main_menu: bool,
+    /// Server to connect to, an IP address or hostname. The default in the
+    /// main menu.
+    pub address: String,
+    pub port: u16,
+    /// Player name to log in with. A random name is picked if empty.
+    pub name: String,
+    /// Password to log in with. New accounts are registered with it.
+    pub password: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            fov: 72.0,
+            render_scale: 1.0,
+            present_mode: wgpu::PresentMode::AutoVsync,
+            hdr_output: false,
+            fps_max: 0,
+            fps_max_unfocused: 20,
+            view_bobbing: true,
+            view_bobbing_amount: 1.0,
+            mouse_at_render: true,
+            mouse_sensitivity: 0.1,
+            invert_mouse: false,
+            camera_smoothing: 0.0,
+            hotbar_scroll_sensitivity: 1.0,
+            hotbar_scroll_invert: false,
+            gamepad: true,
+            gamepad_deadzone: 0.15,
+            gamepad_look_sensitivity: 180.0,
+            display_gamma: 1.0,
+            deterministic: false,
+            disabled_render_passes: HashSet::new(),
+            trusted_scripts: HashSet::new(),
+            server_scripts: false,
+            greedy_meshing: true,
+            retain_meshes: false,
+            farmesh_range: 0,
+            waving_nodes: true,
+            texture_pack: None,
+            texture_filter: TextureFilter::Bilinear,
+            anisotropic_filter: 1,
+            // Like in Luanti
+            texture_min_size: 64,
+            fallback_texture_size: 16,
+            fallback_texture_color: [0x80, 0x80, 0x80],
+            main_menu: true,
+            address: String::from("127.0.0.1"),
+            port: 3000,
+            name: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl Settings {
+    const FILE_NAME: &str = "cubetonic.conf";
+
+    /// Returns the directory for configuration and other persistent data,
+    /// creating it if it doesn't exist.
+    pub fn config_dir() -> anyhow::Result<PathBuf> {
+        let mut dir = std::env::home_dir().ok_or_else(|| anyhow!("No home directory"))?;
+        dir.push(".cubetonic");
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Loads the settings from the config directory.
+    /// Returns the default settings if the file doesn't exist.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = Self::config_dir()?.join(Self::FILE_NAME);
+        let mut settings = Self::default();
+
+        if !path.try_exists()? {
+            return Ok(settings);
+        }
+
+        let text = fs::read_to_string(&path)?;
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected \"key = value\""))
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .with_context(|| format!("{:?}, line {}", path, line_index + 1))?;
+            settings
+                .set(key, value)
+                .with_context(|| format!("{:?}, line {}", path, line_index + 1))?;
+        }
+
+        Ok(settings)
+    }
+
+    /// Applies settings given as command-line arguments, in the form
+    /// `--some-key value`, which is equivalent to `some_key = value` in the
+    /// settings file. A flag without value, e.g. `--some-key`, means
+    /// `some_key = true`.
+    pub fn apply_args(&mut self, args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            let key = arg
+                .strip_prefix("--")
+                .ok_or_else(|| anyhow!("Unexpected argument \"{}\"", arg))?
+                .replace('-', "_");
+            let value = args
+                .next_if(|value| !value.starts_with("--"))
+                .unwrap_or_else(|| String::from("true"));
+            self.set(&key, &value)
+                .with_context(|| format!("Argument \"{}\"", arg))?;
+        }
+        Ok(())
+    }
+
+    /// Sets a setting from its string representation.
+    /// Unknown keys are ignored with a warning.
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "fov" => self.fov = value.parse()?,
+            "render_scale" => {
+                let scale: f32 = value.parse()?;
+                if !(0.1..=4.0).contains(&scale) {
+                    return Err(anyhow!("render_scale must be between 0.1 and 4"));
+                }
+                self.render_scale = scale;
+            }
+            "present_mode" => {
+                self.present_mode = match value {
+                    "auto_vsync" => wgpu::PresentMode::AutoVsync,
+                    "auto_no_vsync" => wgpu::PresentMode::AutoNoVsync,
+                    "fifo" => wgpu::PresentMode::Fifo,
+                    "fifo_relaxed" => wgpu::PresentMode::FifoRelaxed,
+                    "mailbox" => wgpu::PresentMode::Mailbox,
+                    "immediate" => wgpu::PresentMode::Immediate,
+                    _ => return Err(anyhow!("Invalid present mode \"{}\"", value)),
+                }
+            }
+            "hdr_output" => self.hdr_output = parse_bool(value)?,
+            "fps_max" => self.fps_max = value.parse()?,
+            "fps_max_unfocused" => self.fps_max_unfocused = value.parse()?,
+            "view_bobbing" => self.view_bobbing = parse_bool(value)?,
+            "view_bobbing_amount" => self.view_bobbing_amount = value.parse()?,
+            "mouse_at_render" => self.mouse_at_render = parse_bool(value)?,
+            "mouse_sensitivity" => self.mouse_sensitivity = value.parse()?,
+            "invert_mouse" => self.invert_mouse = parse_bool(value)?,
+            "camera_smoothing" => {
+                let smoothing: f32 = value.parse()?;
+                if !(0.0..=0.99).contains(&smoothing) {
+                    return Err(anyhow!("camera_smoothing must be between 0 and 0.99"));
+                }
+                self.camera_smoothing = smoothing;
+            }
+            "hotbar_scroll_sensitivity" => self.hotbar_scroll_sensitivity = value.parse()?,
+            "hotbar_scroll_invert" => self.hotbar_scroll_invert = parse_bool(value)?,
+            "gamepad" => self.gamepad = parse_bool(value)?,
+            "gamepad_deadzone" => {
+                let deadzone: f32 = value.parse()?;
+                if !(0.0..=0.9).contains(&deadzone) {
+                    return Err(anyhow!("gamepad_deadzone must be between 0 and 0.9"));
+                }
+                self.gamepad_deadzone = deadzone;
+            }
+            "gamepad_look_sensitivity" => self.gamepad_look_sensitivity = value.parse()?,
+            "display_gamma" => {
+                let gamma: f32 = value.parse()?;
+                if !(0.33..=3.0).contains(&gamma) {
+                    return Err(anyhow!("display_gamma must be between 0.33 and 3"));
+                }
+                self.display_gamma = gamma;
+            }
+            "deterministic" => self.deterministic = parse_bool(value)?,
+            "disabled_render_passes" => {
+                self.disabled_render_passes = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "trusted_scripts" => {
+                self.trusted_scripts = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "server_scripts" => self.server_scripts = parse_bool(value)?,
+            "greedy_meshing" => self.greedy_meshing = parse_bool(value)?,
+            "retain_meshes" => self.retain_meshes = parse_bool(value)?,
+            "farmesh_range" => {
+                let range: u32 = value.parse()?;
+                if range > 4000 {
+                    return Err(anyhow!("farmesh_range must be at most 4000"));
+                }
+                self.farmesh_range = range;
+            }
+            "waving_nodes" => self.waving_nodes = parse_bool(value)?,
+            // Compare to Luanti, the texture_path setting
+            "texture_pack" => {
+                self.texture_pack = (!value.is_empty()).then(|| PathBuf::from(value));
+            }
+            // Compare to Luanti, the bilinear_filter and trilinear_filter
+            // settings
+            "texture_filter" => {
+                self.texture_filter = match value {
+                    "nearest" => TextureFilter::Nearest,
+                    "bilinear" => TextureFilter::Bilinear,
+                    "trilinear" => TextureFilter::Trilinear,
+                    _ => return Err(anyhow!("Invalid texture filter \"{}\"", value)),
+                }
+            }
+            "anisotropic_filter" => {
+                let anisotropy: u16 = value.parse()?;
+                if ![1, 2, 4, 8, 16].contains(&anisotropy) {
+                    return Err(anyhow!("anisotropic_filter must be 1, 2, 4, 8 or 16"));
+                }
+                self.anisotropic_filter = anisotropy;
+            }
+            "texture_min_size" => {
+                let size: u32 = value.parse()?;
+                if size > 1024 {
+                    return Err(anyhow!("texture_min_size must be at most 1024"));
+                }
+                self.texture_min_size = size;
+            }
+            "fallback_texture_size" => {
+                let size: u32 = value.parse()?;
+                if !(2..=256).contains(&size) {
+                    return Err(anyhow!("fallback_texture_size must be between 2 and 256"));
+                }
+                self.fallback_texture_size = size;
+            }
+            "fallback_texture_color" => {
+                let [r, g, b, _] = text::parse_color_srgb(value)
+                    .ok_or_else(|| anyhow!("Invalid color \"{}\"", value))?;
+                self.fallback_texture_color = [r, g, b];
+            }
+            "main_menu" => self.main_menu = parse_bool(value)?,
+            "address" => self.address = String::from(value),
+            "port" => self.port = value.parse()?,
+            "name" => self.name = String::from(value),
+            "password" => self.password = String::from(value),
+            _ => println!("Ignoring unknown setting \"{}\"", key),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(anyhow!("Invalid boolean value \"{}\"", value)),
+    }
+}
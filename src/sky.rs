@@ -0,0 +1,719 @@
+//! The sky pass: draws the sun, moon and stars behind the world. Servers can
+//! change them with TOCLIENT_SET_SUN, TOCLIENT_SET_MOON and
+//! TOCLIENT_SET_STARS, their position follows the time of day.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use glam::{Vec2, Vec3, Vec4};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use wgpu::util::DeviceExt;
+
+use crate::camera::CameraParams;
+use crate::media::MediaManager;
+use crate::post_process::PostProcess;
+use crate::srgb_to_linear;
+use crate::texture::MyTexture;
+
+// Compare to Luanti, skyparams.h, SunParams
+#[derive(Debug, Clone)]
+pub struct SunParams {
+    pub visible: bool,
+    pub texture: String,
+    /// Colors the sun depending on the time of day
+    pub tonemap: String,
+    /// Drawn at the horizon around sunrise and sunset
+    pub sunrise: String,
+    pub sunrise_visible: bool,
+    pub scale: f32,
+}
+
+impl Default for SunParams {
+    // Compare to Luanti, skyparams.h, SkyboxDefaults::getSunDefaults
+    fn default() -> Self {
+        Self {
+            visible: true,
+            texture: String::from("sun.png"),
+            tonemap: String::from("sun_tonemap.png"),
+            sunrise: String::from("sunrisebg.png"),
+            sunrise_visible: true,
+            scale: 1.0,
+        }
+    }
+}
+
+// Compare to Luanti, skyparams.h, MoonParams
+#[derive(Debug, Clone)]
+pub struct MoonParams {
+    pub visible: bool,
+    pub texture: String,
+    /// Colors the moon depending on the time of day
+    pub tonemap: String,
+    pub scale: f32,
+}
+
+impl Default for MoonParams {
+    // Compare to Luanti, skyparams.h, SkyboxDefaults::getMoonDefaults
+    fn default() -> Self {
+        Self {
+            visible: true,
+            texture: String::from("moon.png"),
+            tonemap: String::from("moon_tonemap.png"),
+            scale: 1.0,
+        }
+    }
+}
+
+// Compare to Luanti, skyparams.h, StarParams
+#[derive(Debug, Clone)]
+pub struct StarParams {
+    pub visible: bool,
+    pub count: u32,
+    /// Linear color, the alpha is the brightness at night
+    pub color: Vec4,
+    pub scale: f32,
+    /// Minimum brightness during the day
+    pub day_opacity: f32,
+}
+
+impl Default for StarParams {
+    // Compare to Luanti, skyparams.h, SkyboxDefaults::getStarDefaults
+    fn default() -> Self {
+        Self {
+            visible: true,
+            count: 1000,
+            color: Vec4::new(
+                srgb_to_linear(235),
+                srgb_to_linear(235),
+                srgb_to_linear(255),
+                105.0 / 255.0,
+            ),
+            scale: 1.0,
+            day_opacity: 0.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyVertex {
+    /// In nodes, relative to the world origin like all geometry. The shader
+    /// moves it to the far plane.
+    position: Vec3,
+    uv: Vec2,
+    /// Multiplied with the texture color
+    color: Vec4,
+}
+
+impl SkyVertex {
+    fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBS: [wgpu::VertexAttribute; 3] =
+            wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x4];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<SkyVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+struct SkyTexture {
+    // Kept alive for the bind group
+    _texture: MyTexture,
+    bind_group: wgpu::BindGroup,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Body {
+    Sun,
+    Moon,
+}
+
+/// A range of indices drawn with the same texture.
+struct Batch {
+    texture: String,
+    indices: Range<u32>,
+}
+
+pub struct SkyRenderer {
+    pipeline: wgpu::RenderPipeline,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// By name, None if the texture couldn't be loaded. The white texture
+    /// is stored under an empty name.
+    textures: HashMap<String, Option<SkyTexture>>,
+    /// Tonemaps are sampled on the CPU. None if the image couldn't be loaded.
+    tonemaps: HashMap<String, Option<image::RgbaImage>>,
+
+    sun: SunParams,
+    moon: MoonParams,
+    stars: StarParams,
+    /// Unit vectors, before the rotation by the time of day
+    star_dirs: Vec<Vec3>,
+
+    /// 0.0 and 1.0 are midnight, 0.5 is noon
+    time_of_day: f32,
+    /// Ratio of game time to real time
+    time_speed: f32,
+
+    /// The camera position of the current frame, the sky moves with it
+    origin: Vec3,
+    vertices: Vec<SkyVertex>,
+    indices: Vec<u32>,
+    batches: Vec<Batch>,
+}
+
+impl SkyRenderer {
+    /// Distance of the sky geometry from the camera, in nodes. Doesn't
+    /// matter as long as it's beyond z_near, it's drawn at the far plane.
+    const DISTANCE: f32 = 10.0;
+    // Half sizes at a distance of 1, compare to Luanti, client/sky.cpp
+    const SUN_SIZE: f32 = 0.07 * 1.7;
+    const MOON_SIZE: f32 = 0.04 * 1.9;
+    const STAR_SIZE: f32 = 0.003;
+    /// Luanti limits the star count to this
+    const MAX_STARS: u32 = 0x10000;
+    /// Luanti's default time_speed setting
+    pub const DEFAULT_TIME_SPEED: f32 = 72.0;
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Sky texture bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Sky sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sky pipeline layout"),
+            bind_group_layouts: &[camera_bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("sky_shader.wgsl"));
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sky render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[SkyVertex::layout()],
+            },
+            // Sky bodies are seen from both sides while they orbit
+            primitive: wgpu::PrimitiveState::default(),
+            // Only drawn where the world left the depth buffer empty
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: MyTexture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: PostProcess::COLOR_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+            cache: None,
+        });
+
+        let mut renderer = Self {
+            pipeline,
+            texture_bind_group_layout,
+            sampler,
+            textures: HashMap::new(),
+            tonemaps: HashMap::new(),
+
+            sun: SunParams::default(),
+            moon: MoonParams::default(),
+            stars: StarParams::default(),
+            star_dirs: Vec::new(),
+
+            time_of_day: 0.5,
+            time_speed: Self::DEFAULT_TIME_SPEED,
+
+            origin: Vec3::ZERO,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            batches: Vec::new(),
+        };
+        renderer.add_white_texture(device, queue);
+        renderer.generate_stars();
+        renderer
+    }
+
+    /// Goes back to the default sky and forgets the loaded textures, for
+    /// connecting to another server.
+    pub fn reset(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.textures.clear();
+        self.tonemaps.clear();
+        self.add_white_texture(device, queue);
+        self.sun = SunParams::default();
+        self.moon = MoonParams::default();
+        self.set_stars(StarParams::default());
+        self.time_of_day = 0.5;
+        self.time_speed = Self::DEFAULT_TIME_SPEED;
+    }
+
+    fn add_white_texture(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        let texture = self.create_texture(device, queue, "Sky white texture", &white.into());
+        self.textures.insert(String::new(), Some(texture));
+    }
+
+    fn create_texture(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: &str,
+        img: &image::DynamicImage,
+    ) -> SkyTexture {
+        // Only fails for invalid images, which DynamicImage can't be
+        let texture = MyTexture::from_image(device, queue, name, img).unwrap();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(name),
+            layout: &self.texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+        SkyTexture {
+            _texture: texture,
+            bind_group,
+        }
+    }
+
+    /// Uploads the texture if it hasn't been uploaded yet.
+    /// Returns false if it couldn't be loaded.
+    fn load_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        name: &str,
+    ) -> bool {
+        if !self.textures.contains_key(name) {
+            // TODO: texture modifiers, only the base image is used for now
+            let base_name = name.split('^').next().unwrap_or_default();
+            let texture = match media.load_image(base_name) {
+                Ok(Some(img)) => Some(self.create_texture(device, queue, name, &img)),
+                Ok(None) => {
+                    println!("Sky texture \"{}\" not found", name);
+                    None
+                }
+                Err(err) => {
+                    println!("Error while loading sky texture \"{}\": {:?}", name, err);
+                    None
+                }
+            };
+            self.textures.insert(String::from(name), texture);
+        }
+        self.textures[name].is_some()
+    }
+
+    /// Returns the tonemap color for the current time of day, white if there
+    /// is no tonemap.
+    // Compare to Luanti, client/sky.cpp, Sky::draw_sun
+    fn tonemap_color(&mut self, media: &MediaManager, name: &str) -> Vec4 {
+        if name.is_empty() {
+            return Vec4::ONE;
+        }
+        let tonemap = self.tonemaps.entry(String::from(name)).or_insert_with(|| {
+            match media.load_image(name) {
+                Ok(img) => img.map(|img| img.to_rgba8()),
+                Err(err) => {
+                    println!("Error while loading sky tonemap \"{}\": {:?}", name, err);
+                    None
+                }
+            }
+        });
+        let Some(tonemap) = tonemap else {
+            return Vec4::ONE;
+        };
+
+        // The left edge is used at noon, the right edge at midnight
+        let offset = 1.0
+            - ((self.time_of_day - 0.5) * std::f32::consts::PI)
+                .sin()
+                .abs();
+        let x = ((offset * tonemap.width() as f32) as u32).min(tonemap.width() - 1);
+        let [r, g, b, _] = tonemap.get_pixel(x, 0).0;
+        Vec4::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), 1.0)
+    }
+
+    pub fn set_sun(&mut self, sun: SunParams) {
+        self.sun = sun;
+    }
+
+    pub fn set_moon(&mut self, moon: MoonParams) {
+        self.moon = moon;
+    }
+
+    pub fn set_stars(&mut self, stars: StarParams) {
+        let count_changed = stars.count != self.stars.count;
+        self.stars = stars;
+        if count_changed {
+            self.generate_stars();
+        }
+    }
+
+    /// Scatters the stars evenly over the sky. The same seed is used every
+    /// time, so the stars don't jump around when the count changes.
+    // Compare to Luanti, client/sky.cpp, Sky::updateStars
+    fn generate_stars(&mut self) {
+        let mut rng = StdRng::seed_from_u64(100);
+        let count = self.stars.count.min(Self::MAX_STARS);
+        self.star_dirs = (0..count)
+            .map(|_| {
+                loop {
+                    let dir = Vec3::new(
+                        rng.random_range(-1.0..1.0),
+                        rng.random_range(-1.0..1.0),
+                        rng.random_range(-1.0..1.0),
+                    );
+                    // Rejection sampling, so the corners of the cube aren't
+                    // more crowded
+                    let length = dir.length();
+                    if length > 0.01 && length <= 1.0 {
+                        break dir / length;
+                    }
+                }
+            })
+            .collect();
+    }
+
+    /// `time_of_day` is in the range 0.0 to 1.0, 0.5 is noon.
+    pub fn set_time_of_day(&mut self, time_of_day: f32, time_speed: f32) {
+        self.time_of_day = time_of_day;
+        self.time_speed = time_speed;
+    }
+
+    /// The brightness of sunlight at the current time of day, 0.0 to 1.0.
+    // Compare to Luanti, daynightratio.h, time_to_daynight_ratio
+    pub fn day_night_ratio(&self) -> f32 {
+        const VALUES: [(f32, f32); 9] = [
+            (4375.0, 175.0),
+            (4625.0, 175.0),
+            (4875.0, 250.0),
+            (5125.0, 350.0),
+            (5375.0, 500.0),
+            (5625.0, 675.0),
+            (5875.0, 875.0),
+            (6125.0, 1000.0),
+            (6375.0, 1000.0),
+        ];
+
+        // Symmetric around noon
+        let mut t = self.time_of_day * 24000.0;
+        if t > 12000.0 {
+            t = 24000.0 - t;
+        }
+
+        let ratio = if t <= VALUES[1].0 {
+            VALUES[1].1
+        } else if t >= VALUES[7].0 {
+            1000.0
+        } else {
+            let i = VALUES.iter().position(|&(time, _)| time > t).unwrap();
+            let (t0, v0) = VALUES[i - 1];
+            let (t1, v1) = VALUES[i];
+            let f = (t - t0) / (t1 - t0);
+            f * v1 + (1.0 - f) * v0
+        };
+        ratio / 1000.0
+    }
+
+    /// Advances the time of day between updates from the server.
+    // Compare to Luanti, environment.cpp, Environment::stepTimeOfDay
+    pub fn step(&mut self, dtime: f32) {
+        self.time_of_day = (self.time_of_day + dtime * self.time_speed / (24.0 * 3600.0)).fract();
+    }
+
+    /// Moves a point from the south (-Z) to its place on the orbit at the
+    /// given time of day. `horizon` is 90 degrees for the sun and -90
+    /// degrees for the moon, which rise in the east and west respectively.
+    // Compare to Luanti, client/sky.cpp, Sky::place_sky_body
+    fn place(&self, pos: Vec3, horizon: f32) -> Vec3 {
+        let pos = rotate_xz(pos, horizon);
+        rotate_xy(pos, self.time_of_day * 360.0 - 90.0)
+    }
+
+    fn add_quad(&mut self, texture: &str, corners: [Vec3; 4], color: Vec4) {
+        let index_offset = self.vertices.len() as u32;
+        let uvs = [
+            Vec2::new(0.0, 1.0),
+            Vec2::ONE,
+            Vec2::new(1.0, 0.0),
+            Vec2::ZERO,
+        ];
+        for (corner, uv) in corners.into_iter().zip(uvs) {
+            self.vertices.push(SkyVertex {
+                position: self.origin + corner * Self::DISTANCE,
+                uv,
+                color,
+            });
+        }
+
+        let first_index = self.indices.len() as u32;
+        self.indices
+            .extend([0, 1, 2, 2, 3, 0].map(|index| index_offset + index));
+        let last_index = self.indices.len() as u32;
+
+        // Consecutive quads with the same texture are drawn together
+        match self.batches.last_mut() {
+            Some(batch) if batch.texture == texture => batch.indices.end = last_index,
+            _ => self.batches.push(Batch {
+                texture: String::from(texture),
+                indices: first_index..last_index,
+            }),
+        }
+    }
+
+    /// Adds the sun or moon, a square facing the center of the orbit.
+    /// Untextured bodies are drawn in a plain color.
+    fn add_body(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        body: Body,
+    ) {
+        let (texture, tonemap, size, horizon, fallback_color) = match body {
+            Body::Sun => (
+                self.sun.texture.clone(),
+                self.sun.tonemap.clone(),
+                Self::SUN_SIZE * self.sun.scale,
+                90.0,
+                Vec4::new(1.0, 1.0, 0.7, 1.0),
+            ),
+            Body::Moon => (
+                self.moon.texture.clone(),
+                self.moon.tonemap.clone(),
+                Self::MOON_SIZE * self.moon.scale,
+                -90.0,
+                Vec4::new(0.85, 0.875, 1.0, 1.0),
+            ),
+        };
+
+        let color = self.tonemap_color(media, &tonemap);
+        let corners = [
+            Vec3::new(-size, -size, -1.0),
+            Vec3::new(size, -size, -1.0),
+            Vec3::new(size, size, -1.0),
+            Vec3::new(-size, size, -1.0),
+        ]
+        .map(|corner| self.place(corner, horizon));
+        if self.load_texture(device, queue, media, &texture) {
+            self.add_quad(&texture, corners, color);
+        } else {
+            self.add_quad("", corners, color * fallback_color);
+        }
+    }
+
+    /// The glow at the horizon around sunrise and sunset.
+    // Compare to Luanti, client/sky.cpp, Sky::draw_sunrise
+    fn add_sunrise(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, media: &MediaManager) {
+        let mid = if self.time_of_day < 0.5 { 0.25 } else { 0.75 };
+        let fade = (1.0 - (self.time_of_day - mid).abs() * 35.0).clamp(0.0, 1.0);
+        if fade == 0.0 {
+            return;
+        }
+        let texture = self.sun.sunrise.clone();
+        if !self.load_texture(device, queue, media, &texture) {
+            return;
+        }
+
+        let fade = ease_curve(fade);
+        let y = -(1.0 - fade) * 0.22;
+        let horizon = if self.time_of_day < 0.5 { 90.0 } else { -90.0 };
+        let corners = [
+            Vec3::new(-1.0, -0.05 + y, -1.0),
+            Vec3::new(1.0, -0.05 + y, -1.0),
+            Vec3::new(1.0, 0.2 + y, -1.0),
+            Vec3::new(-1.0, 0.2 + y, -1.0),
+        ]
+        .map(|corner| rotate_xz(corner, horizon));
+        self.add_quad(&texture, corners, Vec4::new(1.0, 1.0, 1.0, fade));
+    }
+
+    // Compare to Luanti, client/sky.cpp, Sky::draw_stars
+    fn add_stars(&mut self) {
+        let day_opacity = self.stars.day_opacity.clamp(0.0, 1.0);
+        let night = if self.time_of_day < 0.5 {
+            self.time_of_day
+        } else {
+            1.0 - self.time_of_day
+        };
+        let brightness = ((0.25 - night) * 20.0).clamp(day_opacity, 1.0);
+        if brightness <= 0.0 {
+            return;
+        }
+
+        let color = self.stars.color * Vec4::new(1.0, 1.0, 1.0, brightness);
+        let size = Self::STAR_SIZE * self.stars.scale;
+        let star_dirs = std::mem::take(&mut self.star_dirs);
+        for &dir in &star_dirs {
+            let right = dir.cross(Vec3::Y).normalize_or(Vec3::X) * size;
+            let up = right.cross(dir).normalize_or(Vec3::Z) * size;
+            let corners = [
+                dir - right - up,
+                dir + right - up,
+                dir + right + up,
+                dir - right + up,
+            ]
+            .map(|corner| rotate_xy(corner, self.time_of_day * 360.0 - 90.0));
+            self.add_quad("", corners, color);
+        }
+        self.star_dirs = star_dirs;
+    }
+
+    /// Builds the geometry of the sky bodies for this frame.
+    // Compare to Luanti, client/sky.cpp, Sky::render
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        media: &MediaManager,
+        camera: &CameraParams,
+    ) {
+        self.vertices.clear();
+        self.indices.clear();
+        self.batches.clear();
+
+        self.origin = camera.pos;
+        if self.stars.visible {
+            self.add_stars();
+        }
+        if self.sun.visible && self.sun.sunrise_visible {
+            self.add_sunrise(device, queue, media);
+        }
+        if self.moon.visible {
+            self.add_body(device, queue, media, Body::Moon);
+        }
+        if self.sun.visible {
+            self.add_body(device, queue, media, Body::Sun);
+        }
+    }
+
+    /// Draws the geometry built by `prepare` behind the world.
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        color: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        if self.indices.is_empty() {
+            return;
+        }
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky vertex buffer"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky index buffer"),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Sky pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color,
+                depth_slice: None,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            ..wgpu::RenderPassDescriptor::default()
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, camera_bind_group, &[]);
+        pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        for batch in &self.batches {
+            // Batches only reference loaded textures
+            let Some(Some(texture)) = self.textures.get(&batch.texture) else {
+                continue;
+            };
+            pass.set_bind_group(1, &texture.bind_group, &[]);
+            pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+        }
+    }
+}
+
+// Compare to Irrlicht, vector3d.h, vector3d::rotateXZBy
+fn rotate_xz(pos: Vec3, degrees: f32) -> Vec3 {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    Vec3::new(pos.x * cos - pos.z * sin, pos.y, pos.x * sin + pos.z * cos)
+}
+
+// Compare to Irrlicht, vector3d.h, vector3d::rotateXYBy
+fn rotate_xy(pos: Vec3, degrees: f32) -> Vec3 {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    Vec3::new(pos.x * cos - pos.y * sin, pos.x * sin + pos.y * cos, pos.z)
+}
+
+// Compare to Luanti, util/numeric.h, easeCurve
+pub fn ease_curve(t: f32) -> f32 {
+    t * t * t * (t * (6.0 * t - 15.0) + 10.0)
+}
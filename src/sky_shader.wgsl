@@ -0,0 +1,49 @@
+struct CameraUniform {
+    view: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    fog_color: vec3<f32>,
+    fog_end: f32,
+    time: f32,
+    waving: u32,
+    daylight: f32,
+}
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+@group(1) @binding(0)
+var the_texture: texture_2d<f32>;
+
+@group(1) @binding(1)
+var the_sampler: sampler;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    // Multiplied with the texture color
+    @location(2) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    let clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    // On the far plane, so anything drawn by the world pass is in front
+    out.clip_position = vec4<f32>(clip_position.xy, clip_position.w, clip_position.w);
+    out.uv = model.uv;
+    out.color = model.color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // No fog, the sky is infinitely far away
+    return textureSample(the_texture, the_sampler, in.uv) * in.color;
+}
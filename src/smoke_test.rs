@@ -0,0 +1,105 @@
+//! `--smoke-test <address>` mode: connect to a server, wait until enough
+//! mapblocks have been meshed and rendered, then exit with a status code.
+//! Meant for end-to-end health checks in automated environments.
+//!
+//! A display is still required, use e.g. `xvfb-run` on headless machines.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmokeTestResult {
+    Passed,
+    Failed,
+}
+
+impl SmokeTestResult {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Passed => 0,
+            Self::Failed => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SmokeTest {
+    pub address: SocketAddr,
+    /// Number of meshed mapblocks required to pass
+    min_blocks: usize,
+    deadline: Instant,
+}
+
+impl SmokeTest {
+    const DEFAULT_MIN_BLOCKS: usize = 50;
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Removes the smoke test arguments (`--smoke-test <address>` and
+    /// optionally `--smoke-test-blocks <count>`) from `args`.
+    /// Returns None if not running a smoke test.
+    pub fn from_args(args: &mut Vec<String>) -> anyhow::Result<Option<Self>> {
+        let address = take_arg(args, "--smoke-test")?;
+        let min_blocks = take_arg(args, "--smoke-test-blocks")?;
+
+        let Some(address) = address else {
+            if min_blocks.is_some() {
+                return Err(anyhow!("--smoke-test-blocks requires --smoke-test"));
+            }
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            address: address.parse()?,
+            min_blocks: match min_blocks {
+                Some(count) => count.parse()?,
+                None => Self::DEFAULT_MIN_BLOCKS,
+            },
+            deadline: Instant::now() + Self::TIMEOUT,
+        }))
+    }
+
+    /// Called after a frame has been rendered with `rendered_blocks` meshed
+    /// mapblocks. Returns the result once the test is finished.
+    pub fn check_frame(&self, rendered_blocks: usize) -> Option<SmokeTestResult> {
+        if rendered_blocks >= self.min_blocks {
+            println!(
+                "Smoke test passed: rendered a frame with {} mapblocks",
+                rendered_blocks
+            );
+            return Some(SmokeTestResult::Passed);
+        }
+        self.check_timeout()
+    }
+
+    pub fn check_timeout(&self) -> Option<SmokeTestResult> {
+        if Instant::now() >= self.deadline {
+            println!(
+                "Smoke test failed: less than {} mapblocks after {:?}",
+                self.min_blocks,
+                Self::TIMEOUT
+            );
+            return Some(SmokeTestResult::Failed);
+        }
+        None
+    }
+
+    pub fn disconnected(&self, reason: &str) -> SmokeTestResult {
+        println!("Smoke test failed: disconnected: {}", reason);
+        SmokeTestResult::Failed
+    }
+}
+
+/// Removes `name` and its value from `args`, returning the value.
+fn take_arg(args: &mut Vec<String>, name: &str) -> anyhow::Result<Option<String>> {
+    let Some(index) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if index + 1 >= args.len() {
+        return Err(anyhow!("Missing value for argument \"{}\"", name));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
@@ -0,0 +1,251 @@
+//! Sound files from the MediaManager. Short files are decoded once in the
+//! background and kept in memory, so frequent sounds like footsteps don't
+//! hit the disk and the decoder every time they are played. Until then, and
+//! for long files like music, the file is decoded while it's playing, on
+//! the audio thread.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rodio::{Decoder, Source};
+
+use crate::luanti_client::LuantiClientRunner;
+use crate::media::MediaManager;
+
+/// A decoded .ogg file. Cloning is cheap, the samples are shared.
+#[derive(Clone)]
+pub struct SoundData {
+    channels: u16,
+    sample_rate: u32,
+    /// Interleaved by channel
+    samples: Arc<[i16]>,
+}
+
+impl SoundData {
+    /// Decodes an Ogg Vorbis file, the only format Luanti supports.
+    // Compare to Luanti, client/sound/ogg_file.cpp, RAMOggVorbisFile
+    pub fn decode(data: Arc<[u8]>) -> anyhow::Result<Self> {
+        let decoder = Decoder::new_vorbis(Cursor::new(data))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        if channels == 0 || sample_rate == 0 {
+            return Err(anyhow!("Invalid sound format"));
+        }
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples: decoder.collect(),
+        })
+    }
+
+    /// Returns a rodio source playing the sound from the start.
+    pub fn source(&self) -> SoundSource {
+        SoundSource {
+            data: self.clone(),
+            pos: 0,
+        }
+    }
+}
+
+pub struct SoundSource {
+    data: SoundData,
+    pos: usize,
+}
+
+impl Iterator for SoundSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.data.samples.get(self.pos).copied()?;
+        self.pos += 1;
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.data.samples.len() - self.pos;
+        (remaining, Some(remaining))
+    }
+}
+
+impl Source for SoundSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.data.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.data.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.data.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.data.samples.len() / self.data.channels as usize;
+        Some(Duration::from_secs_f64(
+            frames as f64 / self.data.sample_rate as f64,
+        ))
+    }
+}
+
+/// A sound ready to be played, either from memory or decoded while playing.
+pub type PlayableSound = Box<dyn Source<Item = i16> + Send>;
+
+/// Decoded files, evicting the least recently used ones when they get too
+/// large.
+#[derive(Default)]
+struct SoundCache {
+    /// By file name, with the time of the last use
+    entries: HashMap<String, (SoundData, u64)>,
+    /// Files being decoded in the background
+    decoding: HashSet<String>,
+    /// Total number of samples of the entries
+    samples: usize,
+    /// Incremented on every use, for finding the least recently used entry
+    clock: u64,
+    /// Incremented by SoundManager::clear, so files decoded for the previous
+    /// server are dropped
+    generation: u64,
+}
+
+impl SoundCache {
+    /// Total size of the decoded files, in samples (64 MiB)
+    const MAX_SAMPLES: usize = 32 * 1024 * 1024;
+
+    fn get(&mut self, file: &str) -> Option<SoundData> {
+        self.clock += 1;
+        let (sound, last_used) = self.entries.get_mut(file)?;
+        *last_used = self.clock;
+        Some(sound.clone())
+    }
+
+    fn insert(&mut self, file: String, sound: SoundData) {
+        self.samples += sound.samples.len();
+        if let Some((old, _)) = self.entries.insert(file, (sound, self.clock)) {
+            self.samples -= old.samples.len();
+        }
+        while self.samples > Self::MAX_SAMPLES {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(file, _)| file.clone())
+            else {
+                break;
+            };
+            let (evicted, _) = self.entries.remove(&oldest).unwrap();
+            self.samples -= evicted.samples.len();
+        }
+    }
+}
+
+/// Looks up sound groups by name and caches the decoded files.
+// Compare to Luanti, client/sound/sound_manager.cpp, OpenALSoundManager
+pub struct SoundManager {
+    /// The files of each sound group that was played, by group name
+    groups: HashMap<String, Vec<String>>,
+    /// Shared with the decoder threads
+    cache: Arc<Mutex<SoundCache>>,
+    /// Picks the file of a group, seeded in deterministic mode like
+    /// LuantiClientRunner::rng
+    rng: StdRng,
+}
+
+impl SoundManager {
+    /// Files larger than this are always decoded while playing, like
+    /// Luanti streams long sounds. 512 KiB of Ogg Vorbis are a few minutes.
+    const MAX_CACHED_FILE_SIZE: usize = 512 * 1024;
+
+    pub fn new(deterministic: bool) -> Self {
+        Self {
+            groups: HashMap::new(),
+            cache: Arc::default(),
+            rng: if deterministic {
+                StdRng::seed_from_u64(LuantiClientRunner::DETERMINISTIC_SEED)
+            } else {
+                StdRng::from_os_rng()
+            },
+        }
+    }
+
+    /// Returns a random file of the sound group `name`, ready to play.
+    /// Returns Ok(None) if the group has no files.
+    pub fn get(
+        &mut self,
+        media: &MediaManager,
+        name: &str,
+    ) -> anyhow::Result<Option<PlayableSound>> {
+        let files = self
+            .groups
+            .entry(String::from(name))
+            .or_insert_with(|| Self::group_files(media, name));
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let file = &files[self.rng.random_range(0..files.len())];
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(sound) = cache.get(file) {
+            return Ok(Some(Box::new(sound.source())));
+        }
+        let Some(data) = media.load_bytes(file)? else {
+            return Ok(None);
+        };
+        let data: Arc<[u8]> = data.into();
+        let source = Decoder::new_vorbis(Cursor::new(data.clone()))?;
+
+        if data.len() <= Self::MAX_CACHED_FILE_SIZE && cache.decoding.insert(file.clone()) {
+            let shared_cache = self.cache.clone();
+            let generation = cache.generation;
+            let file = file.clone();
+            std::thread::spawn(move || {
+                let result = SoundData::decode(data);
+                let mut cache = shared_cache.lock().unwrap();
+                if cache.generation != generation {
+                    return;
+                }
+                cache.decoding.remove(&file);
+                match result {
+                    Ok(sound) => cache.insert(file, sound),
+                    Err(err) => println!("Error while decoding sound \"{}\": {:?}", file, err),
+                }
+            });
+        }
+        Ok(Some(Box::new(source)))
+    }
+    /// Finds the files of a sound group: "<name>.ogg" and "<name>.<N>.ogg".
+    // Compare to Luanti, client/sound/sound_manager.cpp, OpenALSoundManager::getLoadedSoundNameFromGroup
+    fn group_files(media: &MediaManager, name: &str) -> Vec<String> {
+        media
+            .names()
+            .filter(|file| {
+                let Some(stem) = file.strip_suffix(".ogg") else {
+                    return false;
+                };
+                stem == name
+                    || stem
+                        .strip_prefix(name)
+                        .and_then(|rest| rest.strip_prefix('.'))
+                        .is_some_and(|index| index.parse::<u32>().is_ok())
+            })
+            .map(String::from)
+            .collect()
+    }
+
+    /// Forgets all sounds, e.g. when disconnecting, as the next server has
+    /// different media.
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        let mut cache = self.cache.lock().unwrap();
+        let generation = cache.generation + 1;
+        *cache = SoundCache {
+            generation,
+            ..SoundCache::default()
+        };
+    }
+}
@@ -0,0 +1,139 @@
+//! SRP-6a password authentication, as used by Luanti: SHA-256 and the
+//! 2048-bit group from RFC 5054. The password never leaves the client, the
+//! server only stores a salted verifier.
+// Compare to Luanti, util/srp.cpp and util/auth.cpp
+
+use anyhow::anyhow;
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// RFC 5054, Appendix A, 2048-bit group
+const N_HEX: &str = "\
+    AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050\
+    A37329CBB4A099ED8193E0757767A13DD52312AB4B03310DCD7F48A9DA04FD50\
+    E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE82918A9962F0B93B8\
+    55F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773B\
+    CA97B43A23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748\
+    544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB3786160279004E57AE6\
+    AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DBFBB6\
+    94B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+const G: u32 = 2;
+
+/// Length of the generated salts, like in Luanti
+const SALT_LEN: usize = 16;
+/// Length of the random secret `a`
+const SECRET_LEN: usize = 32;
+
+fn group() -> (BigUint, BigUint) {
+    (
+        BigUint::parse_bytes(N_HEX.as_bytes(), 16).unwrap(),
+        BigUint::from(G),
+    )
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Big-endian bytes, left-padded with zeros to the size of N.
+fn pad(num: &BigUint, n: &BigUint) -> Vec<u8> {
+    let len = n.to_bytes_be().len();
+    let bytes = num.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+/// x = H(s | H(I | ":" | P)). The salt is hashed exactly as sent, including
+/// leading zeros.
+fn calculate_x(salt: &[u8], name_lower: &str, password: &str) -> BigUint {
+    let inner = hash(&[name_lower.as_bytes(), b":", password.as_bytes()]);
+    BigUint::from_bytes_be(&hash(&[salt, &inner]))
+}
+
+/// Generates a random salt and the matching verifier, for registering a new
+/// account. Returns (salt, verifier).
+pub fn create_verifier(name: &str, password: &str, rng: &mut impl RngCore) -> (Vec<u8>, Vec<u8>) {
+    let (n, g) = group();
+    let mut salt = vec![0; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+
+    let x = calculate_x(&salt, &name.to_lowercase(), password);
+    let verifier = g.modpow(&x, &n);
+    (salt, verifier.to_bytes_be())
+}
+
+/// The client side of an SRP login.
+pub struct SrpClient {
+    name: String,
+    password: String,
+    /// Random secret
+    a: BigUint,
+    /// A = g^a, sent to the server
+    big_a: BigUint,
+}
+
+impl SrpClient {
+    pub fn new(name: &str, password: &str, rng: &mut impl RngCore) -> Self {
+        let (n, g) = group();
+        let mut bytes = [0; SECRET_LEN];
+        rng.fill_bytes(&mut bytes);
+        let a = BigUint::from_bytes_be(&bytes);
+        let big_a = g.modpow(&a, &n);
+
+        Self {
+            name: String::from(name),
+            password: String::from(password),
+            a,
+            big_a,
+        }
+    }
+
+    /// The public value A, sent in TOSERVER_SRP_BYTES_A.
+    pub fn bytes_a(&self) -> Vec<u8> {
+        self.big_a.to_bytes_be()
+    }
+
+    /// Processes the salt and B from TOCLIENT_SRP_BYTES_S_B and returns the
+    /// proof M to send in TOSERVER_SRP_BYTES_M.
+    pub fn process_challenge(&self, salt: &[u8], bytes_b: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (n, g) = group();
+        let big_b = BigUint::from_bytes_be(bytes_b);
+        if &big_b % &n == BigUint::ZERO {
+            return Err(anyhow!("Server sent an invalid SRP value B"));
+        }
+
+        let u = BigUint::from_bytes_be(&hash(&[&pad(&self.big_a, &n), &pad(&big_b, &n)]));
+        if u == BigUint::ZERO {
+            return Err(anyhow!("Server sent an invalid SRP value B"));
+        }
+        let k = BigUint::from_bytes_be(&hash(&[&n.to_bytes_be(), &pad(&g, &n)]));
+        let x = calculate_x(salt, &self.name.to_lowercase(), &self.password);
+
+        // S = (B - k * g^x) ^ (a + u * x) mod N
+        let kv = k * g.modpow(&x, &n) % &n;
+        let base = (&big_b % &n + &n - kv) % &n;
+        let s = base.modpow(&(&self.a + u * x), &n);
+        let key = hash(&[&s.to_bytes_be()]);
+
+        // M = H(H(N) xor H(g) | H(I) | s | A | B | K)
+        let hash_n = hash(&[&n.to_bytes_be()]);
+        let hash_g = hash(&[&g.to_bytes_be()]);
+        let hash_xor: Vec<u8> = hash_n.iter().zip(hash_g).map(|(a, b)| a ^ b).collect();
+        let hash_name = hash(&[self.name.as_bytes()]);
+        let m = hash(&[
+            &hash_xor,
+            &hash_name,
+            salt,
+            &self.big_a.to_bytes_be(),
+            &big_b.to_bytes_be(),
+            &key,
+        ]);
+        Ok(m.to_vec())
+    }
+}
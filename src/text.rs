@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use glam::{Vec2, Vec4};
+
+use crate::overlay::{OverlayRenderer, OverlayTextureId};
+use crate::srgb_to_linear;
+
+/// A glyph in the atlas texture.
+struct Glyph {
+    uv_min: Vec2,
+    uv_max: Vec2,
+    size: Vec2,
+    /// Offset of the bitmap's top left corner from the pen position on the
+    /// baseline
+    offset: Vec2,
+    advance: f32,
+}
+
+/// Draws text using the overlay renderer. The glyphs are rasterized once into
+/// an atlas texture at a fixed size.
+// TODO: only Latin-1 is in the atlas, other characters are drawn as '?'
+pub struct TextRenderer {
+    texture: OverlayTextureId,
+    glyphs: HashMap<char, Glyph>,
+    ascent: f32,
+    line_height: f32,
+}
+
+impl TextRenderer {
+    const FONT: &[u8] = include_bytes!("DejaVuSans.ttf");
+    const ATLAS_WIDTH: u32 = 512;
+    const FALLBACK_CHAR: char = '?';
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        overlay: &mut OverlayRenderer,
+        px: f32,
+    ) -> Self {
+        let font = fontdue::Font::from_bytes(Self::FONT, fontdue::FontSettings::default())
+            .expect("builtin font is valid");
+        let line_metrics = font.horizontal_line_metrics(px).unwrap();
+
+        let chars = (' '..='~').chain('\u{a0}'..='\u{ff}');
+        let rasterized: Vec<(char, fontdue::Metrics, Vec<u8>)> = chars
+            .map(|c| {
+                let (metrics, bitmap) = font.rasterize(c, px);
+                (c, metrics, bitmap)
+            })
+            .collect();
+
+        // Simple row packing, the glyphs are all about the same height
+        let row_height = rasterized
+            .iter()
+            .map(|(_, metrics, _)| metrics.height as u32)
+            .max()
+            .unwrap()
+            + 1;
+        let mut positions = Vec::with_capacity(rasterized.len());
+        let (mut x, mut y) = (0, 0);
+        for (_, metrics, _) in &rasterized {
+            let width = metrics.width as u32 + 1;
+            if x + width > Self::ATLAS_WIDTH {
+                x = 0;
+                y += row_height;
+            }
+            positions.push((x, y));
+            x += width;
+        }
+        let atlas_size = Vec2::new(Self::ATLAS_WIDTH as f32, (y + row_height) as f32);
+
+        // White, the coverage is stored in the alpha channel
+        let mut atlas = image::RgbaImage::from_pixel(
+            Self::ATLAS_WIDTH,
+            y + row_height,
+            image::Rgba([255, 255, 255, 0]),
+        );
+        let mut glyphs = HashMap::new();
+        for ((c, metrics, bitmap), (x, y)) in rasterized.into_iter().zip(positions) {
+            for (index, coverage) in bitmap.into_iter().enumerate() {
+                let gx = x + (index % metrics.width) as u32;
+                let gy = y + (index / metrics.width) as u32;
+                atlas.get_pixel_mut(gx, gy).0[3] = coverage;
+            }
+
+            let min = Vec2::new(x as f32, y as f32);
+            let size = Vec2::new(metrics.width as f32, metrics.height as f32);
+            glyphs.insert(
+                c,
+                Glyph {
+                    uv_min: min / atlas_size,
+                    uv_max: (min + size) / atlas_size,
+                    size,
+                    offset: Vec2::new(
+                        metrics.xmin as f32,
+                        -(metrics.ymin as f32 + metrics.height as f32),
+                    ),
+                    advance: metrics.advance_width,
+                },
+            );
+        }
+
+        let texture = overlay.add_texture(device, queue, "Font atlas", &atlas.into());
+
+        Self {
+            texture,
+            glyphs,
+            ascent: line_metrics.ascent,
+            line_height: line_metrics.new_line_size.ceil(),
+        }
+    }
+
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    fn glyph(&self, c: char) -> &Glyph {
+        self.glyphs
+            .get(&c)
+            .unwrap_or_else(|| &self.glyphs[&Self::FALLBACK_CHAR])
+    }
+
+    /// Returns the width of a single line of text, in pixels.
+    pub fn width(&self, text: &str) -> f32 {
+        text.chars().map(|c| self.glyph(c).advance).sum()
+    }
+
+    /// Draws a single line of text, `pos` is the top left corner.
+    pub fn draw(&self, overlay: &mut OverlayRenderer, text: &str, pos: Vec2, color: Vec4) {
+        // Whole pixels, so the glyphs aren't blurry
+        let mut pen = Vec2::new(pos.x, pos.y + self.ascent).round();
+        for c in text.chars() {
+            let glyph = self.glyph(c);
+            if glyph.size.x > 0.0 {
+                let min = pen + glyph.offset;
+                overlay.image(
+                    self.texture,
+                    min,
+                    min + glyph.size,
+                    glyph.uv_min,
+                    glyph.uv_max,
+                    color,
+                );
+            }
+            pen.x += glyph.advance;
+        }
+    }
+
+    /// Draws a single line of text made of runs with different colors, see
+    /// `colorize`. The alpha of the colors is multiplied by `alpha`.
+    pub fn draw_colored(
+        &self,
+        overlay: &mut OverlayRenderer,
+        runs: &[(String, Vec4)],
+        pos: Vec2,
+        alpha: f32,
+    ) {
+        let mut pos = pos;
+        for (run, color) in runs {
+            self.draw(overlay, run, pos, color.with_w(color.w * alpha));
+            pos.x += self.width(run);
+        }
+    }
+
+    /// Splits text into lines no wider than `max_width`, breaking at spaces
+    /// where possible. Existing line breaks are kept.
+    pub fn wrap(&self, text: &str, max_width: f32) -> Vec<String> {
+        let mut lines = Vec::new();
+        for paragraph in text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split(' ') {
+                let candidate = if line.is_empty() {
+                    String::from(word)
+                } else {
+                    format!("{} {}", line, word)
+                };
+                if self.width(&candidate) <= max_width || line.is_empty() {
+                    line = candidate;
+                } else {
+                    lines.push(std::mem::replace(&mut line, String::from(word)));
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Removes Luanti's escape sequences (colors, translations) from a string.
+// Compare to Luanti, util/string.h, unescape_enriched
+pub fn strip_escapes(text: &str) -> String {
+    colorize(text, Vec4::ONE)
+        .into_iter()
+        .map(|(run, _)| run)
+        .collect()
+}
+
+/// Splits a string into runs of text with the colors set by Luanti's color
+/// escapes, e.g. "\x1b(c@#ff0000)". Text before the first color escape gets
+/// `default_color`. Other escapes are removed.
+// Compare to Luanti, util/enriched_string.cpp, EnrichedString::addAtEnd
+pub fn colorize(text: &str, default_color: Vec4) -> Vec<(String, Vec4)> {
+    let mut runs = vec![(String::new(), default_color)];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            runs.last_mut().unwrap().0.push(c);
+            continue;
+        }
+        // Escapes with parameters, e.g. "\x1b(c@#ff0000)". Others are
+        // single-character, e.g. "\x1bE".
+        if chars.next() != Some('(') {
+            continue;
+        }
+        let escape: String = chars.by_ref().take_while(|&c| c != ')').collect();
+        if let Some(color) = escape.strip_prefix("c@").and_then(parse_color) {
+            runs.push((String::new(), color));
+        }
+    }
+    runs.retain(|(run, _)| !run.is_empty());
+    runs
+}
+
+/// Parses a color like `parse_color_srgb` and converts it to a linear
+/// color.
+pub fn parse_color(color: &str) -> Option<Vec4> {
+    let [r, g, b, a] = parse_color_srgb(color)?;
+    Some(Vec4::new(
+        srgb_to_linear(r),
+        srgb_to_linear(g),
+        srgb_to_linear(b),
+        a as f32 / 255.0,
+    ))
+}
+
+/// Parses a color in one of the formats Luanti accepts: "#rgb", "#rgba",
+/// "#rrggbb", "#rrggbbaa" or a common color name. Returns the sRGB channels
+/// and alpha.
+// Compare to Luanti, util/string.cpp, parseColorString
+pub fn parse_color_srgb(color: &str) -> Option<[u8; 4]> {
+    let hex = match color.strip_prefix('#') {
+        Some(hex) => hex,
+        None => match color.to_ascii_lowercase().as_str() {
+            "black" => "000000",
+            "white" => "ffffff",
+            "gray" | "grey" => "808080",
+            "silver" => "c0c0c0",
+            "red" => "ff0000",
+            "maroon" => "800000",
+            "orange" => "ffa500",
+            "yellow" => "ffff00",
+            "gold" => "ffd700",
+            "lime" => "00ff00",
+            "green" => "008000",
+            "cyan" | "aqua" => "00ffff",
+            "blue" => "0000ff",
+            "navy" => "000080",
+            "magenta" | "fuchsia" => "ff00ff",
+            "purple" => "800080",
+            "pink" => "ffc0cb",
+            "brown" => "a52a2a",
+            _ => return None,
+        },
+    };
+    if !hex.is_ascii() {
+        return None;
+    }
+
+    let digits: Vec<u8> = match hex.len() {
+        // Short forms repeat each digit, "#f80" is "#ff8800"
+        3 | 4 => hex
+            .chars()
+            .map(|digit| u8::from_str_radix(&format!("{digit}{digit}"), 16))
+            .collect::<Result<_, _>>()
+            .ok()?,
+        6 | 8 => (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+            .collect::<Result<_, _>>()
+            .ok()?,
+        _ => return None,
+    };
+    let alpha = digits.get(3).copied().unwrap_or(255);
+    Some([digits[0], digits[1], digits[2], alpha])
+}
@@ -1,6 +1,5 @@
-use std::path::Path;
-
-use image::{GenericImageView, ImageReader};
+use anyhow::anyhow;
+use image::GenericImageView;
 use wgpu::util::DeviceExt;
 
 pub struct MyTexture {
@@ -9,26 +8,6 @@ pub struct MyTexture {
 }
 
 impl MyTexture {
-    pub fn from_bytes(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        name: &str,
-        bytes: &[u8],
-    ) -> anyhow::Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, name, &img)
-    }
-
-    pub fn from_path(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        name: &str,
-        path: &Path,
-    ) -> anyhow::Result<Self> {
-        let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
-        Self::from_image(device, queue, name, &img)
-    }
-
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -65,6 +44,66 @@ impl MyTexture {
         Ok(Self { texture, view })
     }
 
+    /// Creates a 2D array texture with one layer per image, e.g. for the
+    /// frames of an animation. Each layer is given with its mip levels,
+    /// starting with the full size. All layers must have the same size and
+    /// the same number of mip levels.
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: &str,
+        layers: &[Vec<image::RgbaImage>],
+    ) -> anyhow::Result<Self> {
+        let first = layers.first().ok_or_else(|| anyhow!("No layers"))?;
+        let (width, height) = first
+            .first()
+            .ok_or_else(|| anyhow!("No mip levels"))?
+            .dimensions();
+        let mip_level_count = first.len() as u32;
+        if layers.iter().any(|levels| {
+            levels.len() != first.len()
+                || levels.first().map(|level| level.dimensions()) != Some((width, height))
+        }) {
+            return Err(anyhow!("Layers have different sizes"));
+        }
+
+        // Layer by layer, each with all of its mip levels
+        let data: Vec<u8> = layers
+            .iter()
+            .flatten()
+            .flat_map(|level| level.as_raw().iter().copied())
+            .collect();
+
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some(name),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: layers.len() as u32,
+                },
+                mip_level_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            &data,
+        );
+
+        // Explicit, would be D2 for a single layer otherwise
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(name),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..wgpu::TextureViewDescriptor::default()
+        });
+
+        Ok(Self { texture, view })
+    }
+
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
     pub fn new_depth(device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) -> Self {
@@ -90,4 +129,33 @@ impl MyTexture {
 
         Self { texture, view }
     }
+
+    /// Creates a texture that can be rendered to and then sampled from.
+    pub fn new_render_target(
+        device: &wgpu::Device,
+        size: winit::dpi::PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("render target texture"),
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("render target texture view"),
+            ..wgpu::TextureViewDescriptor::default()
+        });
+
+        Self { texture, view }
+    }
 }
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use glam::Vec3;
+
+use crate::settings::Settings;
+
+/// Local waypoints, saved per server as a JSON object (name to position) in
+/// the `waypoints` subdirectory of the config directory.
+#[derive(Debug, Default)]
+pub struct Waypoints {
+    /// None until connected to a server, waypoints aren't saved then
+    path: Option<PathBuf>,
+    list: BTreeMap<String, Vec3>,
+}
+
+impl Waypoints {
+    /// Loads the waypoints for the given server.
+    /// Returns no waypoints if nothing has been saved yet.
+    pub fn load(address: SocketAddr) -> anyhow::Result<Self> {
+        let mut path = Settings::config_dir()?;
+        path.push("waypoints");
+        fs::create_dir_all(&path)?;
+        // ':' isn't allowed in file names on Windows
+        path.push(format!("{}.json", address.to_string().replace(':', "_")));
+
+        let list: BTreeMap<String, [f32; 3]> = if path.try_exists()? {
+            let text = fs::read_to_string(&path)?;
+            serde_json::from_str(&text).with_context(|| format!("Invalid waypoints {:?}", path))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path: Some(path),
+            list: list
+                .into_iter()
+                .map(|(name, pos)| (name, Vec3::from_array(pos)))
+                .collect(),
+        })
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let list: BTreeMap<&String, [f32; 3]> = self
+            .list
+            .iter()
+            .map(|(name, pos)| (name, pos.to_array()))
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&list)?)?;
+        Ok(())
+    }
+
+    /// Adds a waypoint, replacing an existing one with the same name.
+    pub fn set(&mut self, name: String, pos: Vec3) -> anyhow::Result<()> {
+        self.list.insert(name, pos);
+        self.save()
+    }
+
+    /// Returns false if there was no waypoint with this name.
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<bool> {
+        if self.list.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec3)> {
+        self.list.iter()
+    }
+
+    /// Returns a name like "Waypoint 3" that isn't used yet.
+    pub fn unused_name(&self) -> String {
+        (1..)
+            .map(|n| format!("Waypoint {}", n))
+            .find(|name| !self.list.contains_key(name))
+            .unwrap()
+    }
+}
@@ -0,0 +1,63 @@
+//! Data model for the wielded items. Besides the main hand, there's an offhand
+//! slot that isn't known to Luanti itself, but is used by server mods through
+//! HUD elements and detached inventories. It is controlled from Lua.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WieldHand {
+    Main,
+    Offhand,
+}
+
+impl FromStr for WieldHand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" => Ok(Self::Main),
+            "offhand" => Ok(Self::Offhand),
+            _ => Err(anyhow!("invalid hand \"{}\", expected main or offhand", s)),
+        }
+    }
+}
+
+impl fmt::Display for WieldHand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Main => write!(f, "main"),
+            Self::Offhand => write!(f, "offhand"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WieldSlot {
+    /// The item string, e.g. "default:torch 5". None if the hand is empty.
+    pub item: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WieldState {
+    pub main: WieldSlot,
+    pub offhand: WieldSlot,
+}
+
+impl WieldState {
+    pub fn slot(&self, hand: WieldHand) -> &WieldSlot {
+        match hand {
+            WieldHand::Main => &self.main,
+            WieldHand::Offhand => &self.offhand,
+        }
+    }
+
+    pub fn slot_mut(&mut self, hand: WieldHand) -> &mut WieldSlot {
+        match hand {
+            WieldHand::Main => &mut self.main,
+            WieldHand::Offhand => &mut self.offhand,
+        }
+    }
+}
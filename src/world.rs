@@ -0,0 +1,381 @@
+//! Runtime world state on the main thread, organized as an ECS. Things in the
+//! world are entities with components, and the methods of `World` are the
+//! systems operating on them. The entities are mapblocks, active objects
+//! and smoke puffs.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use glam::{I16Vec3, Quat, Vec2, Vec3};
+use luanti_core::MapNodePos;
+use luanti_protocol::types::ActiveObjectCommand;
+
+use crate::active_object::{ActiveObject, Damage, quat_to_rotation, rotation_to_quat};
+use crate::camera_controller::{PhysicsOverride, PlayerPos};
+use crate::frustum::{BoundingSphere, Frustum};
+use crate::light::{self, LIGHT_SUN, LightBank};
+use crate::map::LuantiMap;
+use crate::meshgen::{MapblockMesh, Mesh};
+use crate::node_def::NodeDefManager;
+
+/// A mapblock whose mesh was received. The mesh may be empty, in which case
+/// the entity has no GpuMesh.
+pub struct Mapblock {
+    pub blockpos: I16Vec3,
+    /// See MapblockMesh::timestamp_task_spawned
+    pub timestamp_task_spawned: Instant,
+    /// Number of meshes received for this mapblock, for debugging
+    pub remesh_count: u32,
+}
+
+/// The id the server gave an active object.
+pub struct ObjectId(pub u16);
+
+/// Shown for a moment where an object died.
+// Compare to Luanti, client/content_cso.cpp, SmokePuffCSO
+pub struct SmokePuff {
+    /// In nodes
+    pub pos: Vec3,
+    /// In seconds
+    age: f32,
+}
+
+impl SmokePuff {
+    /// In seconds
+    const LIFETIME: f32 = 1.0;
+}
+
+/// The GPU buffers of a non-empty mesh.
+pub struct GpuMesh {
+    pub num_indices: u32,
+    pub num_vertices: u32,
+    pub index_buffer: wgpu::Buffer,
+    pub vertex_buffer: wgpu::Buffer,
+}
+
+/// The CPU-side copy of a non-empty mesh, only kept if
+/// Settings::retain_meshes is enabled.
+pub struct CpuMesh(pub Mesh);
+
+/// Whether the entity passed culling this frame.
+pub struct Visible(pub bool);
+
+/// A mesh to draw this frame, extracted from the ECS so recording render
+/// passes doesn't need to borrow it.
+pub struct MeshDraw {
+    pub num_indices: u32,
+    pub index_buffer: wgpu::Buffer,
+    pub vertex_buffer: wgpu::Buffer,
+}
+
+pub struct World {
+    pub ecs: hecs::World,
+    /// Mapblock entities by position
+    mapblocks: HashMap<I16Vec3, hecs::Entity>,
+    /// Number of meshes received for all mapblocks, for debugging
+    pub remesh_count_total: u32,
+    /// Active object entities by object id
+    active_objects: HashMap<u16, hecs::Entity>,
+}
+
+impl World {
+    /// Limit for chains of attached objects, which also protects against
+    /// attachment cycles
+    const MAX_ATTACHMENT_DEPTH: u32 = 16;
+
+    pub fn new() -> Self {
+        Self {
+            ecs: hecs::World::new(),
+            mapblocks: HashMap::new(),
+            remesh_count_total: 0,
+            active_objects: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of mapblocks with a mesh, including empty ones.
+    pub fn mapblock_count(&self) -> usize {
+        self.mapblocks.len()
+    }
+
+    /// Returns whether the mesh of a mapblock was received, even if it's
+    /// empty.
+    pub fn has_mapblock(&self, blockpos: I16Vec3) -> bool {
+        self.mapblocks.contains_key(&blockpos)
+    }
+
+    /// Returns the number of vertices of all meshes, for debugging.
+    pub fn vertex_count(&self) -> u64 {
+        self.ecs
+            .query::<&GpuMesh>()
+            .iter()
+            .map(|(_, mesh)| mesh.num_vertices as u64)
+            .sum()
+    }
+
+    /// Spawns the entity for a mapblock mesh, or updates the existing one.
+    pub fn insert_mapblock_mesh(&mut self, mesh: MapblockMesh) {
+        self.remesh_count_total += 1;
+        let blockpos = mesh.blockpos.vec();
+
+        let entity = match self.mapblocks.get(&blockpos) {
+            Some(&entity) => {
+                let mut mapblock = self.ecs.get::<&mut Mapblock>(entity).unwrap();
+                mapblock.remesh_count += 1;
+                // A meshgen task for the same mapblock might have started
+                // later, but finished earlier than this one.
+                // Don't replace the new data with our outdated data in that case.
+                if mesh.timestamp_task_spawned <= mapblock.timestamp_task_spawned {
+                    return;
+                }
+                mapblock.timestamp_task_spawned = mesh.timestamp_task_spawned;
+                entity
+            }
+            None => {
+                let entity = self.ecs.spawn((Mapblock {
+                    blockpos,
+                    timestamp_task_spawned: mesh.timestamp_task_spawned,
+                    remesh_count: 1,
+                },));
+                self.mapblocks.insert(blockpos, entity);
+                entity
+            }
+        };
+
+        match (mesh.index_buffer, mesh.vertex_buffer, mesh.bounding_sphere) {
+            (Some(index_buffer), Some(vertex_buffer), Some(bounding_sphere)) => {
+                let gpu_mesh = GpuMesh {
+                    num_indices: mesh.num_indices,
+                    num_vertices: mesh.num_vertices,
+                    index_buffer,
+                    vertex_buffer,
+                };
+                self.ecs
+                    .insert(entity, (gpu_mesh, bounding_sphere, Visible(false)))
+                    .unwrap();
+            }
+            // The mesh became empty, fails if it already was
+            _ => {
+                let _ = self
+                    .ecs
+                    .remove::<(GpuMesh, BoundingSphere, Visible)>(entity);
+            }
+        }
+
+        match mesh.mesh {
+            Some(cpu_mesh) => self.ecs.insert_one(entity, CpuMesh(cpu_mesh)).unwrap(),
+            None => {
+                let _ = self.ecs.remove_one::<CpuMesh>(entity);
+            }
+        }
+    }
+
+    /// Culling system, updates Visible for all entities with a bounding
+    /// sphere. Returns the number of visible and culled entities.
+    pub fn cull(&mut self, camera_pos: Vec3, frustum: &Frustum, view_distance: f32) -> (u32, u32) {
+        let mut drawn: u32 = 0;
+        // TODO: drop meshes that are continuously culled for 30s or so
+        let mut culled: u32 = 0;
+
+        for (_, (sphere, visible)) in self.ecs.query_mut::<(&BoundingSphere, &mut Visible)>() {
+            // TODO: this filters out some blocks the frustum culling doesn't,
+            // but there are no visible glitches.
+            // is the frustum culling buggy / too conservative?
+            let distance_sq = camera_pos.distance_squared(sphere.center);
+            let max_distance = view_distance + sphere.radius;
+
+            visible.0 = distance_sq <= max_distance * max_distance && sphere.is_on_frustum(frustum);
+            if visible.0 {
+                drawn += 1;
+            } else {
+                culled += 1;
+            }
+        }
+
+        (drawn, culled)
+    }
+
+    /// Render extraction system, collects the visible meshes.
+    pub fn extract_draws(&self) -> Vec<MeshDraw> {
+        self.ecs
+            .query::<(&GpuMesh, &Visible)>()
+            .iter()
+            .filter(|(_, (_, visible))| visible.0)
+            .map(|(_, (mesh, _))| MeshDraw {
+                num_indices: mesh.num_indices,
+                index_buffer: mesh.index_buffer.clone(),
+                vertex_buffer: mesh.vertex_buffer.clone(),
+            })
+            .collect()
+    }
+
+    /// Spawns the entity for an active object, replacing an existing one
+    /// with the same id.
+    pub fn add_active_object(&mut self, id: u16, object: ActiveObject) {
+        let entity = self.ecs.spawn((ObjectId(id), object));
+        if let Some(old) = self.active_objects.insert(id, entity) {
+            self.ecs.despawn(old).unwrap();
+        }
+    }
+
+    pub fn remove_active_object(&mut self, id: u16) {
+        match self.active_objects.remove(&id) {
+            Some(entity) => self.ecs.despawn(entity).unwrap(),
+            None => println!("Cannot remove unknown active object {}", id),
+        }
+    }
+
+    /// Despawns all active objects and smoke puffs, e.g. when
+    /// disconnecting. The mapblocks are kept.
+    pub fn clear_active_objects(&mut self) {
+        for (_, entity) in self.active_objects.drain() {
+            self.ecs.despawn(entity).unwrap();
+        }
+        let puffs: Vec<hecs::Entity> = self
+            .ecs
+            .query::<&SmokePuff>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in puffs {
+            self.ecs.despawn(entity).unwrap();
+        }
+    }
+
+    /// The position of an active object, in nodes.
+    pub fn object_pos(&self, id: u16) -> Option<Vec3> {
+        let entity = *self.active_objects.get(&id)?;
+        Some(self.ecs.get::<&ActiveObject>(entity).ok()?.pos)
+    }
+
+    /// The zoom_fov object property of the local player, once its object
+    /// and properties are known.
+    pub fn local_player_zoom_fov(&self) -> Option<f32> {
+        let mut query = self.ecs.query::<&ActiveObject>();
+        let (_, object) = query.iter().find(|(_, object)| object.is_local_player)?;
+        Some(object.props.as_ref()?.zoom_fov)
+    }
+
+    /// The physics override of the local player, once its object is known.
+    pub fn local_player_physics_override(&self) -> Option<PhysicsOverride> {
+        let mut query = self.ecs.query::<&ActiveObject>();
+        let (_, object) = query.iter().find(|(_, object)| object.is_local_player)?;
+        Some(object.physics_override)
+    }
+
+    /// Returns Some if the object lost health, except for the local
+    /// player, whose health is sent separately. Dead objects leave a smoke
+    /// puff.
+    pub fn process_object_message(
+        &mut self,
+        id: u16,
+        message: ActiveObjectCommand,
+    ) -> Option<Damage> {
+        let object = self
+            .active_objects
+            .get(&id)
+            .and_then(|&entity| self.ecs.get::<&mut ActiveObject>(entity).ok());
+        let Some(mut object) = object else {
+            println!("Received message for unknown active object {}", id);
+            return None;
+        };
+        let damage = object.process_message(message)?;
+        if object.is_local_player {
+            return None;
+        }
+        if damage.died {
+            let pos = object.pos;
+            drop(object);
+            self.ecs.spawn((SmokePuff { pos, age: 0.0 },));
+        }
+        Some(damage)
+    }
+
+    /// Movement system for active objects, called every frame. The object
+    /// of the local player follows `player`.
+    pub fn step_active_objects(&mut self, dtime: f32, player: &PlayerPos) {
+        for (_, object) in self.ecs.query_mut::<&mut ActiveObject>() {
+            if object.is_local_player {
+                object.follow_local_player(player);
+            }
+            object.step(dtime);
+        }
+        self.update_attachments();
+
+        let mut expired = Vec::new();
+        for (entity, puff) in self.ecs.query_mut::<&mut SmokePuff>() {
+            puff.age += dtime;
+            if puff.age > SmokePuff::LIFETIME {
+                expired.push(entity);
+            }
+        }
+        for entity in expired {
+            self.ecs.despawn(entity).unwrap();
+        }
+    }
+
+    /// Lighting system for active objects: objects take the brightest light
+    /// of the nodes around them. Outside of the loaded map, they are lit by
+    /// the sun.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::updateLight
+    pub fn update_object_light(&mut self, map: &LuantiMap, node_def: &NodeDefManager) {
+        for (_, object) in self.ecs.query_mut::<&mut ActiveObject>() {
+            let light = object
+                .light_positions()
+                .into_iter()
+                .filter_map(|pos| {
+                    let node = map.get_node(&MapNodePos((pos + 0.5).floor().as_i16vec3()))?;
+                    let def = node_def.get_with_fallback(node.content_id);
+                    Some(light::vertex_light(
+                        light::get_light(node, LightBank::Day, def),
+                        light::get_light(node, LightBank::Night, def),
+                        def.light_source,
+                    ))
+                })
+                .reduce(Vec2::max);
+            object.light = light.unwrap_or(light::vertex_light(LIGHT_SUN, 0, 0));
+        }
+    }
+
+    /// Returns the position and rotation of an object, following its
+    /// attachments.
+    fn object_transform(&self, object: &ActiveObject, depth: u32) -> (Vec3, Quat) {
+        let parent = object
+            .attachment
+            .as_ref()
+            .filter(|_| depth < Self::MAX_ATTACHMENT_DEPTH)
+            .and_then(|attachment| {
+                let entity = *self.active_objects.get(&attachment.parent_id)?;
+                Some((attachment, self.ecs.get::<&ActiveObject>(entity).ok()?))
+            });
+        match parent {
+            Some((attachment, parent)) => {
+                let (parent_pos, parent_rotation) = self.object_transform(&parent, depth + 1);
+                (
+                    parent_pos + parent_rotation * attachment.position,
+                    parent_rotation * rotation_to_quat(attachment.rotation),
+                )
+            }
+            None => (object.pos, rotation_to_quat(object.rotation)),
+        }
+    }
+
+    /// Moves attached objects along with their parents.
+    // Compare to Luanti, client/content_cao.cpp, GenericCAO::updateAttachments
+    fn update_attachments(&mut self) {
+        let transforms: Vec<(hecs::Entity, Vec3, Quat)> = self
+            .ecs
+            .query::<&ActiveObject>()
+            .iter()
+            .filter(|(_, object)| object.attachment.is_some())
+            .map(|(entity, object)| {
+                let (pos, rotation) = self.object_transform(object, 0);
+                (entity, pos, rotation)
+            })
+            .collect();
+        for (entity, pos, rotation) in transforms {
+            let mut object = self.ecs.get::<&mut ActiveObject>(entity).unwrap();
+            object.pos = pos;
+            object.rotation = quat_to_rotation(rotation);
+        }
+    }
+}